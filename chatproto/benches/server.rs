@@ -0,0 +1,187 @@
+//! compares the sharded reference server with the same server behind a single lock, as in the lab
+//! guidance, when many clients send and poll at the same time
+
+use std::{collections::HashMap, net::IpAddr, sync::Arc};
+
+use async_std::sync::Mutex;
+use async_trait::async_trait;
+#[cfg(feature = "federation")]
+use chatproto::{
+  admin::PeerAuditEntry,
+  messages::{Outgoing, ServerMessage, ServerReply},
+};
+use chatproto::{
+  admin::{AuditEntry, Operator},
+  core::{MessageServer, ServerConfig},
+  messages::{
+    Action, Address, AdminCommand, AdminReply, Chunk, ClientError, ClientId, ClientMessage,
+    ClientPollReply, ClientReply, HistoryPage, HistoryQuery, Sequence, ServerId,
+  },
+  solutions::reference::Server,
+};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+
+/// every call waits for the previous one to complete
+struct SingleLock<M>(Mutex<M>);
+
+#[async_trait]
+impl<M: MessageServer + Send + Sync> MessageServer for SingleLock<M> {
+  const GROUP_NAME: &'static str = "single lock";
+
+  fn with_config(id: ServerId, config: ServerConfig) -> Self {
+    SingleLock(Mutex::new(M::with_config(id, config)))
+  }
+
+  async fn register_local_client(&self, name: String) -> ClientId {
+    self.0.lock().await.register_local_client(name).await
+  }
+
+  async fn list_users(&self) -> HashMap<ClientId, String> {
+    self.0.lock().await.list_users().await
+  }
+
+  async fn list_addresses(&self) -> HashMap<ClientId, Address> {
+    self.0.lock().await.list_addresses().await
+  }
+
+  async fn resolve(&self, address: &Address) -> Option<ClientId> {
+    self.0.lock().await.resolve(address).await
+  }
+
+  async fn handle_sequenced_message<A: Send>(&self, msg: Sequence<A>) -> Result<A, ClientError> {
+    self.0.lock().await.handle_sequenced_message(msg).await
+  }
+
+  async fn client_poll(&self, client: ClientId) -> ClientPollReply {
+    self.0.lock().await.client_poll(client).await
+  }
+
+  async fn upload_chunk(&self, src: ClientId, chunk: Chunk) -> Result<u64, ClientError> {
+    self.0.lock().await.upload_chunk(src, chunk).await
+  }
+
+  async fn history(&self, client: ClientId, query: HistoryQuery) -> HistoryPage {
+    self.0.lock().await.history(client, query).await
+  }
+
+  async fn download_chunk(
+    &self,
+    client: ClientId,
+    src: ClientId,
+    hash: [u8; 32],
+    offset: u64,
+  ) -> Result<Chunk, ClientError> {
+    let server = self.0.lock().await;
+    server.download_chunk(client, src, hash, offset).await
+  }
+
+  async fn admin(&self, by: Operator, command: AdminCommand) -> Result<AdminReply, ClientError> {
+    self.0.lock().await.admin(by, command).await
+  }
+
+  async fn address_banned(&self, addr: IpAddr) -> bool {
+    self.0.lock().await.address_banned(addr).await
+  }
+
+  async fn audit_log(&self) -> Vec<AuditEntry> {
+    self.0.lock().await.audit_log().await
+  }
+
+  #[cfg(feature = "federation")]
+  async fn peer_audit_log(&self) -> Vec<PeerAuditEntry> {
+    self.0.lock().await.peer_audit_log().await
+  }
+
+  async fn permitted(&self, client: ClientId, action: Action) -> Result<(), ClientError> {
+    self.0.lock().await.permitted(client, action).await
+  }
+
+  async fn registration_permitted(&self, addr: IpAddr) -> Result<(), ClientError> {
+    self.0.lock().await.registration_permitted(addr).await
+  }
+
+  async fn handle_client_message(&self, src: ClientId, msg: ClientMessage) -> Vec<ClientReply> {
+    self.0.lock().await.handle_client_message(src, msg).await
+  }
+
+  #[cfg(feature = "federation")]
+  async fn handle_server_message(&self, msg: ServerMessage) -> ServerReply {
+    self.0.lock().await.handle_server_message(msg).await
+  }
+
+  #[cfg(feature = "federation")]
+  async fn announces(&self) -> Vec<Outgoing<ServerMessage>> {
+    self.0.lock().await.announces().await
+  }
+
+  #[cfg(feature = "federation")]
+  async fn route_to(&self, destination: ServerId) -> Option<Vec<ServerId>> {
+    self.0.lock().await.route_to(destination).await
+  }
+}
+
+const MESSAGES: usize = 64;
+
+/// pairs of clients, the first one sending to the second one
+async fn setup<M: MessageServer>(pairs: usize) -> (Arc<M>, Vec<(ClientId, ClientId)>) {
+  let server: M = MessageServer::new(ServerId::default());
+  let mut clients = Vec::new();
+  for n in 0..pairs {
+    let src = server.register_local_client(format!("sender {n}")).await;
+    let dest = server.register_local_client(format!("recipient {n}")).await;
+    clients.push((src, dest));
+  }
+  (Arc::new(server), clients)
+}
+
+/// each sender sends MESSAGES messages while its recipient polls them, all pairs at the same time
+async fn exchange<M: MessageServer + Send + Sync + 'static>(
+  server: &Arc<M>,
+  clients: &[(ClientId, ClientId)],
+) {
+  let mut tasks = Vec::new();
+  for (src, dest) in clients.iter().copied() {
+    let sender = server.clone();
+    tasks.push(async_std::task::spawn(async move {
+      for i in 0..MESSAGES {
+        let msg = ClientMessage::Text {
+          dest,
+          content: i.to_string(),
+        };
+        sender.handle_client_message(src, msg).await;
+      }
+    }));
+    let poller = server.clone();
+    tasks.push(async_std::task::spawn(async move {
+      let mut received = 0;
+      while received < MESSAGES {
+        match poller.client_poll(dest).await {
+          ClientPollReply::Nothing => async_std::task::yield_now().await,
+          _ => received += 1,
+        }
+      }
+    }));
+  }
+  for t in tasks {
+    t.await;
+  }
+}
+
+fn bench_exchange<M: MessageServer + Send + Sync + 'static>(c: &mut Criterion, name: &str) {
+  let mut group = c.benchmark_group(name);
+  for pairs in [1, 4, 16] {
+    let (server, clients) = async_std::task::block_on(setup::<M>(pairs));
+    group.bench_with_input(BenchmarkId::from_parameter(pairs), &pairs, |b, _| {
+      b.iter(|| async_std::task::block_on(exchange(&server, &clients)))
+    });
+  }
+  group.finish();
+}
+
+fn concurrent_clients(c: &mut Criterion) {
+  bench_exchange::<Server>(c, "sharded");
+  bench_exchange::<SingleLock<Server>>(c, "single lock");
+}
+
+criterion_group!(benches, concurrent_clients);
+criterion_main!(benches);
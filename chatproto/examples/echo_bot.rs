@@ -0,0 +1,25 @@
+//! a bot that repeats what it is told
+//!
+//! cargo run -p chatproto --example echo_bot -- 127.0.0.1:4666
+
+use std::net::SocketAddr;
+
+use chatproto::bot::{Bot, UdpTransport};
+
+fn main() -> anyhow::Result<()> {
+  pretty_env_logger::init();
+  let target: SocketAddr = std::env::args()
+    .nth(1)
+    .unwrap_or_else(|| "127.0.0.1:4666".to_string())
+    .parse()?;
+  async_std::task::block_on(async {
+    let transport = UdpTransport::new(target).await?;
+    let mut bot = Bot::new("echo", transport)
+      .command("echo", |rq| Some(rq.args.join(" ")))
+      .command("status", |_| Some("up and running".to_string()))
+      .on_message(|_, content| Some(content.to_string()));
+    let id = bot.connect().await?;
+    println!("echo bot registered as {}", id);
+    bot.run().await
+  })
+}
@@ -0,0 +1,242 @@
+use std::{collections::HashSet, fs::OpenOptions, io::Write, net::IpAddr, path::PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+  history::now,
+  messages::{AdminCommand, AdminReply, ClientError, ClientId, ServerId},
+};
+
+/// who sent an administration command
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Operator {
+  /// a client, that must have the admin role
+  Client(ClientId),
+  /// the local admin socket of the server, always allowed
+  Console,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct AuditEntry {
+  /// milliseconds since the unix epoch
+  pub timestamp: u64,
+  pub by: Operator,
+  pub command: AdminCommand,
+  pub outcome: Result<AdminReply, ClientError>,
+}
+
+/// a message from another server that the federation policy rejected
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct PeerAuditEntry {
+  /// milliseconds since the unix epoch
+  pub timestamp: u64,
+  /// the server that was not accepted, or that sent what was not accepted
+  pub server: ServerId,
+  pub reason: String,
+}
+
+/// admins, bans and the audit log of a server
+pub struct AdminState {
+  admins: HashSet<ClientId>,
+  banned: HashSet<ClientId>,
+  banned_addresses: HashSet<IpAddr>,
+  audit: Vec<AuditEntry>,
+  peer_audit: Vec<PeerAuditEntry>,
+  /// both audit logs are also appended to this file, one json entry per line
+  audit_file: Option<PathBuf>,
+}
+
+impl AdminState {
+  pub fn new(admins: HashSet<ClientId>, audit_file: Option<PathBuf>) -> Self {
+    AdminState {
+      admins,
+      banned: HashSet::new(),
+      banned_addresses: HashSet::new(),
+      audit: Vec::new(),
+      peer_audit: Vec::new(),
+      audit_file,
+    }
+  }
+
+  pub fn is_admin(&self, by: &Operator) -> bool {
+    match by {
+      Operator::Console => true,
+      Operator::Client(c) => self.admins.contains(c) && !self.banned.contains(c),
+    }
+  }
+
+  pub fn is_banned(&self, client: &ClientId) -> bool {
+    self.banned.contains(client)
+  }
+
+  pub fn is_address_banned(&self, addr: &IpAddr) -> bool {
+    self.banned_addresses.contains(addr)
+  }
+
+  /// applies the commands that only change the bans or the admin role
+  /// returns None for the commands that must be handled by the server
+  pub fn apply(&mut self, command: &AdminCommand) -> Option<AdminReply> {
+    match command {
+      AdminCommand::Ban(c) => self.banned.insert(*c),
+      AdminCommand::Unban(c) => self.banned.remove(c),
+      AdminCommand::BanAddress(a) => self.banned_addresses.insert(*a),
+      AdminCommand::UnbanAddress(a) => self.banned_addresses.remove(a),
+      AdminCommand::Grant(c) => self.admins.insert(*c),
+      AdminCommand::Revoke(c) => self.admins.remove(c),
+      AdminCommand::Kick(_)
+      | AdminCommand::Broadcast(_)
+      | AdminCommand::MailboxDepth(_)
+      | AdminCommand::AssignRole { .. } => return None,
+    };
+    Some(AdminReply::Done)
+  }
+
+  /// forgets everything about a client that was force-unregistered
+  pub fn forget(&mut self, client: &ClientId) {
+    self.admins.remove(client);
+  }
+
+  /// records a command in the audit log, denied commands included
+  pub fn record(
+    &mut self,
+    by: Operator,
+    command: AdminCommand,
+    outcome: &Result<AdminReply, ClientError>,
+  ) {
+    let entry = AuditEntry {
+      timestamp: now(),
+      by,
+      command,
+      outcome: outcome.clone(),
+    };
+    log::info!("audit: {:?}", entry);
+    self.append(&entry);
+    self.audit.push(entry);
+  }
+
+  /// records a message rejected by the federation policy
+  pub fn record_peer(&mut self, server: ServerId, reason: String) {
+    let entry = PeerAuditEntry {
+      timestamp: now(),
+      server,
+      reason,
+    };
+    log::warn!("audit: {:?}", entry);
+    self.append(&entry);
+    self.peer_audit.push(entry);
+  }
+
+  fn append<E: Serialize>(&self, entry: &E) {
+    if let Some(path) = &self.audit_file {
+      let written = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(anyhow::Error::from)
+        .and_then(|mut file| {
+          serde_json::to_writer(&mut file, entry)?;
+          file.write_all(b"\n")?;
+          Ok(())
+        });
+      if let Err(rr) = written {
+        log::error!(
+          "could not write the audit log to {}: {}",
+          path.display(),
+          rr
+        );
+      }
+    }
+  }
+
+  pub fn audit(&self) -> &[AuditEntry] {
+    &self.audit
+  }
+
+  pub fn peer_audit(&self) -> &[PeerAuditEntry] {
+    &self.peer_audit
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn roles_and_bans() {
+    let admin = ClientId::default();
+    let user = ClientId::default();
+    let mut state = AdminState::new(HashSet::from([admin]), None);
+    assert!(state.is_admin(&Operator::Console));
+    assert!(state.is_admin(&Operator::Client(admin)));
+    assert!(!state.is_admin(&Operator::Client(user)));
+
+    assert_eq!(
+      state.apply(&AdminCommand::Grant(user)),
+      Some(AdminReply::Done)
+    );
+    assert!(state.is_admin(&Operator::Client(user)));
+    assert_eq!(
+      state.apply(&AdminCommand::Ban(user)),
+      Some(AdminReply::Done)
+    );
+    // banned admins lose their privileges
+    assert!(state.is_banned(&user));
+    assert!(!state.is_admin(&Operator::Client(user)));
+    state.apply(&AdminCommand::Unban(user));
+    assert!(state.is_admin(&Operator::Client(user)));
+
+    let addr = IpAddr::from([10, 0, 0, 1]);
+    state.apply(&AdminCommand::BanAddress(addr));
+    assert!(state.is_address_banned(&addr));
+    assert!(!state.is_address_banned(&IpAddr::from([10, 0, 0, 2])));
+    assert_eq!(state.apply(&AdminCommand::MailboxDepth(user)), None);
+  }
+
+  #[test]
+  fn audit_file() {
+    let path = std::env::temp_dir().join(format!("chatproto-audit-{}", uuid::Uuid::new_v4()));
+    let mut state = AdminState::new(HashSet::new(), Some(path.clone()));
+    let user = ClientId::default();
+    state.record(
+      Operator::Client(user),
+      AdminCommand::Kick(user),
+      &Err(ClientError::NotAdmin),
+    );
+    state.record(
+      Operator::Console,
+      AdminCommand::Broadcast("hello".into()),
+      &Ok(AdminReply::Reached(2)),
+    );
+    let written = std::fs::read_to_string(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    let entries = written
+      .lines()
+      .map(|l| serde_json::from_str::<AuditEntry>(l).unwrap())
+      .collect::<Vec<_>>();
+    assert_eq!(entries, state.audit());
+  }
+
+  #[test]
+  fn peer_audit() {
+    let path = std::env::temp_dir().join(format!("chatproto-audit-{}", uuid::Uuid::new_v4()));
+    let mut state = AdminState::new(HashSet::new(), Some(path.clone()));
+    let user = ClientId::default();
+    let server = ServerId::default();
+    state.record(
+      Operator::Console,
+      AdminCommand::Ban(user),
+      &Ok(AdminReply::Done),
+    );
+    state.record_peer(server, "denied".into());
+    let written = std::fs::read_to_string(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    let lines: Vec<&str> = written.lines().collect();
+    assert_eq!(lines.len(), 2);
+    assert_eq!(
+      serde_json::from_str::<PeerAuditEntry>(lines[1]).unwrap(),
+      state.peer_audit()[0]
+    );
+    assert_eq!(state.audit().len(), 1);
+    assert_eq!(state.peer_audit()[0].server, server);
+  }
+}
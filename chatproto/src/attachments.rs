@@ -0,0 +1,446 @@
+use std::{
+  collections::{HashMap, HashSet},
+  time::Duration,
+};
+
+use crypto_hash::{digest, Algorithm};
+
+use crate::{
+  core::CHUNK_SIZE,
+  messages::{AttachmentMeta, Chunk, ClientError, ClientId},
+};
+
+/// SHA256 of an attachment content
+pub fn content_hash(data: &[u8]) -> [u8; 32] {
+  digest(Algorithm::SHA256, data).try_into().unwrap()
+}
+
+/// splits an attachment content into chunks of CHUNK_SIZE bytes
+/// an empty attachment is made of a single empty chunk
+pub fn chunks(data: &[u8]) -> Vec<Chunk> {
+  let hash = content_hash(data);
+  if data.is_empty() {
+    return vec![Chunk {
+      hash,
+      size: 0,
+      offset: 0,
+      data: Vec::new(),
+    }];
+  }
+  data
+    .chunks(CHUNK_SIZE)
+    .enumerate()
+    .map(|(n, part)| Chunk {
+      hash,
+      size: data.len() as u64,
+      offset: (n * CHUNK_SIZE) as u64,
+      data: part.to_vec(),
+    })
+    .collect()
+}
+
+struct Attachment {
+  size: u64,
+  data: Vec<u8>,
+  /// when the first chunk was received, in milliseconds since the unix epoch
+  created: u64,
+  /// offsets of the chunks that were received
+  received: HashSet<u64>,
+  /// clients allowed to download the attachment, besides its owner, with the offsets they downloaded
+  readers: HashMap<ClientId, HashSet<u64>>,
+}
+
+impl Attachment {
+  fn received_bytes(&self) -> u64 {
+    self
+      .received
+      .iter()
+      .map(|offset| (self.size - offset).min(CHUNK_SIZE as u64))
+      .sum()
+  }
+
+  fn complete(&self) -> bool {
+    self.received_bytes() == self.size
+  }
+
+  /// an empty attachment has a single empty chunk, like in chunks()
+  fn chunk_count(&self) -> usize {
+    self.size.div_ceil(CHUNK_SIZE as u64).max(1) as usize
+  }
+}
+
+/// attachments uploaded by clients, with a per client quota
+/// an attachment is removed, and its quota released, once all its recipients downloaded it, or when it
+/// expires
+pub struct AttachmentStore {
+  quota: u64,
+  /// how long attachments are kept, forever if None
+  ttl: Option<Duration>,
+  attachments: HashMap<ClientId, HashMap<[u8; 32], Attachment>>,
+  usage: HashMap<ClientId, u64>,
+}
+
+impl AttachmentStore {
+  pub fn new(quota: u64, ttl: Option<Duration>) -> Self {
+    AttachmentStore {
+      quota,
+      ttl,
+      attachments: HashMap::new(),
+      usage: HashMap::new(),
+    }
+  }
+
+  /// stores a chunk received at the given time, in milliseconds since the unix epoch, and returns the
+  /// number of bytes received so far
+  /// the space for the whole attachment is reserved when its first chunk is received, after the expired
+  /// attachments of the owner were removed
+  pub fn upload(&mut self, owner: ClientId, chunk: Chunk, now: u64) -> Result<u64, ClientError> {
+    let expected_len = chunk
+      .size
+      .checked_sub(chunk.offset)
+      .map(|remaining| remaining.min(CHUNK_SIZE as u64));
+    if !chunk.offset.is_multiple_of(CHUNK_SIZE as u64)
+      || expected_len != Some(chunk.data.len() as u64)
+    {
+      return Err(ClientError::InvalidChunk);
+    }
+    self.expire(owner, now);
+    if !self.contains(owner, &chunk.hash) {
+      let used = self.usage.entry(owner).or_default();
+      if used.saturating_add(chunk.size) > self.quota {
+        return Err(ClientError::QuotaExceeded);
+      }
+      *used += chunk.size;
+      self.attachments.entry(owner).or_default().insert(
+        chunk.hash,
+        Attachment {
+          size: chunk.size,
+          data: vec![0; chunk.size as usize],
+          created: now,
+          received: HashSet::new(),
+          readers: HashMap::new(),
+        },
+      );
+    }
+    let attachment = self.get_mut(owner, &chunk.hash).unwrap();
+    if attachment.size != chunk.size {
+      return Err(ClientError::InvalidChunk);
+    }
+    let start = chunk.offset as usize;
+    attachment.data[start..start + chunk.data.len()].copy_from_slice(&chunk.data);
+    attachment.received.insert(chunk.offset);
+    let received = attachment.received_bytes();
+    if received == attachment.size && content_hash(&attachment.data) != chunk.hash {
+      self.remove(owner, chunk.hash);
+      return Err(ClientError::InvalidChunk);
+    }
+    Ok(received)
+  }
+
+  fn contains(&self, owner: ClientId, hash: &[u8; 32]) -> bool {
+    self
+      .attachments
+      .get(&owner)
+      .is_some_and(|a| a.contains_key(hash))
+  }
+
+  fn get_mut(&mut self, owner: ClientId, hash: &[u8; 32]) -> Option<&mut Attachment> {
+    self.attachments.get_mut(&owner)?.get_mut(hash)
+  }
+
+  /// removes an attachment, releasing its quota
+  pub fn remove(&mut self, owner: ClientId, hash: [u8; 32]) {
+    let owned = match self.attachments.get_mut(&owner) {
+      Some(owned) => owned,
+      None => return,
+    };
+    if let Some(attachment) = owned.remove(&hash) {
+      if owned.is_empty() {
+        self.attachments.remove(&owner);
+      }
+      self
+        .usage
+        .entry(owner)
+        .and_modify(|used| *used -= attachment.size);
+    }
+  }
+
+  /// removes the attachments of the owner that are older than the ttl
+  pub fn expire(&mut self, owner: ClientId, now: u64) {
+    let ttl = match self.ttl {
+      Some(ttl) => ttl.as_millis() as u64,
+      None => return,
+    };
+    let expired = match self.attachments.get(&owner) {
+      Some(owned) => owned
+        .iter()
+        .filter(|(_, a)| now.saturating_sub(a.created) >= ttl)
+        .map(|(hash, _)| *hash)
+        .collect::<Vec<_>>(),
+      None => return,
+    };
+    for hash in expired {
+      self.remove(owner, hash);
+    }
+  }
+
+  /// allows a client to download a completely uploaded attachment
+  pub fn share(
+    &mut self,
+    owner: ClientId,
+    meta: &AttachmentMeta,
+    reader: ClientId,
+  ) -> Result<(), ClientError> {
+    match self.get_mut(owner, &meta.hash) {
+      Some(attachment) if attachment.size == meta.size && attachment.complete() => {
+        attachment.readers.entry(reader).or_default();
+        Ok(())
+      }
+      _ => Err(ClientError::UnknownAttachment),
+    }
+  }
+
+  /// the chunk starting at offset, if the attachment is complete and the client is allowed to read it
+  /// once every recipient downloaded all the chunks, or once it expired, the attachment is removed
+  pub fn download(
+    &mut self,
+    client: ClientId,
+    owner: ClientId,
+    hash: [u8; 32],
+    offset: u64,
+    now: u64,
+  ) -> Result<Chunk, ClientError> {
+    self.expire(owner, now);
+    let attachment = match self.get_mut(owner, &hash) {
+      Some(a) if a.complete() && (client == owner || a.readers.contains_key(&client)) => a,
+      _ => return Err(ClientError::UnknownAttachment),
+    };
+    if !offset.is_multiple_of(CHUNK_SIZE as u64) || offset >= attachment.size.max(1) {
+      return Err(ClientError::InvalidChunk);
+    }
+    let start = offset as usize;
+    let end = (start + CHUNK_SIZE).min(attachment.data.len());
+    let chunk = Chunk {
+      hash,
+      size: attachment.size,
+      offset,
+      data: attachment.data[start..end].to_vec(),
+    };
+    let chunk_count = attachment.chunk_count();
+    if let Some(downloaded) = attachment.readers.get_mut(&client) {
+      downloaded.insert(offset);
+      if downloaded.len() == chunk_count {
+        attachment.readers.remove(&client);
+        if attachment.readers.is_empty() {
+          self.remove(owner, hash);
+        }
+      }
+    }
+    Ok(chunk)
+  }
+
+  /// attachment bytes currently stored for a client
+  pub fn usage(&self, owner: ClientId) -> u64 {
+    self.usage.get(&owner).copied().unwrap_or_default()
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  fn meta(data: &[u8]) -> AttachmentMeta {
+    AttachmentMeta {
+      name: "file".into(),
+      size: data.len() as u64,
+      hash: content_hash(data),
+    }
+  }
+
+  #[test]
+  fn out_of_order_upload() {
+    let owner = ClientId::default();
+    let reader = ClientId::default();
+    let data = (0..10000_u32).map(|n| n as u8).collect::<Vec<_>>();
+    let mut store = AttachmentStore::new(1 << 20, None);
+    let mut parts = chunks(&data);
+    assert_eq!(parts.len(), 3);
+    parts.reverse();
+    let received = parts
+      .into_iter()
+      .map(|chunk| store.upload(owner, chunk, 0).unwrap())
+      .collect::<Vec<_>>();
+    assert_eq!(received, [10000 - 8192, 10000 - 4096, 10000]);
+    assert_eq!(store.usage(owner), 10000);
+
+    let hash = content_hash(&data);
+    assert_eq!(
+      store.download(reader, owner, hash, 0, 0),
+      Err(ClientError::UnknownAttachment)
+    );
+    store.share(owner, &meta(&data), reader).unwrap();
+    let mut downloaded = Vec::new();
+    while (downloaded.len() as u64) < 10000 {
+      let chunk = store
+        .download(reader, owner, hash, downloaded.len() as u64, 0)
+        .unwrap();
+      downloaded.extend(chunk.data);
+    }
+    assert_eq!(downloaded, data);
+  }
+
+  #[test]
+  fn incomplete_upload() {
+    let owner = ClientId::default();
+    let data = vec![1; 5000];
+    let mut store = AttachmentStore::new(1 << 20, None);
+    store.upload(owner, chunks(&data).remove(0), 0).unwrap();
+    assert_eq!(
+      store.share(owner, &meta(&data), ClientId::default()),
+      Err(ClientError::UnknownAttachment)
+    );
+    assert_eq!(
+      store.download(owner, owner, content_hash(&data), 0, 0),
+      Err(ClientError::UnknownAttachment)
+    );
+  }
+
+  #[test]
+  fn hash_mismatch() {
+    let owner = ClientId::default();
+    let data = vec![1; 100];
+    let mut store = AttachmentStore::new(1 << 20, None);
+    let mut chunk = chunks(&data).remove(0);
+    chunk.data[12] = 2;
+    assert_eq!(
+      store.upload(owner, chunk, 0),
+      Err(ClientError::InvalidChunk)
+    );
+    assert_eq!(store.usage(owner), 0);
+  }
+
+  #[test]
+  fn misaligned_chunk() {
+    let owner = ClientId::default();
+    let mut store = AttachmentStore::new(1 << 20, None);
+    let mut chunk = chunks(&[1; 5000]).remove(1);
+    chunk.offset -= 1;
+    assert_eq!(
+      store.upload(owner, chunk, 0),
+      Err(ClientError::InvalidChunk)
+    );
+  }
+
+  #[test]
+  fn quota() {
+    let owner = ClientId::default();
+    let mut store = AttachmentStore::new(6000, None);
+    for chunk in chunks(&[1; 5000]) {
+      store.upload(owner, chunk, 0).unwrap();
+    }
+    assert_eq!(
+      store.upload(owner, chunks(&[2; 1001]).remove(0), 0),
+      Err(ClientError::QuotaExceeded)
+    );
+    store
+      .upload(owner, chunks(&[2; 1000]).remove(0), 0)
+      .unwrap();
+    // quotas are per client
+    store
+      .upload(ClientId::default(), chunks(&[2; 1001]).remove(0), 0)
+      .unwrap();
+    store.remove(owner, content_hash(&[1; 5000]));
+    assert_eq!(store.usage(owner), 1000);
+  }
+
+  #[test]
+  fn released_after_download() {
+    let owner = ClientId::default();
+    let readers = [ClientId::default(), ClientId::default()];
+    let data = vec![3; 5000];
+    let hash = content_hash(&data);
+    let mut store = AttachmentStore::new(1 << 20, None);
+    for chunk in chunks(&data) {
+      store.upload(owner, chunk, 0).unwrap();
+    }
+    for reader in readers {
+      store.share(owner, &meta(&data), reader).unwrap();
+    }
+    // downloads by the owner, or of the same chunk again, do not count
+    store.download(owner, owner, hash, 0, 0).unwrap();
+    store.download(readers[0], owner, hash, 0, 0).unwrap();
+    store.download(readers[0], owner, hash, 0, 0).unwrap();
+    store.download(readers[0], owner, hash, 4096, 0).unwrap();
+    assert_eq!(store.usage(owner), 5000);
+    assert_eq!(
+      store.download(readers[0], owner, hash, 0, 0),
+      Err(ClientError::UnknownAttachment)
+    );
+    store.download(readers[1], owner, hash, 4096, 0).unwrap();
+    store.download(readers[1], owner, hash, 0, 0).unwrap();
+    assert_eq!(store.usage(owner), 0);
+    assert_eq!(
+      store.download(owner, owner, hash, 0, 0),
+      Err(ClientError::UnknownAttachment)
+    );
+  }
+
+  #[test]
+  fn empty_attachment() {
+    let (owner, reader) = (ClientId::default(), ClientId::default());
+    let hash = content_hash(&[]);
+    let mut store = AttachmentStore::new(1 << 20, None);
+    for chunk in chunks(&[]) {
+      assert_eq!(store.upload(owner, chunk, 0), Ok(0));
+    }
+    store.share(owner, &meta(&[]), reader).unwrap();
+    assert_eq!(
+      store.download(reader, owner, hash, CHUNK_SIZE as u64, 0),
+      Err(ClientError::InvalidChunk)
+    );
+    let chunk = store.download(reader, owner, hash, 0, 0).unwrap();
+    assert_eq!((chunk.size, chunk.data.len()), (0, 0));
+    // the only reader got it, so it is gone
+    assert_eq!(
+      store.download(owner, owner, hash, 0, 0),
+      Err(ClientError::UnknownAttachment)
+    );
+  }
+
+  #[test]
+  fn expiry() {
+    let owner = ClientId::default();
+    let mut store = AttachmentStore::new(6000, Some(Duration::from_secs(60)));
+    for chunk in chunks(&[1; 5000]) {
+      store.upload(owner, chunk, 1000).unwrap();
+    }
+    assert_eq!(
+      store.upload(owner, chunks(&[2; 5000]).remove(0), 60999),
+      Err(ClientError::QuotaExceeded)
+    );
+    store
+      .upload(owner, chunks(&[2; 5000]).remove(0), 61000)
+      .unwrap();
+    assert_eq!(store.usage(owner), 5000);
+    assert_eq!(
+      store.download(owner, owner, content_hash(&[1; 5000]), 0, 0),
+      Err(ClientError::UnknownAttachment)
+    );
+
+    // expired attachments are not downloaded, even when the owner uploads nothing more
+    let reader = ClientId::default();
+    let data = [2; 5000];
+    for chunk in chunks(&data).into_iter().skip(1) {
+      store.upload(owner, chunk, 61000).unwrap();
+    }
+    store.share(owner, &meta(&data), reader).unwrap();
+    store
+      .download(reader, owner, content_hash(&data), 0, 120999)
+      .unwrap();
+    assert_eq!(
+      store.download(reader, owner, content_hash(&data), 4096, 121000),
+      Err(ClientError::UnknownAttachment)
+    );
+    assert_eq!(store.usage(owner), 0);
+  }
+}
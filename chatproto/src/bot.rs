@@ -0,0 +1,448 @@
+use std::{collections::HashMap, io::Cursor, net::SocketAddr, sync::Arc, time::Duration};
+
+use async_std::net::UdpSocket;
+use async_trait::async_trait;
+
+use crate::{
+  client::Client,
+  core::{MessageServer, WORKPROOF_STRENGTH},
+  messages::{
+    ClientError, ClientId, ClientMessage, ClientPollReply, ClientQuery, ClientReply, MessageId,
+    Sequence,
+  },
+  netproto::{decode, encode},
+  workproof::gen_workproof,
+};
+
+/// how a bot reaches its server
+/// a server that does not know the client fails with a ClientError::UnknownClient, that the bot recovers from
+#[async_trait]
+pub trait Transport: Send {
+  /// registers a new client with the given name
+  async fn register(&mut self, name: &str) -> anyhow::Result<ClientId>;
+  /// sends a sequenced ClientQuery::Message
+  async fn message(&mut self, sq: Sequence<ClientQuery>) -> anyhow::Result<Vec<ClientReply>>;
+  /// sends a sequenced ClientQuery::Poll
+  async fn poll(&mut self, sq: Sequence<ClientQuery>) -> anyhow::Result<ClientPollReply>;
+  /// called after a failure, before trying again
+  async fn reconnect(&mut self) -> anyhow::Result<()> {
+    Ok(())
+  }
+}
+
+/// talks to a server over UDP, using the netproto encoding
+pub struct UdpTransport {
+  target: SocketAddr,
+  socket: UdpSocket,
+  timeout: Duration,
+}
+
+impl UdpTransport {
+  pub async fn new(target: SocketAddr) -> anyhow::Result<Self> {
+    Ok(UdpTransport {
+      target,
+      socket: Self::bind(target).await?,
+      timeout: Duration::from_secs(2),
+    })
+  }
+
+  async fn bind(target: SocketAddr) -> anyhow::Result<UdpSocket> {
+    let local = if target.is_ipv4() {
+      "0.0.0.0:0"
+    } else {
+      "[::]:0"
+    };
+    let socket = UdpSocket::bind(local).await?;
+    socket.connect(target).await?;
+    Ok(socket)
+  }
+
+  async fn exchange<X, F>(&self, sq: &Sequence<ClientQuery>, f: F) -> anyhow::Result<X>
+  where
+    F: FnOnce(&mut Cursor<Vec<u8>>) -> anyhow::Result<X>,
+  {
+    let mut wr = Cursor::new(Vec::new());
+    encode::sequence(&mut wr, sq, encode::client_query)?;
+    self.socket.send(&wr.into_inner()).await?;
+    let mut buf = vec![0u8; 8192];
+    let n = async_std::future::timeout(self.timeout, self.socket.recv(&mut buf)).await??;
+    let mut cursor = Cursor::new(buf[..n].to_vec());
+    f(&mut cursor)
+  }
+}
+
+#[async_trait]
+impl Transport for UdpTransport {
+  async fn register(&mut self, name: &str) -> anyhow::Result<ClientId> {
+    let tempid = ClientId::default();
+    let workproof = gen_workproof((&tempid).into(), WORKPROOF_STRENGTH, u128::MAX).unwrap();
+    let sq = Sequence {
+      seqid: 0,
+      src: tempid,
+      workproof,
+      content: ClientQuery::Register(name.to_string()),
+    };
+    self.exchange(&sq, decode::clientid).await
+  }
+
+  async fn message(&mut self, sq: Sequence<ClientQuery>) -> anyhow::Result<Vec<ClientReply>> {
+    let replies = self.exchange(&sq, decode::client_replies).await?;
+    // the single error of a message refused before looking at its destinations
+    if replies[..] == [ClientReply::Error(ClientError::UnknownClient)] {
+      return Err(ClientError::UnknownClient.into());
+    }
+    Ok(replies)
+  }
+
+  async fn poll(&mut self, sq: Sequence<ClientQuery>) -> anyhow::Result<ClientPollReply> {
+    match self.exchange(&sq, decode::client_poll_reply).await? {
+      ClientPollReply::Error(rr) => Err(rr.into()),
+      reply => Ok(reply),
+    }
+  }
+
+  async fn reconnect(&mut self) -> anyhow::Result<()> {
+    self.socket = Self::bind(self.target).await?;
+    Ok(())
+  }
+}
+
+/// talks to a server running in the same process
+pub struct LocalTransport<M> {
+  server: Arc<M>,
+}
+
+impl<M> LocalTransport<M> {
+  pub fn new(server: Arc<M>) -> Self {
+    LocalTransport { server }
+  }
+}
+
+#[async_trait]
+impl<M: MessageServer + Send + Sync + 'static> Transport for LocalTransport<M> {
+  async fn register(&mut self, name: &str) -> anyhow::Result<ClientId> {
+    Ok(self.server.register_local_client(name.to_string()).await)
+  }
+
+  async fn message(&mut self, sq: Sequence<ClientQuery>) -> anyhow::Result<Vec<ClientReply>> {
+    let src = sq.src;
+    match self.server.handle_sequenced_message(sq).await? {
+      ClientQuery::Message(msg) => Ok(self.server.handle_client_message(src, msg).await),
+      q => anyhow::bail!("expected a message, got {:?}", q),
+    }
+  }
+
+  async fn poll(&mut self, sq: Sequence<ClientQuery>) -> anyhow::Result<ClientPollReply> {
+    let src = sq.src;
+    match self.server.handle_sequenced_message(sq).await? {
+      ClientQuery::Poll => Ok(self.server.client_poll(src).await),
+      q => anyhow::bail!("expected a poll, got {:?}", q),
+    }
+  }
+}
+
+/// a `!command` received by a bot
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Request {
+  pub src: ClientId,
+  pub id: MessageId,
+  /// command name, without the leading '!'
+  pub command: String,
+  pub args: Vec<String>,
+}
+
+impl Request {
+  /// parses "!command arg1 arg2", returns None for plain messages
+  pub fn parse(src: ClientId, id: MessageId, content: &str) -> Option<Self> {
+    let rest = content.strip_prefix('!')?;
+    if rest.starts_with(char::is_whitespace) {
+      return None;
+    }
+    let mut words = rest.split_whitespace();
+    let command = words.next()?.to_string();
+    Some(Request {
+      src,
+      id,
+      command,
+      args: words.map(|w| w.to_string()).collect(),
+    })
+  }
+}
+
+/// handles a command, the returned text is sent back to the sender
+pub type Handler = Box<dyn Fn(&Request) -> Option<String> + Send + Sync>;
+
+/// handles a message that is not a command
+pub type MessageHandler = Box<dyn Fn(ClientId, &str) -> Option<String> + Send + Sync>;
+
+/// an automated user, that answers `!command` messages
+pub struct Bot<T> {
+  name: String,
+  transport: T,
+  client: Option<Client>,
+  commands: HashMap<String, Handler>,
+  fallback: Option<MessageHandler>,
+  poll_interval: Duration,
+  max_retries: u32,
+}
+
+impl<T: Transport> Bot<T> {
+  pub fn new(name: &str, transport: T) -> Self {
+    Bot {
+      name: name.to_string(),
+      transport,
+      client: None,
+      commands: HashMap::new(),
+      fallback: None,
+      poll_interval: Duration::from_secs(1),
+      max_retries: 8,
+    }
+  }
+
+  /// registers a handler for `!name`
+  pub fn command<F>(mut self, name: &str, handler: F) -> Self
+  where
+    F: Fn(&Request) -> Option<String> + Send + Sync + 'static,
+  {
+    self.commands.insert(name.to_string(), Box::new(handler));
+    self
+  }
+
+  /// registers a handler for messages that are not commands, they are ignored otherwise
+  pub fn on_message<F>(mut self, handler: F) -> Self
+  where
+    F: Fn(ClientId, &str) -> Option<String> + Send + Sync + 'static,
+  {
+    self.fallback = Some(Box::new(handler));
+    self
+  }
+
+  /// time between two polls of an empty mailbox
+  pub fn poll_interval(mut self, interval: Duration) -> Self {
+    self.poll_interval = interval;
+    self
+  }
+
+  /// number of reconnection attempts before giving up
+  pub fn max_retries(mut self, retries: u32) -> Self {
+    self.max_retries = retries;
+    self
+  }
+
+  /// the id of the bot, if registered
+  pub fn id(&self) -> Option<ClientId> {
+    self.client.as_ref().map(|c| c.id())
+  }
+
+  /// registers the bot
+  pub async fn connect(&mut self) -> anyhow::Result<ClientId> {
+    let id = self.transport.register(&self.name).await?;
+    log::info!("bot {} registered as {}", self.name, id);
+    self.client = Some(Client::new(id));
+    Ok(id)
+  }
+
+  fn sequence(&mut self, query: ClientQuery) -> anyhow::Result<Sequence<ClientQuery>> {
+    match self.client.as_mut() {
+      Some(client) => Ok(client.sequence(query)),
+      None => anyhow::bail!("bot {} is not registered", self.name),
+    }
+  }
+
+  /// sends a text message
+  pub async fn send(
+    &mut self,
+    dest: ClientId,
+    content: String,
+  ) -> anyhow::Result<Vec<ClientReply>> {
+    let sq = self.sequence(ClientQuery::Message(ClientMessage::Text { dest, content }))?;
+    self.transport.message(sq).await
+  }
+
+  fn dispatch(&self, src: ClientId, id: MessageId, content: &str) -> Option<String> {
+    match Request::parse(src, id, content) {
+      Some(request) => match self.commands.get(&request.command) {
+        Some(handler) => handler(&request),
+        None => Some(format!("unknown command: !{}", request.command)),
+      },
+      None => self.fallback.as_ref().and_then(|f| f(src, content)),
+    }
+  }
+
+  /// polls once and handles the received message
+  /// returns false when the mailbox was empty
+  pub async fn step(&mut self) -> anyhow::Result<bool> {
+    let sq = self.sequence(ClientQuery::Poll)?;
+    let (src, answer) = match self.transport.poll(sq).await? {
+      ClientPollReply::Nothing => return Ok(false),
+      ClientPollReply::Message { src, id, content } => (src, self.dispatch(src, id, &content)),
+      other => {
+        log::debug!("bot {} ignores {:?}", self.name, other);
+        return Ok(true);
+      }
+    };
+    if let Some(answer) = answer {
+      for reply in self.send(src, answer).await? {
+        if let ClientReply::Error(rr) = reply {
+          log::warn!("bot {} could not answer {}: {}", self.name, src, rr);
+        }
+      }
+    }
+    Ok(true)
+  }
+
+  /// tries to get back to a working state after an error, with an exponential backoff
+  /// the bot registers again if the server does not know it anymore
+  async fn recover(&mut self, rr: anyhow::Error) -> anyhow::Result<()> {
+    let mut last = rr;
+    for attempt in 0..self.max_retries {
+      log::warn!(
+        "bot {}: {}, retrying (attempt {})",
+        self.name,
+        last,
+        attempt + 1
+      );
+      let outcome = if last.downcast_ref::<ClientError>() == Some(&ClientError::UnknownClient) {
+        self.connect().await.map(|_| ())
+      } else {
+        self.transport.reconnect().await
+      };
+      match outcome {
+        Ok(()) => return Ok(()),
+        Err(rr) => last = rr,
+      }
+      async_std::task::sleep(Duration::from_millis(100 << attempt.min(8))).await;
+    }
+    Err(last)
+  }
+
+  /// registers if needed, and then handles messages forever
+  pub async fn run(&mut self) -> anyhow::Result<()> {
+    if self.client.is_none() {
+      self.connect().await?;
+    }
+    loop {
+      match self.step().await {
+        Ok(true) => (),
+        Ok(false) => async_std::task::sleep(self.poll_interval).await,
+        Err(rr) => self.recover(rr).await?,
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use std::collections::VecDeque;
+
+  use super::*;
+
+  /// replays scripted poll results, and records what the bot does
+  #[derive(Default)]
+  struct Scripted {
+    polls: VecDeque<anyhow::Result<ClientPollReply>>,
+    registered: Vec<ClientId>,
+    reconnects: usize,
+    sent: Vec<(u128, ClientMessage)>,
+  }
+
+  #[async_trait]
+  impl Transport for Scripted {
+    async fn register(&mut self, _name: &str) -> anyhow::Result<ClientId> {
+      let id = ClientId::default();
+      self.registered.push(id);
+      Ok(id)
+    }
+
+    async fn message(&mut self, sq: Sequence<ClientQuery>) -> anyhow::Result<Vec<ClientReply>> {
+      match sq.content {
+        ClientQuery::Message(msg) => self.sent.push((sq.seqid, msg)),
+        q => anyhow::bail!("unexpected {:?}", q),
+      }
+      Ok(vec![ClientReply::Delivered(0.into())])
+    }
+
+    async fn poll(&mut self, _sq: Sequence<ClientQuery>) -> anyhow::Result<ClientPollReply> {
+      self
+        .polls
+        .pop_front()
+        .unwrap_or(Ok(ClientPollReply::Nothing))
+    }
+
+    async fn reconnect(&mut self) -> anyhow::Result<()> {
+      self.reconnects += 1;
+      Ok(())
+    }
+  }
+
+  fn text(src: ClientId, content: &str) -> anyhow::Result<ClientPollReply> {
+    Ok(ClientPollReply::Message {
+      src,
+      id: 1.into(),
+      content: content.into(),
+    })
+  }
+
+  #[test]
+  fn parse() {
+    let src = ClientId::default();
+    let rq = Request::parse(src, 3.into(), "!deploy  prod now").unwrap();
+    assert_eq!(rq.command, "deploy");
+    assert_eq!(rq.args, ["prod", "now"]);
+    assert_eq!(Request::parse(src, 3.into(), "deploy prod"), None);
+    assert_eq!(Request::parse(src, 3.into(), "! deploy"), None);
+  }
+
+  #[test]
+  fn commands_and_recovery() {
+    async_std::task::block_on(async {
+      let user = ClientId::default();
+      let transport = Scripted {
+        polls: VecDeque::from([
+          text(user, "!status"),
+          Err(anyhow::anyhow!("network down")),
+          Err(ClientError::UnknownClient.into()),
+          text(user, "hello"),
+          text(user, "!reboot"),
+        ]),
+        ..Default::default()
+      };
+      let mut bot = Bot::new("test", transport)
+        .command("status", |_| Some("all good".into()))
+        .on_message(|_, content| Some(content.to_uppercase()))
+        .poll_interval(Duration::from_millis(1));
+      let first = bot.connect().await.unwrap();
+      let mut handled = 0;
+      loop {
+        match bot.step().await {
+          Ok(true) => handled += 1,
+          Ok(false) => break,
+          Err(rr) => bot.recover(rr).await.unwrap(),
+        }
+      }
+      assert_eq!(handled, 3);
+      let transport = &bot.transport;
+      assert_eq!(transport.reconnects, 1);
+      // registered again after the server forgot about the bot, sequences restart
+      assert_eq!(transport.registered.len(), 2);
+      assert_eq!(transport.registered[0], first);
+      assert_eq!(bot.id(), Some(transport.registered[1]));
+      let sent = transport
+        .sent
+        .iter()
+        .map(|(seqid, msg)| match msg {
+          ClientMessage::Text { dest, content } if *dest == user => (*seqid, content.as_str()),
+          m => panic!("unexpected message {:?}", m),
+        })
+        .collect::<Vec<_>>();
+      assert_eq!(
+        sent,
+        [
+          (2, "all good"),
+          (2, "HELLO"),
+          (4, "unknown command: !reboot")
+        ]
+      );
+    });
+  }
+}
@@ -14,6 +14,9 @@ impl Client {
   pub fn new(id: ClientId) -> Self {
     Client { id, curid: 0 }
   }
+  pub fn id(&self) -> ClientId {
+    self.id
+  }
   pub fn sequence<A>(&mut self, content: A) -> Sequence<A> {
     self.curid += 1;
     let workproof = gen_workproof((&self.id).into(), WORKPROOF_STRENGTH, u128::MAX).unwrap();
@@ -1,21 +1,97 @@
-use std::collections::HashMap;
+use std::{
+  collections::{HashMap, HashSet},
+  net::IpAddr,
+  path::PathBuf,
+  sync::Arc,
+  time::Duration,
+};
 
 use async_trait::async_trait;
 
-use crate::messages::{ClientError, ClientId, ClientMessage, ClientPollReply, ClientReply, Sequence, ServerId};
 #[cfg(feature = "federation")]
-use crate::messages::{ServerMessage, ServerReply};
+use crate::{
+  admin::PeerAuditEntry,
+  messages::{Outgoing, ServerMessage, ServerReply},
+};
+use crate::{
+  admin::{AuditEntry, Operator},
+  federation::FederationPolicy,
+  filter::MessageFilter,
+  history::Retention,
+  mailbox::{MailboxQuota, OverflowPolicy},
+  messages::{
+    Action, Address, AdminCommand, AdminReply, Chunk, ClientError, ClientId, ClientMessage,
+    ClientPollReply, ClientReply, HistoryPage, HistoryQuery, Sequence, ServerId,
+  },
+  roles::RoleConfig,
+  signing::SigningKey,
+};
 
 pub const MAILBOX_SIZE: usize = 256;
+/// maximum size of the message contents held in a mailbox
+pub const MAILBOX_BYTES: usize = 1024 * 1024;
 pub const WORKPROOF_STRENGTH: u32 = 8;
+/// maximum amount of attachment bytes stored for a single client
+pub const ATTACHMENT_QUOTA: u64 = 16 * 1024 * 1024;
+/// attachments are transfered in chunks of this size, so that they fit in a datagram
+pub const CHUNK_SIZE: usize = 4096;
+/// maximum number of messages in a history page
+pub const HISTORY_PAGE_SIZE: u64 = 32;
+/// maximum encoded size of the entries of a history page, so that it fits in the datagrams of the client
+pub const HISTORY_PAGE_BYTES: usize = 7168;
+/// how many times messages can be forwarded between servers, by default
+pub const MAX_HOPS: u32 = 16;
+
+/// server tunables
+#[derive(Clone, Debug, Default)]
+pub struct ServerConfig {
+  /// how long conversations are kept in the history
+  pub history: Retention,
+  /// limits of each client mailbox
+  pub mailbox: MailboxQuota,
+  /// what happens to messages sent to a full mailbox
+  pub overflow: OverflowPolicy,
+  /// content filters, applied in order to Text, MText and Edit messages
+  pub filters: Vec<Arc<dyn MessageFilter>>,
+  /// clients allowed to send administration commands
+  pub admins: HashSet<ClientId>,
+  /// file the audit log of administration commands is appended to
+  pub audit_log: Option<PathBuf>,
+  /// roles and their permissions
+  pub roles: RoleConfig,
+  /// how long attachments are kept when they are not downloaded by all their recipients, forever if None
+  pub attachment_ttl: Option<Duration>,
+  /// how long messages for unknown clients are kept, forever if None
+  pub delayed_ttl: Option<Duration>,
+  /// servers the announces are sent to
+  pub neighbours: Vec<ServerId>,
+  /// how long routes are kept when they are not announced again, forever if None
+  pub route_ttl: Option<Duration>,
+  /// how many times the messages of the local clients can be forwarded, MAX_HOPS if None
+  pub max_hops: Option<u32>,
+  /// the servers that are accepted, and how many clients they can announce
+  pub federation: FederationPolicy,
+  /// key this server signs its announces and messages with, a random one if None
+  pub signing_key: Option<SigningKey>,
+  /// human-readable name of this server, in the addresses of its clients, its uuid if None
+  pub name: Option<String>,
+}
 
 #[async_trait]
 pub trait MessageServer {
   /// group name
   const GROUP_NAME: &'static str;
 
+  /// create a new server, with the default configuration
+  fn new(id: ServerId) -> Self
+  where
+    Self: Sized,
+  {
+    Self::with_config(id, ServerConfig::default())
+  }
+
   /// create a new server, this is the constructor function
-  fn new(id: ServerId) -> Self;
+  fn with_config(id: ServerId, config: ServerConfig) -> Self;
 
   /// register a new client, that will then be able to send and receive messages.
   /// The first argument is the client screen name.
@@ -25,27 +101,122 @@ pub trait MessageServer {
   /// also lists known remote users if federation is enabled
   async fn list_users(&self) -> HashMap<ClientId, String>;
 
+  /// list known users with their qualified address, name@server
+  /// remote users of a server whose name is not known are qualified with its uuid
+  async fn list_addresses(&self) -> HashMap<ClientId, Address>;
+
+  /// the user with this address, None if there is none, or several of them
+  async fn resolve(&self, address: &Address) -> Option<ClientId>;
+
   /// handles a sequenced message
   /// you must verify:
   ///  * the workproof first, and then,
   ///  * that sequence numbers are increasing
+  /// banned clients get ClientError::Banned
   async fn handle_sequenced_message<A: Send>(&self, msg: Sequence<A>) -> Result<A, ClientError>;
 
   /// pull function for the client
   async fn client_poll(&self, client: ClientId) -> ClientPollReply;
 
+  /// stores an attachment chunk uploaded by a client, and returns the number of bytes received so far
+  /// * the content hash is checked once all chunks are received
+  /// * the total size of the attachments of a client must not exceed ATTACHMENT_QUOTA
+  /// * attachments stop counting once all their recipients downloaded every chunk, or after attachment_ttl
+  async fn upload_chunk(&self, src: ClientId, chunk: Chunk) -> Result<u64, ClientError>;
+
+  /// returns a page of the conversation between client and query.with, oldest message first
+  /// * only messages before query.before are returned, at most query.limit and HISTORY_PAGE_SIZE, and no more
+  ///   than fit in HISTORY_PAGE_BYTES
+  /// * edits and deletions are applied to the history
+  /// * messages are forgotten according to the configured retention
+  async fn history(&self, client: ClientId, query: HistoryQuery) -> HistoryPage;
+
+  /// fetches the chunk starting at offset of an attachment uploaded by src
+  /// only src and the recipients of an Attachment message can download it, until they all downloaded it
+  async fn download_chunk(
+    &self,
+    client: ClientId,
+    src: ClientId,
+    hash: [u8; 32],
+    offset: u64,
+  ) -> Result<Chunk, ClientError>;
+
+  /// handles an administration command, and records it in the audit log
+  /// * only the console and admins are allowed, others get NotAdmin (and the attempt is recorded)
+  /// * Kick unregisters the client, and drops its mailbox
+  /// * Broadcast stores a Notice in every local mailbox, and returns the number of mailboxes reached
+  /// * MailboxDepth returns UnknownClient for clients that are not local
+  /// * AssignRole returns UnknownRole if the role is not in the configuration
+  async fn admin(&self, by: Operator, command: AdminCommand) -> Result<AdminReply, ClientError>;
+
+  /// true if datagrams from this address must be ignored
+  async fn address_banned(&self, addr: IpAddr) -> bool;
+
+  /// the administration commands handled so far, oldest first
+  async fn audit_log(&self) -> Vec<AuditEntry>;
+
+  #[cfg(feature = "federation")]
+  /// the server messages rejected by the federation policy, oldest first
+  async fn peer_audit_log(&self) -> Vec<PeerAuditEntry>;
+
+  /// fails with PermissionDenied if the role of the client does not allow the action
+  async fn permitted(&self, client: ClientId, action: Action) -> Result<(), ClientError>;
+
+  /// fails with PermissionDenied if the role of the address does not allow Register
+  /// this is how the network layer checks registrations, that do not have a client yet
+  async fn registration_permitted(&self, addr: IpAddr) -> Result<(), ClientError>;
+
   /// handles a client message
   /// * if the user is unknown, it might be that it is remote, so messages should be kept until the user becomes known
   ///   as a result, the "Delayed" message should be sent
-  /// * until polled, messages are to be stored. There is a maximum mailbox size after which an error should be returned
+  ///   if the user is still unknown after delayed_ttl, the message is dropped, and the sender gets
+  ///   DelayedError::UnknownRecipient when polling
+  /// * MText destinations that are listed several times get the message once, with the same reply for each entry
+  /// * remote destinations reached through the same neighbour share a single Transfer, that is the reply for each
+  ///   of them, the network layer sends each distinct Transfer once
+  /// * until polled, messages are to be stored. When the mailbox quota (messages or bytes) is reached, the
+  ///   overflow policy applies: BoxFull is returned, the oldest messages are dropped, or messages are spilled to disk
+  /// * stored messages get a new MessageId, that is returned in the Delivered reply
+  /// * Edit and Delete messages rewrite or remove the message in place if it is still unread, otherwise
+  ///   an Edited or Deleted event is stored in the recipient mailbox
+  /// * Attachment messages are only delivered if the attachment was completely uploaded by src
+  /// * before anything is queued, the content goes through the configured filters, that can rewrite it or
+  ///   reject it with ClientError::Rejected (one error per destination)
+  /// * the role of src must allow MassMessage for MText messages to more than roles.max_recipients
+  ///   destinations, and RemoteMessage for remote destinations, or PermissionDenied is returned
   async fn handle_client_message(&self, src: ClientId, msg: ClientMessage) -> Vec<ClientReply>;
 
   #[cfg(feature = "federation")]
   /// handles a server message
+  /// * messages the federation policy does not accept are rejected with an Error that gives the reason,
+  ///   and recorded in the peer audit log: routes through servers that are not allowed, messages from them,
+  ///   announces of more clients than the limit of their server, and announces of local clients
   /// * might be an announce (which might trigger waiting messages to be sent)
-  /// * might be a message for this server, or another
+  ///   announces whose route already contains this server are ignored, and the ones that change the route
+  ///   to a server or its clients are passed on to the neighbours, with this server appended to the route
+  ///   delta announces only apply on top of the version they follow, a gap is answered with a SyncRequest to
+  ///   the neighbour it came from, and a sync request with a full delta (since version 0)
+  /// * might withdraw a link or clients, which is passed on like an announce
+  ///   messages for clients that can no longer be reached are kept as if they were unknown, until the route
+  ///   comes back, or until delayed_ttl
+  /// * might be a message for this server, or another, or both
+  ///   the local destinations get it directly, and the others are grouped by next hop, with an Outgoing per
+  ///   neighbour that only lists the destinations reached through it
+  ///   unknown destinations, full mailboxes, and messages that waited too long for a route are reported with a
+  ///   DeliveryError, that goes back to srcsrv and ends up in the mailbox of the sender as a DelayedError
+  ///   messages are forwarded with one hop less, the ones with no hops left are reported with HopLimit, and
+  ///   the destinations of a message (known by srcsrv and id) that were already handled are dropped
   async fn handle_server_message(&self, msg: ServerMessage) -> ServerReply;
 
+  #[cfg(feature = "federation")]
+  /// the announces the network layer sends periodically to the configured neighbours
+  /// * the local clients that changed since the previous announce, as a delta with a route made of this
+  ///   server only
+  /// * the clients of each known server, along the shortest route, with this server appended, as an empty
+  ///   delta for versioned servers
+  /// * no announce is sent to a server that already is in its route
+  async fn announces(&self) -> Vec<Outgoing<ServerMessage>>;
+
   #[cfg(feature = "federation")]
   /// gives the best route to a server
   /// as a first approximation, you can give any route
@@ -0,0 +1,179 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::{
+  messages::{ServerId, ServerMessage},
+  signing::{self, VerifyingKey},
+};
+
+/// the servers messages are accepted from
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum PeerList {
+  #[default]
+  Any,
+  /// only these servers
+  Allow(HashSet<ServerId>),
+  /// all the servers but these
+  Deny(HashSet<ServerId>),
+}
+
+/// what is accepted from the other servers
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FederationPolicy {
+  /// checked for every server of a route, and for the server of the sender of a message
+  pub peers: PeerList,
+  /// maximum number of clients a server can announce, unlimited if None
+  pub max_clients: Option<usize>,
+  /// limits for some servers, that replace max_clients
+  pub client_limits: HashMap<ServerId, usize>,
+  /// public keys of the servers, their announces and messages must be signed with them
+  pub trusted: HashMap<ServerId, VerifyingKey>,
+  /// rejects the announces and messages of the servers that are not trusted
+  pub require_signatures: bool,
+}
+
+impl FederationPolicy {
+  pub fn allows(&self, srv: &ServerId) -> bool {
+    match &self.peers {
+      PeerList::Any => true,
+      PeerList::Allow(allowed) => allowed.contains(srv),
+      PeerList::Deny(denied) => !denied.contains(srv),
+    }
+  }
+
+  /// the first server of the route that is not allowed, if there is one
+  pub fn check_route(&self, route: &[ServerId]) -> Result<(), ServerId> {
+    match route.iter().find(|srv| !self.allows(srv)) {
+      Some(srv) => Err(*srv),
+      None => Ok(()),
+    }
+  }
+
+  pub fn client_limit(&self, srv: &ServerId) -> Option<usize> {
+    self.client_limits.get(srv).copied().or(self.max_clients)
+  }
+
+  /// checks the signature of the server the message comes from, fails with the reason
+  /// deltas that change nothing only refresh the route, that is not signed, so they do not need a signature
+  pub fn check_signature(&self, message: &ServerMessage) -> Result<(), String> {
+    if let ServerMessage::AnnounceDelta {
+      since,
+      version,
+      joined,
+      left,
+      ..
+    } = message
+    {
+      if since == version && joined.is_empty() && left.is_empty() {
+        return Ok(());
+      }
+    }
+    let signer = match signing::signer(message) {
+      Some(s) => s,
+      None => return Ok(()),
+    };
+    match self.trusted.get(&signer) {
+      Some(key) if signing::verify(key, message) => Ok(()),
+      Some(_) => Err(format!("bad signature from {}", signer)),
+      None if self.require_signatures => Err(format!("{} is not trusted", signer)),
+      None => Ok(()),
+    }
+  }
+
+  /// fails with the limit when the server announces more clients
+  pub fn check_clients(&self, srv: &ServerId, count: usize) -> Result<(), usize> {
+    match self.client_limit(srv) {
+      Some(limit) if count > limit => Err(limit),
+      _ => Ok(()),
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::messages::{ClientId, DelayedError};
+
+  #[test]
+  fn peers_and_limits() {
+    let (a, b, c) = (
+      ServerId::default(),
+      ServerId::default(),
+      ServerId::default(),
+    );
+    let mut policy = FederationPolicy::default();
+    assert_eq!(policy.check_route(&[a, b, c]), Ok(()));
+    assert_eq!(policy.check_clients(&a, 100_000), Ok(()));
+
+    policy.peers = PeerList::Deny(HashSet::from([b]));
+    assert!(policy.allows(&a));
+    assert_eq!(policy.check_route(&[a, b, c]), Err(b));
+    policy.peers = PeerList::Allow(HashSet::from([a, b]));
+    assert_eq!(policy.check_route(&[a, b]), Ok(()));
+    assert_eq!(policy.check_route(&[c, b]), Err(c));
+
+    policy.max_clients = Some(10);
+    policy.client_limits.insert(b, 2);
+    assert_eq!(policy.check_clients(&a, 10), Ok(()));
+    assert_eq!(policy.check_clients(&a, 11), Err(10));
+    assert_eq!(policy.check_clients(&b, 3), Err(2));
+    assert_eq!(policy.client_limit(&c), Some(10));
+  }
+
+  #[test]
+  fn signatures() {
+    let (a, b) = (ServerId::default(), ServerId::default());
+    let key = signing::generate();
+    let announce = |origin| ServerMessage::Announce {
+      route: vec![origin],
+      clients: HashMap::new(),
+      signature: Vec::new(),
+      server_name: String::new(),
+      version: 1,
+    };
+    let mut signed = announce(a);
+    signing::sign(&key, &mut signed);
+    let mut policy = FederationPolicy::default();
+    assert_eq!(policy.check_signature(&announce(a)), Ok(()));
+
+    policy.trusted.insert(a, key.verifying_key());
+    assert_eq!(policy.check_signature(&signed), Ok(()));
+    assert!(policy.check_signature(&announce(a)).is_err());
+    assert_eq!(policy.check_signature(&announce(b)), Ok(()));
+    policy.require_signatures = true;
+    assert!(policy.check_signature(&announce(b)).is_err());
+
+    let refresh = ServerMessage::AnnounceDelta {
+      route: vec![a, b],
+      since: 4,
+      version: 4,
+      joined: HashMap::new(),
+      left: Vec::new(),
+      signature: Vec::new(),
+      server_name: String::new(),
+    };
+    assert_eq!(policy.check_signature(&refresh), Ok(()));
+    // the local withdrawal of a link has no signer, relayed ones are signed by the server that lost it
+    let withdraw = ServerMessage::Withdraw {
+      route: vec![b],
+      signature: Vec::new(),
+      version: 1,
+    };
+    assert_eq!(policy.check_signature(&withdraw), Ok(()));
+    let mut withdraw = ServerMessage::Withdraw {
+      route: vec![b, a],
+      signature: Vec::new(),
+      version: 1,
+    };
+    assert!(policy.check_signature(&withdraw).is_err());
+    signing::sign(&key, &mut withdraw);
+    assert_eq!(policy.check_signature(&withdraw), Ok(()));
+    let forged = ServerMessage::DeliveryError {
+      src: ClientId::default(),
+      srcsrv: b,
+      error: DelayedError::UnknownRecipient(ClientId::default()),
+      origin: a,
+      signature: Vec::new(),
+    };
+    assert!(policy.check_signature(&forged).is_err());
+  }
+}
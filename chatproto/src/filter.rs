@@ -0,0 +1,276 @@
+use std::{
+  collections::HashMap,
+  fmt::Debug,
+  sync::Mutex,
+  time::{Duration, Instant},
+};
+
+use crate::messages::ClientId;
+
+/// outcome of a filter
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Verdict {
+  Accept,
+  /// the message is accepted with this content instead
+  Rewrite(String),
+  /// the message is refused, the reason is sent back to the sender
+  Reject(String),
+}
+
+/// inspects messages before they are queued
+pub trait MessageFilter: Debug + Send + Sync {
+  fn check(&self, src: ClientId, dest: &[ClientId], content: &str) -> Verdict;
+}
+
+/// runs the filters in order, each one seeing the content rewritten by the previous ones
+/// returns the final content, or the reason of the first rejection
+pub fn apply<F: AsRef<dyn MessageFilter>>(
+  filters: &[F],
+  src: ClientId,
+  dest: &[ClientId],
+  content: &str,
+) -> Result<String, String> {
+  let mut content = content.to_string();
+  for filter in filters {
+    match filter.as_ref().check(src, dest, &content) {
+      Verdict::Accept => (),
+      Verdict::Rewrite(rewritten) => content = rewritten,
+      Verdict::Reject(reason) => return Err(reason),
+    }
+  }
+  Ok(content)
+}
+
+/// rejects messages longer than the given number of characters
+#[derive(Debug)]
+pub struct MaxLength(pub usize);
+
+impl MessageFilter for MaxLength {
+  fn check(&self, _src: ClientId, _dest: &[ClientId], content: &str) -> Verdict {
+    if content.chars().count() > self.0 {
+      Verdict::Reject(format!("message longer than {} characters", self.0))
+    } else {
+      Verdict::Accept
+    }
+  }
+}
+
+/// rejects messages containing one of the words, ignoring case
+#[derive(Debug)]
+pub struct BannedWords(pub Vec<String>);
+
+impl MessageFilter for BannedWords {
+  fn check(&self, _src: ClientId, _dest: &[ClientId], content: &str) -> Verdict {
+    let lower = content.to_lowercase();
+    let found = lower
+      .split(|c: char| !c.is_alphanumeric())
+      .find(|w| self.0.iter().any(|banned| banned.to_lowercase() == *w));
+    match found {
+      Some(w) => Verdict::Reject(format!("banned word: {}", w)),
+      None => Verdict::Accept,
+    }
+  }
+}
+
+/// replaces links with a placeholder
+#[derive(Debug)]
+pub struct StripLinks;
+
+fn is_link(word: &str) -> bool {
+  ["http://", "https://", "www."]
+    .iter()
+    .any(|prefix| word.to_lowercase().starts_with(prefix))
+}
+
+impl MessageFilter for StripLinks {
+  fn check(&self, _src: ClientId, _dest: &[ClientId], content: &str) -> Verdict {
+    let mut found = false;
+    // words keep the whitespace that follows them, so that it is left untouched
+    let stripped = content
+      .split_inclusive(char::is_whitespace)
+      .map(|part| {
+        let word = part.trim_end_matches(char::is_whitespace);
+        if is_link(word) {
+          found = true;
+          format!("[link removed]{}", &part[word.len()..])
+        } else {
+          part.to_string()
+        }
+      })
+      .collect::<String>();
+    if found {
+      Verdict::Rewrite(stripped)
+    } else {
+      Verdict::Accept
+    }
+  }
+}
+
+/// last message of a client, and how many times in a row it was sent
+#[derive(Debug)]
+struct Repeated {
+  content: String,
+  count: usize,
+  at: Instant,
+}
+
+/// spam heuristics: too many recipients at once, or the same content sent over and over
+#[derive(Debug)]
+pub struct Spam {
+  pub max_recipients: usize,
+  /// number of identical messages a client can send in a row
+  pub max_repeats: usize,
+  /// identical messages only count as repeats when they are sent within this duration of each other
+  pub window: Duration,
+  last: Mutex<HashMap<ClientId, Repeated>>,
+  /// the clients that did not send anything within the window are forgotten at most once per window
+  pruned: Mutex<Instant>,
+}
+
+impl Spam {
+  pub fn new(max_recipients: usize, max_repeats: usize, window: Duration) -> Self {
+    Spam {
+      max_recipients,
+      max_repeats,
+      window,
+      last: Mutex::new(HashMap::new()),
+      pruned: Mutex::new(Instant::now()),
+    }
+  }
+
+  fn check_at(&self, src: ClientId, dest: &[ClientId], content: &str, now: Instant) -> Verdict {
+    if dest.len() > self.max_recipients {
+      return Verdict::Reject(format!("more than {} recipients", self.max_recipients));
+    }
+    let mut last = self.last.lock().unwrap();
+    {
+      let mut pruned = self.pruned.lock().unwrap();
+      if now.duration_since(*pruned) >= self.window {
+        last.retain(|_, r| now.duration_since(r.at) < self.window);
+        *pruned = now;
+      }
+    }
+    let entry = last.entry(src).or_insert_with(|| Repeated {
+      content: String::new(),
+      count: 0,
+      at: now,
+    });
+    if entry.content == content && now.duration_since(entry.at) < self.window {
+      entry.count += 1;
+    } else {
+      entry.content = content.to_string();
+      entry.count = 1;
+    }
+    entry.at = now;
+    if entry.count > self.max_repeats {
+      Verdict::Reject("repeated message".into())
+    } else {
+      Verdict::Accept
+    }
+  }
+}
+
+impl MessageFilter for Spam {
+  fn check(&self, src: ClientId, dest: &[ClientId], content: &str) -> Verdict {
+    self.check_at(src, dest, content, Instant::now())
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use std::sync::Arc;
+
+  use super::*;
+
+  #[test]
+  fn stacking() {
+    let src = ClientId::default();
+    let dest = [ClientId::default()];
+    let filters: Vec<Arc<dyn MessageFilter>> = vec![
+      Arc::new(StripLinks),
+      Arc::new(MaxLength(20)),
+      Arc::new(BannedWords(vec!["Spam".into()])),
+    ];
+    assert_eq!(
+      apply(
+        &filters,
+        src,
+        &dest,
+        "see https://example.com/a/very/long/path"
+      ),
+      Ok("see [link removed]".into())
+    );
+    assert_eq!(
+      apply(&filters, src, &dest, "this is way too long for us"),
+      Err("message longer than 20 characters".into())
+    );
+    assert_eq!(
+      apply(&filters, src, &dest, "no SPAM, please"),
+      Err("banned word: spam".into())
+    );
+    // only whole words are banned
+    assert_eq!(apply(&filters, src, &dest, "spammer"), Ok("spammer".into()));
+  }
+
+  #[test]
+  fn links_after_any_whitespace() {
+    let src = ClientId::default();
+    let dest = [ClientId::default()];
+    assert_eq!(
+      StripLinks.check(src, &dest, "see\nhttp://x"),
+      Verdict::Rewrite("see\n[link removed]".into())
+    );
+    assert_eq!(
+      StripLinks.check(src, &dest, "a\twww.x.org  b http://y\n"),
+      Verdict::Rewrite("a\t[link removed]  b [link removed]\n".into())
+    );
+    assert_eq!(StripLinks.check(src, &dest, "no\nlinks"), Verdict::Accept);
+  }
+
+  #[test]
+  fn spam() {
+    let src = ClientId::default();
+    let dest = [ClientId::default(), ClientId::default()];
+    let filter = Spam::new(1, 2, Duration::from_secs(60));
+    assert!(matches!(filter.check(src, &dest, "hi"), Verdict::Reject(_)));
+    assert_eq!(filter.check(src, &dest[..1], "hi"), Verdict::Accept);
+    assert_eq!(filter.check(src, &dest[..1], "hi"), Verdict::Accept);
+    assert!(matches!(
+      filter.check(src, &dest[..1], "hi"),
+      Verdict::Reject(_)
+    ));
+    assert_eq!(
+      filter.check(ClientId::default(), &dest[..1], "hi"),
+      Verdict::Accept
+    );
+    assert_eq!(filter.check(src, &dest[..1], "hello"), Verdict::Accept);
+  }
+
+  #[test]
+  fn spam_window() {
+    let src = ClientId::default();
+    let dest = [ClientId::default()];
+    let window = Duration::from_secs(60);
+    let filter = Spam::new(1, 1, window);
+    let start = Instant::now();
+    assert_eq!(filter.check_at(src, &dest, "hi", start), Verdict::Accept);
+    assert!(matches!(
+      filter.check_at(src, &dest, "hi", start + window / 2),
+      Verdict::Reject(_)
+    ));
+    // the same message is fine once the window is over
+    let later = start + window * 2;
+    assert_eq!(filter.check_at(src, &dest, "hi", later), Verdict::Accept);
+    for _ in 0..10 {
+      filter.check_at(ClientId::default(), &dest, "hi", later);
+    }
+    assert_eq!(filter.last.lock().unwrap().len(), 11);
+    // the clients that did not send anything within the window are forgotten
+    let other = ClientId::default();
+    assert_eq!(
+      filter.check_at(other, &dest, "hi", later + window),
+      Verdict::Accept
+    );
+    assert_eq!(filter.last.lock().unwrap().len(), 1);
+  }
+}
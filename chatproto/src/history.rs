@@ -0,0 +1,280 @@
+use std::{
+  collections::{HashMap, VecDeque},
+  time::{Duration, SystemTime},
+};
+
+use crate::{
+  core::HISTORY_PAGE_BYTES,
+  messages::{ClientId, HistoryBound, HistoryEntry, HistoryPage, MessageId},
+};
+
+/// how long conversations are kept
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Retention {
+  /// maximum number of messages kept per conversation
+  pub max_messages: usize,
+  /// messages older than this are forgotten
+  pub max_age: Option<Duration>,
+}
+
+impl Default for Retention {
+  fn default() -> Self {
+    Retention {
+      max_messages: 1000,
+      max_age: None,
+    }
+  }
+}
+
+/// milliseconds since the unix epoch, as used in history timestamps
+pub fn now() -> u64 {
+  SystemTime::now()
+    .duration_since(SystemTime::UNIX_EPOCH)
+    .map(|d| d.as_millis() as u64)
+    .unwrap_or_default()
+}
+
+/// size of a number in the network protocol
+fn number_len(n: u128) -> usize {
+  match n {
+    0..251 => 1,
+    251..0x1_0000 => 3,
+    0x1_0000..0x1_0000_0000 => 5,
+    0x1_0000_0000..0x1_0000_0000_0000_0000 => 9,
+    _ => 17,
+  }
+}
+
+/// size of an encoded history entry
+fn encoded_len(e: &HistoryEntry) -> usize {
+  number_len((&e.id).into())
+    + 17
+    + number_len(e.timestamp as u128)
+    + number_len(e.content.len() as u128)
+    + e.content.len()
+}
+
+/// conversations are identified by the pair of their participants, smallest first
+pub(crate) fn conversation(a: ClientId, b: ClientId) -> (ClientId, ClientId) {
+  (a.min(b), a.max(b))
+}
+
+/// messages exchanged between pairs of clients, ordered by message id
+pub struct HistoryStore {
+  retention: Retention,
+  conversations: HashMap<(ClientId, ClientId), VecDeque<HistoryEntry>>,
+}
+
+impl HistoryStore {
+  pub fn new(retention: Retention) -> Self {
+    HistoryStore {
+      retention,
+      conversations: HashMap::new(),
+    }
+  }
+
+  /// stores a message sent from src to dest at the given time
+  pub fn record(
+    &mut self,
+    src: ClientId,
+    dest: ClientId,
+    id: MessageId,
+    content: String,
+    timestamp: u64,
+  ) {
+    let entries = self
+      .conversations
+      .entry(conversation(src, dest))
+      .or_default();
+    let pos = entries.partition_point(|e| e.id < id);
+    entries.insert(
+      pos,
+      HistoryEntry {
+        id,
+        src,
+        timestamp,
+        content,
+      },
+    );
+    while entries.len() > self.retention.max_messages {
+      entries.pop_front();
+    }
+    self.expire(src, dest, timestamp);
+  }
+
+  /// rewrites a message, if it was sent by src
+  pub fn edit(&mut self, src: ClientId, dest: ClientId, id: MessageId, content: String) {
+    if let Some(entries) = self.conversations.get_mut(&conversation(src, dest)) {
+      for e in entries.iter_mut().filter(|e| e.id == id && e.src == src) {
+        e.content = content.clone();
+      }
+    }
+  }
+
+  /// forgets a message, if it was sent by src
+  pub fn delete(&mut self, src: ClientId, dest: ClientId, id: MessageId) {
+    if let Some(entries) = self.conversations.get_mut(&conversation(src, dest)) {
+      entries.retain(|e| !(e.id == id && e.src == src));
+    }
+  }
+
+  /// forgets the messages of the conversation between a and b that are older than the retention age
+  /// entries are ordered by id, and so roughly by time, only the oldest ones are looked at
+  pub fn expire(&mut self, a: ClientId, b: ClientId, now: u64) {
+    let max_age = match self.retention.max_age {
+      Some(a) => a.as_millis() as u64,
+      None => return,
+    };
+    let oldest = now.saturating_sub(max_age);
+    let key = conversation(a, b);
+    if let Some(entries) = self.conversations.get_mut(&key) {
+      while entries.front().is_some_and(|e| e.timestamp < oldest) {
+        entries.pop_front();
+      }
+      if entries.is_empty() {
+        self.conversations.remove(&key);
+      }
+    }
+  }
+
+  /// the last `limit` messages of the conversation between client and with, that are before the bound
+  /// the page is cut so that it encodes in less than HISTORY_PAGE_BYTES, a single message that is larger
+  /// than that is sent with its content truncated
+  pub fn page(
+    &self,
+    client: ClientId,
+    with: ClientId,
+    before: &HistoryBound,
+    limit: usize,
+  ) -> HistoryPage {
+    let entries = match self.conversations.get(&conversation(client, with)) {
+      None => {
+        return HistoryPage {
+          entries: Vec::new(),
+          more: false,
+        }
+      }
+      Some(e) => e,
+    };
+    let end = match before {
+      HistoryBound::Latest => entries.len(),
+      HistoryBound::Id(id) => entries.partition_point(|e| e.id < *id),
+      HistoryBound::Time(t) => entries.partition_point(|e| e.timestamp < *t),
+    };
+    let mut start = end;
+    let mut size = 0;
+    while start > 0 && end - start < limit {
+      size += encoded_len(&entries[start - 1]);
+      if size > HISTORY_PAGE_BYTES && start < end {
+        break;
+      }
+      start -= 1;
+    }
+    let mut page: Vec<HistoryEntry> = entries.range(start..end).cloned().collect();
+    if let [e] = &mut page[..] {
+      let excess = encoded_len(e).saturating_sub(HISTORY_PAGE_BYTES);
+      if excess > 0 {
+        let mut cut = e.content.len().saturating_sub(excess);
+        while !e.content.is_char_boundary(cut) {
+          cut -= 1;
+        }
+        e.content.truncate(cut);
+      }
+    }
+    HistoryPage {
+      entries: page,
+      more: start > 0,
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  fn ids(page: &HistoryPage) -> Vec<u128> {
+    page.entries.iter().map(|e| u128::from(&e.id)).collect()
+  }
+
+  #[test]
+  fn pagination() {
+    let c1 = ClientId::default();
+    let c2 = ClientId::default();
+    let mut store = HistoryStore::new(Retention::default());
+    for n in 0..10_u128 {
+      let (src, dest) = if n % 3 == 0 { (c2, c1) } else { (c1, c2) };
+      store.record(src, dest, n.into(), n.to_string(), 1000 + n as u64);
+    }
+    store.record(c1, ClientId::default(), 42.into(), "other".into(), 0);
+
+    let page = store.page(c2, c1, &HistoryBound::Latest, 4);
+    assert_eq!(ids(&page), [6, 7, 8, 9]);
+    assert!(page.more);
+    let page = store.page(c1, c2, &HistoryBound::Id(6.into()), 4);
+    assert_eq!(ids(&page), [2, 3, 4, 5]);
+    assert!(page.more);
+    let page = store.page(c1, c2, &HistoryBound::Id(2.into()), 4);
+    assert_eq!(ids(&page), [0, 1]);
+    assert!(!page.more);
+    let page = store.page(c1, c2, &HistoryBound::Time(1005), 2);
+    assert_eq!(ids(&page), [3, 4]);
+    assert_eq!(page.entries[0].src, c2);
+    assert_eq!(page.entries[1].src, c1);
+  }
+
+  #[test]
+  fn edit_and_delete() {
+    let c1 = ClientId::default();
+    let c2 = ClientId::default();
+    let mut store = HistoryStore::new(Retention::default());
+    store.record(c1, c2, 1.into(), "helo".into(), 0);
+    store.record(c1, c2, 2.into(), "oops".into(), 0);
+    store.edit(c1, c2, 1.into(), "hello".into());
+    // only the sender can edit its messages
+    store.edit(c2, c1, 1.into(), "hijacked".into());
+    store.delete(c1, c2, 2.into());
+    let page = store.page(c2, c1, &HistoryBound::Latest, 10);
+    assert_eq!(ids(&page), [1]);
+    assert_eq!(page.entries[0].content, "hello");
+  }
+
+  #[test]
+  fn retention() {
+    let c1 = ClientId::default();
+    let c2 = ClientId::default();
+    let mut store = HistoryStore::new(Retention {
+      max_messages: 3,
+      max_age: Some(Duration::from_millis(100)),
+    });
+    for n in 0..5_u128 {
+      store.record(c1, c2, n.into(), n.to_string(), 0);
+    }
+    let page = store.page(c1, c2, &HistoryBound::Latest, 10);
+    assert_eq!(ids(&page), [2, 3, 4]);
+    store.record(c1, c2, 5.into(), "5".into(), 101);
+    let page = store.page(c1, c2, &HistoryBound::Latest, 10);
+    assert_eq!(ids(&page), [5]);
+  }
+
+  #[test]
+  fn page_size() {
+    let c1 = ClientId::default();
+    let c2 = ClientId::default();
+    let mut store = HistoryStore::new(Retention::default());
+    for n in 0..10_u128 {
+      store.record(c1, c2, n.into(), "x".repeat(2000), 0);
+    }
+    let page = store.page(c1, c2, &HistoryBound::Latest, 10);
+    assert_eq!(ids(&page), [7, 8, 9]);
+    assert!(page.more);
+    let size = page.entries.iter().map(encoded_len).sum::<usize>();
+    assert!(size <= HISTORY_PAGE_BYTES);
+
+    // a message that does not fit alone is truncated
+    store.record(c1, c2, 10.into(), "é".repeat(HISTORY_PAGE_BYTES), 0);
+    let page = store.page(c1, c2, &HistoryBound::Latest, 10);
+    assert_eq!(ids(&page), [10]);
+    assert!(encoded_len(&page.entries[0]) <= HISTORY_PAGE_BYTES);
+    assert!(page.entries[0].content.chars().all(|c| c == 'é'));
+  }
+}
@@ -1,8 +1,21 @@
+pub mod admin;
+pub mod attachments;
+pub mod bot;
 pub mod client;
 pub mod core;
+pub mod federation;
+pub mod filter;
+pub mod history;
+pub mod mailbox;
 pub mod messages;
 pub mod netproto;
+pub mod roles;
+pub mod shard;
+pub mod signing;
+#[cfg(feature = "federation")]
+pub mod simulator;
 pub mod solutions;
-#[cfg(test)]
+#[cfg(any(test, feature = "conformance"))]
 pub mod testing;
+pub mod topology;
 pub mod workproof;
@@ -0,0 +1,469 @@
+use std::{collections::VecDeque, io::SeekFrom, path::PathBuf};
+
+use async_std::{
+  fs::{self, File, OpenOptions},
+  io::{prelude::*, BufReader},
+};
+
+use crate::{
+  core::{MAILBOX_BYTES, MAILBOX_SIZE},
+  messages::{ClientError, ClientId, ClientPollReply, MessageId},
+};
+
+/// limits of a mailbox, messages are refused (or dropped, or spilled) when any of them is reached
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MailboxQuota {
+  pub max_messages: usize,
+  /// total size of the stored message contents
+  pub max_bytes: usize,
+}
+
+impl Default for MailboxQuota {
+  fn default() -> Self {
+    MailboxQuota {
+      max_messages: MAILBOX_SIZE,
+      max_bytes: MAILBOX_BYTES,
+    }
+  }
+}
+
+/// what to do when a mailbox is full
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum OverflowPolicy {
+  /// refuse the new message with BoxFull
+  #[default]
+  Reject,
+  /// drop the oldest messages, the recipient is then told how many were lost
+  DropOldest,
+  /// store the new messages in a file in the given directory, until the mailbox is drained
+  /// once the file reaches max_bytes, or when it can not be written, messages are refused with BoxFull
+  Spill { dir: PathBuf, max_bytes: u64 },
+}
+
+/// size of a mailbox entry, when counting bytes
+fn weight(reply: &ClientPollReply) -> usize {
+  match reply {
+    ClientPollReply::Message { content, .. }
+    | ClientPollReply::Edited { content, .. }
+    | ClientPollReply::Notice(content) => content.len(),
+    ClientPollReply::Attachment { meta, .. } => meta.name.len(),
+    _ => 0,
+  }
+}
+
+/// messages stored on disk, one json value per line
+struct Spill {
+  path: PathBuf,
+  max_bytes: u64,
+  read_pos: u64,
+  /// size of the file
+  write_pos: u64,
+  count: usize,
+}
+
+impl Spill {
+  /// false if the file would grow over max_bytes
+  async fn push(&mut self, reply: &ClientPollReply) -> anyhow::Result<bool> {
+    let mut line = serde_json::to_vec(reply)?;
+    line.push(b'\n');
+    if self.write_pos + line.len() as u64 > self.max_bytes {
+      return Ok(false);
+    }
+    let mut file = OpenOptions::new()
+      .create(true)
+      .append(true)
+      .open(&self.path)
+      .await?;
+    file.write_all(&line).await?;
+    file.flush().await?;
+    self.write_pos += line.len() as u64;
+    self.count += 1;
+    Ok(true)
+  }
+
+  async fn pop(&mut self) -> anyhow::Result<ClientPollReply> {
+    let mut file = File::open(&self.path).await?;
+    file.seek(SeekFrom::Start(self.read_pos)).await?;
+    let mut line = String::new();
+    self.read_pos += BufReader::new(file).read_line(&mut line).await? as u64;
+    self.count -= 1;
+    if self.count == 0 {
+      self.clear().await;
+    }
+    Ok(serde_json::from_str(&line)?)
+  }
+
+  /// forgets the spilled messages, and removes the file
+  async fn clear(&mut self) {
+    self.count = 0;
+    self.read_pos = 0;
+    self.write_pos = 0;
+    if let Err(rr) = fs::remove_file(&self.path).await {
+      log::warn!("could not remove {}: {}", self.path.display(), rr);
+    }
+  }
+}
+
+/// unread messages of a client
+pub struct Mailbox {
+  owner: ClientId,
+  quota: MailboxQuota,
+  policy: OverflowPolicy,
+  entries: VecDeque<ClientPollReply>,
+  bytes: usize,
+  /// number of messages dropped since the last notice
+  dropped: u64,
+  spill: Option<Spill>,
+}
+
+impl Mailbox {
+  pub fn new(owner: ClientId, quota: MailboxQuota, policy: OverflowPolicy) -> Self {
+    let spill = match &policy {
+      OverflowPolicy::Spill { dir, max_bytes } => Some(Spill {
+        path: dir.join(format!("{}.mailbox", owner.0)),
+        max_bytes: *max_bytes,
+        read_pos: 0,
+        write_pos: 0,
+        count: 0,
+      }),
+      _ => None,
+    };
+    Mailbox {
+      owner,
+      quota,
+      policy,
+      entries: VecDeque::new(),
+      bytes: 0,
+      dropped: 0,
+      spill,
+    }
+  }
+
+  fn fits(&self, size: usize) -> bool {
+    self.entries.len() < self.quota.max_messages && self.bytes + size <= self.quota.max_bytes
+  }
+
+  /// stores a message, according to the overflow policy
+  pub async fn push(&mut self, reply: ClientPollReply) -> Result<(), ClientError> {
+    let size = weight(&reply);
+    let spilling = self.spill.as_ref().map(|s| s.count > 0).unwrap_or(false);
+    if !spilling && self.fits(size) {
+      self.bytes += size;
+      self.entries.push_back(reply);
+      return Ok(());
+    }
+    match (&self.policy, self.spill.as_mut()) {
+      (OverflowPolicy::Spill { .. }, Some(spill)) => match spill.push(&reply).await {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(ClientError::BoxFull(self.owner)),
+        Err(rr) => {
+          log::error!("could not spill the mailbox of {}: {}", self.owner, rr);
+          Err(ClientError::BoxFull(self.owner))
+        }
+      },
+      (OverflowPolicy::DropOldest, _)
+        if size <= self.quota.max_bytes && self.quota.max_messages > 0 =>
+      {
+        while !self.fits(size) {
+          if let Some(old) = self.entries.pop_front() {
+            self.bytes -= weight(&old);
+            self.dropped += 1;
+          }
+        }
+        self.bytes += size;
+        self.entries.push_back(reply);
+        Ok(())
+      }
+      _ => Err(ClientError::BoxFull(self.owner)),
+    }
+  }
+
+  /// the next message, preceded by a notice if messages were dropped
+  /// spilled messages that can not be read back are counted as dropped
+  pub async fn pop(&mut self) -> ClientPollReply {
+    if self.dropped > 0 {
+      let count = std::mem::take(&mut self.dropped);
+      return ClientPollReply::Dropped { count };
+    }
+    if let Some(reply) = self.entries.pop_front() {
+      self.bytes -= weight(&reply);
+      return reply;
+    }
+    match self.spill.as_mut() {
+      Some(spill) if spill.count > 0 => {
+        let count = spill.count as u64;
+        match spill.pop().await {
+          Ok(reply) => reply,
+          Err(rr) => {
+            log::error!("could not read the mailbox of {}: {}", self.owner, rr);
+            spill.clear().await;
+            ClientPollReply::Dropped { count }
+          }
+        }
+      }
+      _ => ClientPollReply::Nothing,
+    }
+  }
+
+  /// rewrites an unread message, returns false if it is not in memory
+  /// an edit that would take the mailbox over its byte quota is refused with BoxFull, with any policy: the message
+  /// keeps its place, so there is nothing to drop or spill instead
+  pub fn edit(&mut self, src: ClientId, id: MessageId, content: &str) -> Result<bool, ClientError> {
+    for reply in self.entries.iter_mut() {
+      if let ClientPollReply::Message {
+        src: msrc,
+        id: mid,
+        content: mcontent,
+      } = reply
+      {
+        if *msrc == src && *mid == id {
+          let bytes = self.bytes - mcontent.len() + content.len();
+          if content.len() > mcontent.len() && bytes > self.quota.max_bytes {
+            return Err(ClientError::BoxFull(self.owner));
+          }
+          self.bytes = bytes;
+          *mcontent = content.to_string();
+          return Ok(true);
+        }
+      }
+    }
+    Ok(false)
+  }
+
+  /// removes an unread message, returns false if it is not in memory
+  pub fn delete(&mut self, src: ClientId, id: MessageId) -> bool {
+    let pos = self.entries.iter().position(|reply| {
+      matches!(reply,
+        ClientPollReply::Message { src: msrc, id: mid, .. }
+        | ClientPollReply::Attachment { src: msrc, id: mid, .. }
+        if *msrc == src && *mid == id)
+    });
+    match pos.and_then(|p| self.entries.remove(p)) {
+      Some(old) => {
+        self.bytes -= weight(&old);
+        true
+      }
+      None => false,
+    }
+  }
+
+  /// number of unread messages, including spilled ones
+  pub fn len(&self) -> usize {
+    self.entries.len() + self.spill.as_ref().map(|s| s.count).unwrap_or(0)
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.len() == 0 && self.dropped == 0
+  }
+
+  /// size of the messages held in memory
+  pub fn bytes(&self) -> usize {
+    self.bytes
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use async_std::task::block_on;
+
+  use super::*;
+
+  fn text(src: ClientId, id: u128, content: &str) -> ClientPollReply {
+    ClientPollReply::Message {
+      src,
+      id: id.into(),
+      content: content.into(),
+    }
+  }
+
+  fn quota(max_messages: usize, max_bytes: usize) -> MailboxQuota {
+    MailboxQuota {
+      max_messages,
+      max_bytes,
+    }
+  }
+
+  fn spill_dir() -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("chatproto-spill-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+  }
+
+  #[test]
+  fn reject() {
+    block_on(async {
+      let owner = ClientId::default();
+      let src = ClientId::default();
+      let mut mbox = Mailbox::new(owner, quota(3, 10), OverflowPolicy::Reject);
+      mbox.push(text(src, 1, "12345")).await.unwrap();
+      mbox.push(text(src, 2, "12345")).await.unwrap();
+      assert_eq!(
+        mbox.push(text(src, 3, "1")).await,
+        Err(ClientError::BoxFull(owner))
+      );
+      assert_eq!(mbox.bytes(), 10);
+      assert_eq!(mbox.pop().await, text(src, 1, "12345"));
+      mbox.push(text(src, 3, "1")).await.unwrap();
+      mbox.push(text(src, 4, "")).await.unwrap();
+      assert_eq!(
+        mbox.push(text(src, 5, "")).await,
+        Err(ClientError::BoxFull(owner))
+      );
+    })
+  }
+
+  #[test]
+  fn drop_oldest() {
+    block_on(async {
+      let owner = ClientId::default();
+      let src = ClientId::default();
+      let mut mbox = Mailbox::new(owner, quota(3, 10), OverflowPolicy::DropOldest);
+      for n in 0..5 {
+        mbox.push(text(src, n, "1")).await.unwrap();
+      }
+      mbox.push(text(src, 5, "123456789")).await.unwrap();
+      assert_eq!(
+        mbox.push(text(src, 6, "12345678901")).await,
+        Err(ClientError::BoxFull(owner))
+      );
+      assert_eq!(mbox.pop().await, ClientPollReply::Dropped { count: 4 });
+      assert_eq!(mbox.pop().await, text(src, 4, "1"));
+      assert_eq!(mbox.pop().await, text(src, 5, "123456789"));
+      assert_eq!(mbox.pop().await, ClientPollReply::Nothing);
+      assert!(mbox.is_empty());
+    })
+  }
+
+  #[test]
+  fn spill() {
+    block_on(async {
+      let dir = spill_dir();
+      let owner = ClientId::default();
+      let src = ClientId::default();
+      let policy = OverflowPolicy::Spill {
+        dir: dir.clone(),
+        max_bytes: 1 << 20,
+      };
+      let mut mbox = Mailbox::new(owner, quota(2, 100), policy);
+      for n in 0..3 {
+        mbox.push(text(src, n, &n.to_string())).await.unwrap();
+      }
+      assert_eq!(mbox.len(), 3);
+      // once spilling, new messages go to disk to keep the ordering
+      assert_eq!(mbox.pop().await, text(src, 0, "0"));
+      mbox.push(text(src, 3, "3")).await.unwrap();
+      // spilled messages can not be edited in place
+      assert_eq!(mbox.edit(src, 1.into(), "one"), Ok(true));
+      assert_eq!(mbox.edit(src, 2.into(), "two"), Ok(false));
+      for (n, content) in [(1, "one"), (2, "2"), (3, "3")] {
+        assert_eq!(mbox.pop().await, text(src, n, content));
+      }
+      assert_eq!(mbox.pop().await, ClientPollReply::Nothing);
+      assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+      std::fs::remove_dir(&dir).unwrap();
+    })
+  }
+
+  #[test]
+  fn spill_limit() {
+    block_on(async {
+      let dir = spill_dir();
+      let owner = ClientId::default();
+      let src = ClientId::default();
+      let line = serde_json::to_vec(&text(src, 1, "1")).unwrap().len() as u64 + 1;
+      let policy = OverflowPolicy::Spill {
+        dir: dir.clone(),
+        max_bytes: 2 * line,
+      };
+      let mut mbox = Mailbox::new(owner, quota(1, 100), policy);
+      for n in 0..3 {
+        mbox.push(text(src, n, "1")).await.unwrap();
+      }
+      assert_eq!(
+        mbox.push(text(src, 3, "1")).await,
+        Err(ClientError::BoxFull(owner))
+      );
+      assert_eq!(mbox.len(), 3);
+      std::fs::remove_dir_all(&dir).unwrap();
+    })
+  }
+
+  #[test]
+  fn spill_errors() {
+    block_on(async {
+      // the spill directory does not exist, messages are refused once the memory is full
+      let dir = std::env::temp_dir().join(format!("chatproto-missing-{}", uuid::Uuid::new_v4()));
+      let owner = ClientId::default();
+      let src = ClientId::default();
+      let policy = OverflowPolicy::Spill {
+        dir: dir.clone(),
+        max_bytes: 1 << 20,
+      };
+      let mut mbox = Mailbox::new(owner, quota(1, 100), policy.clone());
+      mbox.push(text(src, 0, "0")).await.unwrap();
+      assert_eq!(
+        mbox.push(text(src, 1, "1")).await,
+        Err(ClientError::BoxFull(owner))
+      );
+      assert_eq!(mbox.pop().await, text(src, 0, "0"));
+      mbox.push(text(src, 2, "2")).await.unwrap();
+
+      // the spill file disappears, its messages are reported as dropped
+      std::fs::create_dir_all(&dir).unwrap();
+      let mut mbox = Mailbox::new(owner, quota(1, 100), policy);
+      for n in 0..3 {
+        mbox.push(text(src, n, &n.to_string())).await.unwrap();
+      }
+      std::fs::remove_file(dir.join(format!("{}.mailbox", owner.0))).unwrap();
+      assert_eq!(mbox.pop().await, text(src, 0, "0"));
+      assert_eq!(mbox.pop().await, ClientPollReply::Dropped { count: 2 });
+      assert!(mbox.is_empty());
+      mbox.push(text(src, 3, "3")).await.unwrap();
+      assert_eq!(mbox.pop().await, text(src, 3, "3"));
+      std::fs::remove_dir_all(&dir).unwrap();
+    })
+  }
+
+  #[test]
+  fn edit_and_delete() {
+    block_on(async {
+      let owner = ClientId::default();
+      let src = ClientId::default();
+      let mut mbox = Mailbox::new(owner, quota(10, 100), OverflowPolicy::Reject);
+      mbox.push(text(src, 1, "helo")).await.unwrap();
+      mbox.push(text(src, 2, "oops")).await.unwrap();
+      assert_eq!(
+        mbox.edit(ClientId::default(), 1.into(), "hijacked"),
+        Ok(false)
+      );
+      assert_eq!(mbox.edit(src, 1.into(), "hello"), Ok(true));
+      assert!(mbox.delete(src, 2.into()));
+      assert!(!mbox.delete(src, 2.into()));
+      assert_eq!(mbox.bytes(), 5);
+      assert_eq!(mbox.pop().await, text(src, 1, "hello"));
+      assert_eq!(mbox.pop().await, ClientPollReply::Nothing);
+    })
+  }
+
+  #[test]
+  fn edit_quota() {
+    block_on(async {
+      let owner = ClientId::default();
+      let src = ClientId::default();
+      for policy in [OverflowPolicy::Reject, OverflowPolicy::DropOldest] {
+        let mut mbox = Mailbox::new(owner, quota(10, 10), policy);
+        mbox.push(text(src, 1, "12345")).await.unwrap();
+        mbox.push(text(src, 2, "1234")).await.unwrap();
+        assert_eq!(mbox.edit(src, 2.into(), "12345"), Ok(true));
+        assert_eq!(
+          mbox.edit(src, 2.into(), "123456"),
+          Err(ClientError::BoxFull(owner))
+        );
+        assert_eq!(mbox.edit(src, 1.into(), "1"), Ok(true));
+        assert_eq!(mbox.bytes(), 6);
+        assert_eq!(mbox.pop().await, text(src, 1, "1"));
+        assert_eq!(mbox.pop().await, text(src, 2, "12345"));
+      }
+    })
+  }
+}
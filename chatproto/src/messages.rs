@@ -1,4 +1,4 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, net::IpAddr};
 
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
@@ -11,6 +11,11 @@ pub struct ClientId(pub(crate) Uuid);
   Serialize, Deserialize, std::hash::Hash, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Debug,
 )]
 pub struct ServerId(pub(crate) Uuid);
+/// identifies a message stored by a server, allocated in increasing order
+#[derive(
+  Serialize, Deserialize, std::hash::Hash, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Debug,
+)]
+pub struct MessageId(pub(crate) u128);
 
 impl From<u128> for ClientId {
   fn from(value: u128) -> Self {
@@ -24,6 +29,12 @@ impl From<u128> for ServerId {
   }
 }
 
+impl From<u128> for MessageId {
+  fn from(value: u128) -> Self {
+    MessageId(value)
+  }
+}
+
 impl From<&ClientId> for u128 {
   fn from(value: &ClientId) -> Self {
     value.0.to_u128_le()
@@ -36,6 +47,12 @@ impl From<&ServerId> for u128 {
   }
 }
 
+impl From<&MessageId> for u128 {
+  fn from(value: &MessageId) -> Self {
+    value.0
+  }
+}
+
 impl Default for ClientId {
   fn default() -> ClientId {
     ClientId(Uuid::new_v4())
@@ -60,6 +77,13 @@ impl From<Uuid> for ServerId {
   }
 }
 
+impl ServerId {
+  /// name of a server that was not given one, its uuid
+  pub fn default_name(&self) -> String {
+    self.0.to_string()
+  }
+}
+
 impl std::fmt::Display for ClientId {
   fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
     write!(f, "ClientId({})", self.0)
@@ -72,6 +96,12 @@ impl std::fmt::Display for ServerId {
   }
 }
 
+impl std::fmt::Display for MessageId {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "MessageId({})", self.0)
+  }
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
 pub struct Sequence<A> {
   pub seqid: u128,
@@ -93,6 +123,140 @@ pub enum ClientQuery {
   Message(ClientMessage),
   Poll,
   ListUsers,
+  /// stores a chunk of an attachment, before it is sent
+  Upload(Chunk),
+  /// fetches a chunk of an attachment sent by src
+  Download {
+    src: ClientId,
+    hash: [u8; 32],
+    offset: u64,
+  },
+  /// fetches past messages of a conversation
+  History(HistoryQuery),
+  /// operator command, only allowed to admins
+  Admin(AdminCommand),
+  /// lists known users with their qualified address
+  ListAddresses,
+  /// finds the user with this address
+  Resolve(Address),
+}
+
+/// qualified address of a user, written `name@server`
+/// server names can not contain a '@', the last one of an address separates them from the user name
+#[derive(Serialize, Deserialize, std::hash::Hash, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Address {
+  pub name: String,
+  /// human-readable name of the server of the user
+  pub server: String,
+}
+
+impl std::fmt::Display for Address {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "{}@{}", self.name, self.server)
+  }
+}
+
+impl std::str::FromStr for Address {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s.rsplit_once('@') {
+      Some((name, server)) if !name.is_empty() && !server.is_empty() => Ok(Address {
+        name: name.to_string(),
+        server: server.to_string(),
+      }),
+      _ => Err(format!("expected <name>@<server>, got {}", s)),
+    }
+  }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum AdminCommand {
+  /// rejects all requests from a client
+  Ban(ClientId),
+  Unban(ClientId),
+  /// ignores all datagrams from an address
+  BanAddress(IpAddr),
+  UnbanAddress(IpAddr),
+  /// force-unregisters a client, its mailbox is dropped
+  Kick(ClientId),
+  /// stores a notice in every local mailbox
+  Broadcast(String),
+  /// number of unread messages of a client
+  MailboxDepth(ClientId),
+  /// gives the admin role to a client
+  Grant(ClientId),
+  Revoke(ClientId),
+  /// sets the role of a client, that defines its permissions
+  AssignRole {
+    client: ClientId,
+    role: String,
+  },
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum AdminReply {
+  Done,
+  Depth(u64),
+  /// number of mailboxes that received a broadcast
+  Reached(u64),
+}
+
+/// where a history page ends
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum HistoryBound {
+  /// up to the most recent message
+  Latest,
+  /// messages before the given id
+  Id(MessageId),
+  /// messages before the given timestamp
+  Time(u64),
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct HistoryQuery {
+  /// the other participant of the conversation
+  pub with: ClientId,
+  pub before: HistoryBound,
+  /// maximum number of messages to return
+  pub limit: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct HistoryEntry {
+  pub id: MessageId,
+  pub src: ClientId,
+  /// milliseconds since the unix epoch, when the message was stored
+  pub timestamp: u64,
+  pub content: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct HistoryPage {
+  /// oldest message first
+  pub entries: Vec<HistoryEntry>,
+  /// true if there are older messages
+  pub more: bool,
+}
+
+/// description of a completely uploaded attachment
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct AttachmentMeta {
+  pub name: String,
+  pub size: u64,
+  /// SHA256 of the whole content
+  pub hash: [u8; 32],
+}
+
+/// part of an attachment, starting at offset
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Chunk {
+  /// SHA256 of the whole content
+  pub hash: [u8; 32],
+  /// size of the whole content
+  pub size: u64,
+  pub offset: u64,
+  pub data: Vec<u8>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
@@ -104,6 +268,19 @@ pub enum ClientMessage {
     dest: Vec<ClientId>,
     content: String,
   },
+  /// rewrites a previously sent message, for all the listed recipients
+  Edit {
+    dest: Vec<ClientId>,
+    id: MessageId,
+    content: String,
+  },
+  /// retracts a previously sent message, for all the listed recipients
+  Delete { dest: Vec<ClientId>, id: MessageId },
+  /// sends a previously uploaded attachment
+  Attachment {
+    dest: Vec<ClientId>,
+    meta: AttachmentMeta,
+  },
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
@@ -112,6 +289,13 @@ pub struct FullyQualifiedMessage {
   pub srcsrv: ServerId,
   pub dsts: Vec<(ClientId, ServerId)>,
   pub content: String,
+  /// chosen by srcsrv, so that servers can drop the copies of a message they already handled
+  pub id: MessageId,
+  /// how many more times the message can be forwarded
+  pub hops: u32,
+  /// signatures of srcsrv, one for each destination client in the order of dsts, see signing.rs
+  /// relays keep the signatures of the destinations they pass on, and hops is not signed
+  pub signature: Vec<u8>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
@@ -122,9 +306,62 @@ pub enum ServerMessage {
     /// The last element is the closest to us, and the first the farthest.
     route: Vec<ServerId>,
     /// list of clients registed on the source server, with their names
+    /// encoded sorted by ClientId, so that the encoding is always the same
     clients: HashMap<ClientId, String>,
+    /// signature of the first server of the route, for the clients and its name
+    signature: Vec<u8>,
+    /// human-readable name of the first server of the route, in the addresses of its clients, empty if unknown
+    server_name: String,
+    /// chosen by the first server of the route, that increases it with each of its announces
+    version: u64,
   },
   Message(FullyQualifiedMessage),
+  /// the first server of the route can no longer be reached from the second one, or from us if the
+  /// route has a single server. The rest of the route is the list of servers that were traversed.
+  Withdraw {
+    route: Vec<ServerId>,
+    /// signature of the second server of the route, empty when there is none
+    signature: Vec<u8>,
+    /// chosen by the second server of the route, that increases it with each of its withdrawals, it is
+    /// set by the server when the route only has the neighbour
+    version: u64,
+  },
+  /// clients that left the first server of the route, traversed like an announce
+  WithdrawClients {
+    route: Vec<ServerId>,
+    clients: Vec<ClientId>,
+    /// signature of the first server of the route, for the clients
+    signature: Vec<u8>,
+  },
+  /// a message of src could not be delivered, sent back to srcsrv along its route
+  DeliveryError {
+    src: ClientId,
+    srcsrv: ServerId,
+    error: DelayedError,
+    /// the server that could not deliver the message
+    origin: ServerId,
+    /// signature of origin
+    signature: Vec<u8>,
+  },
+  /// changes of the clients of the first server of the route, from version `since` to `version`
+  /// a delta since version 0 holds all the clients, and replaces what was known
+  AnnounceDelta {
+    route: Vec<ServerId>,
+    since: u64,
+    version: u64,
+    /// clients that joined or were renamed, encoded sorted like the clients of an announce
+    joined: HashMap<ClientId, String>,
+    left: Vec<ClientId>,
+    /// signature of the first server of the route, for everything but the route
+    signature: Vec<u8>,
+    /// like the server name of an announce
+    server_name: String,
+  },
+  /// asks a neighbour for all the clients of origin, when a delta was missed
+  SyncRequest {
+    origin: ServerId,
+    requester: ServerId,
+  },
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
@@ -134,6 +371,27 @@ pub enum ClientError {
   SequenceError,  // sequence number not increasing
   BoxFull(ClientId),
   InternalError,
+  QuotaExceeded,     // attachment storage quota exceeded
+  UnknownAttachment, // attachment is unknown, incomplete or not shared with the client
+  InvalidChunk,      // chunk does not match the attachment, or content hash mismatch
+  Rejected(String),  // message refused by a filter, with the reason
+  Banned,            // client was banned by an admin
+  NotAdmin,          // admin command sent by a regular client
+  UnknownRole,       // role assigned by an admin does not exist
+  // the role of the client does not allow this
+  PermissionDenied { action: Action },
+}
+
+/// actions that require a permission
+/// there is no CreateChannel: the server has no channels, a permission to create them would never be checked
+#[derive(Serialize, Deserialize, std::hash::Hash, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+  /// registering, allowed by the role of the address the registration comes from
+  Register,
+  /// sending a MText message or an attachment to many recipients
+  MassMessage,
+  /// sending messages to clients of other servers
+  RemoteMessage,
 }
 
 impl std::fmt::Display for ClientError {
@@ -144,6 +402,14 @@ impl std::fmt::Display for ClientError {
       ClientError::InternalError => "InternalError".fmt(f),
       ClientError::WorkProofError => "WorkProofError".fmt(f),
       ClientError::UnknownClient => "UnknownClient".fmt(f),
+      ClientError::QuotaExceeded => "QuotaExceeded".fmt(f),
+      ClientError::UnknownAttachment => "UnknownAttachment".fmt(f),
+      ClientError::InvalidChunk => "InvalidChunk".fmt(f),
+      ClientError::Rejected(reason) => write!(f, "Rejected({})", reason),
+      ClientError::Banned => "Banned".fmt(f),
+      ClientError::NotAdmin => "NotAdmin".fmt(f),
+      ClientError::PermissionDenied { action } => write!(f, "PermissionDenied({:?})", action),
+      ClientError::UnknownRole => "UnknownRole".fmt(f),
     }
   }
 }
@@ -164,7 +430,8 @@ impl std::error::Error for ClientError {
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
 pub enum ClientReply {
-  Delivered,
+  /// stored in the recipient mailbox, under the given message id
+  Delivered(MessageId),
   Error(ClientError),
   /// unknown recipient, no relays found
   Delayed,
@@ -174,14 +441,49 @@ pub enum ClientReply {
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
 pub enum ClientPollReply {
-  Message { src: ClientId, content: String },
+  Message {
+    src: ClientId,
+    id: MessageId,
+    content: String,
+  },
+  /// an already polled message was edited by its sender
+  Edited {
+    src: ClientId,
+    id: MessageId,
+    content: String,
+  },
+  /// an already polled message was retracted by its sender
+  Deleted {
+    src: ClientId,
+    id: MessageId,
+  },
+  /// an attachment is available for download
+  Attachment {
+    src: ClientId,
+    id: MessageId,
+    meta: AttachmentMeta,
+  },
   DelayedError(DelayedError),
   Nothing,
+  /// the oldest messages of the mailbox were dropped because it was full
+  Dropped {
+    count: u64,
+  },
+  /// a notice broadcast by an admin
+  Notice(String),
+  /// the poll was refused, for example because the server does not know the client
+  Error(ClientError),
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
 pub enum DelayedError {
   UnknownRecipient(ClientId),
+  /// the mailbox of a client of another server was full
+  RemoteBoxFull(ClientId),
+  /// the server of the recipient could no longer be reached
+  Unroutable(ClientId),
+  /// the message was forwarded too many times on its way to the recipient, routes might loop
+  HopLimit(ClientId),
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
@@ -192,7 +494,8 @@ pub struct Outgoing<A> {
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
 pub enum ServerReply {
-  Outgoing(Vec<Outgoing<FullyQualifiedMessage>>),
+  /// messages to send to other servers, chat messages as well as announces
+  Outgoing(Vec<Outgoing<ServerMessage>>),
   EmptyRoute,
   Error(String),
 }
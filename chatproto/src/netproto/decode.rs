@@ -1,12 +1,15 @@
-use std::{collections::HashMap, io::Read};
+use std::{collections::HashMap, io::Read, net::IpAddr};
 
 use byteorder::{LittleEndian, ReadBytesExt};
 use uuid::Uuid;
 
+use crate::core::CHUNK_SIZE;
 use crate::messages::{
-  AuthMessage, ClientId, ClientMessage, ClientPollReply, ClientQuery, ClientReply, Sequence,
-  ServerId, ServerMessage,
+  Action, Address, AdminCommand, AdminReply, AttachmentMeta, AuthMessage, Chunk, ClientError,
+  ClientId, ClientMessage, ClientPollReply, ClientQuery, ClientReply, HistoryBound, HistoryEntry,
+  HistoryPage, HistoryQuery, Sequence, ServerId, ServerMessage,
 };
+use crate::signing::SIGNATURE_LENGTH;
 
 pub fn u128<R: Read>(rd: &mut R) -> anyhow::Result<u128> {
   todo!()
@@ -64,3 +67,230 @@ where
 {
   todo!()
 }
+
+pub fn bytes<R: Read>(rd: &mut R) -> anyhow::Result<Vec<u8>> {
+  bytes_at_most(rd, u64::MAX)
+}
+
+/// the length comes from the network, so nothing is allocated before the bytes are actually read
+fn bytes_at_most<R: Read>(rd: &mut R, max: u64) -> anyhow::Result<Vec<u8>> {
+  let len = u64::try_from(u128(rd)?)?;
+  anyhow::ensure!(len <= max, "{} bytes, more than the {} allowed", len, max);
+  let mut out = Vec::new();
+  rd.take(len).read_to_end(&mut out)?;
+  anyhow::ensure!(
+    out.len() as u64 == len,
+    "expected {} bytes, got {}",
+    len,
+    out.len()
+  );
+  Ok(out)
+}
+
+/// the signatures of a message, one for each of its signers
+pub fn signature<R: Read>(rd: &mut R, signers: usize) -> anyhow::Result<Vec<u8>> {
+  bytes_at_most(rd, (signers * SIGNATURE_LENGTH) as u64)
+}
+
+fn hash<R: Read>(rd: &mut R) -> anyhow::Result<[u8; 32]> {
+  let mut out = [0; 32];
+  rd.read_exact(&mut out)?;
+  Ok(out)
+}
+
+pub fn attachment_meta<R: Read>(rd: &mut R) -> anyhow::Result<AttachmentMeta> {
+  let name = string(rd)?;
+  let size = u64::try_from(u128(rd)?)?;
+  let hash = hash(rd)?;
+  Ok(AttachmentMeta { name, size, hash })
+}
+
+pub fn chunk<R: Read>(rd: &mut R) -> anyhow::Result<Chunk> {
+  let hash = hash(rd)?;
+  let size = u64::try_from(u128(rd)?)?;
+  let offset = u64::try_from(u128(rd)?)?;
+  let data = bytes_at_most(rd, CHUNK_SIZE as u64)?;
+  Ok(Chunk {
+    hash,
+    size,
+    offset,
+    data,
+  })
+}
+
+pub fn client_error<R: Read>(rd: &mut R) -> anyhow::Result<ClientError> {
+  Ok(match rd.read_u8()? {
+    0 => ClientError::WorkProofError,
+    1 => ClientError::UnknownClient,
+    2 => ClientError::SequenceError,
+    3 => ClientError::BoxFull(clientid(rd)?),
+    4 => ClientError::InternalError,
+    5 => ClientError::QuotaExceeded,
+    6 => ClientError::UnknownAttachment,
+    7 => ClientError::InvalidChunk,
+    8 => ClientError::Rejected(string(rd)?),
+    9 => ClientError::Banned,
+    10 => ClientError::NotAdmin,
+    11 => ClientError::UnknownRole,
+    12 => ClientError::PermissionDenied {
+      action: match rd.read_u8()? {
+        0 => Action::Register,
+        1 => Action::MassMessage,
+        2 => Action::RemoteMessage,
+        n => anyhow::bail!("invalid action variant {}", n),
+      },
+    },
+    n => anyhow::bail!("invalid client error variant {}", n),
+  })
+}
+
+pub fn upload_reply<R: Read>(rd: &mut R) -> anyhow::Result<Result<u64, ClientError>> {
+  Ok(match rd.read_u8()? {
+    0 => Ok(u64::try_from(u128(rd)?)?),
+    1 => Err(client_error(rd)?),
+    n => anyhow::bail!("invalid upload reply variant {}", n),
+  })
+}
+
+pub fn download_reply<R: Read>(rd: &mut R) -> anyhow::Result<Result<Chunk, ClientError>> {
+  Ok(match rd.read_u8()? {
+    0 => Ok(chunk(rd)?),
+    1 => Err(client_error(rd)?),
+    n => anyhow::bail!("invalid download reply variant {}", n),
+  })
+}
+
+pub fn history_query<R: Read>(rd: &mut R) -> anyhow::Result<HistoryQuery> {
+  let with = clientid(rd)?;
+  let before = match rd.read_u8()? {
+    0 => HistoryBound::Latest,
+    1 => HistoryBound::Id(u128(rd)?.into()),
+    2 => HistoryBound::Time(u64::try_from(u128(rd)?)?),
+    n => anyhow::bail!("invalid history bound variant {}", n),
+  };
+  let limit = u64::try_from(u128(rd)?)?;
+  Ok(HistoryQuery {
+    with,
+    before,
+    limit,
+  })
+}
+
+pub fn history_page<R: Read>(rd: &mut R) -> anyhow::Result<HistoryPage> {
+  let len = u128(rd)?;
+  let mut entries = Vec::new();
+  for _ in 0..len {
+    let id = u128(rd)?.into();
+    let src = clientid(rd)?;
+    let timestamp = u64::try_from(u128(rd)?)?;
+    let content = string(rd)?;
+    entries.push(HistoryEntry {
+      id,
+      src,
+      timestamp,
+      content,
+    });
+  }
+  let more = match rd.read_u8()? {
+    0 => false,
+    1 => true,
+    n => anyhow::bail!("invalid boolean {}", n),
+  };
+  Ok(HistoryPage { entries, more })
+}
+
+fn ipaddr<R: Read>(rd: &mut R) -> anyhow::Result<IpAddr> {
+  Ok(match rd.read_u8()? {
+    0 => {
+      let mut octets = [0; 4];
+      rd.read_exact(&mut octets)?;
+      IpAddr::from(octets)
+    }
+    1 => {
+      let mut octets = [0; 16];
+      rd.read_exact(&mut octets)?;
+      IpAddr::from(octets)
+    }
+    n => anyhow::bail!("invalid address variant {}", n),
+  })
+}
+
+pub fn admin_command<R: Read>(rd: &mut R) -> anyhow::Result<AdminCommand> {
+  Ok(match rd.read_u8()? {
+    0 => AdminCommand::Ban(clientid(rd)?),
+    1 => AdminCommand::Unban(clientid(rd)?),
+    2 => AdminCommand::BanAddress(ipaddr(rd)?),
+    3 => AdminCommand::UnbanAddress(ipaddr(rd)?),
+    4 => AdminCommand::Kick(clientid(rd)?),
+    5 => AdminCommand::Broadcast(string(rd)?),
+    6 => AdminCommand::MailboxDepth(clientid(rd)?),
+    7 => AdminCommand::Grant(clientid(rd)?),
+    8 => AdminCommand::Revoke(clientid(rd)?),
+    9 => AdminCommand::AssignRole {
+      client: clientid(rd)?,
+      role: string(rd)?,
+    },
+    n => anyhow::bail!("invalid admin command variant {}", n),
+  })
+}
+
+pub fn admin_reply<R: Read>(rd: &mut R) -> anyhow::Result<Result<AdminReply, ClientError>> {
+  Ok(match rd.read_u8()? {
+    0 => Ok(match rd.read_u8()? {
+      0 => AdminReply::Done,
+      1 => AdminReply::Depth(u64::try_from(u128(rd)?)?),
+      2 => AdminReply::Reached(u64::try_from(u128(rd)?)?),
+      n => anyhow::bail!("invalid admin reply variant {}", n),
+    }),
+    1 => Err(client_error(rd)?),
+    n => anyhow::bail!("invalid admin reply variant {}", n),
+  })
+}
+
+pub fn addresses<R: Read>(rd: &mut R) -> anyhow::Result<HashMap<ClientId, Address>> {
+  let len = u128(rd)?;
+  let mut addresses = HashMap::new();
+  for _ in 0..len {
+    let id = clientid(rd)?;
+    let name = string(rd)?;
+    let server = string(rd)?;
+    addresses.insert(id, Address { name, server });
+  }
+  Ok(addresses)
+}
+
+pub fn userlist_reply<R: Read>(
+  rd: &mut R,
+) -> anyhow::Result<Result<HashMap<ClientId, String>, ClientError>> {
+  Ok(match rd.read_u8()? {
+    0 => Ok(userlist(rd)?),
+    1 => Err(client_error(rd)?),
+    n => anyhow::bail!("invalid user list reply variant {}", n),
+  })
+}
+
+pub fn addresses_reply<R: Read>(
+  rd: &mut R,
+) -> anyhow::Result<Result<HashMap<ClientId, Address>, ClientError>> {
+  Ok(match rd.read_u8()? {
+    0 => Ok(addresses(rd)?),
+    1 => Err(client_error(rd)?),
+    n => anyhow::bail!("invalid addresses reply variant {}", n),
+  })
+}
+
+pub fn history_reply<R: Read>(rd: &mut R) -> anyhow::Result<Result<HistoryPage, ClientError>> {
+  Ok(match rd.read_u8()? {
+    0 => Ok(history_page(rd)?),
+    1 => Err(client_error(rd)?),
+    n => anyhow::bail!("invalid history reply variant {}", n),
+  })
+}
+
+pub fn resolve_reply<R: Read>(rd: &mut R) -> anyhow::Result<Result<ClientId, ClientError>> {
+  Ok(match rd.read_u8()? {
+    0 => Ok(clientid(rd)?),
+    1 => Err(client_error(rd)?),
+    n => anyhow::bail!("invalid resolve reply variant {}", n),
+  })
+}
@@ -1,10 +1,12 @@
-use std::{collections::HashMap, io::Write};
+use std::{collections::HashMap, io::Write, net::IpAddr};
 
 use byteorder::{LittleEndian, WriteBytesExt};
 use uuid::Uuid;
 
 use crate::messages::{
-  AuthMessage, ClientId, ClientMessage, ClientPollReply, ClientQuery, ClientReply, Sequence, ServerId, ServerMessage,
+  Action, Address, AdminCommand, AdminReply, AttachmentMeta, AuthMessage, Chunk, ClientError,
+  ClientId, ClientMessage, ClientPollReply, ClientQuery, ClientReply, HistoryBound, HistoryPage,
+  HistoryQuery, Sequence, ServerId, ServerMessage,
 };
 
 pub fn u128<W>(w: &mut W, m: &u128) -> anyhow::Result<()>
@@ -49,6 +51,7 @@ where
   todo!()
 }
 
+/// the maps of clients are written sorted by ClientId, so that a message is always encoded the same way
 pub fn server<W>(w: &mut W, m: &ServerMessage) -> anyhow::Result<()>
 where
   W: Write,
@@ -99,3 +102,314 @@ where
 {
   todo!()
 }
+
+pub fn bytes<W>(w: &mut W, m: &[u8]) -> anyhow::Result<()>
+where
+  W: Write,
+{
+  u128(w, &(m.len() as u128))?;
+  w.write_all(m)?;
+  Ok(())
+}
+
+pub fn attachment_meta<W>(w: &mut W, m: &AttachmentMeta) -> anyhow::Result<()>
+where
+  W: Write,
+{
+  string(w, &m.name)?;
+  u128(w, &(m.size as u128))?;
+  w.write_all(&m.hash)?;
+  Ok(())
+}
+
+pub fn chunk<W>(w: &mut W, m: &Chunk) -> anyhow::Result<()>
+where
+  W: Write,
+{
+  w.write_all(&m.hash)?;
+  u128(w, &(m.size as u128))?;
+  u128(w, &(m.offset as u128))?;
+  bytes(w, &m.data)
+}
+
+pub fn client_error<W>(w: &mut W, m: &ClientError) -> anyhow::Result<()>
+where
+  W: Write,
+{
+  match m {
+    ClientError::WorkProofError => w.write_u8(0)?,
+    ClientError::UnknownClient => w.write_u8(1)?,
+    ClientError::SequenceError => w.write_u8(2)?,
+    ClientError::BoxFull(c) => {
+      w.write_u8(3)?;
+      clientid(w, c)?;
+    }
+    ClientError::InternalError => w.write_u8(4)?,
+    ClientError::QuotaExceeded => w.write_u8(5)?,
+    ClientError::UnknownAttachment => w.write_u8(6)?,
+    ClientError::InvalidChunk => w.write_u8(7)?,
+    ClientError::Rejected(reason) => {
+      w.write_u8(8)?;
+      string(w, reason)?;
+    }
+    ClientError::Banned => w.write_u8(9)?,
+    ClientError::NotAdmin => w.write_u8(10)?,
+    ClientError::UnknownRole => w.write_u8(11)?,
+    ClientError::PermissionDenied { action } => {
+      w.write_u8(12)?;
+      w.write_u8(match action {
+        Action::Register => 0,
+        Action::MassMessage => 1,
+        Action::RemoteMessage => 2,
+      })?;
+    }
+  }
+  Ok(())
+}
+
+pub fn upload_reply<W>(w: &mut W, m: &Result<u64, ClientError>) -> anyhow::Result<()>
+where
+  W: Write,
+{
+  match m {
+    Ok(received) => {
+      w.write_u8(0)?;
+      u128(w, &(*received as u128))
+    }
+    Err(rr) => {
+      w.write_u8(1)?;
+      client_error(w, rr)
+    }
+  }
+}
+
+pub fn download_reply<W>(w: &mut W, m: &Result<Chunk, ClientError>) -> anyhow::Result<()>
+where
+  W: Write,
+{
+  match m {
+    Ok(c) => {
+      w.write_u8(0)?;
+      chunk(w, c)
+    }
+    Err(rr) => {
+      w.write_u8(1)?;
+      client_error(w, rr)
+    }
+  }
+}
+
+pub fn history_query<W>(w: &mut W, m: &HistoryQuery) -> anyhow::Result<()>
+where
+  W: Write,
+{
+  clientid(w, &m.with)?;
+  match &m.before {
+    HistoryBound::Latest => w.write_u8(0)?,
+    HistoryBound::Id(id) => {
+      w.write_u8(1)?;
+      u128(w, &id.into())?;
+    }
+    HistoryBound::Time(t) => {
+      w.write_u8(2)?;
+      u128(w, &(*t as u128))?;
+    }
+  }
+  u128(w, &(m.limit as u128))
+}
+
+pub fn history_page<W>(w: &mut W, m: &HistoryPage) -> anyhow::Result<()>
+where
+  W: Write,
+{
+  u128(w, &(m.entries.len() as u128))?;
+  for e in &m.entries {
+    u128(w, &(&e.id).into())?;
+    clientid(w, &e.src)?;
+    u128(w, &(e.timestamp as u128))?;
+    string(w, &e.content)?;
+  }
+  w.write_u8(m.more as u8)?;
+  Ok(())
+}
+
+fn ipaddr<W>(w: &mut W, m: &IpAddr) -> anyhow::Result<()>
+where
+  W: Write,
+{
+  match m {
+    IpAddr::V4(a) => {
+      w.write_u8(0)?;
+      w.write_all(&a.octets())?;
+    }
+    IpAddr::V6(a) => {
+      w.write_u8(1)?;
+      w.write_all(&a.octets())?;
+    }
+  }
+  Ok(())
+}
+
+pub fn admin_command<W>(w: &mut W, m: &AdminCommand) -> anyhow::Result<()>
+where
+  W: Write,
+{
+  match m {
+    AdminCommand::Ban(c) => {
+      w.write_u8(0)?;
+      clientid(w, c)
+    }
+    AdminCommand::Unban(c) => {
+      w.write_u8(1)?;
+      clientid(w, c)
+    }
+    AdminCommand::BanAddress(a) => {
+      w.write_u8(2)?;
+      ipaddr(w, a)
+    }
+    AdminCommand::UnbanAddress(a) => {
+      w.write_u8(3)?;
+      ipaddr(w, a)
+    }
+    AdminCommand::Kick(c) => {
+      w.write_u8(4)?;
+      clientid(w, c)
+    }
+    AdminCommand::Broadcast(content) => {
+      w.write_u8(5)?;
+      string(w, content)
+    }
+    AdminCommand::MailboxDepth(c) => {
+      w.write_u8(6)?;
+      clientid(w, c)
+    }
+    AdminCommand::Grant(c) => {
+      w.write_u8(7)?;
+      clientid(w, c)
+    }
+    AdminCommand::Revoke(c) => {
+      w.write_u8(8)?;
+      clientid(w, c)
+    }
+    AdminCommand::AssignRole { client, role } => {
+      w.write_u8(9)?;
+      clientid(w, client)?;
+      string(w, role)
+    }
+  }
+}
+
+pub fn admin_reply<W>(w: &mut W, m: &Result<AdminReply, ClientError>) -> anyhow::Result<()>
+where
+  W: Write,
+{
+  match m {
+    Ok(reply) => {
+      w.write_u8(0)?;
+      match reply {
+        AdminReply::Done => w.write_u8(0)?,
+        AdminReply::Depth(n) => {
+          w.write_u8(1)?;
+          u128(w, &(*n as u128))?;
+        }
+        AdminReply::Reached(n) => {
+          w.write_u8(2)?;
+          u128(w, &(*n as u128))?;
+        }
+      }
+      Ok(())
+    }
+    Err(rr) => {
+      w.write_u8(1)?;
+      client_error(w, rr)
+    }
+  }
+}
+
+/// sorted by ClientId, like the maps of server messages
+pub fn addresses<W>(w: &mut W, m: &HashMap<ClientId, Address>) -> anyhow::Result<()>
+where
+  W: Write,
+{
+  let mut sorted: Vec<(&ClientId, &Address)> = m.iter().collect();
+  sorted.sort();
+  u128(w, &(sorted.len() as u128))?;
+  for (id, address) in sorted {
+    clientid(w, id)?;
+    string(w, &address.name)?;
+    string(w, &address.server)?;
+  }
+  Ok(())
+}
+
+/// the reply to ListUsers, an error when the sequence is refused
+pub fn userlist_reply<W>(
+  w: &mut W,
+  m: &Result<HashMap<ClientId, String>, ClientError>,
+) -> anyhow::Result<()>
+where
+  W: Write,
+{
+  match m {
+    Ok(users) => {
+      w.write_u8(0)?;
+      userlist(w, users)
+    }
+    Err(rr) => {
+      w.write_u8(1)?;
+      client_error(w, rr)
+    }
+  }
+}
+
+pub fn addresses_reply<W>(
+  w: &mut W,
+  m: &Result<HashMap<ClientId, Address>, ClientError>,
+) -> anyhow::Result<()>
+where
+  W: Write,
+{
+  match m {
+    Ok(list) => {
+      w.write_u8(0)?;
+      addresses(w, list)
+    }
+    Err(rr) => {
+      w.write_u8(1)?;
+      client_error(w, rr)
+    }
+  }
+}
+
+pub fn history_reply<W>(w: &mut W, m: &Result<HistoryPage, ClientError>) -> anyhow::Result<()>
+where
+  W: Write,
+{
+  match m {
+    Ok(page) => {
+      w.write_u8(0)?;
+      history_page(w, page)
+    }
+    Err(rr) => {
+      w.write_u8(1)?;
+      client_error(w, rr)
+    }
+  }
+}
+
+/// the reply to Resolve, UnknownClient when no user, or several, have the address
+pub fn resolve_reply<W>(w: &mut W, m: &Result<ClientId, ClientError>) -> anyhow::Result<()>
+where
+  W: Write,
+{
+  match m {
+    Ok(id) => {
+      w.write_u8(0)?;
+      clientid(w, id)
+    }
+    Err(rr) => {
+      w.write_u8(1)?;
+      client_error(w, rr)
+    }
+  }
+}
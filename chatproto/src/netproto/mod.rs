@@ -7,6 +7,7 @@ mod test {
   use std::io::Cursor;
   use uuid::uuid;
 
+  use crate::core::CHUNK_SIZE;
   use crate::messages::*;
 
   use super::decode;
@@ -18,6 +19,9 @@ mod test {
       ServerMessage::Announce {
         route: vec![ServerId::default()],
         clients: HashMap::from([(ClientId::default(), "Roger".to_string())]),
+        signature: Vec::new(),
+        server_name: String::new(),
+        version: 0,
       },
       ServerMessage::Announce {
         route: vec![ServerId::default(), ServerId::default()],
@@ -25,18 +29,27 @@ mod test {
           (ClientId::default(), "user 1".to_string()),
           (ClientId::default(), "user 2".to_string()),
         ]),
+        signature: Vec::new(),
+        server_name: String::new(),
+        version: 1_700_000_000_000,
       },
       ServerMessage::Announce {
         route: (0..4000).map(|_| ServerId::default()).collect::<Vec<_>>(),
         clients: (0..6000)
           .map(|_| (ClientId::default(), "same name".to_string()))
           .collect::<HashMap<_, _>>(),
+        signature: Vec::new(),
+        server_name: String::new(),
+        version: u64::MAX,
       },
       ServerMessage::Message(FullyQualifiedMessage {
         src: ClientId::default(),
         srcsrv: ServerId::default(),
         dsts: vec![(ClientId::default(), ServerId::default())],
         content: "Hello".into(),
+        id: MessageId::from(12),
+        hops: 16,
+        signature: (0..64).collect(),
       }),
       ServerMessage::Message(FullyQualifiedMessage {
         src: ClientId::default(),
@@ -46,7 +59,49 @@ mod test {
           (ClientId::default(), ServerId::default()),
         ],
         content: "World!".into(),
+        id: MessageId::from(u128::MAX),
+        hops: 0,
+        signature: Vec::new(),
       }),
+      ServerMessage::Withdraw {
+        route: vec![ServerId::default(), ServerId::default()],
+        signature: vec![5; 64],
+        version: 42,
+      },
+      ServerMessage::WithdrawClients {
+        route: vec![ServerId::default()],
+        clients: vec![ClientId::default(), ClientId::default()],
+        signature: Vec::new(),
+      },
+      ServerMessage::DeliveryError {
+        src: ClientId::default(),
+        srcsrv: ServerId::default(),
+        error: DelayedError::RemoteBoxFull(ClientId::default()),
+        origin: ServerId::default(),
+        signature: vec![6; 64],
+      },
+      ServerMessage::AnnounceDelta {
+        route: vec![ServerId::default(), ServerId::default()],
+        since: 0,
+        version: 1_700_000_000_000,
+        joined: HashMap::from([(ClientId::default(), "user 1".to_string())]),
+        left: Vec::new(),
+        signature: Vec::new(),
+        server_name: String::new(),
+      },
+      ServerMessage::AnnounceDelta {
+        route: vec![ServerId::default()],
+        since: 12,
+        version: 7000,
+        joined: HashMap::new(),
+        left: (0..6000).map(|_| ClientId::default()).collect(),
+        signature: Vec::new(),
+        server_name: String::new(),
+      },
+      ServerMessage::SyncRequest {
+        origin: ServerId::default(),
+        requester: ServerId::default(),
+      },
     ]
   }
 
@@ -59,11 +114,14 @@ mod test {
             uuid!["27293ea0-23c5-49e3-97ba-9d9337c1f414"].into(),
             "hardcoded".into(),
           )]),
+          signature: Vec::new(),
+          server_name: String::new(),
+          version: 7,
         },
         vec![
           0, 1, 16, 115, 32, 55, 175, 211, 132, 77, 147, 171, 78, 235, 175, 100, 222, 135, 27, 1,
           16, 39, 41, 62, 160, 35, 197, 73, 227, 151, 186, 157, 147, 55, 193, 244, 20, 9, 104, 97,
-          114, 100, 99, 111, 100, 101, 100,
+          114, 100, 99, 111, 100, 101, 100, 0, 0, 7,
         ],
       ),
       (
@@ -81,6 +139,9 @@ mod test {
             ),
           ],
           content: "Yes!".into(),
+          id: MessageId::from(0x12345678),
+          hops: 16,
+          signature: Vec::new(),
         }),
         vec![
           1, 16, 80, 6, 77, 218, 134, 93, 64, 112, 168, 67, 170, 202, 41, 44, 184, 94, 16, 149,
@@ -88,7 +149,76 @@ mod test {
           119, 47, 112, 10, 64, 116, 155, 132, 226, 100, 5, 13, 171, 89, 16, 47, 6, 253, 122, 142,
           123, 70, 134, 159, 125, 102, 168, 228, 232, 145, 82, 16, 91, 130, 107, 77, 243, 48, 75,
           95, 131, 174, 198, 254, 5, 183, 247, 96, 16, 109, 26, 131, 191, 201, 1, 65, 108, 138,
-          179, 18, 64, 158, 9, 10, 15, 4, 89, 101, 115, 33,
+          179, 18, 64, 158, 9, 10, 15, 4, 89, 101, 115, 33, 252, 120, 86, 52, 18, 16, 0,
+        ],
+      ),
+      (
+        ServerMessage::AnnounceDelta {
+          route: vec![uuid!["732037af-d384-4d93-ab4e-ebaf64de871b"].into()],
+          since: 3,
+          version: 300,
+          joined: HashMap::from([(
+            uuid!["27293ea0-23c5-49e3-97ba-9d9337c1f414"].into(),
+            "renamed".into(),
+          )]),
+          left: vec![uuid!["5b826b4d-f330-4b5f-83ae-c6fe05b7f760"].into()],
+          signature: Vec::new(),
+          server_name: String::new(),
+        },
+        vec![
+          5, 1, 16, 115, 32, 55, 175, 211, 132, 77, 147, 171, 78, 235, 175, 100, 222, 135, 27, 3,
+          251, 44, 1, 1, 16, 39, 41, 62, 160, 35, 197, 73, 227, 151, 186, 157, 147, 55, 193, 244,
+          20, 7, 114, 101, 110, 97, 109, 101, 100, 1, 16, 91, 130, 107, 77, 243, 48, 75, 95, 131,
+          174, 198, 254, 5, 183, 247, 96, 0, 0,
+        ],
+      ),
+      (
+        ServerMessage::Announce {
+          route: vec![uuid!["732037af-d384-4d93-ab4e-ebaf64de871b"].into()],
+          clients: HashMap::from([
+            (
+              uuid!["5b826b4d-f330-4b5f-83ae-c6fe05b7f760"].into(),
+              "a".into(),
+            ),
+            (
+              uuid!["27293ea0-23c5-49e3-97ba-9d9337c1f414"].into(),
+              "b".into(),
+            ),
+          ]),
+          signature: vec![1, 2, 3],
+          server_name: "paris".into(),
+          version: 300,
+        },
+        vec![
+          0, 1, 16, 115, 32, 55, 175, 211, 132, 77, 147, 171, 78, 235, 175, 100, 222, 135, 27, 2,
+          16, 39, 41, 62, 160, 35, 197, 73, 227, 151, 186, 157, 147, 55, 193, 244, 20, 1, 98, 16,
+          91, 130, 107, 77, 243, 48, 75, 95, 131, 174, 198, 254, 5, 183, 247, 96, 1, 97, 3, 1, 2,
+          3, 5, 112, 97, 114, 105, 115, 251, 44, 1,
+        ],
+      ),
+      (
+        ServerMessage::Withdraw {
+          route: vec![
+            uuid!["732037af-d384-4d93-ab4e-ebaf64de871b"].into(),
+            uuid!["27293ea0-23c5-49e3-97ba-9d9337c1f414"].into(),
+          ],
+          signature: vec![1, 2],
+          version: 0x12345,
+        },
+        vec![
+          2, 2, 16, 115, 32, 55, 175, 211, 132, 77, 147, 171, 78, 235, 175, 100, 222, 135, 27, 16,
+          39, 41, 62, 160, 35, 197, 73, 227, 151, 186, 157, 147, 55, 193, 244, 20, 2, 1, 2, 252,
+          69, 35, 1, 0,
+        ],
+      ),
+      (
+        ServerMessage::SyncRequest {
+          origin: uuid!["732037af-d384-4d93-ab4e-ebaf64de871b"].into(),
+          requester: uuid!["27293ea0-23c5-49e3-97ba-9d9337c1f414"].into(),
+        },
+        vec![
+          6, 16, 115, 32, 55, 175, 211, 132, 77, 147, 171, 78, 235, 175, 100, 222, 135, 27, 16, 39,
+          41, 62, 160, 35, 197, 73, 227, 151, 186, 157, 147, 55, 193, 244, 20,
         ],
       ),
     ]
@@ -336,6 +466,23 @@ mod test {
     round_trip(encode::client_query, decode::client_query, &query, &[3]);
   }
 
+  #[test]
+  fn client_query_list_addresses() {
+    let query = ClientQuery::ListAddresses;
+    round_trip(encode::client_query, decode::client_query, &query, &[8]);
+  }
+
+  #[test]
+  fn client_query_resolve() {
+    let query = ClientQuery::Resolve("bob@paris".parse().unwrap());
+    round_trip(
+      encode::client_query,
+      decode::client_query,
+      &query,
+      &[9, 3, 98, 111, 98, 5, 112, 97, 114, 105, 115],
+    );
+  }
+
   #[test]
   fn string_decode() {
     let mut cursor = Cursor::new([
@@ -345,6 +492,251 @@ mod test {
     assert_eq!(decoded, "Hello World ;)");
   }
 
+  #[test]
+  fn upload_reply() {
+    round_trip(
+      encode::upload_reply,
+      decode::upload_reply,
+      &Ok(0x1234),
+      &[0, 251, 52, 18],
+    );
+    round_trip(
+      encode::upload_reply,
+      decode::upload_reply,
+      &Err(ClientError::QuotaExceeded),
+      &[1, 5],
+    );
+    round_trip(
+      encode::upload_reply,
+      decode::upload_reply,
+      &Err(ClientError::Rejected("spam".into())),
+      &[1, 8, 4, 115, 112, 97, 109],
+    );
+  }
+
+  #[test]
+  fn client_poll_reply_error() {
+    round_trip(
+      encode::client_poll_reply,
+      decode::client_poll_reply,
+      &ClientPollReply::Error(ClientError::UnknownClient),
+      &[8, 1],
+    );
+  }
+
+  #[test]
+  fn admin_command() {
+    let samples: [(AdminCommand, &[u8]); 3] = [
+      (
+        AdminCommand::BanAddress(std::net::IpAddr::from([10, 0, 0, 1])),
+        &[2, 0, 10, 0, 0, 1],
+      ),
+      (
+        AdminCommand::UnbanAddress("::1".parse().unwrap()),
+        &[3, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1],
+      ),
+      (AdminCommand::Broadcast("hi".into()), &[5, 2, 104, 105]),
+    ];
+    for (command, encoded) in samples {
+      round_trip(
+        encode::admin_command,
+        decode::admin_command,
+        &command,
+        encoded,
+      );
+    }
+  }
+
+  #[test]
+  fn admin_reply() {
+    round_trip(
+      encode::admin_reply,
+      decode::admin_reply,
+      &Ok(AdminReply::Depth(300)),
+      &[0, 1, 251, 44, 1],
+    );
+    round_trip(
+      encode::admin_reply,
+      decode::admin_reply,
+      &Err(ClientError::Banned),
+      &[1, 9],
+    );
+    round_trip(
+      encode::admin_reply,
+      decode::admin_reply,
+      &Err(ClientError::PermissionDenied {
+        action: Action::RemoteMessage,
+      }),
+      &[1, 12, 2],
+    );
+  }
+
+  #[test]
+  fn oversized_lengths() {
+    // a length that claims 16 EiB, followed by nothing
+    let mut huge = vec![253];
+    huge.extend_from_slice(&u64::MAX.to_le_bytes());
+    assert!(decode::bytes(&mut Cursor::new(huge)).is_err());
+
+    let mut chunk = vec![7; 32];
+    chunk.extend_from_slice(&[3, 0, 251]);
+    chunk.extend_from_slice(&(CHUNK_SIZE as u16 + 1).to_le_bytes());
+    chunk.resize(chunk.len() + CHUNK_SIZE + 1, 0);
+    assert!(decode::chunk(&mut Cursor::new(chunk)).is_err());
+
+    let mut signature = vec![65];
+    signature.resize(66, 0);
+    assert!(decode::signature(&mut Cursor::new(signature.clone()), 1).is_err());
+    assert_eq!(
+      decode::signature(&mut Cursor::new(signature), 2).unwrap(),
+      vec![0; 65]
+    );
+  }
+
+  #[test]
+  fn download_reply() {
+    let chunk = Chunk {
+      hash: [7; 32],
+      size: 3,
+      offset: 0,
+      data: vec![1, 2, 3],
+    };
+    let mut encoded = vec![0];
+    encoded.extend([7; 32]);
+    encoded.extend([3, 0, 3, 1, 2, 3]);
+    round_trip(
+      encode::download_reply,
+      decode::download_reply,
+      &Ok(chunk),
+      &encoded,
+    );
+    round_trip(
+      encode::download_reply,
+      decode::download_reply,
+      &Err(ClientError::BoxFull(
+        uuid!["732037af-d384-4d93-ab4e-ebaf64de871b"].into(),
+      )),
+      &[
+        1, 3, 16, 115, 32, 55, 175, 211, 132, 77, 147, 171, 78, 235, 175, 100, 222, 135, 27,
+      ],
+    );
+  }
+
+  #[test]
+  fn history_query() {
+    let query = HistoryQuery {
+      with: uuid!["732037af-d384-4d93-ab4e-ebaf64de871b"].into(),
+      before: HistoryBound::Id(300.into()),
+      limit: 32,
+    };
+    round_trip(
+      encode::history_query,
+      decode::history_query,
+      &query,
+      &[
+        16, 115, 32, 55, 175, 211, 132, 77, 147, 171, 78, 235, 175, 100, 222, 135, 27, 1, 251, 44,
+        1, 32,
+      ],
+    );
+  }
+
+  #[test]
+  fn addresses() {
+    let addresses = HashMap::from([
+      (
+        uuid!["5b826b4d-f330-4b5f-83ae-c6fe05b7f760"].into(),
+        "alice@home".parse().unwrap(),
+      ),
+      (
+        uuid!["27293ea0-23c5-49e3-97ba-9d9337c1f414"].into(),
+        "alice@paris".parse().unwrap(),
+      ),
+    ]);
+    round_trip(
+      encode::addresses,
+      decode::addresses,
+      &addresses,
+      &[
+        2, 16, 39, 41, 62, 160, 35, 197, 73, 227, 151, 186, 157, 147, 55, 193, 244, 20, 5, 97, 108,
+        105, 99, 101, 5, 112, 97, 114, 105, 115, 16, 91, 130, 107, 77, 243, 48, 75, 95, 131, 174,
+        198, 254, 5, 183, 247, 96, 5, 97, 108, 105, 99, 101, 4, 104, 111, 109, 101,
+      ],
+    );
+  }
+
+  #[test]
+  fn history_page() {
+    let page = HistoryPage {
+      entries: vec![HistoryEntry {
+        id: 7.into(),
+        src: uuid!["732037af-d384-4d93-ab4e-ebaf64de871b"].into(),
+        timestamp: 0x12345678,
+        content: "Hi".into(),
+      }],
+      more: true,
+    };
+    round_trip(
+      encode::history_page,
+      decode::history_page,
+      &page,
+      &[
+        1, 7, 16, 115, 32, 55, 175, 211, 132, 77, 147, 171, 78, 235, 175, 100, 222, 135, 27, 252,
+        120, 86, 52, 18, 2, 72, 105, 1,
+      ],
+    );
+  }
+
+  #[test]
+  fn query_replies() {
+    round_trip(
+      encode::userlist_reply,
+      decode::userlist_reply,
+      &Ok(HashMap::new()),
+      &[0, 0],
+    );
+    round_trip(
+      encode::userlist_reply,
+      decode::userlist_reply,
+      &Err(ClientError::SequenceError),
+      &[1, 2],
+    );
+    round_trip(
+      encode::addresses_reply,
+      decode::addresses_reply,
+      &Err(ClientError::Banned),
+      &[1, 9],
+    );
+    round_trip(
+      encode::history_reply,
+      decode::history_reply,
+      &Ok(HistoryPage {
+        entries: Vec::new(),
+        more: false,
+      }),
+      &[0, 0, 0],
+    );
+    round_trip(
+      encode::history_reply,
+      decode::history_reply,
+      &Err(ClientError::UnknownClient),
+      &[1, 1],
+    );
+    round_trip(
+      encode::resolve_reply,
+      decode::resolve_reply,
+      &Ok(uuid!["732037af-d384-4d93-ab4e-ebaf64de871b"].into()),
+      &[
+        0, 16, 115, 32, 55, 175, 211, 132, 77, 147, 171, 78, 235, 175, 100, 222, 135, 27,
+      ],
+    );
+    round_trip(
+      encode::resolve_reply,
+      decode::resolve_reply,
+      &Err(ClientError::UnknownClient),
+      &[1, 1],
+    );
+  }
+
   #[test]
   fn sequence() {
     let src = Sequence {
@@ -0,0 +1,200 @@
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::messages::{Action, ClientError, ClientId};
+
+/// role definitions, and the role of each client
+/// it can be read from JSON, where missing fields have their default value
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RoleConfig {
+  /// permissions of each role
+  pub roles: HashMap<String, HashSet<Action>>,
+  /// clients that do not have the default role
+  pub assignments: HashMap<ClientId, String>,
+  /// role of the addresses clients register from, only Register is checked against it
+  pub addresses: HashMap<IpAddr, String>,
+  /// role of the other clients and addresses
+  pub default_role: String,
+  /// MText messages and attachments to more recipients than this require the MassMessage permission
+  pub max_recipients: usize,
+}
+
+impl Default for RoleConfig {
+  /// a single "user" role, that can do everything
+  fn default() -> Self {
+    RoleConfig {
+      roles: HashMap::from([(
+        "user".to_string(),
+        HashSet::from([Action::Register, Action::MassMessage, Action::RemoteMessage]),
+      )]),
+      assignments: HashMap::new(),
+      addresses: HashMap::new(),
+      default_role: "user".to_string(),
+      max_recipients: 16,
+    }
+  }
+}
+
+impl RoleConfig {
+  /// reads a configuration, all the roles it gives must exist
+  pub fn from_json(json: &str) -> anyhow::Result<Self> {
+    let config: RoleConfig = serde_json::from_str(json)?;
+    let given = config
+      .assignments
+      .values()
+      .chain(config.addresses.values())
+      .chain(std::iter::once(&config.default_role));
+    for role in given {
+      anyhow::ensure!(config.roles.contains_key(role), "unknown role {}", role);
+    }
+    Ok(config)
+  }
+}
+
+/// checks the permissions of clients
+pub struct Roles {
+  config: RoleConfig,
+}
+
+impl Roles {
+  pub fn new(config: RoleConfig) -> Self {
+    Roles { config }
+  }
+
+  pub fn role_of(&self, client: &ClientId) -> &str {
+    self
+      .config
+      .assignments
+      .get(client)
+      .unwrap_or(&self.config.default_role)
+  }
+
+  /// fails with PermissionDenied if the role of the client does not allow the action
+  pub fn check(&self, client: &ClientId, action: Action) -> Result<(), ClientError> {
+    self.allow(self.role_of(client), action)
+  }
+
+  /// fails with PermissionDenied if the role of the address does not allow Register
+  pub fn check_registration(&self, addr: &IpAddr) -> Result<(), ClientError> {
+    let role = self
+      .config
+      .addresses
+      .get(addr)
+      .unwrap_or(&self.config.default_role);
+    self.allow(role, Action::Register)
+  }
+
+  fn allow(&self, role: &str, action: Action) -> Result<(), ClientError> {
+    let allowed = self
+      .config
+      .roles
+      .get(role)
+      .map(|permissions| permissions.contains(&action))
+      .unwrap_or(false);
+    if allowed {
+      Ok(())
+    } else {
+      Err(ClientError::PermissionDenied { action })
+    }
+  }
+
+  /// checks the MassMessage permission if there are too many recipients
+  pub fn check_recipients(&self, client: &ClientId, recipients: usize) -> Result<(), ClientError> {
+    if recipients > self.config.max_recipients {
+      self.check(client, Action::MassMessage)
+    } else {
+      Ok(())
+    }
+  }
+
+  /// gives a role to a client, the role must exist
+  pub fn assign(&mut self, client: ClientId, role: &str) -> Result<(), ClientError> {
+    if !self.config.roles.contains_key(role) {
+      return Err(ClientError::UnknownRole);
+    }
+    self.config.assignments.insert(client, role.to_string());
+    Ok(())
+  }
+
+  /// the client goes back to the default role
+  pub fn forget(&mut self, client: &ClientId) {
+    self.config.assignments.remove(client);
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn permissions() {
+    let staff = ClientId::default();
+    let user = ClientId::default();
+    let mut roles = Roles::new(RoleConfig {
+      roles: HashMap::from([
+        ("guest".to_string(), HashSet::new()),
+        (
+          "staff".to_string(),
+          HashSet::from([Action::Register, Action::MassMessage]),
+        ),
+      ]),
+      assignments: HashMap::from([(staff, "staff".to_string())]),
+      addresses: HashMap::from([("10.0.0.1".parse().unwrap(), "staff".to_string())]),
+      default_role: "guest".to_string(),
+      max_recipients: 2,
+    });
+    assert_eq!(roles.role_of(&user), "guest");
+    assert_eq!(
+      roles.check(&user, Action::Register),
+      Err(ClientError::PermissionDenied {
+        action: Action::Register
+      })
+    );
+    assert_eq!(roles.check(&staff, Action::Register), Ok(()));
+    assert_eq!(
+      roles.check_registration(&"10.0.0.1".parse().unwrap()),
+      Ok(())
+    );
+    assert!(roles
+      .check_registration(&"10.0.0.2".parse().unwrap())
+      .is_err());
+    assert_eq!(
+      roles.check(&staff, Action::RemoteMessage),
+      Err(ClientError::PermissionDenied {
+        action: Action::RemoteMessage
+      })
+    );
+    assert_eq!(roles.check_recipients(&user, 2), Ok(()));
+    assert!(roles.check_recipients(&user, 3).is_err());
+    assert_eq!(roles.check_recipients(&staff, 3), Ok(()));
+
+    assert_eq!(roles.assign(user, "admin"), Err(ClientError::UnknownRole));
+    roles.assign(user, "staff").unwrap();
+    assert_eq!(roles.check_recipients(&user, 3), Ok(()));
+    roles.forget(&user);
+    assert_eq!(roles.role_of(&user), "guest");
+  }
+
+  #[test]
+  fn from_json() {
+    let staff = ClientId::default();
+    let config = RoleConfig::from_json(&format!(
+      r#"{{
+        "roles": {{ "guest": [], "staff": ["Register", "MassMessage"] }},
+        "assignments": {{ "{}": "staff" }},
+        "addresses": {{ "10.0.0.1": "staff" }},
+        "default_role": "guest"
+      }}"#,
+      uuid::Uuid::from_u128_le((&staff).into())
+    ))
+    .unwrap();
+    assert_eq!(config.assignments[&staff], "staff");
+    assert_eq!(config.addresses[&"10.0.0.1".parse().unwrap()], "staff");
+    assert_eq!(config.max_recipients, RoleConfig::default().max_recipients);
+    assert!(RoleConfig::from_json(r#"{ "default_role": "guest" }"#).is_err());
+    assert!(RoleConfig::from_json(r#"{ "roles": { "user": ["Fly"] } }"#).is_err());
+  }
+}
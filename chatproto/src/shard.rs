@@ -0,0 +1,59 @@
+use std::{
+  collections::hash_map::RandomState,
+  hash::{BuildHasher, Hash},
+};
+
+/// a value split in independent shards, selected by hashing a key
+/// each shard has its own lock, so that operations on keys in different shards never wait for each other
+pub struct Shards<T> {
+  hasher: RandomState,
+  shards: Vec<T>,
+}
+
+impl<T> Shards<T> {
+  pub fn new(count: usize, init: impl Fn() -> T) -> Self {
+    assert!(count > 0, "at least one shard is needed");
+    Shards {
+      hasher: RandomState::new(),
+      shards: (0..count).map(|_| init()).collect(),
+    }
+  }
+
+  /// the shard holding the key
+  pub fn get<K: Hash>(&self, key: &K) -> &T {
+    let n = self.hasher.hash_one(key) as usize % self.shards.len();
+    &self.shards[n]
+  }
+
+  pub fn iter(&self) -> impl Iterator<Item = &T> {
+    self.shards.iter()
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use std::sync::Mutex;
+
+  use super::*;
+
+  #[test]
+  fn stable_shards() {
+    let shards = Shards::new(8, || Mutex::new(Vec::new()));
+    for n in 0..100_u32 {
+      shards.get(&n).lock().unwrap().push(n);
+    }
+    for n in 0..100_u32 {
+      assert!(shards.get(&n).lock().unwrap().contains(&n));
+    }
+    let total: usize = shards.iter().map(|s| s.lock().unwrap().len()).sum();
+    assert_eq!(total, 100);
+    // keys are spread over the shards
+    assert!(
+      shards
+        .iter()
+        .filter(|s| !s.lock().unwrap().is_empty())
+        .count()
+        > 1
+    );
+  }
+}
@@ -0,0 +1,555 @@
+//! signatures of announces and messages, by the server they come from
+//!
+//! What is signed does not depend on the order of the maps, and leaves out what servers change on the way: the
+//! route after the server that signs, and the hops of messages. Relays could rewrite that part of the route, so it
+//! only tells which way a message came, not that it went through these servers. Announces and withdrawals sign a
+//! version, so that an older one can be told apart and is not replayed. Messages get a signature for each destination
+//! client, so that relays can split them between their next hops, but not send them to other clients.
+//!
+//! Servers also sign the challenges their neighbours send when they connect, to prove who they are.
+
+use std::collections::HashMap;
+
+use anyhow::Context;
+use ed25519_dalek::{Signature, Signer, Verifier};
+pub use ed25519_dalek::{SigningKey, VerifyingKey};
+
+use crate::messages::{ClientId, DelayedError, FullyQualifiedMessage, ServerId, ServerMessage};
+
+/// the signature of a message is this long for each of its destinations
+pub const SIGNATURE_LENGTH: usize = ed25519_dalek::SIGNATURE_LENGTH;
+
+pub fn generate() -> SigningKey {
+  SigningKey::generate(&mut rand::rngs::OsRng)
+}
+
+/// the server that signs a message, None for the messages that are not signed
+pub fn signer(message: &ServerMessage) -> Option<ServerId> {
+  match message {
+    ServerMessage::Announce { route, .. } | ServerMessage::AnnounceDelta { route, .. } => {
+      route.first().copied()
+    }
+    ServerMessage::Message(fqm) => Some(fqm.srcsrv),
+    // the server that lost the link, the route of a local withdrawal only has the neighbour
+    ServerMessage::Withdraw { route, .. } => route.get(1).copied(),
+    ServerMessage::WithdrawClients { route, .. } => route.first().copied(),
+    ServerMessage::DeliveryError { origin, .. } => Some(*origin),
+    _ => None,
+  }
+}
+
+fn signature_mut(message: &mut ServerMessage) -> Option<&mut Vec<u8>> {
+  match message {
+    ServerMessage::Announce { signature, .. }
+    | ServerMessage::AnnounceDelta { signature, .. }
+    | ServerMessage::Withdraw { signature, .. }
+    | ServerMessage::WithdrawClients { signature, .. }
+    | ServerMessage::DeliveryError { signature, .. } => Some(signature),
+    ServerMessage::Message(fqm) => Some(&mut fqm.signature),
+    _ => None,
+  }
+}
+
+/// the signature of the message for the destination at this position of dsts, empty if there is none
+pub fn destination_signature(fqm: &FullyQualifiedMessage, n: usize) -> &[u8] {
+  fqm
+    .signature
+    .get(n * SIGNATURE_LENGTH..(n + 1) * SIGNATURE_LENGTH)
+    .unwrap_or(&[])
+}
+
+/// keeps the destinations of the message for which keep is true, with their signatures
+pub fn retain_destinations(
+  fqm: &mut FullyQualifiedMessage,
+  mut keep: impl FnMut(&(ClientId, ServerId)) -> bool,
+) {
+  let mut dsts = Vec::new();
+  let mut signature = Vec::new();
+  for (n, dst) in fqm.dsts.iter().enumerate() {
+    if keep(dst) {
+      dsts.push(*dst);
+      signature.extend_from_slice(destination_signature(fqm, n));
+    }
+  }
+  fqm.dsts = dsts;
+  fqm.signature = signature;
+}
+
+fn uuid(out: &mut Vec<u8>, id: u128) {
+  out.extend_from_slice(&id.to_le_bytes());
+}
+
+fn string(out: &mut Vec<u8>, s: &str) {
+  out.extend_from_slice(&(s.len() as u64).to_le_bytes());
+  out.extend_from_slice(s.as_bytes());
+}
+
+fn clients(out: &mut Vec<u8>, clients: &HashMap<ClientId, String>) {
+  let mut sorted: Vec<(&ClientId, &String)> = clients.iter().collect();
+  sorted.sort();
+  out.extend_from_slice(&(sorted.len() as u64).to_le_bytes());
+  for (client, name) in sorted {
+    uuid(out, client.into());
+    string(out, name);
+  }
+}
+
+/// the bytes that are signed
+pub fn signed_bytes(message: &ServerMessage) -> Option<Vec<u8>> {
+  let mut out = Vec::new();
+  let origin = signer(message)?;
+  match message {
+    ServerMessage::Announce {
+      clients: announced,
+      server_name,
+      version,
+      ..
+    } => {
+      out.extend_from_slice(b"announce");
+      uuid(&mut out, (&origin).into());
+      string(&mut out, server_name);
+      out.extend_from_slice(&version.to_le_bytes());
+      clients(&mut out, announced);
+    }
+    ServerMessage::AnnounceDelta {
+      since,
+      version,
+      joined,
+      left,
+      server_name,
+      ..
+    } => {
+      out.extend_from_slice(b"delta");
+      uuid(&mut out, (&origin).into());
+      string(&mut out, server_name);
+      out.extend_from_slice(&since.to_le_bytes());
+      out.extend_from_slice(&version.to_le_bytes());
+      clients(&mut out, joined);
+      let mut left = left.clone();
+      left.sort();
+      out.extend_from_slice(&(left.len() as u64).to_le_bytes());
+      for client in &left {
+        uuid(&mut out, client.into());
+      }
+    }
+    // without the destinations, that have their own signatures
+    ServerMessage::Message(fqm) => {
+      out.extend_from_slice(b"message");
+      uuid(&mut out, (&origin).into());
+      uuid(&mut out, (&fqm.src).into());
+      uuid(&mut out, (&fqm.id).into());
+      string(&mut out, &fqm.content);
+    }
+    ServerMessage::Withdraw { route, version, .. } => {
+      out.extend_from_slice(b"withdraw");
+      uuid(&mut out, (&route[0]).into());
+      uuid(&mut out, (&origin).into());
+      out.extend_from_slice(&version.to_le_bytes());
+    }
+    ServerMessage::WithdrawClients { clients, .. } => {
+      out.extend_from_slice(b"withdraw clients");
+      uuid(&mut out, (&origin).into());
+      let mut clients = clients.clone();
+      clients.sort();
+      out.extend_from_slice(&(clients.len() as u64).to_le_bytes());
+      for client in &clients {
+        uuid(&mut out, client.into());
+      }
+    }
+    ServerMessage::DeliveryError {
+      src, srcsrv, error, ..
+    } => {
+      out.extend_from_slice(b"delivery error");
+      uuid(&mut out, (&origin).into());
+      uuid(&mut out, src.into());
+      uuid(&mut out, srcsrv.into());
+      let (variant, client) = match error {
+        DelayedError::UnknownRecipient(c) => (0u8, c),
+        DelayedError::RemoteBoxFull(c) => (1, c),
+        DelayedError::Unroutable(c) => (2, c),
+        DelayedError::HopLimit(c) => (3, c),
+      };
+      out.push(variant);
+      uuid(&mut out, client.into());
+    }
+    _ => return None,
+  }
+  Some(out)
+}
+
+/// what the signature of a message covers for one of its destinations
+fn destination_bytes(bytes: &[u8], dest: &ClientId) -> Vec<u8> {
+  let mut out = bytes.to_vec();
+  uuid(&mut out, dest.into());
+  out
+}
+
+/// sets the signature of the message, the messages that are not signed are left as they are
+pub fn sign(key: &SigningKey, message: &mut ServerMessage) {
+  if let Some(bytes) = signed_bytes(message) {
+    let signature = match message {
+      ServerMessage::Message(fqm) => fqm
+        .dsts
+        .iter()
+        .flat_map(|(dest, _)| key.sign(&destination_bytes(&bytes, dest)).to_bytes())
+        .collect(),
+      _ => key.sign(&bytes).to_bytes().to_vec(),
+    };
+    if let Some(field) = signature_mut(message) {
+      *field = signature;
+    }
+  }
+}
+
+fn verify_bytes(key: &VerifyingKey, bytes: &[u8], signature: &[u8]) -> bool {
+  match Signature::from_slice(signature) {
+    Ok(signature) => key.verify(bytes, &signature).is_ok(),
+    Err(_) => false,
+  }
+}
+
+/// true if the signature of the message was made with the key
+/// messages must be signed for each of their destinations
+pub fn verify(key: &VerifyingKey, message: &ServerMessage) -> bool {
+  let bytes = match signed_bytes(message) {
+    Some(b) => b,
+    None => return false,
+  };
+  match message {
+    ServerMessage::Message(fqm) => {
+      !fqm.dsts.is_empty()
+        && fqm.signature.len() == fqm.dsts.len() * SIGNATURE_LENGTH
+        && fqm
+          .dsts
+          .iter()
+          .zip(fqm.signature.chunks(SIGNATURE_LENGTH))
+          .all(|((dest, _), signature)| {
+            verify_bytes(key, &destination_bytes(&bytes, dest), signature)
+          })
+    }
+    ServerMessage::Announce { signature, .. }
+    | ServerMessage::AnnounceDelta { signature, .. }
+    | ServerMessage::Withdraw { signature, .. }
+    | ServerMessage::WithdrawClients { signature, .. }
+    | ServerMessage::DeliveryError { signature, .. } => verify_bytes(key, &bytes, signature),
+    _ => false,
+  }
+}
+
+/// random bytes that a neighbour that connects has to sign
+pub fn challenge() -> [u8; 32] {
+  let mut challenge = [0; 32];
+  rand::RngCore::fill_bytes(&mut rand::rngs::OsRng, &mut challenge);
+  challenge
+}
+
+fn link_bytes(challenge: &[u8], from: &ServerId, to: &ServerId) -> Vec<u8> {
+  let mut out = b"link".to_vec();
+  uuid(&mut out, from.into());
+  uuid(&mut out, to.into());
+  out.extend_from_slice(challenge);
+  out
+}
+
+/// the answer of the server that connects, from, to the challenge of its neighbour, to
+pub fn sign_link(key: &SigningKey, challenge: &[u8], from: &ServerId, to: &ServerId) -> Vec<u8> {
+  key
+    .sign(&link_bytes(challenge, from, to))
+    .to_bytes()
+    .to_vec()
+}
+
+/// true if the answer to the challenge was signed with the key
+pub fn verify_link(
+  key: &VerifyingKey,
+  challenge: &[u8],
+  from: &ServerId,
+  to: &ServerId,
+  answer: &[u8],
+) -> bool {
+  verify_bytes(key, &link_bytes(challenge, from, to), answer)
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+  bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn from_hex(s: &str) -> anyhow::Result<Vec<u8>> {
+  anyhow::ensure!(s.len().is_multiple_of(2), "odd number of hex digits");
+  (0..s.len())
+    .step_by(2)
+    .map(|i| {
+      s.get(i..i + 2)
+        .and_then(|b| u8::from_str_radix(b, 16).ok())
+        .with_context(|| format!("bad hex digits at {}", i))
+    })
+    .collect()
+}
+
+/// a public key, written in hex
+pub fn parse_public_key(s: &str) -> anyhow::Result<VerifyingKey> {
+  let bytes: [u8; 32] = from_hex(s)?
+    .try_into()
+    .map_err(|_| anyhow::anyhow!("a public key is 32 bytes"))?;
+  Ok(VerifyingKey::from_bytes(&bytes)?)
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::messages::{FullyQualifiedMessage, MessageId};
+
+  #[test]
+  fn announces() {
+    let key = generate();
+    let (origin, relay) = (ServerId::default(), ServerId::default());
+    let clients: HashMap<ClientId, String> = (0..20)
+      .map(|i| (ClientId::default(), format!("user {i}")))
+      .collect();
+    let mut announce = ServerMessage::Announce {
+      route: vec![origin],
+      clients: clients.clone(),
+      signature: Vec::new(),
+      server_name: String::new(),
+      version: 4,
+    };
+    assert!(!verify(&key.verifying_key(), &announce));
+    sign(&key, &mut announce);
+    assert!(verify(&key.verifying_key(), &announce));
+    assert!(!verify(&generate().verifying_key(), &announce));
+
+    // relays only append to the route, and the order of the map does not matter
+    let (signature, mut reordered) = match announce {
+      ServerMessage::Announce { signature, .. } => (signature, HashMap::new()),
+      _ => unreachable!(),
+    };
+    let mut sorted: Vec<_> = clients.into_iter().collect();
+    sorted.sort();
+    reordered.extend(sorted.into_iter().rev());
+    let mut relayed = ServerMessage::Announce {
+      route: vec![origin, relay],
+      clients: reordered,
+      signature,
+      server_name: String::new(),
+      version: 4,
+    };
+    assert!(verify(&key.verifying_key(), &relayed));
+    let mut renewed = relayed.clone();
+    if let ServerMessage::Announce { version, .. } = &mut renewed {
+      *version = 5;
+    }
+    assert!(!verify(&key.verifying_key(), &renewed));
+    if let ServerMessage::Announce { clients, .. } = &mut relayed {
+      clients.insert(ClientId::default(), "injected".into());
+    }
+    assert!(!verify(&key.verifying_key(), &relayed));
+  }
+
+  #[test]
+  fn messages() {
+    let key = generate();
+    let (b, c) = (ServerId::default(), ServerId::default());
+    let (c1, c2, c3) = (
+      ClientId::default(),
+      ClientId::default(),
+      ClientId::default(),
+    );
+    let fqm = FullyQualifiedMessage {
+      src: ClientId::default(),
+      srcsrv: ServerId::default(),
+      dsts: vec![(c1, b), (c2, c), (c3, c)],
+      content: "signed".into(),
+      id: MessageId::from(3),
+      hops: 8,
+      signature: Vec::new(),
+    };
+    let mut message = ServerMessage::Message(fqm.clone());
+    sign(&key, &mut message);
+    let signed = match message {
+      ServerMessage::Message(f) => f,
+      _ => unreachable!(),
+    };
+    assert_eq!(signed.signature.len(), 3 * SIGNATURE_LENGTH);
+
+    // relays split the destinations between their next hops
+    let mut forwarded = FullyQualifiedMessage {
+      hops: 7,
+      ..signed.clone()
+    };
+    retain_destinations(&mut forwarded, |(_, srv)| *srv == c);
+    assert_eq!(forwarded.dsts, vec![(c2, c), (c3, c)]);
+    assert!(verify(
+      &key.verifying_key(),
+      &ServerMessage::Message(forwarded.clone())
+    ));
+
+    // but they can not add a destination, or send the message to another client
+    let mut added = signed.clone();
+    added.dsts.push((ClientId::default(), c));
+    assert!(!verify(
+      &key.verifying_key(),
+      &ServerMessage::Message(added)
+    ));
+    let mut redirected = forwarded.clone();
+    redirected.dsts[1].0 = ClientId::default();
+    assert!(!verify(
+      &key.verifying_key(),
+      &ServerMessage::Message(redirected)
+    ));
+    let mut swapped = forwarded;
+    swapped.dsts.swap(0, 1);
+    assert!(!verify(
+      &key.verifying_key(),
+      &ServerMessage::Message(swapped)
+    ));
+    let mut emptied = signed.clone();
+    retain_destinations(&mut emptied, |_| false);
+    assert!(!verify(
+      &key.verifying_key(),
+      &ServerMessage::Message(emptied)
+    ));
+
+    let rewritten = ServerMessage::Message(FullyQualifiedMessage {
+      content: "rewritten".into(),
+      ..signed
+    });
+    assert!(!verify(&key.verifying_key(), &rewritten));
+  }
+
+  #[test]
+  fn withdrawals() {
+    let key = generate();
+    let (a, b, relay) = (
+      ServerId::default(),
+      ServerId::default(),
+      ServerId::default(),
+    );
+
+    // the server that lost the link signs, relays append to the route
+    let mut withdraw = ServerMessage::Withdraw {
+      route: vec![a, b],
+      signature: Vec::new(),
+      version: 9,
+    };
+    sign(&key, &mut withdraw);
+    assert_eq!(signer(&withdraw), Some(b));
+    if let ServerMessage::Withdraw { route, .. } = &mut withdraw {
+      route.push(relay);
+    }
+    assert!(verify(&key.verifying_key(), &withdraw));
+    let mut renewed = withdraw.clone();
+    if let ServerMessage::Withdraw { version, .. } = &mut renewed {
+      *version = 10;
+    }
+    assert!(!verify(&key.verifying_key(), &renewed));
+    if let ServerMessage::Withdraw { route, .. } = &mut withdraw {
+      route[0] = relay;
+    }
+    assert!(!verify(&key.verifying_key(), &withdraw));
+    // a withdrawal of the link to the neighbour itself can not be signed
+    let mut local = ServerMessage::Withdraw {
+      route: vec![a],
+      signature: Vec::new(),
+      version: 9,
+    };
+    sign(&key, &mut local);
+    assert!(!verify(&key.verifying_key(), &local));
+
+    let (c1, c2) = (ClientId::default(), ClientId::default());
+    let mut clients = ServerMessage::WithdrawClients {
+      route: vec![a],
+      clients: vec![c1, c2],
+      signature: Vec::new(),
+    };
+    sign(&key, &mut clients);
+    if let ServerMessage::WithdrawClients { route, clients, .. } = &mut clients {
+      route.push(relay);
+      clients.reverse();
+    }
+    assert!(verify(&key.verifying_key(), &clients));
+    if let ServerMessage::WithdrawClients { clients, .. } = &mut clients {
+      clients.push(ClientId::default());
+    }
+    assert!(!verify(&key.verifying_key(), &clients));
+  }
+
+  #[test]
+  fn delivery_errors() {
+    let key = generate();
+    let (c1, c2) = (ClientId::default(), ClientId::default());
+    let mut error = ServerMessage::DeliveryError {
+      src: c1,
+      srcsrv: ServerId::default(),
+      error: DelayedError::RemoteBoxFull(c2),
+      origin: ServerId::default(),
+      signature: Vec::new(),
+    };
+    assert!(!verify(&key.verifying_key(), &error));
+    sign(&key, &mut error);
+    assert!(verify(&key.verifying_key(), &error));
+    assert!(!verify(&generate().verifying_key(), &error));
+    for forged in [
+      DelayedError::UnknownRecipient(c2),
+      DelayedError::RemoteBoxFull(c1),
+    ] {
+      let mut forged_error = error.clone();
+      if let ServerMessage::DeliveryError { error, .. } = &mut forged_error {
+        *error = forged;
+      }
+      assert!(!verify(&key.verifying_key(), &forged_error));
+    }
+    // a relay can not claim the error of another server
+    if let ServerMessage::DeliveryError { origin, .. } = &mut error {
+      *origin = ServerId::default();
+    }
+    assert!(!verify(&key.verifying_key(), &error));
+  }
+
+  #[test]
+  fn links() {
+    let key = generate();
+    let (a, b) = (ServerId::default(), ServerId::default());
+    let challenge = challenge();
+    let answer = sign_link(&key, &challenge, &a, &b);
+    assert!(verify_link(
+      &key.verifying_key(),
+      &challenge,
+      &a,
+      &b,
+      &answer
+    ));
+    // replayed to another server, or for another challenge
+    assert!(!verify_link(
+      &key.verifying_key(),
+      &challenge,
+      &a,
+      &a,
+      &answer
+    ));
+    assert!(!verify_link(
+      &key.verifying_key(),
+      &[0; 32],
+      &a,
+      &b,
+      &answer
+    ));
+    assert!(!verify_link(
+      &generate().verifying_key(),
+      &challenge,
+      &a,
+      &b,
+      &answer
+    ));
+    assert!(!verify_link(&key.verifying_key(), &challenge, &a, &b, &[]));
+  }
+
+  #[test]
+  fn hex_keys() {
+    let key = generate().verifying_key();
+    let hex = to_hex(key.as_bytes());
+    assert_eq!(hex.len(), 64);
+    assert_eq!(parse_public_key(&hex).unwrap(), key);
+    assert!(parse_public_key(&hex[2..]).is_err());
+    assert!(parse_public_key("zz").is_err());
+  }
+}
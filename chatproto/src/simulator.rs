@@ -0,0 +1,795 @@
+//! deterministic federation of in-process servers
+//!
+//! The simulator plays the network between servers: it carries the `Transfer` replies of clients, the
+//! `Outgoing` replies of servers and their periodic announces to their next hop. Delays, losses, reordering and partitions are driven by a
+//! seeded random generator, and time is a tick counter, so that a run only depends on its seed.
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use std::{
+  collections::{BTreeMap, HashMap, HashSet},
+  ops::RangeInclusive,
+};
+
+use crate::{
+  core::{MessageServer, ServerConfig},
+  messages::{
+    ClientId, ClientMessage, ClientPollReply, ClientReply, ServerId, ServerMessage, ServerReply,
+  },
+};
+
+/// network conditions
+#[derive(Clone, Debug)]
+pub struct SimConfig {
+  pub seed: u64,
+  /// ticks a message spends on a link
+  pub delay: RangeInclusive<u64>,
+  /// probability for a message to be lost
+  pub loss: f64,
+  /// if false, each link delivers its messages in the order they were sent
+  pub reorder: bool,
+}
+
+impl Default for SimConfig {
+  fn default() -> Self {
+    SimConfig {
+      seed: 0,
+      delay: 1..=1,
+      loss: 0.0,
+      reorder: false,
+    }
+  }
+}
+
+/// what happened to the messages between servers
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SimStats {
+  pub sent: u64,
+  pub delivered: u64,
+  /// randomly lost
+  pub lost: u64,
+  /// sent on a link that was cut, or cut while they were in flight
+  pub partitioned: u64,
+  /// server replies that are neither Outgoing nor empty, with the server that made them
+  pub rejected: Vec<(ServerId, ServerReply)>,
+}
+
+/// links of servers 0 to n - 1, in a line
+pub fn line(n: usize) -> Vec<(usize, usize)> {
+  (1..n).map(|i| (i - 1, i)).collect()
+}
+
+/// links of servers 0 to n - 1, in a ring
+pub fn ring(n: usize) -> Vec<(usize, usize)> {
+  let mut links = line(n);
+  if n > 2 {
+    links.push((n - 1, 0));
+  }
+  links
+}
+
+/// links of servers 1 to n - 1 to server 0
+pub fn star(n: usize) -> Vec<(usize, usize)> {
+  (1..n).map(|i| (0, i)).collect()
+}
+
+struct Packet {
+  from: usize,
+  to: usize,
+  message: ServerMessage,
+}
+
+/// servers, identified by their index, linked according to the declared topology
+pub struct Simulator<M> {
+  servers: Vec<(ServerId, M)>,
+  links: HashSet<(usize, usize)>,
+  cut: HashSet<(usize, usize)>,
+  config: SimConfig,
+  rng: StdRng,
+  now: u64,
+  /// messages in flight, by delivery time and then sending order
+  queue: BTreeMap<(u64, u64), Packet>,
+  sequence: u64,
+  /// delivery time of the last message of each directed link, to keep them in order
+  last_delivery: HashMap<(usize, usize), u64>,
+  stats: SimStats,
+}
+
+fn link(a: usize, b: usize) -> (usize, usize) {
+  (a.min(b), a.max(b))
+}
+
+impl<M: MessageServer> Simulator<M> {
+  /// count servers with the default configuration, and their linked servers as neighbours
+  pub fn new(count: usize, links: &[(usize, usize)], config: SimConfig) -> Self {
+    let ids: Vec<ServerId> = (0..count).map(|_| ServerId::default()).collect();
+    let servers = ids
+      .iter()
+      .enumerate()
+      .map(|(n, id)| {
+        let neighbours = links
+          .iter()
+          .filter_map(|(a, b)| match (*a == n, *b == n) {
+            (true, _) => Some(ids[*b]),
+            (_, true) => Some(ids[*a]),
+            _ => None,
+          })
+          .collect();
+        let server_config = ServerConfig {
+          neighbours,
+          ..ServerConfig::default()
+        };
+        (*id, M::with_config(*id, server_config))
+      })
+      .collect();
+    Self::with_servers(servers, links, config)
+  }
+
+  /// the neighbours in the configuration of the servers should match the links
+  pub fn with_servers(
+    servers: Vec<(ServerId, M)>,
+    links: &[(usize, usize)],
+    config: SimConfig,
+  ) -> Self {
+    for (a, b) in links {
+      assert!(
+        *a < servers.len() && *b < servers.len() && a != b,
+        "bad link {}-{}",
+        a,
+        b
+      );
+    }
+    Simulator {
+      servers,
+      links: links.iter().map(|(a, b)| link(*a, *b)).collect(),
+      cut: HashSet::new(),
+      rng: StdRng::seed_from_u64(config.seed),
+      config,
+      now: 0,
+      queue: BTreeMap::new(),
+      sequence: 0,
+      last_delivery: HashMap::new(),
+      stats: SimStats::default(),
+    }
+  }
+
+  pub fn id(&self, n: usize) -> ServerId {
+    self.servers[n].0
+  }
+
+  pub fn server(&self, n: usize) -> &M {
+    &self.servers[n].1
+  }
+
+  pub fn stats(&self) -> &SimStats {
+    &self.stats
+  }
+
+  /// current tick
+  pub fn now(&self) -> u64 {
+    self.now
+  }
+
+  fn index(&self, id: ServerId) -> Option<usize> {
+    self.servers.iter().position(|(s, _)| *s == id)
+  }
+
+  /// messages can no longer go between a and b, including those in flight
+  pub fn cut(&mut self, a: usize, b: usize) {
+    self.cut.insert(link(a, b));
+  }
+
+  pub fn heal(&mut self, a: usize, b: usize) {
+    self.cut.remove(&link(a, b));
+  }
+
+  /// cuts every link between the group and the other servers
+  pub fn partition(&mut self, group: &[usize]) {
+    let crossing: Vec<(usize, usize)> = self
+      .links
+      .iter()
+      .filter(|(a, b)| group.contains(a) != group.contains(b))
+      .copied()
+      .collect();
+    self.cut.extend(crossing);
+  }
+
+  pub fn heal_all(&mut self) {
+    self.cut.clear();
+  }
+
+  /// cuts the link, and both ends notice and withdraw it
+  pub async fn disconnect(&mut self, a: usize, b: usize) {
+    self.cut(a, b);
+    for (n, other) in [(a, b), (b, a)] {
+      // the server versions the withdrawal of its own link
+      let withdrawal = ServerMessage::Withdraw {
+        route: vec![self.id(other)],
+        signature: Vec::new(),
+        version: 0,
+      };
+      let reply = self.servers[n].1.handle_server_message(withdrawal).await;
+      self.reply(n, reply);
+    }
+  }
+
+  pub async fn register(&mut self, n: usize, name: &str) -> ClientId {
+    self.servers[n]
+      .1
+      .register_local_client(name.to_string())
+      .await
+  }
+
+  /// handles a client message, and sends the transfers to the next hop
+  pub async fn send(&mut self, n: usize, src: ClientId, msg: ClientMessage) -> Vec<ClientReply> {
+    let replies = self.servers[n].1.handle_client_message(src, msg).await;
+    for (i, r) in replies.iter().enumerate() {
+      if let ClientReply::Transfer(nexthop, message) = r {
+        // destinations that share a next hop share the transfer
+        if !replies[..i].contains(r) {
+          self.forward(n, *nexthop, message.clone());
+        }
+      }
+    }
+    replies
+  }
+
+  pub async fn poll(&self, n: usize, client: ClientId) -> ClientPollReply {
+    self.servers[n].1.client_poll(client).await
+  }
+
+  /// every server sends its periodic announces
+  pub async fn announce_all(&mut self) {
+    for n in 0..self.servers.len() {
+      self.announce(n).await;
+    }
+  }
+
+  /// server n sends its periodic announces
+  pub async fn announce(&mut self, n: usize) {
+    for o in self.servers[n].1.announces().await {
+      self.forward(n, o.nexthop, o.message);
+    }
+  }
+
+  /// sends to a server by its id, that must be a neighbour
+  fn forward(&mut self, from: usize, nexthop: ServerId, message: ServerMessage) {
+    match self.index(nexthop) {
+      Some(to) if self.links.contains(&link(from, to)) => self.transmit(from, to, message),
+      _ => {
+        let reply = ServerReply::Error(format!("{} is not a neighbour", nexthop));
+        self.stats.rejected.push((self.id(from), reply));
+      }
+    }
+  }
+
+  fn transmit(&mut self, from: usize, to: usize, message: ServerMessage) {
+    self.stats.sent += 1;
+    if self.cut.contains(&link(from, to)) {
+      self.stats.partitioned += 1;
+      return;
+    }
+    if self.config.loss > 0.0 && self.rng.gen_bool(self.config.loss) {
+      self.stats.lost += 1;
+      return;
+    }
+    let mut at = self.now + self.rng.gen_range(self.config.delay.clone()).max(1);
+    if !self.config.reorder {
+      let last = self.last_delivery.entry((from, to)).or_default();
+      at = at.max(*last);
+      *last = at;
+    }
+    self.sequence += 1;
+    self
+      .queue
+      .insert((at, self.sequence), Packet { from, to, message });
+  }
+
+  /// delivers the messages of the next tick that has some, false if nothing is in flight
+  pub async fn step(&mut self) -> bool {
+    let at = match self.queue.keys().next() {
+      Some((at, _)) => *at,
+      None => return false,
+    };
+    self.now = at;
+    while let Some(entry) = self.queue.first_entry() {
+      if entry.key().0 != at {
+        break;
+      }
+      let packet = entry.remove();
+      self.deliver(packet).await;
+    }
+    true
+  }
+
+  /// runs until no message is in flight, and returns the number of ticks that elapsed
+  /// panics after max_ticks, as messages are probably looping
+  pub async fn run(&mut self, max_ticks: u64) -> u64 {
+    let start = self.now;
+    while self.step().await {
+      assert!(
+        self.now - start <= max_ticks,
+        "messages still in flight after {} ticks",
+        max_ticks
+      );
+    }
+    self.now - start
+  }
+
+  async fn deliver(&mut self, packet: Packet) {
+    let Packet { from, to, message } = packet;
+    if self.cut.contains(&link(from, to)) {
+      self.stats.partitioned += 1;
+      return;
+    }
+    self.stats.delivered += 1;
+    let reply = self.servers[to].1.handle_server_message(message).await;
+    self.reply(to, reply);
+  }
+
+  fn reply(&mut self, n: usize, reply: ServerReply) {
+    match reply {
+      ServerReply::Outgoing(outgoing) => {
+        for o in outgoing {
+          self.forward(n, o.nexthop, o.message);
+        }
+      }
+      r => self.stats.rejected.push((self.id(n), r)),
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::{
+    core::MAILBOX_SIZE,
+    federation::FederationPolicy,
+    messages::{Address, DelayedError},
+    signing,
+    solutions::reference::Server,
+  };
+
+  fn text(dest: ClientId, content: &str) -> ClientMessage {
+    ClientMessage::Text {
+      dest,
+      content: content.into(),
+    }
+  }
+
+  fn received(reply: ClientPollReply) -> Option<(ClientId, String)> {
+    match reply {
+      ClientPollReply::Message { src, content, .. } => Some((src, content)),
+      _ => None,
+    }
+  }
+
+  #[test]
+  fn multi_hop_delivery() {
+    async_std::task::block_on(async {
+      let mut sim: Simulator<Server> = Simulator::new(4, &line(4), SimConfig::default());
+      let c0 = sim.register(0, "first").await;
+      let c3 = sim.register(3, "last").await;
+      sim.announce_all().await;
+      sim.run(100).await;
+      assert_eq!(
+        sim.server(0).route_to(sim.id(3)).await,
+        Some(vec![sim.id(3), sim.id(2), sim.id(1)])
+      );
+
+      let r = sim.send(0, c0, text(c3, "hello")).await;
+      assert!(matches!(r[..], [ClientReply::Transfer(hop, _)] if hop == sim.id(1)));
+      sim.run(100).await;
+      assert_eq!(
+        received(sim.poll(3, c3).await),
+        Some((c0, "hello".to_string()))
+      );
+      assert_eq!(sim.poll(3, c3).await, ClientPollReply::Nothing);
+      assert!(sim.stats().rejected.is_empty(), "{:?}", sim.stats());
+    })
+  }
+
+  #[test]
+  fn delayed_until_announced() {
+    async_std::task::block_on(async {
+      let mut sim: Simulator<Server> = Simulator::new(3, &ring(3), SimConfig::default());
+      let c0 = sim.register(0, "early").await;
+      let c2 = sim.register(2, "late").await;
+      let r = sim.send(0, c0, text(c2, "waiting")).await;
+      assert_eq!(r, [ClientReply::Delayed]);
+      sim.announce(2).await;
+      sim.run(100).await;
+      assert_eq!(
+        received(sim.poll(2, c2).await),
+        Some((c0, "waiting".to_string()))
+      );
+    })
+  }
+
+  #[test]
+  fn partitions() {
+    async_std::task::block_on(async {
+      let mut sim: Simulator<Server> = Simulator::new(3, &line(3), SimConfig::default());
+      let c0 = sim.register(0, "left").await;
+      let c2 = sim.register(2, "right").await;
+      sim.announce_all().await;
+      sim.run(100).await;
+
+      sim.partition(&[2]);
+      sim.send(0, c0, text(c2, "lost")).await;
+      sim.run(100).await;
+      assert_eq!(sim.stats().partitioned, 1);
+      assert_eq!(sim.poll(2, c2).await, ClientPollReply::Nothing);
+
+      sim.heal_all();
+      sim.send(0, c0, text(c2, "found")).await;
+      sim.run(100).await;
+      assert_eq!(
+        received(sim.poll(2, c2).await),
+        Some((c0, "found".to_string()))
+      );
+    })
+  }
+
+  #[test]
+  fn star_fan_out() {
+    async_std::task::block_on(async {
+      let mut sim: Simulator<Server> = Simulator::new(4, &star(4), SimConfig::default());
+      let src = sim.register(1, "sender").await;
+      let near = sim.register(1, "near").await;
+      let hub = sim.register(0, "hub").await;
+      let a = sim.register(2, "a").await;
+      let b = sim.register(2, "b").await;
+      let c = sim.register(3, "c").await;
+      sim.announce_all().await;
+      sim.run(100).await;
+      sim.announce_all().await;
+      sim.run(100).await;
+
+      let sent = sim.stats().sent;
+      let r = sim
+        .send(
+          1,
+          src,
+          ClientMessage::MText {
+            dest: vec![a, near, hub, b, c],
+            content: "everyone".into(),
+          },
+        )
+        .await;
+      assert!(matches!(r[1], ClientReply::Delivered(_)));
+      // everything goes through the hub, in a single transfer
+      let transfer = &r[0];
+      assert!(matches!(transfer, ClientReply::Transfer(hop, _) if *hop == sim.id(0)));
+      assert!([&r[2], &r[3], &r[4]].iter().all(|t| *t == transfer));
+      sim.run(100).await;
+      // then once to server 2, and once to server 3
+      assert_eq!(sim.stats().sent - sent, 3);
+      for (n, client) in [(1, near), (0, hub), (2, a), (2, b), (3, c)] {
+        assert_eq!(
+          received(sim.poll(n, client).await),
+          Some((src, "everyone".to_string()))
+        );
+        assert_eq!(sim.poll(n, client).await, ClientPollReply::Nothing);
+      }
+      assert!(sim.stats().rejected.is_empty(), "{:?}", sim.stats());
+    })
+  }
+
+  #[test]
+  fn remote_mailbox_full() {
+    async_std::task::block_on(async {
+      let mut sim: Simulator<Server> = Simulator::new(3, &line(3), SimConfig::default());
+      let src = sim.register(0, "sender").await;
+      let dest = sim.register(2, "busy").await;
+      let other = sim.register(2, "other").await;
+      sim.announce_all().await;
+      sim.run(100).await;
+      for n in 0..MAILBOX_SIZE {
+        sim.send(2, other, text(dest, &format!("{n}"))).await;
+      }
+
+      sim.send(0, src, text(dest, "one too many")).await;
+      sim.run(100).await;
+      assert_eq!(
+        sim.poll(0, src).await,
+        ClientPollReply::DelayedError(DelayedError::RemoteBoxFull(dest))
+      );
+      assert!(sim.stats().rejected.is_empty(), "{:?}", sim.stats());
+    })
+  }
+
+  #[test]
+  fn withdrawn_links() {
+    async_std::task::block_on(async {
+      let mut sim: Simulator<Server> = Simulator::new(4, &ring(4), SimConfig::default());
+      let c0 = sim.register(0, "sender").await;
+      let c2 = sim.register(2, "receiver").await;
+      sim.announce_all().await;
+      sim.run(100).await;
+      let route = sim.server(0).route_to(sim.id(2)).await.unwrap();
+      let first = if route[1] == sim.id(1) { 1 } else { 3 };
+      let other = 4 - first;
+
+      sim.disconnect(0, first).await;
+      sim.run(100).await;
+      assert_eq!(
+        sim.server(0).route_to(sim.id(2)).await,
+        Some(vec![sim.id(2), sim.id(other)])
+      );
+      let r = sim.send(0, c0, text(c2, "around")).await;
+      assert!(matches!(r[..], [ClientReply::Transfer(hop, _)] if hop == sim.id(other)));
+      sim.run(100).await;
+      assert_eq!(
+        received(sim.poll(2, c2).await),
+        Some((c0, "around".to_string()))
+      );
+
+      // nothing is left between 0 and 2
+      sim.disconnect(0, other).await;
+      sim.run(100).await;
+      assert_eq!(sim.server(0).route_to(sim.id(2)).await, None);
+      assert_eq!(
+        sim.send(0, c0, text(c2, "later")).await,
+        [ClientReply::Delayed]
+      );
+      assert!(sim.stats().rejected.is_empty(), "{:?}", sim.stats());
+    })
+  }
+
+  #[test]
+  fn missed_delta_resync() {
+    async_std::task::block_on(async {
+      let mut sim: Simulator<Server> = Simulator::new(3, &line(3), SimConfig::default());
+      let first = sim.register(0, "first").await;
+      sim.announce_all().await;
+      sim.run(100).await;
+
+      // the delta with the second client is lost
+      sim.cut(0, 1);
+      let second = sim.register(0, "second").await;
+      sim.announce(0).await;
+      sim.run(100).await;
+      sim.heal(0, 1);
+
+      // the next one does not follow what 1 knows, so it asks for everything
+      let third = sim.register(0, "third").await;
+      sim.announce(0).await;
+      sim.run(100).await;
+      let mut users: Vec<ClientId> = sim.server(2).list_users().await.into_keys().collect();
+      users.sort();
+      let mut expected = vec![first, second, third];
+      expected.sort();
+      assert_eq!(users, expected);
+      assert!(sim.stats().rejected.is_empty(), "{:?}", sim.stats());
+    })
+  }
+
+  #[test]
+  fn inconsistent_loop() {
+    async_std::task::block_on(async {
+      let mut sim: Simulator<Server> = Simulator::new(2, &line(2), SimConfig::default());
+      let c0 = sim.register(0, "sender").await;
+      // each server believes the other one is on the way to far
+      let far = ServerId::default();
+      let remote = ClientId::default();
+      for (n, other) in [(0, 1), (1, 0)] {
+        let announce = ServerMessage::Announce {
+          route: vec![far, sim.id(other)],
+          clients: HashMap::from([(remote, "remote".to_string())]),
+          signature: Vec::new(),
+          server_name: String::new(),
+          version: 1,
+        };
+        sim.server(n).handle_server_message(announce).await;
+      }
+      let r = sim.send(0, c0, text(remote, "around")).await;
+      assert!(matches!(r[..], [ClientReply::Transfer(hop, _)] if hop == sim.id(1)));
+      sim.run(1000).await;
+      // back to 0, where the copy is dropped
+      assert_eq!(sim.stats().sent, 2);
+      assert_eq!(sim.poll(0, c0).await, ClientPollReply::Nothing);
+      assert!(sim.stats().rejected.is_empty(), "{:?}", sim.stats());
+    })
+  }
+
+  #[test]
+  fn hop_limit_reported() {
+    async_std::task::block_on(async {
+      let ids: Vec<ServerId> = (0..4).map(|_| ServerId::default()).collect();
+      let mut servers: Vec<(ServerId, Server)> = Vec::new();
+      for (n, id) in ids.iter().enumerate() {
+        let config = ServerConfig {
+          // the servers before and after, in a line
+          neighbours: ids
+            .iter()
+            .enumerate()
+            .filter(|(m, _)| n.abs_diff(*m) == 1)
+            .map(|(_, id)| *id)
+            .collect(),
+          max_hops: Some(1),
+          ..ServerConfig::default()
+        };
+        servers.push((*id, Server::with_config(*id, config)));
+      }
+      let mut sim = Simulator::with_servers(servers, &line(4), SimConfig::default());
+      let c0 = sim.register(0, "sender").await;
+      let c2 = sim.register(2, "close").await;
+      let c3 = sim.register(3, "too far").await;
+      sim.announce_all().await;
+      sim.run(100).await;
+
+      // 1 forwards, with no hop left for 2
+      sim
+        .send(
+          0,
+          c0,
+          ClientMessage::MText {
+            dest: vec![c2, c3],
+            content: "hello".into(),
+          },
+        )
+        .await;
+      sim.run(100).await;
+      assert_eq!(
+        received(sim.poll(2, c2).await),
+        Some((c0, "hello".to_string()))
+      );
+      assert_eq!(sim.poll(3, c3).await, ClientPollReply::Nothing);
+      assert_eq!(
+        sim.poll(0, c0).await,
+        ClientPollReply::DelayedError(DelayedError::HopLimit(c3))
+      );
+      assert!(sim.stats().rejected.is_empty(), "{:?}", sim.stats());
+    })
+  }
+
+  #[test]
+  fn signed_resync() {
+    async_std::task::block_on(async {
+      let ids: Vec<ServerId> = (0..3).map(|_| ServerId::default()).collect();
+      let keys: Vec<signing::SigningKey> = ids.iter().map(|_| signing::generate()).collect();
+      let trusted: HashMap<ServerId, signing::VerifyingKey> = ids
+        .iter()
+        .zip(&keys)
+        .map(|(id, key)| (*id, key.verifying_key()))
+        .collect();
+      let mut servers: Vec<(ServerId, Server)> = Vec::new();
+      for (n, id) in ids.iter().enumerate() {
+        let config = ServerConfig {
+          neighbours: ids
+            .iter()
+            .enumerate()
+            .filter(|(m, _)| n.abs_diff(*m) == 1)
+            .map(|(_, id)| *id)
+            .collect(),
+          federation: FederationPolicy {
+            trusted: trusted.clone(),
+            require_signatures: true,
+            ..FederationPolicy::default()
+          },
+          signing_key: Some(keys[n].clone()),
+          ..ServerConfig::default()
+        };
+        servers.push((*id, Server::with_config(*id, config)));
+      }
+      let mut sim = Simulator::with_servers(servers, &line(3), SimConfig::default());
+      let first = sim.register(0, "first").await;
+      let far = sim.register(2, "far").await;
+      sim.announce_all().await;
+      sim.run(100).await;
+
+      // 2 misses the second client, and gets the deltas 1 kept, as 0 signed them
+      sim.cut(1, 2);
+      let second = sim.register(0, "second").await;
+      sim.announce(0).await;
+      sim.run(100).await;
+      sim.heal(1, 2);
+      sim.register(0, "third").await;
+      sim.announce_all().await;
+      sim.run(100).await;
+      assert_eq!(sim.server(2).list_users().await.len(), 4);
+
+      sim.send(0, second, text(far, "signed")).await;
+      sim.send(2, far, text(first, "back")).await;
+      sim.run(100).await;
+      assert_eq!(
+        received(sim.poll(2, far).await),
+        Some((second, "signed".to_string()))
+      );
+      assert_eq!(
+        received(sim.poll(0, first).await),
+        Some((far, "back".to_string()))
+      );
+      assert!(sim.stats().rejected.is_empty(), "{:?}", sim.stats());
+      for n in 0..3 {
+        assert!(sim.server(n).peer_audit_log().await.is_empty());
+      }
+    })
+  }
+
+  #[test]
+  fn names_through_relays() {
+    async_std::task::block_on(async {
+      let ids: Vec<ServerId> = (0..3).map(|_| ServerId::default()).collect();
+      let servers = ids
+        .iter()
+        .enumerate()
+        .map(|(n, id)| {
+          let config = ServerConfig {
+            neighbours: ids
+              .iter()
+              .enumerate()
+              .filter(|(m, _)| n.abs_diff(*m) == 1)
+              .map(|(_, id)| *id)
+              .collect(),
+            name: Some(format!("server{n}")),
+            ..ServerConfig::default()
+          };
+          (*id, Server::with_config(*id, config))
+        })
+        .collect();
+      let mut sim = Simulator::with_servers(servers, &line(3), SimConfig::default());
+      let alice = sim.register(0, "alice").await;
+      let bob = sim.register(2, "alice").await;
+      sim.announce_all().await;
+      sim.run(100).await;
+
+      let address: Address = "alice@server0".parse().unwrap();
+      assert_eq!(
+        sim.server(2).list_addresses().await.get(&alice),
+        Some(&address)
+      );
+      let dest = sim.server(2).resolve(&address).await.unwrap();
+      sim.send(2, bob, text(dest, "found you")).await;
+      sim.run(100).await;
+      assert_eq!(
+        received(sim.poll(0, alice).await),
+        Some((bob, "found you".to_string()))
+      );
+      assert!(sim.stats().rejected.is_empty(), "{:?}", sim.stats());
+    })
+  }
+
+  /// what the receiver got, in order, and the network statistics
+  async fn lossy_run(seed: u64) -> (Vec<String>, SimStats) {
+    let config = SimConfig {
+      seed,
+      delay: 1..=10,
+      loss: 0.2,
+      reorder: true,
+    };
+    let mut sim: Simulator<Server> = Simulator::new(5, &ring(5), config);
+    let src = sim.register(0, "sender").await;
+    let dest = sim.register(2, "receiver").await;
+    // announces are lost too, the periodic ones repair what was lost
+    while sim.server(0).route_to(sim.id(2)).await.is_none() {
+      sim.announce_all().await;
+      sim.run(1000).await;
+    }
+    for n in 0..50 {
+      sim.send(0, src, text(dest, &format!("{n}"))).await;
+    }
+    sim.run(1000).await;
+    let mut contents = Vec::new();
+    while let Some((_, content)) = received(sim.poll(2, dest).await) {
+      contents.push(content);
+    }
+    let mut stats = sim.stats().clone();
+    // server ids are random, but the replies are not
+    stats.rejected.clear();
+    (contents, stats)
+  }
+
+  #[test]
+  fn reproducible() {
+    async_std::task::block_on(async {
+      let (contents, stats) = lossy_run(7).await;
+      assert!(stats.lost > 0);
+      assert!(!contents.is_empty() && contents.len() < 50);
+      let mut sorted = contents.clone();
+      sorted.sort_by_key(|c| c.parse::<u32>().unwrap());
+      assert_ne!(contents, sorted, "messages were not reordered");
+      assert_eq!(lossy_run(7).await, (contents, stats));
+    })
+  }
+}
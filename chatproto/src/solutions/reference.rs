@@ -0,0 +1,1851 @@
+use async_std::sync::{Mutex, RwLock};
+use async_trait::async_trait;
+use std::{
+  collections::{HashMap, HashSet, VecDeque},
+  net::IpAddr,
+  sync::{
+    atomic::{AtomicU64, AtomicUsize, Ordering},
+    Arc,
+  },
+  time::Instant,
+};
+use uuid::Uuid;
+
+use crate::{
+  admin::{AdminState, AuditEntry, Operator},
+  attachments::AttachmentStore,
+  core::{
+    MessageServer, ServerConfig, ATTACHMENT_QUOTA, HISTORY_PAGE_SIZE, MAX_HOPS, WORKPROOF_STRENGTH,
+  },
+  filter,
+  history::{conversation, now, HistoryStore},
+  mailbox::Mailbox,
+  messages::{
+    Action, Address, AdminCommand, AdminReply, AttachmentMeta, Chunk, ClientError, ClientId,
+    ClientMessage, ClientPollReply, ClientReply, DelayedError, FullyQualifiedMessage, HistoryPage,
+    HistoryQuery, MessageId, Sequence, ServerId, ServerMessage,
+  },
+  roles::Roles,
+  shard::Shards,
+  signing::{self, SigningKey},
+  topology::Topology,
+  workproof::verify_workproof,
+};
+
+#[cfg(feature = "federation")]
+use crate::{
+  admin::PeerAuditEntry,
+  messages::{Outgoing, ServerReply},
+};
+
+/// number of shards of the client registry and of the history
+const SHARDS: usize = 16;
+/// number of destinations of recent messages between servers that are remembered
+const SEEN_MESSAGES: usize = 4096;
+
+/// a registered client, each with its own locks
+struct Local {
+  name: String,
+  last_seqid: Mutex<u128>,
+  mailbox: Mutex<Mailbox>,
+  /// number of messages sent by this client that wait for unknown clients
+  waiting: AtomicUsize,
+}
+
+/// a message for a client that is not known yet, or that can no longer be reached
+// only sent once the recipient is announced by another server
+#[cfg_attr(not(feature = "federation"), allow(dead_code))]
+struct Delayed {
+  src: ClientId,
+  /// this server, unless the message was passing through
+  srcsrv: ServerId,
+  content: String,
+  since: Instant,
+  #[cfg_attr(not(feature = "federation"), allow(dead_code))]
+  id: MessageId,
+  /// what is left of the hop limit when the message is sent again
+  #[cfg_attr(not(feature = "federation"), allow(dead_code))]
+  hops: u32,
+  /// of srcsrv
+  #[cfg_attr(not(feature = "federation"), allow(dead_code))]
+  signature: Vec<u8>,
+}
+
+/// the last full announce of a server
+#[cfg_attr(not(feature = "federation"), allow(dead_code))]
+struct Announced {
+  version: u64,
+  clients: HashMap<ClientId, String>,
+  signature: Vec<u8>,
+}
+
+/// what the other servers signed, so that it can be passed on as it was
+#[cfg_attr(not(feature = "federation"), allow(dead_code))]
+#[derive(Default)]
+struct Signed {
+  announces: HashMap<ServerId, Announced>,
+  /// the deltas of each server since its last full one, for the neighbours that missed some
+  deltas: HashMap<ServerId, Vec<ServerMessage>>,
+  /// the version of the last withdrawal of each link, by the server at its end and the one that lost it
+  withdrawals: HashMap<(ServerId, ServerId), u64>,
+}
+
+/// the destinations of the recent messages between servers, so that copies of a message are dropped
+/// messages are split by next hop, so a server can get several parts of the same one
+struct Seen {
+  keys: HashSet<(ServerId, MessageId, ClientId)>,
+  order: VecDeque<(ServerId, MessageId, ClientId)>,
+}
+
+impl Seen {
+  fn new() -> Self {
+    Seen {
+      keys: HashSet::new(),
+      order: VecDeque::new(),
+    }
+  }
+
+  /// false if the destination of this message was already seen
+  fn insert(&mut self, srcsrv: ServerId, id: MessageId, dest: ClientId) -> bool {
+    let key = (srcsrv, id, dest);
+    if !self.keys.insert(key) {
+      return false;
+    }
+    self.order.push_back(key);
+    if self.order.len() > SEEN_MESSAGES {
+      if let Some(old) = self.order.pop_front() {
+        self.keys.remove(&old);
+      }
+    }
+    true
+  }
+}
+
+/// changes of the local clients, that delta announces are made of
+#[cfg_attr(not(feature = "federation"), allow(dead_code))]
+struct Changes {
+  version: u64,
+  /// version of the last change of each client, and its name unless it left
+  /// clients that left are kept, so that the deltas can tell it
+  clients: HashMap<ClientId, (u64, Option<String>)>,
+  /// version sent with the last periodic announces
+  announced: u64,
+}
+
+#[cfg_attr(not(feature = "federation"), allow(dead_code))]
+impl Changes {
+  /// versions start from the current time, so that they still increase when the server restarts
+  fn new() -> Self {
+    Changes {
+      version: now(),
+      clients: HashMap::new(),
+      announced: 0,
+    }
+  }
+
+  fn record(&mut self, client: ClientId, name: Option<String>) {
+    self.version += 1;
+    self.clients.insert(client, (self.version, name));
+  }
+
+  /// the clients that joined and the ones that left after a version
+  fn since(&self, version: u64) -> (HashMap<ClientId, String>, Vec<ClientId>) {
+    let mut joined = HashMap::new();
+    let mut left = Vec::new();
+    for (client, (changed, name)) in &self.clients {
+      match name {
+        _ if *changed <= version => (),
+        Some(name) => {
+          joined.insert(*client, name.clone());
+        }
+        None => left.push(*client),
+      }
+    }
+    left.sort();
+    (joined, left)
+  }
+}
+
+/// reference implementation, designed so that clients do not wait for each other:
+/// * clients are spread over the shards of the registry, that is only locked to find or add a client
+/// * each mailbox has its own lock, so polling a client never blocks deliveries to another one
+/// * the history is sharded by conversation
+/// * message ids come from an atomic counter
+/// * remote clients and routes are rarely written, and only read while sending to remote clients
+pub struct Server {
+  id: ServerId,
+  config: ServerConfig,
+  clients: Shards<RwLock<HashMap<ClientId, Arc<Local>>>>,
+  history: Shards<Mutex<HistoryStore>>,
+  next_id: AtomicU64,
+  attachments: Mutex<AttachmentStore>,
+  admin: RwLock<AdminState>,
+  roles: RwLock<Roles>,
+  /// messages for unknown clients, kept until the clients become known
+  delayed: Mutex<HashMap<ClientId, Vec<Delayed>>>,
+  /// clients announced by other servers, with their name and server
+  remote: RwLock<HashMap<ClientId, (String, ServerId)>>,
+  /// links learned from the announces, and the shortest route to each server
+  topology: RwLock<Topology>,
+  changes: Mutex<Changes>,
+  /// version of the clients known for each server that sent delta announces
+  #[cfg_attr(not(feature = "federation"), allow(dead_code))]
+  versions: RwLock<HashMap<ServerId, u64>>,
+  seen: Mutex<Seen>,
+  /// version of the next withdrawal of a link of this server, from the current time like the changes
+  #[cfg_attr(not(feature = "federation"), allow(dead_code))]
+  next_withdrawal: AtomicU64,
+  key: SigningKey,
+  #[cfg_attr(not(feature = "federation"), allow(dead_code))]
+  signed: Mutex<Signed>,
+  /// human-readable name of this server
+  name: String,
+  /// names of the other servers, from their announces
+  server_names: RwLock<HashMap<ServerId, String>>,
+}
+
+#[async_trait]
+impl MessageServer for Server {
+  const GROUP_NAME: &'static str = "reference";
+
+  fn with_config(id: ServerId, config: ServerConfig) -> Self {
+    Server {
+      id,
+      clients: Shards::new(SHARDS, || RwLock::new(HashMap::new())),
+      history: Shards::new(SHARDS, || {
+        Mutex::new(HistoryStore::new(config.history.clone()))
+      }),
+      next_id: AtomicU64::new(0),
+      attachments: Mutex::new(AttachmentStore::new(
+        ATTACHMENT_QUOTA,
+        config.attachment_ttl,
+      )),
+      admin: RwLock::new(AdminState::new(
+        config.admins.clone(),
+        config.audit_log.clone(),
+      )),
+      roles: RwLock::new(Roles::new(config.roles.clone())),
+      delayed: Mutex::new(HashMap::new()),
+      remote: RwLock::new(HashMap::new()),
+      topology: RwLock::new(Topology::new(id, config.route_ttl)),
+      changes: Mutex::new(Changes::new()),
+      versions: RwLock::new(HashMap::new()),
+      seen: Mutex::new(Seen::new()),
+      next_withdrawal: AtomicU64::new(now()),
+      key: config.signing_key.clone().unwrap_or_else(signing::generate),
+      signed: Mutex::new(Signed::default()),
+      name: config.name.clone().unwrap_or_else(|| id.default_name()),
+      server_names: RwLock::new(HashMap::new()),
+      config,
+    }
+  }
+
+  async fn register_local_client(&self, name: String) -> ClientId {
+    let id = ClientId::from(Uuid::new_v4());
+    self.changes.lock().await.record(id, Some(name.clone()));
+    let local = Local {
+      name,
+      last_seqid: Mutex::new(0),
+      mailbox: Mutex::new(Mailbox::new(
+        id,
+        self.config.mailbox.clone(),
+        self.config.overflow.clone(),
+      )),
+      waiting: AtomicUsize::new(0),
+    };
+    self
+      .clients
+      .get(&id)
+      .write()
+      .await
+      .insert(id, Arc::new(local));
+    id
+  }
+
+  async fn handle_sequenced_message<A: Send>(
+    &self,
+    sequence: Sequence<A>,
+  ) -> Result<A, ClientError> {
+    if !verify_workproof(
+      (&sequence.src).into(),
+      sequence.workproof,
+      WORKPROOF_STRENGTH,
+    ) {
+      return Err(ClientError::WorkProofError);
+    }
+    let local = self
+      .local(&sequence.src)
+      .await
+      .ok_or(ClientError::UnknownClient)?;
+    if self.admin.read().await.is_banned(&sequence.src) {
+      return Err(ClientError::Banned);
+    }
+    let mut last = local.last_seqid.lock().await;
+    if sequence.seqid <= *last {
+      return Err(ClientError::SequenceError);
+    }
+    *last = sequence.seqid;
+    Ok(sequence.content)
+  }
+
+  async fn handle_client_message(&self, src: ClientId, msg: ClientMessage) -> Vec<ClientReply> {
+    match msg {
+      ClientMessage::Text { dest, content } => self.send_text(src, &[dest], &content).await,
+      ClientMessage::MText { dest, content } => {
+        if let Err(rr) = self.roles.read().await.check_recipients(&src, dest.len()) {
+          return vec![ClientReply::Error(rr); dest.len()];
+        }
+        self.send_text(src, &dest, &content).await
+      }
+      ClientMessage::Edit { dest, id, content } => {
+        let content = match filter::apply(&self.config.filters, src, &dest, &content) {
+          Ok(c) => c,
+          Err(reason) => {
+            return vec![ClientReply::Error(ClientError::Rejected(reason)); dest.len()]
+          }
+        };
+        let mut replies = Vec::new();
+        for d in dest {
+          replies.push(self.edit(src, d, id, &content).await);
+        }
+        replies
+      }
+      ClientMessage::Delete { dest, id } => {
+        let mut replies = Vec::new();
+        for d in dest {
+          replies.push(self.delete(src, d, id).await);
+        }
+        replies
+      }
+      ClientMessage::Attachment { dest, meta } => {
+        if let Err(rr) = self.roles.read().await.check_recipients(&src, dest.len()) {
+          return vec![ClientReply::Error(rr); dest.len()];
+        }
+        // the name is shown to the recipients like the text of a message
+        let meta = match filter::apply(&self.config.filters, src, &dest, &meta.name) {
+          Ok(name) => AttachmentMeta { name, ..meta },
+          Err(reason) => {
+            return vec![ClientReply::Error(ClientError::Rejected(reason)); dest.len()]
+          }
+        };
+        let id = self.message_id();
+        let mut replies = Vec::new();
+        for d in dest {
+          replies.push(self.send_attachment(src, d, id, &meta).await);
+        }
+        replies
+      }
+    }
+  }
+
+  async fn client_poll(&self, client: ClientId) -> ClientPollReply {
+    match self.local(&client).await {
+      Some(local) => {
+        if local.waiting.load(Ordering::Relaxed) > 0 {
+          self.expire_delayed(client, &local).await;
+        }
+        local.mailbox.lock().await.pop().await
+      }
+      None => ClientPollReply::Nothing,
+    }
+  }
+
+  async fn history(&self, client: ClientId, query: HistoryQuery) -> HistoryPage {
+    let limit = query.limit.min(HISTORY_PAGE_SIZE) as usize;
+    let mut store = self
+      .history
+      .get(&conversation(client, query.with))
+      .lock()
+      .await;
+    store.expire(client, query.with, now());
+    store.page(client, query.with, &query.before, limit)
+  }
+
+  async fn upload_chunk(&self, src: ClientId, chunk: Chunk) -> Result<u64, ClientError> {
+    self.attachments.lock().await.upload(src, chunk, now())
+  }
+
+  async fn download_chunk(
+    &self,
+    client: ClientId,
+    src: ClientId,
+    hash: [u8; 32],
+    offset: u64,
+  ) -> Result<Chunk, ClientError> {
+    self
+      .attachments
+      .lock()
+      .await
+      .download(client, src, hash, offset, now())
+  }
+
+  async fn admin(&self, by: Operator, command: AdminCommand) -> Result<AdminReply, ClientError> {
+    let outcome = if self.admin.read().await.is_admin(&by) {
+      self.run_admin(&command).await
+    } else {
+      Err(ClientError::NotAdmin)
+    };
+    self.admin.write().await.record(by, command, &outcome);
+    outcome
+  }
+
+  async fn address_banned(&self, addr: IpAddr) -> bool {
+    self.admin.read().await.is_address_banned(&addr)
+  }
+
+  async fn audit_log(&self) -> Vec<AuditEntry> {
+    self.admin.read().await.audit().to_vec()
+  }
+
+  #[cfg(feature = "federation")]
+  async fn peer_audit_log(&self) -> Vec<PeerAuditEntry> {
+    self.admin.read().await.peer_audit().to_vec()
+  }
+
+  async fn permitted(&self, client: ClientId, action: Action) -> Result<(), ClientError> {
+    self.roles.read().await.check(&client, action)
+  }
+
+  async fn registration_permitted(&self, addr: IpAddr) -> Result<(), ClientError> {
+    self.roles.read().await.check_registration(&addr)
+  }
+
+  #[cfg(feature = "federation")]
+  async fn handle_server_message(&self, msg: ServerMessage) -> ServerReply {
+    if let Err((server, reason)) = self.check_policy(&msg).await {
+      self.admin.write().await.record_peer(server, reason.clone());
+      return ServerReply::Error(reason);
+    }
+    // only trusted servers can take a name that is already known
+    let trusted = matches!(
+      msg,
+      ServerMessage::Announce { .. } | ServerMessage::AnnounceDelta { .. }
+    ) && self.trusted_signature(&msg);
+    match msg {
+      ServerMessage::Announce {
+        route,
+        clients,
+        signature,
+        server_name,
+        version,
+      } => {
+        let signed = (version, signature, server_name, trusted);
+        self.announce(route, clients, signed).await
+      }
+      ServerMessage::Message(fqm) => ServerReply::Outgoing(self.handle_remote_message(fqm).await),
+      ServerMessage::Withdraw {
+        route,
+        signature,
+        version,
+      } => self.withdraw(route, signature, version).await,
+      ServerMessage::WithdrawClients {
+        route,
+        clients,
+        signature,
+      } => self.withdraw_clients(route, clients, signature).await,
+      message @ ServerMessage::DeliveryError { .. } => {
+        ServerReply::Outgoing(self.pass_error(message).await.into_iter().collect())
+      }
+      ServerMessage::AnnounceDelta {
+        route,
+        since,
+        version,
+        joined,
+        left,
+        signature,
+        server_name,
+      } => {
+        let signed = (signature, server_name, trusted);
+        self
+          .announce_delta(route, since, version, joined, left, signed)
+          .await
+      }
+      ServerMessage::SyncRequest { origin, requester } => self.sync(origin, requester).await,
+    }
+  }
+
+  async fn list_addresses(&self) -> HashMap<ClientId, Address> {
+    let mut addresses = HashMap::new();
+    let names = self.server_names.read().await;
+    for (id, (name, srv)) in self.remote.read().await.iter() {
+      let server = names
+        .get(srv)
+        .cloned()
+        .unwrap_or_else(|| srv.default_name());
+      let name = name.clone();
+      addresses.insert(*id, Address { name, server });
+    }
+    for shard in self.clients.iter() {
+      for (id, local) in shard.read().await.iter() {
+        let (name, server) = (local.name.clone(), self.name.clone());
+        addresses.insert(*id, Address { name, server });
+      }
+    }
+    addresses
+  }
+
+  async fn resolve(&self, address: &Address) -> Option<ClientId> {
+    let addresses = self.list_addresses().await;
+    let mut found = addresses.iter().filter(|(_, a)| *a == address);
+    match (found.next(), found.next()) {
+      (Some((id, _)), None) => Some(*id),
+      _ => None,
+    }
+  }
+
+  async fn list_users(&self) -> HashMap<ClientId, String> {
+    let mut users = HashMap::new();
+    for (id, (name, _)) in self.remote.read().await.iter() {
+      users.insert(*id, name.clone());
+    }
+    for shard in self.clients.iter() {
+      for (id, local) in shard.read().await.iter() {
+        users.insert(*id, local.name.clone());
+      }
+    }
+    users
+  }
+
+  #[cfg(feature = "federation")]
+  async fn announces(&self) -> Vec<Outgoing<ServerMessage>> {
+    // the changes since the previous announces, that also refresh the routes when there are none
+    let local = {
+      let mut changes = self.changes.lock().await;
+      let since = changes.announced;
+      let (joined, left) = changes.since(since);
+      changes.announced = changes.version;
+      let mut delta = ServerMessage::AnnounceDelta {
+        route: vec![self.id],
+        since,
+        version: changes.version,
+        joined,
+        left,
+        signature: Vec::new(),
+        server_name: self.name.clone(),
+      };
+      signing::sign(&self.key, &mut delta);
+      delta
+    };
+    let mut outgoing = self.to_neighbours(&[self.id], local);
+
+    // the servers that sent deltas are announced with their version only, so that the neighbours notice
+    // what they missed, and the others with all their clients, signed when they are still what was received
+    let versions = self.versions.read().await.clone();
+    let mut learned: HashMap<ServerId, HashMap<ClientId, String>> = HashMap::new();
+    for (id, (name, srv)) in self.remote.read().await.iter() {
+      if !versions.contains_key(srv) {
+        learned.entry(*srv).or_default().insert(*id, name.clone());
+      }
+    }
+    let signed = self.signed.lock().await;
+    let names = self.server_names.read().await;
+    let server_name = |srv: &ServerId| names.get(srv).cloned().unwrap_or_default();
+    let topology = self.topology.read().await;
+    for (srv, version) in versions {
+      if let Some(route) = topology.route_to(&srv) {
+        let mut route = route.clone();
+        route.push(self.id);
+        let message = ServerMessage::AnnounceDelta {
+          route: route.clone(),
+          since: version,
+          version,
+          joined: HashMap::new(),
+          left: Vec::new(),
+          signature: Vec::new(),
+          server_name: server_name(&srv),
+        };
+        outgoing.extend(self.to_neighbours(&route, message));
+      }
+    }
+    for (srv, clients) in learned {
+      if let Some(route) = topology.route_to(&srv) {
+        let mut route = route.clone();
+        route.push(self.id);
+        let (version, signature) = match signed.announces.get(&srv) {
+          Some(a) if a.clients == clients => (a.version, a.signature.clone()),
+          Some(a) => (a.version, Vec::new()),
+          None => (0, Vec::new()),
+        };
+        let message = ServerMessage::Announce {
+          route: route.clone(),
+          clients,
+          signature,
+          server_name: server_name(&srv),
+          version,
+        };
+        outgoing.extend(self.to_neighbours(&route, message));
+      }
+    }
+    drop(topology);
+    drop(names);
+    drop(signed);
+    // this is also when messages passing through are given up
+    outgoing.extend(self.expire_transit().await);
+    outgoing
+  }
+
+  #[cfg(feature = "federation")]
+  async fn route_to(&self, destination: ServerId) -> Option<Vec<ServerId>> {
+    self.expire_routes().await;
+    self.topology.read().await.route_to(&destination).cloned()
+  }
+}
+
+fn delivered(pushed: Result<(), ClientError>, id: MessageId) -> ClientReply {
+  match pushed {
+    Ok(()) => ClientReply::Delivered(id),
+    Err(rr) => ClientReply::Error(rr),
+  }
+}
+
+impl Server {
+  /// the registry is only locked while looking the client up
+  async fn local(&self, client: &ClientId) -> Option<Arc<Local>> {
+    self.clients.get(client).read().await.get(client).cloned()
+  }
+
+  fn max_hops(&self) -> u32 {
+    self.config.max_hops.unwrap_or(MAX_HOPS)
+  }
+
+  /// signature of a message of a local client for a destination on another server
+  fn text_signature(&self, src: ClientId, id: MessageId, content: &str, dest: ClientId) -> Vec<u8> {
+    let mut message = ServerMessage::Message(FullyQualifiedMessage {
+      src,
+      srcsrv: self.id,
+      // the server of the destination is not signed
+      dsts: vec![(dest, self.id)],
+      content: content.to_string(),
+      id,
+      hops: 0,
+      signature: Vec::new(),
+    });
+    signing::sign(&self.key, &mut message);
+    match message {
+      ServerMessage::Message(fqm) => fqm.signature,
+      _ => Vec::new(),
+    }
+  }
+
+  fn message_id(&self) -> MessageId {
+    MessageId::from(self.next_id.fetch_add(1, Ordering::Relaxed) as u128)
+  }
+
+  async fn record(&self, src: ClientId, dest: ClientId, id: MessageId, content: String) {
+    self
+      .history
+      .get(&conversation(src, dest))
+      .lock()
+      .await
+      .record(src, dest, id, content, now());
+  }
+
+  async fn send_text(&self, src: ClientId, dest: &[ClientId], content: &str) -> Vec<ClientReply> {
+    let content = match filter::apply(&self.config.filters, src, dest, content) {
+      Ok(c) => c,
+      Err(reason) => return vec![ClientReply::Error(ClientError::Rejected(reason)); dest.len()],
+    };
+    let id = self.message_id();
+    // the id of the message for the other servers, that does not repeat across restarts
+    let remote_id = MessageId::from(Uuid::new_v4().as_u128());
+    let mut replies: Vec<Option<ClientReply>> = vec![None; dest.len()];
+    // remote destinations by next hop, each group gets a single transfer
+    let mut hops: Vec<(ServerId, Vec<(ClientId, ServerId)>)> = Vec::new();
+    let mut allowed = None;
+    for (n, d) in dest.iter().enumerate() {
+      // clients listed several times get the message once, their reply is copied below
+      if dest[..n].contains(d) {
+        continue;
+      }
+      if let Some(local) = self.local(d).await {
+        replies[n] = Some(self.deliver(&local, src, *d, id, &content).await);
+        continue;
+      }
+      // the messages that wait for a route need the permission as much as the transferred ones
+      if allowed.is_none() {
+        allowed = Some(self.roles.read().await.check(&src, Action::RemoteMessage));
+      }
+      if let Some(Err(rr)) = &allowed {
+        replies[n] = Some(ClientReply::Error(rr.clone()));
+        continue;
+      }
+      // copies that come back through a routing loop are dropped
+      self.seen.lock().await.insert(self.id, remote_id, *d);
+      match self.remote_hop(d).await {
+        Some((srv, hop)) => match hops.iter_mut().find(|(h, _)| *h == hop) {
+          Some((_, dsts)) => dsts.push((*d, srv)),
+          None => hops.push((hop, vec![(*d, srv)])),
+        },
+        None => {
+          let delayed = Delayed {
+            src,
+            srcsrv: self.id,
+            content: content.to_string(),
+            since: Instant::now(),
+            id: remote_id,
+            hops: self.max_hops(),
+            signature: self.text_signature(src, remote_id, &content, *d),
+          };
+          self.delay(*d, delayed).await;
+          replies[n] = Some(ClientReply::Delayed);
+        }
+      }
+    }
+    for (hop, dsts) in hops {
+      let reached: Vec<ClientId> = dsts.iter().map(|(d, _)| *d).collect();
+      let mut message = ServerMessage::Message(FullyQualifiedMessage {
+        src,
+        srcsrv: self.id,
+        dsts,
+        content: content.to_string(),
+        id: remote_id,
+        hops: self.max_hops(),
+        signature: Vec::new(),
+      });
+      signing::sign(&self.key, &mut message);
+      let reply = ClientReply::Transfer(hop, message);
+      for (n, d) in dest.iter().enumerate() {
+        if reached.contains(d) {
+          replies[n] = Some(reply.clone());
+        }
+      }
+    }
+    // the other server records the conversation for its client, this one for the sender
+    for (n, d) in dest.iter().enumerate() {
+      if matches!(
+        replies[n],
+        Some(ClientReply::Transfer(..) | ClientReply::Delayed)
+      ) {
+        self.record(src, *d, id, content.clone()).await;
+      }
+    }
+    dest
+      .iter()
+      .map(|d| {
+        let first = dest
+          .iter()
+          .position(|previous| previous == d)
+          .unwrap_or_default();
+        replies[first]
+          .clone()
+          .expect("every destination has a reply")
+      })
+      .collect()
+  }
+
+  async fn deliver(
+    &self,
+    local: &Local,
+    src: ClientId,
+    dest: ClientId,
+    id: MessageId,
+    content: &str,
+  ) -> ClientReply {
+    let pushed = local
+      .mailbox
+      .lock()
+      .await
+      .push(ClientPollReply::Message {
+        src,
+        id,
+        content: content.to_string(),
+      })
+      .await;
+    if pushed.is_ok() {
+      self.record(src, dest, id, content.to_string()).await;
+    }
+    delivered(pushed, id)
+  }
+
+  /// the server of a remote client, and the neighbour its messages are sent to
+  async fn remote_hop(&self, dest: &ClientId) -> Option<(ServerId, ServerId)> {
+    let srv = self.remote.read().await.get(dest).map(|(_, srv)| *srv)?;
+    self.nexthop(&srv).await.map(|hop| (srv, hop))
+  }
+
+  /// keeps a message until its recipient can be reached
+  async fn delay(&self, dest: ClientId, delayed: Delayed) {
+    if delayed.srcsrv == self.id {
+      if let Some(local) = self.local(&delayed.src).await {
+        local.waiting.fetch_add(1, Ordering::Relaxed);
+      }
+    }
+    self
+      .delayed
+      .lock()
+      .await
+      .entry(dest)
+      .or_default()
+      .push(delayed);
+  }
+
+  /// drops the messages of client that waited too long, and tells it which recipients are unknown
+  async fn expire_delayed(&self, client: ClientId, local: &Local) {
+    let ttl = match self.config.delayed_ttl {
+      Some(ttl) => ttl,
+      None => return,
+    };
+    let mut expired = Vec::new();
+    {
+      let mut delayed = self.delayed.lock().await;
+      for (dest, waiting) in delayed.iter_mut() {
+        waiting.retain(|d| {
+          let keep = d.src != client || d.srcsrv != self.id || d.since.elapsed() < ttl;
+          if !keep {
+            expired.push(*dest);
+          }
+          keep
+        });
+      }
+      delayed.retain(|_, waiting| !waiting.is_empty());
+    }
+    local.waiting.fetch_sub(expired.len(), Ordering::Relaxed);
+    let mut mailbox = local.mailbox.lock().await;
+    for dest in expired {
+      let error = ClientPollReply::DelayedError(DelayedError::UnknownRecipient(dest));
+      if let Err(rr) = mailbox.push(error).await {
+        log::warn!("could not tell {} that {} is unknown: {}", client, dest, rr);
+      }
+    }
+  }
+
+  /// drops the messages from other servers that waited too long for a route, and reports them
+  #[cfg(feature = "federation")]
+  async fn expire_transit(&self) -> Vec<Outgoing<ServerMessage>> {
+    let ttl = match self.config.delayed_ttl {
+      Some(ttl) => ttl,
+      None => return Vec::new(),
+    };
+    let mut expired = Vec::new();
+    {
+      let mut delayed = self.delayed.lock().await;
+      for (dest, waiting) in delayed.iter_mut() {
+        waiting.retain(|d| {
+          let keep = d.srcsrv == self.id || d.since.elapsed() < ttl;
+          if !keep {
+            log::warn!("no route to {}, dropping a message from {}", dest, d.src);
+            expired.push((d.src, d.srcsrv, *dest));
+          }
+          keep
+        });
+      }
+      delayed.retain(|_, waiting| !waiting.is_empty());
+    }
+    let mut outgoing = Vec::new();
+    for (src, srcsrv, dest) in expired {
+      let error = DelayedError::Unroutable(dest);
+      outgoing.extend(self.report(src, srcsrv, error).await);
+    }
+    outgoing
+  }
+
+  /// signs a delivery error, and sends it back to the server of the sender
+  #[cfg(feature = "federation")]
+  async fn report(
+    &self,
+    src: ClientId,
+    srcsrv: ServerId,
+    error: DelayedError,
+  ) -> Option<Outgoing<ServerMessage>> {
+    let mut message = ServerMessage::DeliveryError {
+      src,
+      srcsrv,
+      error,
+      origin: self.id,
+      signature: Vec::new(),
+    };
+    signing::sign(&self.key, &mut message);
+    self.pass_error(message).await
+  }
+
+  /// sends a delivery error on to the server of the sender as it is, or gives it to the sender if it is local
+  #[cfg(feature = "federation")]
+  async fn pass_error(&self, message: ServerMessage) -> Option<Outgoing<ServerMessage>> {
+    let (src, srcsrv, error) = match &message {
+      ServerMessage::DeliveryError {
+        src, srcsrv, error, ..
+      } => (*src, *srcsrv, error.clone()),
+      _ => return None,
+    };
+    if srcsrv != self.id {
+      let nexthop = self.nexthop(&srcsrv).await;
+      if nexthop.is_none() {
+        log::warn!(
+          "no route to {}, dropping a delivery error for {}",
+          srcsrv,
+          src
+        );
+      }
+      return nexthop.map(|nexthop| Outgoing { nexthop, message });
+    }
+    match self.local(&src).await {
+      Some(local) => {
+        let pushed = local
+          .mailbox
+          .lock()
+          .await
+          .push(ClientPollReply::DelayedError(error))
+          .await;
+        if let Err(rr) = pushed {
+          log::warn!("could not tell {} about a failed delivery: {}", src, rr);
+        }
+      }
+      None => log::warn!("{} is gone, dropping a delivery error", src),
+    }
+    None
+  }
+
+  /// the neighbour messages for srv must be sent to
+  async fn nexthop(&self, srv: &ServerId) -> Option<ServerId> {
+    self.expire_routes().await;
+    self.topology.read().await.nexthop(srv)
+  }
+
+  /// forgets the routes that were not announced for too long
+  async fn expire_routes(&self) {
+    if self.topology.read().await.expired() && self.topology.write().await.expire() {
+      log::info!("some routes expired");
+    }
+  }
+
+  async fn edit(&self, src: ClientId, dest: ClientId, id: MessageId, content: &str) -> ClientReply {
+    let local = match self.local(&dest).await {
+      Some(l) => l,
+      None => return ClientReply::Error(ClientError::UnknownClient),
+    };
+    let pushed = {
+      let mut mailbox = local.mailbox.lock().await;
+      match mailbox.edit(src, id, content) {
+        Ok(true) => Ok(()),
+        Ok(false) => {
+          mailbox
+            .push(ClientPollReply::Edited {
+              src,
+              id,
+              content: content.to_string(),
+            })
+            .await
+        }
+        Err(rr) => Err(rr),
+      }
+    };
+    if pushed.is_ok() {
+      self
+        .history
+        .get(&conversation(src, dest))
+        .lock()
+        .await
+        .edit(src, dest, id, content.to_string());
+    }
+    delivered(pushed, id)
+  }
+
+  async fn delete(&self, src: ClientId, dest: ClientId, id: MessageId) -> ClientReply {
+    let local = match self.local(&dest).await {
+      Some(l) => l,
+      None => return ClientReply::Error(ClientError::UnknownClient),
+    };
+    let pushed = {
+      let mut mailbox = local.mailbox.lock().await;
+      if mailbox.delete(src, id) {
+        Ok(())
+      } else {
+        mailbox.push(ClientPollReply::Deleted { src, id }).await
+      }
+    };
+    if pushed.is_ok() {
+      self
+        .history
+        .get(&conversation(src, dest))
+        .lock()
+        .await
+        .delete(src, dest, id);
+    }
+    delivered(pushed, id)
+  }
+
+  async fn send_attachment(
+    &self,
+    src: ClientId,
+    dest: ClientId,
+    id: MessageId,
+    meta: &AttachmentMeta,
+  ) -> ClientReply {
+    let local = match self.local(&dest).await {
+      Some(l) => l,
+      None => {
+        // attachments are not sent to other servers, but the sender must be allowed to try
+        let allowed = self.roles.read().await.check(&src, Action::RemoteMessage);
+        return ClientReply::Error(allowed.err().unwrap_or(ClientError::UnknownClient));
+      }
+    };
+    if let Err(rr) = self.attachments.lock().await.share(src, meta, dest) {
+      return ClientReply::Error(rr);
+    }
+    let pushed = local
+      .mailbox
+      .lock()
+      .await
+      .push(ClientPollReply::Attachment {
+        src,
+        id,
+        meta: meta.clone(),
+      })
+      .await;
+    delivered(pushed, id)
+  }
+
+  /// the server that is not accepted by the federation policy, and why
+  #[cfg(feature = "federation")]
+  async fn check_policy(&self, msg: &ServerMessage) -> Result<(), (ServerId, String)> {
+    let policy = &self.config.federation;
+    if let Some(signer) = signing::signer(msg) {
+      policy
+        .check_signature(msg)
+        .map_err(|reason| (signer, reason))?;
+    }
+    let refused = |srv: ServerId| (srv, format!("{} is not an accepted server", srv));
+    let (route, clients) = match msg {
+      ServerMessage::Announce { route, clients, .. } => (route, Some((clients, clients.len()))),
+      ServerMessage::AnnounceDelta {
+        route,
+        since,
+        joined,
+        left,
+        ..
+      } => {
+        let count = match route.first() {
+          Some(origin) if *since > 0 => {
+            let remote = self.remote.read().await;
+            let from_origin =
+              |c: &ClientId| matches!(remote.get(c), Some((_, srv)) if srv == origin);
+            let known = remote.values().filter(|(_, srv)| srv == origin).count();
+            let new = joined.keys().filter(|c| !from_origin(c)).count();
+            let gone = left.iter().filter(|c| from_origin(c)).count();
+            (known + new).saturating_sub(gone)
+          }
+          _ => joined.len(),
+        };
+        (route, Some((joined, count)))
+      }
+      ServerMessage::Withdraw { route, .. } | ServerMessage::WithdrawClients { route, .. } => {
+        (route, None)
+      }
+      ServerMessage::Message(fqm) if !policy.allows(&fqm.srcsrv) => {
+        return Err(refused(fqm.srcsrv))
+      }
+      ServerMessage::DeliveryError { origin, .. } if !policy.allows(origin) => {
+        return Err(refused(*origin))
+      }
+      ServerMessage::SyncRequest { requester, .. } if !policy.allows(requester) => {
+        return Err(refused(*requester))
+      }
+      _ => return Ok(()),
+    };
+    policy.check_route(route).map_err(refused)?;
+    let (origin, (clients, count)) = match (route.first(), clients) {
+      (Some(origin), Some(clients)) => (*origin, clients),
+      _ => return Ok(()),
+    };
+    if let Err(limit) = policy.check_clients(&origin, count) {
+      let reason = format!(
+        "{} announced {} clients, more than {}",
+        origin, count, limit
+      );
+      return Err((origin, reason));
+    }
+    for client in clients.keys() {
+      if self.local(client).await.is_some() {
+        let reason = format!("{} announced {}, that is a local client", origin, client);
+        return Err((origin, reason));
+      }
+    }
+    Ok(())
+  }
+
+  /// stores the routes and the remote clients, and sends the messages that were waiting for them
+  /// announces older than the last one of their server are ignored, so that they can not be replayed
+  #[cfg(feature = "federation")]
+  async fn announce(
+    &self,
+    route: Vec<ServerId>,
+    clients: HashMap<ClientId, String>,
+    (version, signature, server_name, trusted): (u64, Vec<u8>, String, bool),
+  ) -> ServerReply {
+    let origin = match route.first() {
+      Some(s) => *s,
+      None => return ServerReply::EmptyRoute,
+    };
+    if route.contains(&self.id) {
+      log::debug!("ignoring a route from {} that goes through us", origin);
+      return ServerReply::Outgoing(Vec::new());
+    }
+    let known = self
+      .signed
+      .lock()
+      .await
+      .announces
+      .get(&origin)
+      .map(|a| a.version);
+    if let Some(known) = known.filter(|k| version < *k) {
+      log::debug!(
+        "ignoring an announce of {} at version {}, after version {}",
+        origin,
+        version,
+        known
+      );
+      return ServerReply::Outgoing(Vec::new());
+    }
+    let mut changed = self.learn_route(origin, &route).await;
+    let (announced, added) = self.learn_clients(origin, clients.clone()).await;
+    changed |= added;
+    changed |= self.learn_name(origin, &server_name, trusted).await;
+    // the signature only holds for what was received
+    let signature = if announced == clients {
+      signature
+    } else {
+      Vec::new()
+    };
+    self.signed.lock().await.announces.insert(
+      origin,
+      Announced {
+        version,
+        clients,
+        signature: signature.clone(),
+      },
+    );
+
+    let nexthop = match self.nexthop(&origin).await {
+      Some(hop) => hop,
+      None => return ServerReply::Error(format!("no route to {}", origin)),
+    };
+    let mut outgoing = self.release(origin, nexthop, announced.keys()).await;
+    if changed {
+      let route = self.topology.read().await.route_to(&origin).cloned();
+      if let Some(mut route) = route {
+        route.push(self.id);
+        let message = ServerMessage::Announce {
+          route: route.clone(),
+          clients: announced,
+          signature,
+          server_name,
+          version,
+        };
+        outgoing.extend(self.to_neighbours(&route, message));
+      }
+    }
+    ServerReply::Outgoing(outgoing)
+  }
+
+  /// applies the changes of the clients of the first server of the route if they follow the known version, and
+  /// asks the neighbour that sent them for all the clients if some changes were missed
+  #[cfg(feature = "federation")]
+  async fn announce_delta(
+    &self,
+    route: Vec<ServerId>,
+    since: u64,
+    version: u64,
+    joined: HashMap<ClientId, String>,
+    left: Vec<ClientId>,
+    (signature, server_name, trusted): (Vec<u8>, String, bool),
+  ) -> ServerReply {
+    let (origin, sender) = match (route.first(), route.last()) {
+      (Some(o), Some(s)) => (*o, *s),
+      _ => return ServerReply::EmptyRoute,
+    };
+    if route.contains(&self.id) {
+      log::debug!("ignoring a route from {} that goes through us", origin);
+      return ServerReply::Outgoing(Vec::new());
+    }
+    let mut changed = self.learn_route(origin, &route).await;
+    let nexthop = match self.nexthop(&origin).await {
+      Some(hop) => hop,
+      None => return ServerReply::Error(format!("no route to {}", origin)),
+    };
+
+    let mut outgoing = Vec::new();
+    let known = self.versions.read().await.get(&origin).copied();
+    if since > 0 && known.is_none_or(|k| since > k) {
+      log::info!(
+        "missed changes of {} before version {}, asking {} for all of them",
+        origin,
+        since,
+        sender
+      );
+      outgoing.push(Outgoing {
+        nexthop: sender,
+        message: ServerMessage::SyncRequest {
+          origin,
+          requester: self.id,
+        },
+      });
+    }
+    // a delta since 0 replaces everything, the others only apply on top of what they follow
+    let applies = known.map_or(since == 0, |k| since <= k && version > k);
+    // what was not applied only refreshes the route, with the version that is known, the route is set when it is
+    // passed on
+    let delta = |since, version, joined, left, signature| ServerMessage::AnnounceDelta {
+      route: vec![origin],
+      since,
+      version,
+      joined,
+      left,
+      signature,
+      server_name: server_name.clone(),
+    };
+    let mut relayed = known.map(|k| delta(k, k, HashMap::new(), Vec::new(), Vec::new()));
+    if applies {
+      self.versions.write().await.insert(origin, version);
+      let gone = if since == 0 {
+        let remote = self.remote.read().await;
+        remote
+          .iter()
+          .filter(|(c, (_, srv))| *srv == origin && !joined.contains_key(c))
+          .map(|(c, _)| *c)
+          .collect()
+      } else {
+        left.clone()
+      };
+      let (learned, added) = self.learn_clients(origin, joined.clone()).await;
+      let mut removed = false;
+      {
+        let mut remote = self.remote.write().await;
+        for client in gone {
+          // the client might have been announced again by another server since
+          if matches!(remote.get(&client), Some((_, srv)) if *srv == origin) {
+            remote.remove(&client);
+            removed = true;
+          }
+        }
+      }
+      changed |= added || removed;
+      changed |= self.learn_name(origin, &server_name, trusted).await;
+      outgoing.extend(self.release(origin, nexthop, learned.keys()).await);
+      // the neighbours check the delta as it was signed, and apply it the same way; a delta that announced local
+      // clients is passed on without them, and without its signature
+      let received = learned == joined;
+      let message = if received {
+        delta(since, version, joined, left, signature.clone())
+      } else {
+        delta(since, version, learned, left, Vec::new())
+      };
+      let mut signed = self.signed.lock().await;
+      if !received {
+        signed.deltas.remove(&origin);
+      } else {
+        let chain = signed.deltas.entry(origin).or_default();
+        if since == 0 {
+          chain.clear();
+        }
+        chain.push(message.clone());
+      }
+      relayed = Some(message);
+    }
+
+    if changed {
+      let route = self.topology.read().await.route_to(&origin).cloned();
+      if let (Some(mut route), Some(mut message)) = (route, relayed) {
+        route.push(self.id);
+        if let ServerMessage::AnnounceDelta { route: r, .. } = &mut message {
+          r.clone_from(&route);
+        }
+        outgoing.extend(self.to_neighbours(&route, message));
+      }
+    }
+    ServerReply::Outgoing(outgoing)
+  }
+
+  /// sends all the clients of origin to the neighbour that missed some of their changes, as the signed deltas
+  /// since the last full one when origin is another server
+  #[cfg(feature = "federation")]
+  async fn sync(&self, origin: ServerId, requester: ServerId) -> ServerReply {
+    let (route, version, clients) = if origin == self.id {
+      let changes = self.changes.lock().await;
+      let (joined, _) = changes.since(0);
+      (vec![self.id], changes.version, joined)
+    } else {
+      let chain = self.signed.lock().await.deltas.get(&origin).cloned();
+      let route = self.topology.read().await.route_to(&origin).cloned();
+      if let (Some(chain), Some(mut route)) = (chain, route) {
+        route.push(self.id);
+        let outgoing = chain
+          .into_iter()
+          .map(|mut message| {
+            if let ServerMessage::AnnounceDelta { route: r, .. } = &mut message {
+              r.clone_from(&route);
+            }
+            Outgoing {
+              nexthop: requester,
+              message,
+            }
+          })
+          .collect();
+        return ServerReply::Outgoing(outgoing);
+      }
+      let version = self.versions.read().await.get(&origin).copied();
+      let route = self.topology.read().await.route_to(&origin).cloned();
+      let (version, mut route) = match (version, route) {
+        (Some(v), Some(r)) => (v, r),
+        _ => {
+          log::warn!(
+            "{} asked for the clients of {}, that are not known",
+            requester,
+            origin
+          );
+          return ServerReply::Outgoing(Vec::new());
+        }
+      };
+      route.push(self.id);
+      let clients = self
+        .remote
+        .read()
+        .await
+        .iter()
+        .filter(|(_, (_, srv))| *srv == origin)
+        .map(|(c, (name, _))| (*c, name.clone()))
+        .collect();
+      (route, version, clients)
+    };
+    let server_name = if origin == self.id {
+      self.name.clone()
+    } else {
+      let names = self.server_names.read().await;
+      names.get(&origin).cloned().unwrap_or_default()
+    };
+    let mut message = ServerMessage::AnnounceDelta {
+      route,
+      since: 0,
+      version,
+      joined: clients,
+      left: Vec::new(),
+      signature: Vec::new(),
+      server_name,
+    };
+    if origin == self.id {
+      signing::sign(&self.key, &mut message);
+    }
+    ServerReply::Outgoing(vec![Outgoing {
+      nexthop: requester,
+      message,
+    }])
+  }
+
+  /// learns the name of origin, returns true if it changed
+  /// empty names are not known, and names with a '@' could not be told apart in addresses; names are self-declared,
+  /// so only a server that signed its announce with a trusted key can take the name of this server or of another
+  /// one, that then loses it
+  #[cfg(feature = "federation")]
+  async fn learn_name(&self, origin: ServerId, name: &str, trusted: bool) -> bool {
+    if name.is_empty() || name.contains('@') {
+      return false;
+    }
+    let mut names = self.server_names.write().await;
+    let taken = name == self.name || names.iter().any(|(srv, n)| *srv != origin && n == name);
+    if taken && !trusted {
+      log::warn!(
+        "{} announced the name {}, that is already taken",
+        origin,
+        name
+      );
+      return false;
+    }
+    names.retain(|srv, n| *srv == origin || n != name);
+    let previous = names.insert(origin, name.to_string());
+    previous.as_deref() != Some(name)
+  }
+
+  /// true if the message is signed by its origin, with the key the policy trusts for it
+  #[cfg(feature = "federation")]
+  fn trusted_signature(&self, message: &ServerMessage) -> bool {
+    let key = signing::signer(message).and_then(|s| self.config.federation.trusted.get(&s));
+    key.is_some_and(|key| signing::verify(key, message))
+  }
+
+  /// learns the links of the route, returns true if the route to origin changed
+  #[cfg(feature = "federation")]
+  async fn learn_route(&self, origin: ServerId, route: &[ServerId]) -> bool {
+    let mut topology = self.topology.write().await;
+    let before = topology.route_to(&origin).cloned();
+    topology.learn(route);
+    topology.route_to(&origin) != before.as_ref()
+  }
+
+  /// records the clients of origin, except the local ones, returns them and true if some were new or renamed
+  #[cfg(feature = "federation")]
+  async fn learn_clients(
+    &self,
+    origin: ServerId,
+    clients: HashMap<ClientId, String>,
+  ) -> (HashMap<ClientId, String>, bool) {
+    let mut changed = false;
+    let mut learned = HashMap::new();
+    for (client, name) in clients {
+      if self.local(&client).await.is_some() {
+        log::warn!("{} announced {}, that is a local client", origin, client);
+        continue;
+      }
+      let previous = self
+        .remote
+        .write()
+        .await
+        .insert(client, (name.clone(), origin));
+      changed |= previous.as_ref() != Some(&(name.clone(), origin));
+      learned.insert(client, name);
+    }
+    (learned, changed)
+  }
+
+  /// sends the messages that waited for the clients of origin
+  #[cfg(feature = "federation")]
+  async fn release<'a>(
+    &self,
+    origin: ServerId,
+    nexthop: ServerId,
+    clients: impl Iterator<Item = &'a ClientId>,
+  ) -> Vec<Outgoing<ServerMessage>> {
+    let mut outgoing = Vec::new();
+    for client in clients {
+      let waiting = self.delayed.lock().await.remove(client);
+      for d in waiting.unwrap_or_default() {
+        if d.srcsrv == self.id {
+          if let Some(local) = self.local(&d.src).await {
+            local.waiting.fetch_sub(1, Ordering::Relaxed);
+          }
+          // the role of the sender might have changed while the message waited
+          let allowed = self.roles.read().await.check(&d.src, Action::RemoteMessage);
+          if let Err(rr) = allowed {
+            log::warn!("dropping a message from {} to {}: {}", d.src, client, rr);
+            continue;
+          }
+        }
+        outgoing.push(Outgoing {
+          nexthop,
+          message: ServerMessage::Message(FullyQualifiedMessage {
+            src: d.src,
+            srcsrv: d.srcsrv,
+            dsts: vec![(*client, origin)],
+            content: d.content,
+            id: d.id,
+            hops: d.hops,
+            signature: d.signature,
+          }),
+        });
+      }
+    }
+    outgoing
+  }
+
+  /// forgets a link, and passes the withdrawal on if routes changed
+  /// the withdrawal of a link of ours only has the neighbour on its route, and gets versioned and signed here; the
+  /// others are ignored unless they are newer than the last withdrawal of their link, so that they can not be replayed
+  #[cfg(feature = "federation")]
+  async fn withdraw(
+    &self,
+    mut route: Vec<ServerId>,
+    signature: Vec<u8>,
+    mut version: u64,
+  ) -> ServerReply {
+    let gone = match route.first() {
+      Some(s) => *s,
+      None => return ServerReply::EmptyRoute,
+    };
+    if route.contains(&self.id) {
+      log::debug!("ignoring a withdrawal of {} that went through us", gone);
+      return ServerReply::Outgoing(Vec::new());
+    }
+    match route.get(1) {
+      None => version = self.next_withdrawal.fetch_add(1, Ordering::Relaxed),
+      Some(signer) => {
+        let mut signed = self.signed.lock().await;
+        let known = signed.withdrawals.entry((gone, *signer)).or_default();
+        if version <= *known {
+          log::debug!(
+            "ignoring a withdrawal of {} by {} at version {}, after version {}",
+            gone,
+            signer,
+            version,
+            known
+          );
+          return ServerReply::Outgoing(Vec::new());
+        }
+        *known = version;
+      }
+    }
+    if !self.topology.write().await.withdraw(&route) {
+      return ServerReply::Outgoing(Vec::new());
+    }
+    if self.nexthop(&gone).await.is_none() {
+      log::info!("{} can no longer be reached", gone);
+    }
+    route.push(self.id);
+    let mut message = ServerMessage::Withdraw {
+      route: route.clone(),
+      signature,
+      version,
+    };
+    if route.len() == 2 {
+      signing::sign(&self.key, &mut message);
+    }
+    ServerReply::Outgoing(self.to_neighbours(&route, message))
+  }
+
+  /// forgets clients of the first server of the route, and passes the withdrawal on as it was signed
+  #[cfg(feature = "federation")]
+  async fn withdraw_clients(
+    &self,
+    mut route: Vec<ServerId>,
+    clients: Vec<ClientId>,
+    signature: Vec<u8>,
+  ) -> ServerReply {
+    let origin = match route.first() {
+      Some(s) => *s,
+      None => return ServerReply::EmptyRoute,
+    };
+    if route.contains(&self.id) {
+      return ServerReply::Outgoing(Vec::new());
+    }
+    let mut withdrawn = false;
+    {
+      let mut remote = self.remote.write().await;
+      for client in &clients {
+        // the client might have been announced again by another server since
+        if matches!(remote.get(client), Some((_, srv)) if *srv == origin) {
+          remote.remove(client);
+          withdrawn = true;
+        }
+      }
+    }
+    if !withdrawn {
+      return ServerReply::Outgoing(Vec::new());
+    }
+    route.push(self.id);
+    let message = ServerMessage::WithdrawClients {
+      route: route.clone(),
+      clients,
+      signature,
+    };
+    ServerReply::Outgoing(self.to_neighbours(&route, message))
+  }
+
+  /// sends the message to the neighbours that are not on the route
+  #[cfg(feature = "federation")]
+  fn to_neighbours(
+    &self,
+    route: &[ServerId],
+    message: ServerMessage,
+  ) -> Vec<Outgoing<ServerMessage>> {
+    self
+      .config
+      .neighbours
+      .iter()
+      .filter(|n| !route.contains(n))
+      .map(|n| Outgoing {
+        nexthop: *n,
+        message: message.clone(),
+      })
+      .collect()
+  }
+
+  /// delivers the message to its local destinations, and forwards it to the other ones
+  #[cfg(feature = "federation")]
+  async fn handle_remote_message(
+    &self,
+    mut fqm: FullyQualifiedMessage,
+  ) -> Vec<Outgoing<ServerMessage>> {
+    {
+      let mut seen = self.seen.lock().await;
+      let (srcsrv, id) = (fqm.srcsrv, fqm.id);
+      signing::retain_destinations(&mut fqm, |(dest, _)| seen.insert(srcsrv, id, *dest));
+    }
+    if fqm.dsts.is_empty() {
+      log::debug!("dropping a copy of {} from {}", fqm.id, fqm.srcsrv);
+      return Vec::new();
+    }
+    let id = self.message_id();
+    // the destinations reached through each neighbour
+    let mut hops: Vec<(ServerId, Vec<(ClientId, ServerId)>)> = Vec::new();
+    // reported to the sender
+    let mut errors = Vec::new();
+    for (n, (dest, srv)) in fqm.dsts.iter().copied().enumerate() {
+      if srv != self.id {
+        if fqm.hops == 0 {
+          log::warn!(
+            "{} from {} reached the hop limit on its way to {}",
+            fqm.id,
+            fqm.srcsrv,
+            dest
+          );
+          errors.push(DelayedError::HopLimit(dest));
+          continue;
+        }
+        match self.nexthop(&srv).await {
+          Some(nexthop) => match hops.iter_mut().find(|(h, _)| *h == nexthop) {
+            Some((_, dsts)) => dsts.push((dest, srv)),
+            None => hops.push((nexthop, vec![(dest, srv)])),
+          },
+          None => {
+            // kept until the route comes back, like messages sent from here
+            log::info!("no route to {}, delaying a message for {}", srv, dest);
+            self
+              .delay(
+                dest,
+                Delayed {
+                  src: fqm.src,
+                  srcsrv: fqm.srcsrv,
+                  content: fqm.content.clone(),
+                  since: Instant::now(),
+                  id: fqm.id,
+                  hops: fqm.hops - 1,
+                  signature: signing::destination_signature(&fqm, n).to_vec(),
+                },
+              )
+              .await;
+          }
+        }
+        continue;
+      }
+      let local = match self.local(&dest).await {
+        Some(l) => l,
+        None => {
+          log::warn!(
+            "{} is not a local client, dropping a message from {}",
+            dest,
+            fqm.src
+          );
+          errors.push(DelayedError::UnknownRecipient(dest));
+          continue;
+        }
+      };
+      let pushed = local
+        .mailbox
+        .lock()
+        .await
+        .push(ClientPollReply::Message {
+          src: fqm.src,
+          id,
+          content: fqm.content.clone(),
+        })
+        .await;
+      match pushed {
+        Ok(()) => self.record(fqm.src, dest, id, fqm.content.clone()).await,
+        Err(rr) => {
+          log::warn!(
+            "could not deliver a message from {} to {}: {}",
+            fqm.src,
+            dest,
+            rr
+          );
+          errors.push(DelayedError::RemoteBoxFull(dest));
+        }
+      }
+    }
+    let mut outgoing: Vec<Outgoing<ServerMessage>> = hops
+      .into_iter()
+      .map(|(nexthop, dsts)| {
+        // the signatures of the other destinations are left out with them
+        let mut copy = FullyQualifiedMessage {
+          hops: fqm.hops - 1,
+          ..fqm.clone()
+        };
+        signing::retain_destinations(&mut copy, |d| dsts.contains(d));
+        Outgoing {
+          nexthop,
+          message: ServerMessage::Message(copy),
+        }
+      })
+      .collect();
+    for error in errors {
+      outgoing.extend(self.report(fqm.src, fqm.srcsrv, error).await);
+    }
+    outgoing
+  }
+
+  /// the commands that AdminState can not handle alone
+  async fn run_admin(&self, command: &AdminCommand) -> Result<AdminReply, ClientError> {
+    match command {
+      AdminCommand::Kick(client) => {
+        self
+          .clients
+          .get(client)
+          .write()
+          .await
+          .remove(client)
+          .ok_or(ClientError::UnknownClient)?;
+        self.changes.lock().await.record(*client, None);
+        self.admin.write().await.forget(client);
+        self.roles.write().await.forget(client);
+        Ok(AdminReply::Done)
+      }
+      AdminCommand::Broadcast(text) => {
+        let mut reached = 0;
+        for shard in self.clients.iter() {
+          let locals = shard.read().await.values().cloned().collect::<Vec<_>>();
+          for local in locals {
+            let notice = ClientPollReply::Notice(text.clone());
+            if local.mailbox.lock().await.push(notice).await.is_ok() {
+              reached += 1;
+            }
+          }
+        }
+        Ok(AdminReply::Reached(reached))
+      }
+      AdminCommand::MailboxDepth(client) => {
+        let local = self.local(client).await.ok_or(ClientError::UnknownClient)?;
+        let depth = local.mailbox.lock().await.len();
+        Ok(AdminReply::Depth(depth as u64))
+      }
+      AdminCommand::AssignRole { client, role } => {
+        self.roles.write().await.assign(*client, role)?;
+        Ok(AdminReply::Done)
+      }
+      _ => self
+        .admin
+        .write()
+        .await
+        .apply(command)
+        .ok_or(ClientError::InternalError),
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use std::time::Duration;
+
+  use async_std::future::timeout;
+
+  use crate::testing::test_message_server;
+
+  use super::*;
+
+  #[test]
+  fn tester() {
+    test_message_server::<Server>();
+  }
+
+  fn text(dest: ClientId, content: &str) -> ClientMessage {
+    ClientMessage::Text {
+      dest,
+      content: content.into(),
+    }
+  }
+
+  #[test]
+  fn recipients_do_not_wait_for_each_other() {
+    async_std::task::block_on(async {
+      let server = Server::new(ServerId::default());
+      let c1 = server.register_local_client("user 1".into()).await;
+      let c2 = server.register_local_client("user 2".into()).await;
+      let c3 = server.register_local_client("user 3".into()).await;
+
+      // a long operation on the mailbox of c2, such as a slow poll
+      let local = server.local(&c2).await.unwrap();
+      let busy = local.mailbox.lock().await;
+
+      let r = timeout(
+        Duration::from_secs(5),
+        server.handle_client_message(c1, text(c3, "not blocked")),
+      )
+      .await
+      .expect("delivery to c3 waited for the mailbox of c2");
+      assert!(matches!(r[..], [ClientReply::Delivered(_)]));
+      assert!(matches!(
+        server.client_poll(c3).await,
+        ClientPollReply::Message { .. }
+      ));
+      let r = timeout(
+        Duration::from_millis(100),
+        server.handle_client_message(c1, text(c2, "blocked")),
+      )
+      .await;
+      assert!(r.is_err(), "delivery to c2 went through its locked mailbox");
+
+      drop(busy);
+      let r = server
+        .handle_client_message(c1, text(c2, "unblocked"))
+        .await;
+      assert!(matches!(r[..], [ClientReply::Delivered(_)]));
+    })
+  }
+
+  #[test]
+  fn concurrent_deliveries() {
+    const SENDERS: usize = 8;
+    const MESSAGES: usize = 200;
+    async_std::task::block_on(async {
+      let server = Arc::new(Server::new(ServerId::default()));
+      let mut tasks = Vec::new();
+      for n in 0..SENDERS {
+        let src = server.register_local_client(format!("sender {n}")).await;
+        let dest = server.register_local_client(format!("recipient {n}")).await;
+        let server = server.clone();
+        tasks.push(async_std::task::spawn(async move {
+          // each recipient polls while its sender is still sending
+          let sender = {
+            let server = server.clone();
+            async_std::task::spawn(async move {
+              for i in 0..MESSAGES {
+                let r = server
+                  .handle_client_message(src, text(dest, &i.to_string()))
+                  .await;
+                assert!(matches!(r[..], [ClientReply::Delivered(_)]), "{:?}", r);
+              }
+            })
+          };
+          let mut received = Vec::new();
+          while received.len() < MESSAGES {
+            match server.client_poll(dest).await {
+              ClientPollReply::Message {
+                src: s, content, ..
+              } if s == src => received.push(content),
+              ClientPollReply::Nothing => async_std::task::yield_now().await,
+              r => panic!("unexpected reply {:?}", r),
+            }
+          }
+          sender.await;
+          let expected = (0..MESSAGES).map(|i| i.to_string()).collect::<Vec<_>>();
+          assert_eq!(received, expected);
+        }));
+      }
+      for t in tasks {
+        t.await;
+      }
+      // every message got its own id
+      let users = server.list_users().await;
+      assert_eq!(users.len(), SENDERS * 2);
+      let ids = server.next_id.load(Ordering::Relaxed);
+      assert_eq!(ids as usize, SENDERS * MESSAGES);
+    })
+  }
+
+  #[cfg(feature = "federation")]
+  #[test]
+  fn reroute_on_shorter_path() {
+    use crate::simulator::{ring, SimConfig, Simulator};
+
+    async_std::task::block_on(async {
+      let mut sim: Simulator<Server> = Simulator::new(6, &ring(6), SimConfig::default());
+      let c0 = sim.register(0, "near").await;
+      let c4 = sim.register(4, "far").await;
+      // only the long way around is known at first
+      sim.cut(5, 0);
+      sim.announce_all().await;
+      sim.run(100).await;
+      let long = vec![sim.id(4), sim.id(3), sim.id(2), sim.id(1)];
+      assert_eq!(sim.server(0).route_to(sim.id(4)).await, Some(long));
+
+      sim.heal(5, 0);
+      sim.announce(5).await;
+      sim.run(100).await;
+      let short = vec![sim.id(4), sim.id(5)];
+      assert_eq!(sim.server(0).route_to(sim.id(4)).await, Some(short));
+      let r = sim.send(0, c0, text(c4, "shortcut")).await;
+      assert!(matches!(r[..], [ClientReply::Transfer(hop, _)] if hop == sim.id(5)));
+      sim.run(100).await;
+      assert!(matches!(
+        sim.poll(4, c4).await,
+        ClientPollReply::Message { src, .. } if src == c0
+      ));
+    })
+  }
+}
@@ -1,19 +1,25 @@
 use async_std::sync::RwLock;
 use async_trait::async_trait;
 use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::IpAddr;
 use uuid::Uuid;
 
 use crate::{
-  core::{MessageServer, MAILBOX_SIZE, WORKPROOF_STRENGTH},
+  admin::{AuditEntry, Operator},
+  core::{MessageServer, ServerConfig, MAILBOX_SIZE, WORKPROOF_STRENGTH},
   messages::{
-    ClientError, ClientId, ClientMessage, ClientPollReply, ClientReply, FullyQualifiedMessage,
-    Sequence, ServerId,
+    Action, Address, AdminCommand, AdminReply, Chunk, ClientError, ClientId, ClientMessage,
+    ClientPollReply, ClientReply, FullyQualifiedMessage, HistoryPage, HistoryQuery, Sequence,
+    ServerId,
   },
   workproof::verify_workproof,
 };
 
 #[cfg(feature = "federation")]
-use crate::messages::{Outgoing, ServerMessage, ServerReply};
+use crate::{
+  admin::PeerAuditEntry,
+  messages::{Outgoing, ServerMessage, ServerReply},
+};
 
 // this structure will contain the data you need to track in your server
 // this will include things like delivered messages, clients last seen sequence number, etc.
@@ -23,7 +29,7 @@ pub struct Server {}
 impl MessageServer for Server {
   const GROUP_NAME: &'static str = "TODO";
 
-  fn new(id: ServerId) -> Self {
+  fn with_config(id: ServerId, config: ServerConfig) -> Self {
     todo!()
   }
 
@@ -38,6 +44,7 @@ impl MessageServer for Server {
    * the workproof should be checked first
     * the nonce is in sequence.src and should be converted with (&sequence.src).into()
    * then, if the client is known, its last seen sequence number must be verified (and updated)
+   * banned clients are rejected with Banned
   */
   async fn handle_sequenced_message<A: Send>(
     &self,
@@ -48,11 +55,30 @@ impl MessageServer for Server {
 
   /* Here client messages are handled.
      * if the client is local,
-       * if the mailbox is full, BoxFull should be returned
+       * if the mailbox is full, the overflow policy of the configuration applies, and BoxFull is
+         returned for the Reject policy
        * otherwise, Delivered should be returned
      * if the client is unknown, the message should be stored and Delayed must be returned
+       * after the delayed_ttl of the configuration, it is dropped and the sender gets a
+         DelayedError::UnknownRecipient the next time it polls
      * (federation) if the client is remote, Transfer should be returned
 
+     Each stored message gets a fresh MessageId, returned in the Delivered reply. MText destinations
+     that are listed several times only get the message once.
+     Attachment messages are delivered like text messages, but only if the attachment was completely
+     uploaded by src. The recipients must then be allowed to download it.
+
+     Edit and Delete messages look for the message with the same id and source in the recipient
+     mailbox:
+       * if it is still there, it is rewritten or removed, and Delivered is returned
+       * if it was already polled, an Edited or Deleted event is stored instead
+
+     The content of Text, MText and Edit messages must first go through the filters of the
+     configuration, see crate::filter::apply. When a filter rejects the message, nothing is
+     queued and Error(Rejected(reason)) is returned for each destination.
+     The role of src is checked the same way: MassMessage for MText messages with too many
+     destinations, and RemoteMessage for each remote destination.
+
      It is recommended to write an function that handles a single message and use it to handle
      both ClientMessage variants. 
    */
@@ -61,16 +87,85 @@ impl MessageServer for Server {
   }
 
   /* for the given client, return the next message or error if available
+     The crate::mailbox::Mailbox structure implements the quotas and overflow policies. When
+     messages were dropped, a Dropped notice is returned before the remaining messages.
    */
   async fn client_poll(&self, client: ClientId) -> ClientPollReply {
     todo!()
   }
 
+  /* Delivered messages are recorded in the history of the conversation between the sender and
+     the recipient. The crate::history::HistoryStore structure can be used for that.
+   */
+  async fn history(&self, client: ClientId, query: HistoryQuery) -> HistoryPage {
+    todo!()
+  }
+
+  /* Attachments are stored until the sender uploads all its chunks
+     * the chunk offset must be a multiple of CHUNK_SIZE
+     * the total size of the attachments of a client can not exceed ATTACHMENT_QUOTA
+     * once all chunks are received, the hash must be verified
+     * once all the recipients downloaded every chunk, or after attachment_ttl, the attachment is
+       removed and its size no longer counts in the quota
+     The crate::attachments::AttachmentStore structure can be used for that.
+   */
+  async fn upload_chunk(&self, src: ClientId, chunk: Chunk) -> Result<u64, ClientError> {
+    todo!()
+  }
+
+  async fn download_chunk(
+    &self,
+    client: ClientId,
+    src: ClientId,
+    hash: [u8; 32],
+    offset: u64,
+  ) -> Result<Chunk, ClientError> {
+    todo!()
+  }
+
+  /* Administration commands are recorded in the audit log, even when denied. The
+     crate::admin::AdminState structure handles admins, bans and the log, and applies the commands
+     that do not need the rest of the server state.
+   */
+  async fn admin(&self, by: Operator, command: AdminCommand) -> Result<AdminReply, ClientError> {
+    todo!()
+  }
+
+  async fn address_banned(&self, addr: IpAddr) -> bool {
+    todo!()
+  }
+
+  async fn audit_log(&self) -> Vec<AuditEntry> {
+    todo!()
+  }
+
+  /* Messages from other servers that the federation policy rejects are recorded with
+     AdminState::record_peer.
+   */
+  #[cfg(feature = "federation")]
+  async fn peer_audit_log(&self) -> Vec<PeerAuditEntry> {
+    todo!()
+  }
+
+  /* Permissions come from the role of the client, see crate::roles::Roles. The roles are changed
+     with the AssignRole administration command.
+   */
+  async fn permitted(&self, client: ClientId, action: Action) -> Result<(), ClientError> {
+    todo!()
+  }
+
+  /* Registrations are checked against the role of the address, RoleConfig::addresses */
+  async fn registration_permitted(&self, addr: IpAddr) -> Result<(), ClientError> {
+    todo!()
+  }
+
   /* For announces
       * if the route is empty, return EmptyRoute
+      * if the route contains this server, ignore it
       * if not, store the route in some way
       * also store the remote clients
       * if one of these remote clients has messages waiting, return them
+      * if something changed, also return the announce for the neighbours
      For messages
       * if local, deliver them
       * if remote, forward them
@@ -80,10 +175,30 @@ impl MessageServer for Server {
     todo!()
   }
 
+  /* Announce the local clients to each neighbour of the configuration, and pass on what was learned
+     from the other servers, appending this server to the routes. Do not send a route back to a server
+     it contains, it would loop.
+   */
+  #[cfg(feature = "federation")]
+  async fn announces(&self) -> Vec<Outgoing<ServerMessage>> {
+    todo!()
+  }
+
   async fn list_users(&self) -> HashMap<ClientId, String> {
     todo!()
   }
 
+  /* the address of a client is its name and the name of its server, name@server
+     Remote clients are qualified with the names their servers announced, or with their uuids.
+   */
+  async fn list_addresses(&self) -> HashMap<ClientId, Address> {
+    todo!()
+  }
+
+  async fn resolve(&self, address: &Address) -> Option<ClientId> {
+    todo!()
+  }
+
   // return a route to the target server
   // bonus points if it is the shortest route
   #[cfg(feature = "federation")]
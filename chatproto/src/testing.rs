@@ -1,8 +1,29 @@
-use std::collections::HashMap;
+use std::{
+  collections::{HashMap, HashSet},
+  future::Future,
+  panic::AssertUnwindSafe,
+  pin::Pin,
+  sync::Arc,
+  time::Duration,
+};
 
-use anyhow::Context;
-
-use crate::{client::Client, core::*, messages::*};
+use crate::{
+  admin::Operator,
+  attachments,
+  bot::{Bot, LocalTransport},
+  client::Client,
+  core::*,
+  filter::{BannedWords, MaxLength, MessageFilter, StripLinks},
+  history::Retention,
+  mailbox::{MailboxQuota, OverflowPolicy},
+  messages::*,
+  roles::RoleConfig,
+};
+#[cfg(feature = "federation")]
+use crate::{
+  federation::{FederationPolicy, PeerList},
+  signing,
+};
 
 async fn sequence_correct<M: MessageServer>() -> Result<(), ClientError> {
   let sid = ServerId::default();
@@ -103,12 +124,14 @@ async fn simple_client_test<M: MessageServer>() -> anyhow::Result<()> {
       },
     )
     .await;
-  if r != &[ClientReply::Delivered] {
-    anyhow::bail!("expected a single delivered message, got {:?}", r)
-  }
+  let id = match r[..] {
+    [ClientReply::Delivered(id)] => id,
+    _ => anyhow::bail!("expected a single delivered message, got {:?}", r),
+  };
   let reply = server.client_poll(c2).await;
   let expected = ClientPollReply::Message {
     src: c1,
+    id,
     content: "hello".into(),
   };
   if reply != expected {
@@ -146,6 +169,7 @@ async fn multiple_client_messages_test<M: MessageServer>() -> anyhow::Result<()>
   let c1 = server.register_local_client("user 1".to_string()).await;
   let c2 = server.register_local_client("user 2".to_string()).await;
   let c3 = server.register_local_client("user 3".to_string()).await;
+  let mut ids = Vec::new();
   for i in 0..100 {
     let r = server
       .handle_client_message(
@@ -156,8 +180,9 @@ async fn multiple_client_messages_test<M: MessageServer>() -> anyhow::Result<()>
         },
       )
       .await;
-    if r != [ClientReply::Delivered] {
-      anyhow::bail!("A> Could not deliver message {}, got {:?}", i, r);
+    match r[..] {
+      [ClientReply::Delivered(id)] => ids.push(id),
+      _ => anyhow::bail!("A> Could not deliver message {}, got {:?}", i, r),
     }
   }
   for i in 0..100 {
@@ -170,15 +195,17 @@ async fn multiple_client_messages_test<M: MessageServer>() -> anyhow::Result<()>
         },
       )
       .await;
-    if r != [ClientReply::Delivered, ClientReply::Delivered] {
-      anyhow::bail!("B> Could not deliver message {}, got {:?}", i, r);
+    match r[..] {
+      [ClientReply::Delivered(id), ClientReply::Delivered(id3)] if id == id3 => ids.push(id),
+      _ => anyhow::bail!("B> Could not deliver message {}, got {:?}", i, r),
     }
   }
 
-  for i in 0..200 {
+  for (i, id) in ids.iter().copied().enumerate() {
     let reply = server.client_poll(c2).await;
     let expected_reply = ClientPollReply::Message {
       src: c1,
+      id,
       content: i.to_string(),
     };
     if reply != expected_reply {
@@ -189,10 +216,11 @@ async fn multiple_client_messages_test<M: MessageServer>() -> anyhow::Result<()>
       );
     }
   }
-  for i in 100..200 {
+  for (i, id) in ids.iter().copied().enumerate().skip(100) {
     let reply = server.client_poll(c3).await;
     let expected_reply = ClientPollReply::Message {
       src: c1,
+      id,
       content: i.to_string(),
     };
     if reply != expected_reply {
@@ -237,7 +265,7 @@ async fn mixed_results_client_message<M: MessageServer>() -> anyhow::Result<()>
       },
     )
     .await;
-  if m != [ClientReply::Delivered, ClientReply::Delayed] {
+  if !matches!(m[..], [ClientReply::Delivered(_), ClientReply::Delayed]) {
     anyhow::bail!("Expected Delivered/Delayed, but got {:?}", m)
   }
   Ok(())
@@ -260,7 +288,7 @@ async fn mailbox_full<M: MessageServer>() -> anyhow::Result<()> {
         },
       )
       .await;
-    if m != [ClientReply::Delivered] {
+    if !matches!(m[..], [ClientReply::Delivered(_)]) {
       anyhow::bail!("Expected Delivered, but got {:?}", m)
     }
   }
@@ -279,159 +307,3051 @@ async fn mailbox_full<M: MessageServer>() -> anyhow::Result<()> {
   Ok(())
 }
 
-#[cfg(feature = "federation")]
-async fn message_to_outer_user<M: MessageServer>() -> anyhow::Result<()> {
+async fn mailbox_full_bytes<M: MessageServer>() -> anyhow::Result<()> {
   let sid = ServerId::default();
-  let server: M = MessageServer::new(sid);
+  let server: M = MessageServer::with_config(
+    sid,
+    ServerConfig {
+      mailbox: MailboxQuota {
+        max_messages: MAILBOX_SIZE,
+        max_bytes: 10,
+      },
+      ..Default::default()
+    },
+  );
 
   let c1 = server.register_local_client("user 1".to_string()).await;
-  let s1 = ServerId::default();
-  let s2 = ServerId::default();
-  let s3 = ServerId::default();
-  let euuid = ClientId::default();
+  let c2 = server.register_local_client("user 2".to_string()).await;
+  send_text(&server, c1, c2, "12345").await?;
+  send_text(&server, c1, c2, "67890").await?;
+  let m = server
+    .handle_client_message(
+      c1,
+      ClientMessage::Text {
+        dest: c2,
+        content: "!".into(),
+      },
+    )
+    .await;
+  if m != [ClientReply::Error(ClientError::BoxFull(c2))] {
+    anyhow::bail!("Expected BoxFull, but got {:?}", m)
+  }
+  // polling frees space in the mailbox
+  server.client_poll(c2).await;
+  send_text(&server, c1, c2, "!").await?;
+  Ok(())
+}
 
-  log::debug!("route: {} -> {} -> {} -> us", s1, s2, s3);
+async fn mailbox_drop_oldest<M: MessageServer>() -> anyhow::Result<()> {
+  let sid = ServerId::default();
+  let server: M = MessageServer::with_config(
+    sid,
+    ServerConfig {
+      mailbox: MailboxQuota {
+        max_messages: 2,
+        max_bytes: MAILBOX_BYTES,
+      },
+      overflow: OverflowPolicy::DropOldest,
+      ..Default::default()
+    },
+  );
+
+  let c1 = server.register_local_client("user 1".to_string()).await;
+  let c2 = server.register_local_client("user 2".to_string()).await;
+  let mut ids = Vec::new();
+  for n in 0..5 {
+    ids.push(send_text(&server, c1, c2, &n.to_string()).await?);
+  }
+  let expected = [
+    ClientPollReply::Dropped { count: 3 },
+    ClientPollReply::Message {
+      src: c1,
+      id: ids[3],
+      content: "3".into(),
+    },
+    ClientPollReply::Message {
+      src: c1,
+      id: ids[4],
+      content: "4".into(),
+    },
+    ClientPollReply::Nothing,
+  ];
+  for e in expected {
+    let reply = server.client_poll(c2).await;
+    if reply != e {
+      anyhow::bail!("expected {:?}, got {:?}", e, reply);
+    }
+  }
+  Ok(())
+}
+
+async fn mailbox_spill<M: MessageServer>() -> anyhow::Result<()> {
+  let dir = std::env::temp_dir().join(format!("chatproto-spill-{}", uuid::Uuid::new_v4()));
+  std::fs::create_dir_all(&dir)?;
+  let sid = ServerId::default();
+  let server: M = MessageServer::with_config(
+    sid,
+    ServerConfig {
+      mailbox: MailboxQuota {
+        max_messages: 2,
+        max_bytes: MAILBOX_BYTES,
+      },
+      overflow: OverflowPolicy::Spill {
+        dir: dir.clone(),
+        max_bytes: 1 << 20,
+      },
+      ..Default::default()
+    },
+  );
+
+  let c1 = server.register_local_client("user 1".to_string()).await;
+  let c2 = server.register_local_client("user 2".to_string()).await;
+  let mut ids = Vec::new();
+  for n in 0..5 {
+    ids.push(send_text(&server, c1, c2, &n.to_string()).await?);
+  }
+  for (n, id) in ids.into_iter().enumerate() {
+    let reply = server.client_poll(c2).await;
+    let expected = ClientPollReply::Message {
+      src: c1,
+      id,
+      content: n.to_string(),
+    };
+    if reply != expected {
+      anyhow::bail!("expected {:?}, got {:?}", expected, reply);
+    }
+  }
+  let reply = server.client_poll(c2).await;
+  if reply != ClientPollReply::Nothing {
+    anyhow::bail!("expected an empty mailbox, received {:?}", reply);
+  }
+  std::fs::remove_dir_all(&dir)?;
+  Ok(())
+}
+
+async fn filtered_messages<M: MessageServer>() -> anyhow::Result<()> {
+  let sid = ServerId::default();
+  let filters: Vec<Arc<dyn MessageFilter>> = vec![
+    Arc::new(StripLinks),
+    Arc::new(MaxLength(20)),
+    Arc::new(BannedWords(vec!["spam".into()])),
+  ];
+  let server: M = MessageServer::with_config(
+    sid,
+    ServerConfig {
+      filters,
+      ..Default::default()
+    },
+  );
+
+  let c1 = server.register_local_client("user 1".to_string()).await;
+  let c2 = server.register_local_client("user 2".to_string()).await;
+  let c3 = server.register_local_client("user 3".to_string()).await;
+
+  let id = send_text(&server, c1, c2, "see https://example.com/some/page").await?;
+  let reply = server.client_poll(c2).await;
+  let expected = ClientPollReply::Message {
+    src: c1,
+    id,
+    content: "see [link removed]".into(),
+  };
+  if reply != expected {
+    anyhow::bail!("expected {:?}, got {:?}", expected, reply);
+  }
 
   let r = server
-    .handle_server_message(ServerMessage::Announce {
-      route: vec![s1, s2, s3],
-      clients: HashMap::from([(euuid, "external user".into())]),
-    })
+    .handle_client_message(
+      c1,
+      ClientMessage::MText {
+        dest: vec![c2, c3],
+        content: "buy spam".into(),
+      },
+    )
     .await;
-  if r != ServerReply::Outgoing(Vec::new()) {
-    anyhow::bail!("Expected empty outgoing answer, got {:?}", r);
+  let rejected = ClientReply::Error(ClientError::Rejected("banned word: spam".into()));
+  if r != [rejected.clone(), rejected.clone()] {
+    anyhow::bail!("expected two rejections, got {:?}", r);
   }
-  assert_eq!(r, ServerReply::Outgoing(Vec::new()));
+  // the names of attachments go through the filters too
   let r = server
     .handle_client_message(
       c1,
-      ClientMessage::Text {
-        dest: euuid,
-        content: "Hello".to_string(),
+      ClientMessage::Attachment {
+        dest: vec![c2],
+        meta: AttachmentMeta {
+          name: "spam.txt".into(),
+          size: 0,
+          hash: attachments::content_hash(&[]),
+        },
       },
     )
     .await;
-  let expected = [ClientReply::Transfer(
-    s3,
-    ServerMessage::Message(FullyQualifiedMessage {
-      src: c1,
-      srcsrv: sid,
-      dsts: vec![(euuid, s1)],
-      content: "Hello".to_string(),
-    }),
-  )];
+  if r != [rejected] {
+    anyhow::bail!("expected the attachment to be rejected, got {:?}", r);
+  }
 
-  if r != expected {
-    anyhow::bail!("Expected {:?}\n   , got {:?}", expected, r)
+  let id = send_text(&server, c1, c3, "hello").await?;
+  let r = server
+    .handle_client_message(
+      c1,
+      ClientMessage::Edit {
+        dest: vec![c3],
+        id,
+        content: "hello, this is way too long".into(),
+      },
+    )
+    .await;
+  if !matches!(r[..], [ClientReply::Error(ClientError::Rejected(_))]) {
+    anyhow::bail!("expected the edit to be rejected, got {:?}", r);
   }
 
+  // nothing was queued for rejected messages
+  let reply = server.client_poll(c2).await;
+  if reply != ClientPollReply::Nothing {
+    anyhow::bail!("expected an empty mailbox, received {:?}", reply);
+  }
+  let reply = server.client_poll(c3).await;
+  let expected = ClientPollReply::Message {
+    src: c1,
+    id,
+    content: "hello".into(),
+  };
+  if reply != expected {
+    anyhow::bail!("expected {:?}, got {:?}", expected, reply);
+  }
   Ok(())
 }
 
-#[cfg(feature = "federation")]
-async fn message_to_outer_user_delayed<M: MessageServer>() -> anyhow::Result<()> {
+async fn echo_bot<M: MessageServer + Send + Sync + 'static>() -> anyhow::Result<()> {
+  let sid = ServerId::default();
+  let server: Arc<M> = Arc::new(MessageServer::new(sid));
+
+  let user = server.register_local_client("user".to_string()).await;
+  let mut bot = Bot::new("echo", LocalTransport::new(server.clone()))
+    .command("echo", |rq| Some(rq.args.join(" ")));
+  let botid = bot.connect().await?;
+  if !server.list_users().await.contains_key(&botid) {
+    anyhow::bail!("the bot is not listed");
+  }
+
+  send_text(&*server, user, botid, "!echo hello   world").await?;
+  send_text(&*server, user, botid, "just chatting").await?;
+  send_text(&*server, user, botid, "!nope").await?;
+  let mut handled = 0;
+  while bot.step().await? {
+    handled += 1;
+  }
+  if handled != 3 {
+    anyhow::bail!("expected 3 handled messages, got {}", handled);
+  }
+
+  for expected in ["hello world", "unknown command: !nope"] {
+    match server.client_poll(user).await {
+      ClientPollReply::Message { src, content, .. } if src == botid && content == expected => (),
+      reply => anyhow::bail!("expected {:?} from the bot, got {:?}", expected, reply),
+    }
+  }
+  let reply = server.client_poll(user).await;
+  if reply != ClientPollReply::Nothing {
+    anyhow::bail!("expected an empty mailbox, received {:?}", reply);
+  }
+  Ok(())
+}
+
+async fn admin_commands<M: MessageServer>() -> anyhow::Result<()> {
   let sid = ServerId::default();
   let server: M = MessageServer::new(sid);
 
   let c1 = server.register_local_client("user 1".to_string()).await;
-  let s1 = ServerId::default();
-  let s2 = ServerId::default();
-  let s3 = ServerId::default();
-  let euuid = ClientId::default();
+  let c2 = server.register_local_client("user 2".to_string()).await;
+  let c3 = server.register_local_client("user 3".to_string()).await;
+  let mut client2 = Client::new(c2);
 
-  log::debug!("route: {} -> {} -> {} -> us", s1, s2, s3);
+  // regular clients can not administrate
+  let r = server
+    .admin(Operator::Client(c1), AdminCommand::Ban(c2))
+    .await;
+  if r != Err(ClientError::NotAdmin) {
+    anyhow::bail!("expected NotAdmin, got {:?}", r);
+  }
+  let r = server
+    .admin(Operator::Console, AdminCommand::Grant(c1))
+    .await;
+  if r != Ok(AdminReply::Done) {
+    anyhow::bail!("could not grant the admin role, got {:?}", r);
+  }
 
+  server
+    .handle_sequenced_message(client2.sequence(()))
+    .await?;
   let r = server
-    .handle_client_message(
-      c1,
-      ClientMessage::Text {
-        dest: euuid,
-        content: "Hello".to_string(),
-      },
+    .admin(Operator::Client(c1), AdminCommand::Ban(c2))
+    .await;
+  if r != Ok(AdminReply::Done) {
+    anyhow::bail!("could not ban, got {:?}", r);
+  }
+  let r = server.handle_sequenced_message(client2.sequence(())).await;
+  if r != Err(ClientError::Banned) {
+    anyhow::bail!("expected Banned, got {:?}", r);
+  }
+
+  send_text(&server, c1, c3, "one").await?;
+  send_text(&server, c1, c3, "two").await?;
+  let r = server
+    .admin(Operator::Client(c1), AdminCommand::MailboxDepth(c3))
+    .await;
+  if r != Ok(AdminReply::Depth(2)) {
+    anyhow::bail!("expected a depth of 2, got {:?}", r);
+  }
+  let r = server
+    .admin(
+      Operator::Client(c1),
+      AdminCommand::Broadcast("maintenance".into()),
     )
     .await;
-  if r != [ClientReply::Delayed] {
-    anyhow::bail!("Expected a delayed message first, but got {:?}", r);
+  if r != Ok(AdminReply::Reached(3)) {
+    anyhow::bail!("expected a broadcast to 3 mailboxes, got {:?}", r);
+  }
+  server.client_poll(c3).await;
+  server.client_poll(c3).await;
+  let reply = server.client_poll(c3).await;
+  if reply != ClientPollReply::Notice("maintenance".into()) {
+    anyhow::bail!("expected the broadcast notice, got {:?}", reply);
   }
+
   let r = server
-    .handle_server_message(ServerMessage::Announce {
-      route: vec![s1, s2, s3],
-      clients: HashMap::from([(euuid, "external user".into())]),
-    })
+    .admin(Operator::Client(c1), AdminCommand::Kick(c3))
     .await;
-  let expected = ServerReply::Outgoing(vec![Outgoing {
-    nexthop: s3,
-    message: FullyQualifiedMessage {
-      src: c1,
-      srcsrv: sid,
-      dsts: vec![(euuid, s1)],
-      content: "Hello".to_string(),
-    },
-  }]);
-  if r != expected {
-    anyhow::bail!("Expected {:?}\n,    got {:?}", expected, r);
+  if r != Ok(AdminReply::Done) {
+    anyhow::bail!("could not kick, got {:?}", r);
+  }
+  if server.list_users().await.contains_key(&c3) {
+    anyhow::bail!("kicked user is still listed");
+  }
+  let r = server
+    .admin(Operator::Client(c1), AdminCommand::MailboxDepth(c3))
+    .await;
+  if r != Err(ClientError::UnknownClient) {
+    anyhow::bail!("expected UnknownClient, got {:?}", r);
+  }
+
+  let addr = std::net::IpAddr::from([192, 0, 2, 1]);
+  server
+    .admin(Operator::Console, AdminCommand::BanAddress(addr))
+    .await?;
+  if !server.address_banned(addr).await {
+    anyhow::bail!("address is not banned");
   }
 
+  let log = server.audit_log().await;
+  let expected = [
+    (Operator::Client(c1), AdminCommand::Ban(c2)),
+    (Operator::Console, AdminCommand::Grant(c1)),
+    (Operator::Client(c1), AdminCommand::Ban(c2)),
+    (Operator::Client(c1), AdminCommand::MailboxDepth(c3)),
+    (
+      Operator::Client(c1),
+      AdminCommand::Broadcast("maintenance".into()),
+    ),
+    (Operator::Client(c1), AdminCommand::Kick(c3)),
+    (Operator::Client(c1), AdminCommand::MailboxDepth(c3)),
+    (Operator::Console, AdminCommand::BanAddress(addr)),
+  ];
+  let actual = log
+    .iter()
+    .map(|e| (e.by, e.command.clone()))
+    .collect::<Vec<_>>();
+  if actual != expected || log[0].outcome != Err(ClientError::NotAdmin) {
+    anyhow::bail!("unexpected audit log {:?}", log);
+  }
   Ok(())
 }
-async fn all_tests<M: MessageServer>(counter: &mut usize) -> anyhow::Result<()> {
-  sequence_correct::<M>()
-    .await
-    .with_context(|| "sequence_correct")?;
-  *counter = 1;
-  sequence_bad::<M>().await.with_context(|| "sequence_bad")?;
-  *counter += 1;
-  workproof_bad::<M>()
-    .await
-    .with_context(|| "workproof_bad")?;
-  *counter += 1;
-  sequence_unknown_user::<M>()
-    .await
-    .with_context(|| "sequence_unknown_user")?;
-  *counter += 1;
-  sequence_multiple_problems::<M>()
-    .await
-    .with_context(|| "sequence_bad")?;
-  *counter += 1;
-  simple_client_test::<M>()
-    .await
-    .with_context(|| "simple_client_test")?;
-  *counter += 1;
-  list_users_test::<M>()
-    .await
-    .with_context(|| "list_users_test")?;
-  *counter += 1;
-  multiple_client_messages_test::<M>()
-    .await
-    .with_context(|| "multiple_client_message_test")?;
-  *counter += 1;
-  mixed_results_client_message::<M>()
-    .await
-    .with_context(|| "mixed_results_client_message")?;
-  *counter += 1;
-  mailbox_full::<M>().await.with_context(|| "mailbox_full")?;
-  *counter += 1;
-  #[cfg(feature = "federation")]
+
+async fn role_permissions<M: MessageServer>() -> anyhow::Result<()> {
+  let sid = ServerId::default();
+  let guest_address: std::net::IpAddr = "10.0.0.1".parse()?;
+  let server: M = MessageServer::with_config(
+    sid,
+    ServerConfig {
+      roles: RoleConfig {
+        roles: HashMap::from([
+          ("guest".to_string(), HashSet::new()),
+          ("user".to_string(), HashSet::from([Action::Register])),
+          (
+            "staff".to_string(),
+            HashSet::from([Action::Register, Action::MassMessage]),
+          ),
+        ]),
+        assignments: HashMap::new(),
+        addresses: HashMap::from([(guest_address, "guest".to_string())]),
+        default_role: "user".to_string(),
+        max_recipients: 2,
+      },
+      ..Default::default()
+    },
+  );
+
+  if let Err(rr) = server.registration_permitted("10.0.0.2".parse()?).await {
+    anyhow::bail!("registration should be allowed, got {}", rr);
+  }
+  let r = server.registration_permitted(guest_address).await;
+  if r
+    != Err(ClientError::PermissionDenied {
+      action: Action::Register,
+    })
   {
-    message_to_outer_user::<M>()
-      .await
-      .with_context(|| "message_to_outer_user")?;
-    *counter += 1;
-    message_to_outer_user_delayed::<M>()
-      .await
-      .with_context(|| "message_to_outer_user_delayed")?;
-    *counter += 1;
+    anyhow::bail!("expected guests to be denied, got {:?}", r);
   }
-  Ok(())
-}
+  let c1 = server.register_local_client("user 1".to_string()).await;
+  let c2 = server.register_local_client("user 2".to_string()).await;
+  let c3 = server.register_local_client("user 3".to_string()).await;
+  let c4 = server.register_local_client("user 4".to_string()).await;
 
-pub(crate) fn test_message_server<M: MessageServer>() {
-  pretty_env_logger::init();
-  async_std::task::block_on(async {
-    let mut counter = 0;
-    match all_tests::<M>(&mut counter).await {
-      Ok(()) => (),
-      Err(rr) => panic!("counter={}, error={:?}", counter, rr),
-    }
+  let mtext = || ClientMessage::MText {
+    dest: vec![c2, c3, c4],
+    content: "hello everyone".into(),
+  };
+  let r = server.handle_client_message(c1, mtext()).await;
+  let denied = ClientReply::Error(ClientError::PermissionDenied {
+    action: Action::MassMessage,
+  });
+  if r != [denied.clone(), denied.clone(), denied.clone()] {
+    anyhow::bail!("expected the message to be denied, got {:?}", r);
+  }
+  let meta = AttachmentMeta {
+    name: "empty".into(),
+    size: 0,
+    hash: attachments::content_hash(&[]),
+  };
+  let attachment = |dest| ClientMessage::Attachment {
+    dest,
+    meta: meta.clone(),
+  };
+  let r = server
+    .handle_client_message(c1, attachment(vec![c2, c3, c4]))
+    .await;
+  if r != [denied.clone(), denied.clone(), denied] {
+    anyhow::bail!("expected the attachment to be denied, got {:?}", r);
+  }
+  // users can not send to other servers, even when the message would wait for a route
+  let remote_denied = ClientReply::Error(ClientError::PermissionDenied {
+    action: Action::RemoteMessage,
   });
+  let unknown = ClientId::default();
+  let r = server
+    .handle_client_message(
+      c1,
+      ClientMessage::Text {
+        dest: unknown,
+        content: "hello".into(),
+      },
+    )
+    .await;
+  if r != [remote_denied.clone()] {
+    anyhow::bail!("expected the delayed message to be denied, got {:?}", r);
+  }
+  let r = server
+    .handle_client_message(c1, attachment(vec![unknown]))
+    .await;
+  if r != [remote_denied] {
+    anyhow::bail!("expected the attachment to be denied, got {:?}", r);
+  }
+  let reply = server.client_poll(c2).await;
+  if reply != ClientPollReply::Nothing {
+    anyhow::bail!("expected an empty mailbox, received {:?}", reply);
+  }
+  let r = server
+    .handle_client_message(
+      c1,
+      ClientMessage::MText {
+        dest: vec![c2, c3],
+        content: "hello both".into(),
+      },
+    )
+    .await;
+  if !matches!(
+    r[..],
+    [ClientReply::Delivered(_), ClientReply::Delivered(_)]
+  ) {
+    anyhow::bail!("expected two delivered messages, got {:?}", r);
+  }
+
+  let r = server
+    .admin(
+      Operator::Console,
+      AdminCommand::AssignRole {
+        client: c1,
+        role: "root".into(),
+      },
+    )
+    .await;
+  if r != Err(ClientError::UnknownRole) {
+    anyhow::bail!("expected UnknownRole, got {:?}", r);
+  }
+  server
+    .admin(
+      Operator::Console,
+      AdminCommand::AssignRole {
+        client: c1,
+        role: "staff".into(),
+      },
+    )
+    .await?;
+  let r = server.handle_client_message(c1, mtext()).await;
+  if !r.iter().all(|r| matches!(r, ClientReply::Delivered(_))) || r.len() != 3 {
+    anyhow::bail!("expected three delivered messages, got {:?}", r);
+  }
+
+  server
+    .admin(
+      Operator::Console,
+      AdminCommand::AssignRole {
+        client: c1,
+        role: "guest".into(),
+      },
+    )
+    .await?;
+  let r = server.permitted(c1, Action::MassMessage).await;
+  if r
+    != Err(ClientError::PermissionDenied {
+      action: Action::MassMessage,
+    })
+  {
+    anyhow::bail!("expected guests to be denied, got {:?}", r);
+  }
+  Ok(())
+}
+
+async fn edit_unread_message<M: MessageServer>() -> anyhow::Result<()> {
+  let sid = ServerId::default();
+  let server: M = MessageServer::new(sid);
+
+  let c1 = server.register_local_client("user 1".to_string()).await;
+  let c2 = server.register_local_client("user 2".to_string()).await;
+  let c3 = server.register_local_client("user 3".to_string()).await;
+
+  let r = server
+    .handle_client_message(
+      c1,
+      ClientMessage::Text {
+        dest: c2,
+        content: "helo".into(),
+      },
+    )
+    .await;
+  let id = match r[..] {
+    [ClientReply::Delivered(id)] => id,
+    _ => anyhow::bail!("expected a single delivered message, got {:?}", r),
+  };
+  let r = server
+    .handle_client_message(
+      c1,
+      ClientMessage::Edit {
+        dest: vec![c2],
+        id,
+        content: "hello".into(),
+      },
+    )
+    .await;
+  if r != [ClientReply::Delivered(id)] {
+    anyhow::bail!("expected the edit to be delivered, got {:?}", r)
+  }
+  // only the sender can rewrite its messages
+  let r = server
+    .handle_client_message(
+      c3,
+      ClientMessage::Edit {
+        dest: vec![c2],
+        id,
+        content: "hijacked".into(),
+      },
+    )
+    .await;
+  if r != [ClientReply::Delivered(id)] {
+    anyhow::bail!("expected the edit event to be delivered, got {:?}", r)
+  }
+
+  let expected = [
+    ClientPollReply::Message {
+      src: c1,
+      id,
+      content: "hello".into(),
+    },
+    ClientPollReply::Edited {
+      src: c3,
+      id,
+      content: "hijacked".into(),
+    },
+    ClientPollReply::Nothing,
+  ];
+  for e in expected {
+    let reply = server.client_poll(c2).await;
+    if reply != e {
+      anyhow::bail!("expected {:?}, received {:?}", e, reply);
+    }
+  }
+  Ok(())
+}
+
+async fn delete_unread_message<M: MessageServer>() -> anyhow::Result<()> {
+  let sid = ServerId::default();
+  let server: M = MessageServer::new(sid);
+
+  let c1 = server.register_local_client("user 1".to_string()).await;
+  let c2 = server.register_local_client("user 2".to_string()).await;
+  let c3 = server.register_local_client("user 3".to_string()).await;
+
+  let r = server
+    .handle_client_message(
+      c1,
+      ClientMessage::MText {
+        dest: vec![c2, c3],
+        content: "oops".into(),
+      },
+    )
+    .await;
+  let id = match r[..] {
+    [ClientReply::Delivered(id), ClientReply::Delivered(_)] => id,
+    _ => anyhow::bail!("expected two delivered messages, got {:?}", r),
+  };
+  let r = server
+    .handle_client_message(
+      c1,
+      ClientMessage::Text {
+        dest: c2,
+        content: "second".into(),
+      },
+    )
+    .await;
+  let id2 = match r[..] {
+    [ClientReply::Delivered(id2)] if id2 != id => id2,
+    _ => anyhow::bail!("expected a single delivered message, got {:?}", r),
+  };
+  let r = server
+    .handle_client_message(
+      c1,
+      ClientMessage::Delete {
+        dest: vec![c2, c3],
+        id,
+      },
+    )
+    .await;
+  if r != [ClientReply::Delivered(id), ClientReply::Delivered(id)] {
+    anyhow::bail!("expected the deletion to be delivered, got {:?}", r)
+  }
+
+  let expected = ClientPollReply::Message {
+    src: c1,
+    id: id2,
+    content: "second".into(),
+  };
+  let reply = server.client_poll(c2).await;
+  if reply != expected {
+    anyhow::bail!("expected {:?}, received {:?}", expected, reply);
+  }
+  for c in [c2, c3] {
+    let reply = server.client_poll(c).await;
+    if reply != ClientPollReply::Nothing {
+      anyhow::bail!("expected an empty mailbox, received {:?}", reply);
+    }
+  }
+  Ok(())
+}
+
+async fn edit_polled_message<M: MessageServer>() -> anyhow::Result<()> {
+  let sid = ServerId::default();
+  let server: M = MessageServer::new(sid);
+
+  let c1 = server.register_local_client("user 1".to_string()).await;
+  let c2 = server.register_local_client("user 2".to_string()).await;
+
+  let r = server
+    .handle_client_message(
+      c1,
+      ClientMessage::Text {
+        dest: c2,
+        content: "helo".into(),
+      },
+    )
+    .await;
+  let id = match r[..] {
+    [ClientReply::Delivered(id)] => id,
+    _ => anyhow::bail!("expected a single delivered message, got {:?}", r),
+  };
+  server.client_poll(c2).await;
+
+  let r = server
+    .handle_client_message(
+      c1,
+      ClientMessage::Edit {
+        dest: vec![c2],
+        id,
+        content: "hello".into(),
+      },
+    )
+    .await;
+  if r != [ClientReply::Delivered(id)] {
+    anyhow::bail!("expected the edit to be delivered, got {:?}", r)
+  }
+  let r = server
+    .handle_client_message(c1, ClientMessage::Delete { dest: vec![c2], id })
+    .await;
+  if r != [ClientReply::Delivered(id)] {
+    anyhow::bail!("expected the deletion to be delivered, got {:?}", r)
+  }
+
+  let expected = [
+    ClientPollReply::Edited {
+      src: c1,
+      id,
+      content: "hello".into(),
+    },
+    ClientPollReply::Deleted { src: c1, id },
+    ClientPollReply::Nothing,
+  ];
+  for e in expected {
+    let reply = server.client_poll(c2).await;
+    if reply != e {
+      anyhow::bail!("expected {:?}, received {:?}", e, reply);
+    }
+  }
+  Ok(())
+}
+
+async fn attachment_transfer<M: MessageServer>() -> anyhow::Result<()> {
+  let sid = ServerId::default();
+  let server: M = MessageServer::new(sid);
+
+  let c1 = server.register_local_client("user 1".to_string()).await;
+  let c2 = server.register_local_client("user 2".to_string()).await;
+  let c3 = server.register_local_client("user 3".to_string()).await;
+
+  let data = (0..10000_u32).map(|n| (n % 251) as u8).collect::<Vec<_>>();
+  let meta = AttachmentMeta {
+    name: "data.bin".into(),
+    size: data.len() as u64,
+    hash: attachments::content_hash(&data),
+  };
+  let mut chunks = attachments::chunks(&data);
+  let last = chunks.pop().unwrap();
+
+  // the attachment can not be sent before it is completely uploaded
+  for chunk in chunks {
+    server.upload_chunk(c1, chunk).await?;
+  }
+  let r = server
+    .handle_client_message(
+      c1,
+      ClientMessage::Attachment {
+        dest: vec![c2],
+        meta: meta.clone(),
+      },
+    )
+    .await;
+  if r != [ClientReply::Error(ClientError::UnknownAttachment)] {
+    anyhow::bail!("expected UnknownAttachment, got {:?}", r)
+  }
+  let received = server.upload_chunk(c1, last).await?;
+  if received != meta.size {
+    anyhow::bail!(
+      "expected {} bytes to be received, got {}",
+      meta.size,
+      received
+    )
+  }
+
+  let r = server
+    .handle_client_message(
+      c1,
+      ClientMessage::Attachment {
+        dest: vec![c2],
+        meta: meta.clone(),
+      },
+    )
+    .await;
+  let id = match r[..] {
+    [ClientReply::Delivered(id)] => id,
+    _ => anyhow::bail!("expected a single delivered message, got {:?}", r),
+  };
+  let expected = ClientPollReply::Attachment {
+    src: c1,
+    id,
+    meta: meta.clone(),
+  };
+  let reply = server.client_poll(c2).await;
+  if reply != expected {
+    anyhow::bail!("expected {:?}, received {:?}", expected, reply);
+  }
+
+  let mut downloaded = Vec::new();
+  while (downloaded.len() as u64) < meta.size {
+    let chunk = server
+      .download_chunk(c2, c1, meta.hash, downloaded.len() as u64)
+      .await?;
+    downloaded.extend(chunk.data);
+  }
+  if downloaded != data {
+    anyhow::bail!("downloaded content differs from the uploaded one");
+  }
+
+  // only the recipients can download the attachment
+  let r = server.download_chunk(c3, c1, meta.hash, 0).await;
+  if r != Err(ClientError::UnknownAttachment) {
+    anyhow::bail!("expected UnknownAttachment, got {:?}", r)
+  }
+  Ok(())
+}
+
+async fn attachment_quota<M: MessageServer>() -> anyhow::Result<()> {
+  let sid = ServerId::default();
+  let server: M = MessageServer::new(sid);
+
+  let c1 = server.register_local_client("user 1".to_string()).await;
+
+  let chunk = Chunk {
+    hash: [0; 32],
+    size: ATTACHMENT_QUOTA + 1,
+    offset: 0,
+    data: vec![0; CHUNK_SIZE],
+  };
+  let r = server.upload_chunk(c1, chunk).await;
+  if r != Err(ClientError::QuotaExceeded) {
+    anyhow::bail!("expected QuotaExceeded, got {:?}", r)
+  }
+
+  let mut chunk = attachments::chunks(b"hello").remove(0);
+  chunk.data[0] = b'j';
+  let r = server.upload_chunk(c1, chunk).await;
+  if r != Err(ClientError::InvalidChunk) {
+    anyhow::bail!("expected InvalidChunk for a corrupted chunk, got {:?}", r)
+  }
+  Ok(())
+}
+
+async fn attachment_release<M: MessageServer>() -> anyhow::Result<()> {
+  let sid = ServerId::default();
+  let server: M = MessageServer::new(sid);
+
+  let c1 = server.register_local_client("user 1".to_string()).await;
+  let c2 = server.register_local_client("user 2".to_string()).await;
+
+  // an attachment of the whole quota, the next one does not fit
+  let data = vec![7; ATTACHMENT_QUOTA as usize];
+  let meta = AttachmentMeta {
+    name: "big.bin".into(),
+    size: data.len() as u64,
+    hash: attachments::content_hash(&data),
+  };
+  for chunk in attachments::chunks(&data) {
+    server.upload_chunk(c1, chunk).await?;
+  }
+  let next = attachments::chunks(b"next").remove(0);
+  let r = server.upload_chunk(c1, next.clone()).await;
+  if r != Err(ClientError::QuotaExceeded) {
+    anyhow::bail!("expected QuotaExceeded, got {:?}", r)
+  }
+
+  let r = server
+    .handle_client_message(
+      c1,
+      ClientMessage::Attachment {
+        dest: vec![c2],
+        meta: meta.clone(),
+      },
+    )
+    .await;
+  if !matches!(r[..], [ClientReply::Delivered(_)]) {
+    anyhow::bail!("expected a single delivered message, got {:?}", r)
+  }
+  let mut offset = 0;
+  while offset < meta.size {
+    offset += server
+      .download_chunk(c2, c1, meta.hash, offset)
+      .await?
+      .data
+      .len() as u64;
+  }
+
+  // once downloaded by its recipient, the attachment is freed
+  let r = server.download_chunk(c2, c1, meta.hash, 0).await;
+  if r != Err(ClientError::UnknownAttachment) {
+    anyhow::bail!("expected UnknownAttachment, got {:?}", r)
+  }
+  let received = server.upload_chunk(c1, next).await?;
+  if received != 4 {
+    anyhow::bail!("expected 4 bytes to be received, got {}", received)
+  }
+  Ok(())
+}
+
+/// sends a text message, and returns its id
+async fn send_text<M: MessageServer>(
+  server: &M,
+  src: ClientId,
+  dest: ClientId,
+  content: &str,
+) -> anyhow::Result<MessageId> {
+  let r = server
+    .handle_client_message(
+      src,
+      ClientMessage::Text {
+        dest,
+        content: content.to_string(),
+      },
+    )
+    .await;
+  match r[..] {
+    [ClientReply::Delivered(id)] => Ok(id),
+    _ => anyhow::bail!("expected a single delivered message, got {:?}", r),
+  }
+}
+
+async fn history_pagination<M: MessageServer>() -> anyhow::Result<()> {
+  let sid = ServerId::default();
+  let server: M = MessageServer::new(sid);
+
+  let c1 = server.register_local_client("user 1".to_string()).await;
+  let c2 = server.register_local_client("user 2".to_string()).await;
+  let c3 = server.register_local_client("user 3".to_string()).await;
+
+  let mut expected = Vec::new();
+  for n in 0..10 {
+    let (src, dest) = if n % 3 == 0 { (c2, c1) } else { (c1, c2) };
+    let id = send_text(&server, src, dest, &n.to_string()).await?;
+    expected.push((id, src, n.to_string()));
+  }
+  send_text(&server, c1, c3, "not in the conversation").await?;
+  // polling does not remove messages from the history
+  server.client_poll(c2).await;
+  // edits and deletions are applied
+  server
+    .handle_client_message(
+      c1,
+      ClientMessage::Edit {
+        dest: vec![c2],
+        id: expected[1].0,
+        content: "edited".into(),
+      },
+    )
+    .await;
+  expected[1].2 = "edited".into();
+  server
+    .handle_client_message(
+      c1,
+      ClientMessage::Delete {
+        dest: vec![c2],
+        id: expected[2].0,
+      },
+    )
+    .await;
+  expected.remove(2);
+
+  let mut before = HistoryBound::Latest;
+  let mut actual = Vec::new();
+  loop {
+    let page = server
+      .history(
+        c2,
+        HistoryQuery {
+          with: c1,
+          before,
+          limit: 4,
+        },
+      )
+      .await;
+    if page.entries.len() > 4 || page.entries.is_empty() {
+      anyhow::bail!("expected a page of at most 4 messages, got {:?}", page)
+    }
+    before = HistoryBound::Id(page.entries[0].id);
+    for e in page.entries.into_iter().rev() {
+      actual.push((e.id, e.src, e.content));
+    }
+    if !page.more {
+      break;
+    }
+  }
+  actual.reverse();
+  if actual != expected {
+    anyhow::bail!("expected history {:?}, got {:?}", expected, actual)
+  }
+
+  let page = server
+    .history(
+      c1,
+      HistoryQuery {
+        with: c2,
+        before: HistoryBound::Time(0),
+        limit: 4,
+      },
+    )
+    .await;
+  if page
+    != (HistoryPage {
+      entries: Vec::new(),
+      more: false,
+    })
+  {
+    anyhow::bail!("expected an empty history before the epoch, got {:?}", page)
+  }
+  Ok(())
+}
+
+async fn history_retention<M: MessageServer>() -> anyhow::Result<()> {
+  let sid = ServerId::default();
+  let server: M = MessageServer::with_config(
+    sid,
+    ServerConfig {
+      history: Retention {
+        max_messages: 3,
+        max_age: None,
+      },
+      ..Default::default()
+    },
+  );
+
+  let c1 = server.register_local_client("user 1".to_string()).await;
+  let c2 = server.register_local_client("user 2".to_string()).await;
+  let mut ids = Vec::new();
+  for n in 0..5 {
+    ids.push(send_text(&server, c1, c2, &n.to_string()).await?);
+  }
+  let page = server
+    .history(
+      c1,
+      HistoryQuery {
+        with: c2,
+        before: HistoryBound::Latest,
+        limit: HISTORY_PAGE_SIZE,
+      },
+    )
+    .await;
+  let actual = page.entries.iter().map(|e| e.id).collect::<Vec<_>>();
+  if actual != ids[2..] || page.more {
+    anyhow::bail!("expected the last 3 messages only, got {:?}", page)
+  }
+  Ok(())
+}
+
+/// servers choose the ids of the messages they send to others, and sign them with their own keys, so ids are
+/// replaced with 0 and signatures left out in comparisons
+#[cfg(feature = "federation")]
+fn stripped(message: &ServerMessage) -> ServerMessage {
+  let mut message = message.clone();
+  match &mut message {
+    ServerMessage::Message(fqm) => {
+      fqm.id = MessageId::from(0);
+      fqm.signature.clear();
+    }
+    ServerMessage::Announce { signature, .. }
+    | ServerMessage::AnnounceDelta { signature, .. }
+    | ServerMessage::Withdraw { signature, .. }
+    | ServerMessage::WithdrawClients { signature, .. }
+    | ServerMessage::DeliveryError { signature, .. } => signature.clear(),
+    _ => (),
+  }
+  message
+}
+
+/// the contents of the latest messages of a conversation, oldest first
+#[cfg(feature = "federation")]
+async fn contents<M: MessageServer>(server: &M, client: ClientId, with: ClientId) -> Vec<String> {
+  let query = HistoryQuery {
+    with,
+    before: HistoryBound::Latest,
+    limit: HISTORY_PAGE_SIZE,
+  };
+  let page = server.history(client, query).await;
+  page.entries.into_iter().map(|e| e.content).collect()
+}
+
+#[cfg(feature = "federation")]
+fn stripped_transfers(replies: &[ClientReply]) -> Vec<ClientReply> {
+  replies
+    .iter()
+    .map(|r| match r {
+      ClientReply::Transfer(hop, message) => ClientReply::Transfer(*hop, stripped(message)),
+      r => r.clone(),
+    })
+    .collect()
+}
+
+#[cfg(feature = "federation")]
+fn stripped_messages(outgoing: &[Outgoing<ServerMessage>]) -> Vec<Outgoing<ServerMessage>> {
+  outgoing
+    .iter()
+    .map(|o| Outgoing {
+      nexthop: o.nexthop,
+      message: stripped(&o.message),
+    })
+    .collect()
+}
+
+#[cfg(feature = "federation")]
+fn stripped_outgoing(reply: &ServerReply) -> ServerReply {
+  match reply {
+    ServerReply::Outgoing(o) => ServerReply::Outgoing(stripped_messages(o)),
+    r => r.clone(),
+  }
+}
+
+#[cfg(feature = "federation")]
+async fn message_to_outer_user<M: MessageServer>() -> anyhow::Result<()> {
+  let sid = ServerId::default();
+  let server: M = MessageServer::new(sid);
+
+  let c1 = server.register_local_client("user 1".to_string()).await;
+  let s1 = ServerId::default();
+  let s2 = ServerId::default();
+  let s3 = ServerId::default();
+  let euuid = ClientId::default();
+
+  log::debug!("route: {} -> {} -> {} -> us", s1, s2, s3);
+
+  let r = server
+    .handle_server_message(ServerMessage::Announce {
+      route: vec![s1, s2, s3],
+      clients: HashMap::from([(euuid, "external user".into())]),
+      signature: Vec::new(),
+      server_name: String::new(),
+      version: 1,
+    })
+    .await;
+  if r != ServerReply::Outgoing(Vec::new()) {
+    anyhow::bail!("Expected empty outgoing answer, got {:?}", r);
+  }
+  assert_eq!(r, ServerReply::Outgoing(Vec::new()));
+  let r = server
+    .handle_client_message(
+      c1,
+      ClientMessage::Text {
+        dest: euuid,
+        content: "Hello".to_string(),
+      },
+    )
+    .await;
+  let expected = [ClientReply::Transfer(
+    s3,
+    ServerMessage::Message(FullyQualifiedMessage {
+      src: c1,
+      srcsrv: sid,
+      dsts: vec![(euuid, s1)],
+      content: "Hello".to_string(),
+      id: MessageId::from(0),
+      hops: MAX_HOPS,
+      signature: Vec::new(),
+    }),
+  )];
+
+  if stripped_transfers(&r) != expected {
+    anyhow::bail!("Expected {:?}\n   , got {:?}", expected, r)
+  }
+
+  // the server of the sender keeps its side of the conversation
+  let history = contents(&server, c1, euuid).await;
+  if history != ["Hello"] {
+    anyhow::bail!(
+      "expected the sent message in the history, got {:?}",
+      history
+    );
+  }
+
+  Ok(())
+}
+
+#[cfg(feature = "federation")]
+async fn message_to_outer_user_delayed<M: MessageServer>() -> anyhow::Result<()> {
+  let sid = ServerId::default();
+  let server: M = MessageServer::new(sid);
+
+  let c1 = server.register_local_client("user 1".to_string()).await;
+  let s1 = ServerId::default();
+  let s2 = ServerId::default();
+  let s3 = ServerId::default();
+  let euuid = ClientId::default();
+
+  log::debug!("route: {} -> {} -> {} -> us", s1, s2, s3);
+
+  let r = server
+    .handle_client_message(
+      c1,
+      ClientMessage::Text {
+        dest: euuid,
+        content: "Hello".to_string(),
+      },
+    )
+    .await;
+  if r != [ClientReply::Delayed] {
+    anyhow::bail!("Expected a delayed message first, but got {:?}", r);
+  }
+  let r = server
+    .handle_server_message(ServerMessage::Announce {
+      route: vec![s1, s2, s3],
+      clients: HashMap::from([(euuid, "external user".into())]),
+      signature: Vec::new(),
+      server_name: String::new(),
+      version: 1,
+    })
+    .await;
+  let expected = ServerReply::Outgoing(vec![Outgoing {
+    nexthop: s3,
+    message: ServerMessage::Message(FullyQualifiedMessage {
+      src: c1,
+      srcsrv: sid,
+      dsts: vec![(euuid, s1)],
+      content: "Hello".to_string(),
+      id: MessageId::from(0),
+      hops: MAX_HOPS,
+      signature: Vec::new(),
+    }),
+  }]);
+  if stripped_outgoing(&r) != expected {
+    anyhow::bail!("Expected {:?}\n,    got {:?}", expected, r);
+  }
+
+  // the server of the sender keeps its side of the conversation
+  let history = contents(&server, c1, euuid).await;
+  if history != ["Hello"] {
+    anyhow::bail!(
+      "expected the sent message in the history, got {:?}",
+      history
+    );
+  }
+
+  Ok(())
+}
+
+/// the sender gets the message once, even when it is listed several times
+async fn mtext_duplicate_destinations<M: MessageServer>() -> anyhow::Result<()> {
+  let sid = ServerId::default();
+  let server: M = MessageServer::new(sid);
+
+  let c1 = server.register_local_client("user 1".to_string()).await;
+  let c2 = server.register_local_client("user 2".to_string()).await;
+  let c3 = server.register_local_client("user 3".to_string()).await;
+
+  let r = server
+    .handle_client_message(
+      c1,
+      ClientMessage::MText {
+        dest: vec![c2, c3, c2],
+        content: "twice?".into(),
+      },
+    )
+    .await;
+  let id = match r[..] {
+    [ClientReply::Delivered(a), ClientReply::Delivered(b), ClientReply::Delivered(c)]
+      if a == b && b == c =>
+    {
+      a
+    }
+    _ => anyhow::bail!("expected three replies with the same id, got {:?}", r),
+  };
+  for c in [c2, c3] {
+    let expected = ClientPollReply::Message {
+      src: c1,
+      id,
+      content: "twice?".into(),
+    };
+    let reply = server.client_poll(c).await;
+    if reply != expected {
+      anyhow::bail!("expected {:?}, received {:?}", expected, reply);
+    }
+    let reply = server.client_poll(c).await;
+    if reply != ClientPollReply::Nothing {
+      anyhow::bail!(
+        "expected a single copy of the message, received {:?}",
+        reply
+      );
+    }
+  }
+  Ok(())
+}
+
+/// messages, notices and events are polled in the order they were stored, whatever their source
+async fn poll_ordering<M: MessageServer>() -> anyhow::Result<()> {
+  let sid = ServerId::default();
+  let server: M = MessageServer::new(sid);
+
+  let c1 = server.register_local_client("user 1".to_string()).await;
+  let c2 = server.register_local_client("user 2".to_string()).await;
+  let c3 = server.register_local_client("user 3".to_string()).await;
+
+  let a = send_text(&server, c1, c3, "a").await?;
+  let b = send_text(&server, c2, c3, "b").await?;
+  let r = server
+    .handle_client_message(
+      c1,
+      ClientMessage::MText {
+        dest: vec![c2, c3],
+        content: "c".into(),
+      },
+    )
+    .await;
+  let c = match r[..] {
+    [ClientReply::Delivered(_), ClientReply::Delivered(id)] => id,
+    _ => anyhow::bail!("expected two delivered messages, got {:?}", r),
+  };
+  server
+    .admin(Operator::Console, AdminCommand::Broadcast("notice".into()))
+    .await?;
+  let d = send_text(&server, c2, c3, "d").await?;
+
+  let message = |src, id, content: &str| ClientPollReply::Message {
+    src,
+    id,
+    content: content.to_string(),
+  };
+  let expected = [
+    message(c1, a, "a"),
+    message(c2, b, "b"),
+    message(c1, c, "c"),
+    ClientPollReply::Notice("notice".into()),
+    message(c2, d, "d"),
+    ClientPollReply::Nothing,
+  ];
+  for e in expected {
+    let reply = server.client_poll(c3).await;
+    if reply != e {
+      anyhow::bail!("expected {:?}, received {:?}", e, reply);
+    }
+  }
+  Ok(())
+}
+
+async fn sequence_near_max<M: MessageServer>() -> anyhow::Result<()> {
+  let sid = ServerId::default();
+  let server: M = MessageServer::new(sid);
+  let c1 = server.register_local_client("user 1".to_string()).await;
+  let mut client1 = Client::new(c1);
+  let at = |client: &mut Client, seqid| Sequence {
+    seqid,
+    ..client.sequence(())
+  };
+
+  for seqid in [u128::MAX - 1, u128::MAX] {
+    let sq = at(&mut client1, seqid);
+    if let Err(rr) = server.handle_sequenced_message(sq).await {
+      anyhow::bail!("sequence {} was refused: {}", seqid, rr);
+    }
+  }
+  for seqid in [u128::MAX, u128::MAX - 1, 0] {
+    let sq = at(&mut client1, seqid);
+    let r = server.handle_sequenced_message(sq).await;
+    if r != Err(ClientError::SequenceError) {
+      anyhow::bail!("expected a sequence error for {}, got {:?}", seqid, r);
+    }
+  }
+  Ok(())
+}
+
+/// messages to clients that never become known are dropped after the delay, and the sender is told
+async fn delayed_error<M: MessageServer>() -> anyhow::Result<()> {
+  let sid = ServerId::default();
+  let server: M = MessageServer::with_config(
+    sid,
+    ServerConfig {
+      delayed_ttl: Some(Duration::from_millis(50)),
+      ..Default::default()
+    },
+  );
+
+  let c1 = server.register_local_client("user 1".to_string()).await;
+  let c2 = server.register_local_client("user 2".to_string()).await;
+  let unknown = ClientId::default();
+  let r = server
+    .handle_client_message(
+      c1,
+      ClientMessage::Text {
+        dest: unknown,
+        content: "anyone?".into(),
+      },
+    )
+    .await;
+  if r != [ClientReply::Delayed] {
+    anyhow::bail!("expected a delayed message, got {:?}", r);
+  }
+  let reply = server.client_poll(c1).await;
+  if reply != ClientPollReply::Nothing {
+    anyhow::bail!("the message expired too soon, received {:?}", reply);
+  }
+  let id = send_text(&server, c2, c1, "meanwhile").await?;
+
+  async_std::task::sleep(Duration::from_millis(100)).await;
+  let expected = [
+    ClientPollReply::Message {
+      src: c2,
+      id,
+      content: "meanwhile".into(),
+    },
+    ClientPollReply::DelayedError(DelayedError::UnknownRecipient(unknown)),
+    ClientPollReply::Nothing,
+  ];
+  for e in expected {
+    let reply = server.client_poll(c1).await;
+    if reply != e {
+      anyhow::bail!("expected {:?}, received {:?}", e, reply);
+    }
+  }
+  Ok(())
+}
+
+/// delayed messages are sent once their destinations are announced, and only once
+#[cfg(feature = "federation")]
+async fn delayed_until_announce<M: MessageServer>() -> anyhow::Result<()> {
+  let sid = ServerId::default();
+  let server: M = MessageServer::new(sid);
+
+  let c1 = server.register_local_client("user 1".to_string()).await;
+  let c2 = server.register_local_client("user 2".to_string()).await;
+  let s1 = ServerId::default();
+  let s2 = ServerId::default();
+  let r1 = ClientId::default();
+  let r2 = ClientId::default();
+
+  for (src, dest, content) in [(c1, r1, "one"), (c2, r2, "two"), (c1, r1, "three")] {
+    let r = server
+      .handle_client_message(
+        src,
+        ClientMessage::Text {
+          dest,
+          content: content.into(),
+        },
+      )
+      .await;
+    if r != [ClientReply::Delayed] {
+      anyhow::bail!("expected a delayed message, got {:?}", r);
+    }
+  }
+
+  let announce = |route, client, name: &str| ServerMessage::Announce {
+    route,
+    clients: HashMap::from([(client, name.to_string())]),
+    signature: Vec::new(),
+    server_name: String::new(),
+    version: 1,
+  };
+  let fqm = |src, dest, srv, content: &str| Outgoing {
+    nexthop: s1,
+    message: ServerMessage::Message(FullyQualifiedMessage {
+      src,
+      srcsrv: sid,
+      dsts: vec![(dest, srv)],
+      content: content.to_string(),
+      id: MessageId::from(0),
+      hops: MAX_HOPS,
+      signature: Vec::new(),
+    }),
+  };
+  let r = server
+    .handle_server_message(announce(vec![s1], r1, "remote 1"))
+    .await;
+  let expected = ServerReply::Outgoing(vec![fqm(c1, r1, s1, "one"), fqm(c1, r1, s1, "three")]);
+  if stripped_outgoing(&r) != expected {
+    anyhow::bail!("expected {:?}\n,    got {:?}", expected, r);
+  }
+  let r = server
+    .handle_server_message(announce(vec![s1], r1, "remote 1"))
+    .await;
+  if r != ServerReply::Outgoing(Vec::new()) {
+    anyhow::bail!("delayed messages were sent twice, got {:?}", r);
+  }
+  let r = server
+    .handle_server_message(announce(vec![s2, s1], r2, "remote 2"))
+    .await;
+  let expected = ServerReply::Outgoing(vec![fqm(c2, r2, s2, "two")]);
+  if stripped_outgoing(&r) != expected {
+    anyhow::bail!("expected {:?}\n,    got {:?}", expected, r);
+  }
+  Ok(())
+}
+
+/// announces are passed on to the other neighbours when they change something, and periodically
+#[cfg(feature = "federation")]
+async fn announce_propagation<M: MessageServer>() -> anyhow::Result<()> {
+  let sid = ServerId::default();
+  let n1 = ServerId::default();
+  let n2 = ServerId::default();
+  let far = ServerId::default();
+  let server: M = MessageServer::with_config(
+    sid,
+    ServerConfig {
+      neighbours: vec![n1, n2],
+      ..Default::default()
+    },
+  );
+  let c1 = server.register_local_client("user 1".to_string()).await;
+  let r1 = ClientId::default();
+
+  let announce = |nexthop, route, clients: &[(ClientId, &str)]| Outgoing {
+    nexthop,
+    message: ServerMessage::Announce {
+      route,
+      clients: clients
+        .iter()
+        .map(|(id, name)| (*id, name.to_string()))
+        .collect(),
+      signature: Vec::new(),
+      server_name: String::new(),
+      version: 1,
+    },
+  };
+  let same = |r: &[Outgoing<ServerMessage>], expected: &[Outgoing<ServerMessage>]| {
+    let r = stripped_messages(r);
+    r.len() == expected.len() && expected.iter().all(|e| r.contains(e))
+  };
+
+  let delta = |nexthop, since, version, clients: &[(ClientId, &str)]| Outgoing {
+    nexthop,
+    message: ServerMessage::AnnounceDelta {
+      route: vec![sid],
+      since,
+      version,
+      joined: clients
+        .iter()
+        .map(|(id, name)| (*id, name.to_string()))
+        .collect(),
+      left: Vec::new(),
+      signature: Vec::new(),
+      server_name: sid.default_name(),
+    },
+  };
+
+  // the local clients are sent as changes since the beginning, the version is up to the server
+  let r = server.announces().await;
+  let version = match r.first().map(|o| &o.message) {
+    Some(ServerMessage::AnnounceDelta { version, .. }) => *version,
+    _ => anyhow::bail!("expected delta announces, got {:?}", r),
+  };
+  let expected = [
+    delta(n1, 0, version, &[(c1, "user 1")]),
+    delta(n2, 0, version, &[(c1, "user 1")]),
+  ];
+  if !same(&r, &expected) {
+    anyhow::bail!("expected {:?}\n,    got {:?}", expected, r);
+  }
+
+  // learned from n1, passed on to n2 only
+  let learned = announce(n1, vec![far, n1], &[(r1, "remote 1")]).message;
+  let r = server.handle_server_message(learned.clone()).await;
+  let passed = announce(n2, vec![far, n1, sid], &[(r1, "remote 1")]);
+  if r != ServerReply::Outgoing(vec![passed.clone()]) {
+    anyhow::bail!("expected the announce to be passed on to n2, got {:?}", r);
+  }
+  let r = server.handle_server_message(learned).await;
+  if r != ServerReply::Outgoing(Vec::new()) {
+    anyhow::bail!("an announce that changes nothing was passed on: {:?}", r);
+  }
+
+  // routes that already went through this server would loop
+  let looped = ServerId::default();
+  let r2 = ClientId::default();
+  let r = server
+    .handle_server_message(announce(n2, vec![looped, sid, n2], &[(r2, "remote 2")]).message)
+    .await;
+  if r != ServerReply::Outgoing(Vec::new()) {
+    anyhow::bail!("a looping announce was passed on: {:?}", r);
+  }
+  if server.list_users().await.contains_key(&r2) || server.route_to(looped).await.is_some() {
+    anyhow::bail!("a looping announce was not ignored");
+  }
+
+  // nothing changed locally, and far did not send deltas
+  let r = server.announces().await;
+  let expected = [
+    delta(n1, version, version, &[]),
+    delta(n2, version, version, &[]),
+    passed,
+  ];
+  if !same(&r, &expected) {
+    anyhow::bail!("expected {:?}\n,    got {:?}", expected, r);
+  }
+  Ok(())
+}
+
+/// withdrawn links are forgotten and the withdrawal passed on, messages wait for another route
+#[cfg(feature = "federation")]
+async fn route_withdrawal<M: MessageServer>() -> anyhow::Result<()> {
+  let sid = ServerId::default();
+  let n1 = ServerId::default();
+  let n2 = ServerId::default();
+  let far = ServerId::default();
+  let server: M = MessageServer::with_config(
+    sid,
+    ServerConfig {
+      neighbours: vec![n1, n2],
+      ..Default::default()
+    },
+  );
+  let c1 = server.register_local_client("user 1".to_string()).await;
+  let r1 = ClientId::default();
+  let announce = |route| ServerMessage::Announce {
+    route,
+    clients: HashMap::from([(r1, "remote 1".to_string())]),
+    signature: Vec::new(),
+    server_name: String::new(),
+    version: 1,
+  };
+  server.handle_server_message(announce(vec![far, n1])).await;
+
+  let r = server
+    .handle_server_message(ServerMessage::Withdraw {
+      route: vec![far, n1],
+      signature: vec![1; 64],
+      version: 1,
+    })
+    .await;
+  // passed on as n1 signed it
+  let passed = ServerReply::Outgoing(vec![Outgoing {
+    nexthop: n2,
+    message: ServerMessage::Withdraw {
+      route: vec![far, n1, sid],
+      signature: vec![1; 64],
+      version: 1,
+    },
+  }]);
+  if r != passed {
+    anyhow::bail!("expected {:?}\n,    got {:?}", passed, r);
+  }
+  if server.route_to(far).await.is_some() {
+    anyhow::bail!("the route to {} was not withdrawn", far);
+  }
+  let r = server
+    .handle_server_message(ServerMessage::Withdraw {
+      route: vec![far, n1],
+      signature: Vec::new(),
+      version: 2,
+    })
+    .await;
+  if r != ServerReply::Outgoing(Vec::new()) {
+    anyhow::bail!("a withdrawal that changes nothing was passed on: {:?}", r);
+  }
+  let r = server
+    .handle_server_message(ServerMessage::Withdraw {
+      route: Vec::new(),
+      signature: Vec::new(),
+      version: 1,
+    })
+    .await;
+  if r != ServerReply::EmptyRoute {
+    anyhow::bail!("expected an empty route error, got {:?}", r);
+  }
+
+  let r = server
+    .handle_client_message(
+      c1,
+      ClientMessage::Text {
+        dest: r1,
+        content: "rerouted".into(),
+      },
+    )
+    .await;
+  if r != [ClientReply::Delayed] {
+    anyhow::bail!("expected a delayed message, got {:?}", r);
+  }
+  let sent = Outgoing {
+    nexthop: n2,
+    message: ServerMessage::Message(FullyQualifiedMessage {
+      src: c1,
+      srcsrv: sid,
+      dsts: vec![(r1, far)],
+      content: "rerouted".into(),
+      id: MessageId::from(0),
+      hops: MAX_HOPS,
+      signature: Vec::new(),
+    }),
+  };
+  let r = server.handle_server_message(announce(vec![far, n2])).await;
+  match stripped_outgoing(&r) {
+    ServerReply::Outgoing(r) if r.contains(&sent) => Ok(()),
+    r => anyhow::bail!(
+      "expected {:?} once another route is announced, got {:?}",
+      sent,
+      r
+    ),
+  }
+}
+
+/// announces and withdrawals that are older than the last ones of their server are ignored, so that they can not
+/// be replayed
+#[cfg(feature = "federation")]
+async fn replayed_announces<M: MessageServer>() -> anyhow::Result<()> {
+  let sid = ServerId::default();
+  let n1 = ServerId::default();
+  let n2 = ServerId::default();
+  let far = ServerId::default();
+  let server: M = MessageServer::with_config(
+    sid,
+    ServerConfig {
+      neighbours: vec![n1, n2],
+      ..Default::default()
+    },
+  );
+  let (r1, r2) = (ClientId::default(), ClientId::default());
+  let announce = |client, version| ServerMessage::Announce {
+    route: vec![far, n1],
+    clients: HashMap::from([(client, "remote".to_string())]),
+    signature: Vec::new(),
+    server_name: String::new(),
+    version,
+  };
+  let withdraw = |version| ServerMessage::Withdraw {
+    route: vec![far, n1],
+    signature: Vec::new(),
+    version,
+  };
+  let ignored = ServerReply::Outgoing(Vec::new());
+
+  server.handle_server_message(announce(r1, 2)).await;
+  let r = server.handle_server_message(announce(r2, 1)).await;
+  if r != ignored {
+    anyhow::bail!("an older announce was passed on: {:?}", r);
+  }
+  let users = server.list_users().await;
+  if !users.contains_key(&r1) || users.contains_key(&r2) {
+    anyhow::bail!("expected the clients of the last announce, got {:?}", users);
+  }
+
+  let r = server.handle_server_message(withdraw(5)).await;
+  if r == ignored || server.route_to(far).await.is_some() {
+    anyhow::bail!("the route to {} was not withdrawn: {:?}", far, r);
+  }
+  // the link is back, and the same withdrawal comes again
+  server.handle_server_message(announce(r1, 3)).await;
+  let r = server.handle_server_message(withdraw(5)).await;
+  if r != ignored || server.route_to(far).await.is_none() {
+    anyhow::bail!(
+      "a replayed withdrawal removed the route to {}: {:?}",
+      far,
+      r
+    );
+  }
+  server.handle_server_message(withdraw(6)).await;
+  if server.route_to(far).await.is_some() {
+    anyhow::bail!("a newer withdrawal of the route to {} was ignored", far);
+  }
+  Ok(())
+}
+
+/// withdrawn clients are forgotten, unless another server announced them since
+#[cfg(feature = "federation")]
+async fn client_withdrawal<M: MessageServer>() -> anyhow::Result<()> {
+  let sid = ServerId::default();
+  let n1 = ServerId::default();
+  let n2 = ServerId::default();
+  let server: M = MessageServer::with_config(
+    sid,
+    ServerConfig {
+      neighbours: vec![n1, n2],
+      ..Default::default()
+    },
+  );
+  let r1 = ClientId::default();
+  let r2 = ClientId::default();
+  server
+    .handle_server_message(ServerMessage::Announce {
+      route: vec![n1],
+      clients: HashMap::from([(r1, "remote 1".into()), (r2, "remote 2".into())]),
+      signature: Vec::new(),
+      server_name: String::new(),
+      version: 1,
+    })
+    .await;
+
+  let r = server
+    .handle_server_message(ServerMessage::WithdrawClients {
+      route: vec![n1],
+      clients: vec![r1],
+      signature: vec![1; 64],
+    })
+    .await;
+  let passed = ServerReply::Outgoing(vec![Outgoing {
+    nexthop: n2,
+    message: ServerMessage::WithdrawClients {
+      route: vec![n1, sid],
+      clients: vec![r1],
+      signature: vec![1; 64],
+    },
+  }]);
+  if r != passed {
+    anyhow::bail!("expected {:?}\n,    got {:?}", passed, r);
+  }
+  let users = server.list_users().await;
+  if users.contains_key(&r1) || !users.contains_key(&r2) {
+    anyhow::bail!(
+      "expected only {} to be withdrawn, users are {:?}",
+      r1,
+      users
+    );
+  }
+
+  // r2 is not a client of n2
+  let r = server
+    .handle_server_message(ServerMessage::WithdrawClients {
+      route: vec![n2],
+      clients: vec![r2],
+      signature: Vec::new(),
+    })
+    .await;
+  if r != ServerReply::Outgoing(Vec::new()) || !server.list_users().await.contains_key(&r2) {
+    anyhow::bail!("a client was withdrawn by another server: {:?}", r);
+  }
+  Ok(())
+}
+
+/// routes that are not announced again expire, and the messages that waited for them too
+#[cfg(feature = "federation")]
+async fn route_expiry<M: MessageServer>() -> anyhow::Result<()> {
+  let sid = ServerId::default();
+  let s1 = ServerId::default();
+  let server: M = MessageServer::with_config(
+    sid,
+    ServerConfig {
+      route_ttl: Some(Duration::from_millis(50)),
+      delayed_ttl: Some(Duration::from_millis(100)),
+      ..Default::default()
+    },
+  );
+  let c1 = server.register_local_client("user 1".to_string()).await;
+  let r1 = ClientId::default();
+  server
+    .handle_server_message(ServerMessage::Announce {
+      route: vec![s1],
+      clients: HashMap::from([(r1, "remote 1".into())]),
+      signature: Vec::new(),
+      server_name: String::new(),
+      version: 1,
+    })
+    .await;
+  let text = ClientMessage::Text {
+    dest: r1,
+    content: "still there?".into(),
+  };
+  let r = server.handle_client_message(c1, text.clone()).await;
+  if !matches!(r[..], [ClientReply::Transfer(hop, _)] if hop == s1) {
+    anyhow::bail!("expected a transfer to {}, got {:?}", s1, r);
+  }
+
+  async_std::task::sleep(Duration::from_millis(80)).await;
+  if server.route_to(s1).await.is_some() {
+    anyhow::bail!("the route to {} did not expire", s1);
+  }
+  let r = server.handle_client_message(c1, text).await;
+  if r != [ClientReply::Delayed] {
+    anyhow::bail!("expected a delayed message, got {:?}", r);
+  }
+  async_std::task::sleep(Duration::from_millis(150)).await;
+  let expected = ClientPollReply::DelayedError(DelayedError::UnknownRecipient(r1));
+  let reply = server.client_poll(c1).await;
+  if reply != expected {
+    anyhow::bail!("expected {:?}, received {:?}", expected, reply);
+  }
+  Ok(())
+}
+
+/// messages passing through wait for a route like local ones, and keep their origin
+#[cfg(feature = "federation")]
+async fn transit_requeue<M: MessageServer>() -> anyhow::Result<()> {
+  let sid = ServerId::default();
+  let n1 = ServerId::default();
+  let n2 = ServerId::default();
+  let far = ServerId::default();
+  let origin = ServerId::default();
+  let server: M = MessageServer::with_config(
+    sid,
+    ServerConfig {
+      neighbours: vec![n1, n2],
+      ..Default::default()
+    },
+  );
+  let src = ClientId::default();
+  let r1 = ClientId::default();
+  let announce = |route| ServerMessage::Announce {
+    route,
+    clients: HashMap::from([(r1, "remote 1".to_string())]),
+    signature: Vec::new(),
+    server_name: String::new(),
+    version: 1,
+  };
+  let fqm = |id: u128, hops| FullyQualifiedMessage {
+    src,
+    srcsrv: origin,
+    dsts: vec![(r1, far)],
+    content: "passing through".into(),
+    id: MessageId::from(id),
+    hops,
+    signature: Vec::new(),
+  };
+  let through = |nexthop, id| Outgoing {
+    nexthop,
+    message: ServerMessage::Message(fqm(id, 3)),
+  };
+  server.handle_server_message(announce(vec![far, n2])).await;
+  let r = server
+    .handle_server_message(ServerMessage::Message(fqm(1, 4)))
+    .await;
+  if r != ServerReply::Outgoing(vec![through(n2, 1)]) {
+    anyhow::bail!("expected the message to be passed to {}, got {:?}", n2, r);
+  }
+
+  server
+    .handle_server_message(ServerMessage::Withdraw {
+      route: vec![n2],
+      signature: Vec::new(),
+      version: 1,
+    })
+    .await;
+  let r = server
+    .handle_server_message(ServerMessage::Message(fqm(2, 4)))
+    .await;
+  if r != ServerReply::Outgoing(Vec::new()) {
+    anyhow::bail!("the message was sent without a route: {:?}", r);
+  }
+  match server.handle_server_message(announce(vec![far, n1])).await {
+    ServerReply::Outgoing(r) if r.contains(&through(n1, 2)) => Ok(()),
+    r => anyhow::bail!("expected {:?}, got {:?}", through(n1, 2), r),
+  }
+}
+
+/// destinations are grouped by next hop, and the local ones get the message directly
+#[cfg(feature = "federation")]
+async fn grouped_destinations<M: MessageServer>() -> anyhow::Result<()> {
+  let sid = ServerId::default();
+  let s1 = ServerId::default();
+  let s2 = ServerId::default();
+  let far = ServerId::default();
+  let server: M = MessageServer::new(sid);
+  let c1 = server.register_local_client("user 1".to_string()).await;
+  let c2 = server.register_local_client("user 2".to_string()).await;
+  let (r1, r2, r3) = (
+    ClientId::default(),
+    ClientId::default(),
+    ClientId::default(),
+  );
+  for (route, client) in [(vec![s1], r1), (vec![far, s1], r2), (vec![s2], r3)] {
+    server
+      .handle_server_message(ServerMessage::Announce {
+        route,
+        clients: HashMap::from([(client, "remote".to_string())]),
+        signature: Vec::new(),
+        server_name: String::new(),
+        version: 1,
+      })
+      .await;
+  }
+
+  let r = server
+    .handle_client_message(
+      c1,
+      ClientMessage::MText {
+        dest: vec![r1, c2, r3, r2],
+        content: "from here".into(),
+      },
+    )
+    .await;
+  let transfer = |hop, dsts| {
+    ClientReply::Transfer(
+      hop,
+      ServerMessage::Message(FullyQualifiedMessage {
+        src: c1,
+        srcsrv: sid,
+        dsts,
+        content: "from here".into(),
+        id: MessageId::from(0),
+        hops: MAX_HOPS,
+        signature: Vec::new(),
+      }),
+    )
+  };
+  let through_s1 = transfer(s1, vec![(r1, s1), (r2, far)]);
+  let through_s2 = transfer(s2, vec![(r3, s2)]);
+  match &stripped_transfers(&r)[..] {
+    [a, ClientReply::Delivered(_), b, c]
+      if *a == through_s1 && *b == through_s2 && *c == through_s1 => {}
+    _ => anyhow::bail!(
+      "expected {:?} and {:?}, got {:?}",
+      through_s1,
+      through_s2,
+      r
+    ),
+  }
+
+  match server.client_poll(c2).await {
+    ClientPollReply::Message { src, .. } if src == c1 => (),
+    r => anyhow::bail!("expected the message of {}, got {:?}", c1, r),
+  }
+
+  let other = ClientId::default();
+  let r = server
+    .handle_server_message(ServerMessage::Message(FullyQualifiedMessage {
+      src: other,
+      srcsrv: far,
+      dsts: vec![(r3, s2), (c1, sid), (r1, s1), (c2, sid), (r2, far)],
+      content: "from there".into(),
+      id: MessageId::from(1),
+      hops: 2,
+      signature: Vec::new(),
+    }))
+    .await;
+  let outgoing = |nexthop, dsts| Outgoing {
+    nexthop,
+    message: ServerMessage::Message(FullyQualifiedMessage {
+      src: other,
+      srcsrv: far,
+      dsts,
+      content: "from there".into(),
+      id: MessageId::from(1),
+      hops: 1,
+      signature: Vec::new(),
+    }),
+  };
+  let expected = [
+    outgoing(s2, vec![(r3, s2)]),
+    outgoing(s1, vec![(r1, s1), (r2, far)]),
+  ];
+  match r {
+    ServerReply::Outgoing(o) if o.len() == 2 && expected.iter().all(|e| o.contains(e)) => (),
+    r => anyhow::bail!("expected {:?}\n,    got {:?}", expected, r),
+  }
+  for client in [c1, c2] {
+    let reply = server.client_poll(client).await;
+    if !matches!(&reply, ClientPollReply::Message { src, content, .. } if *src == other && content == "from there")
+    {
+      anyhow::bail!("{} expected the message, got {:?}", client, reply);
+    }
+  }
+  Ok(())
+}
+
+/// messages that can not be delivered are reported to the server of their sender, along its route
+#[cfg(feature = "federation")]
+async fn delivery_errors<M: MessageServer>() -> anyhow::Result<()> {
+  let sid = ServerId::default();
+  let n1 = ServerId::default();
+  let origin = ServerId::default();
+  let far = ServerId::default();
+  let server: M = MessageServer::with_config(
+    sid,
+    ServerConfig {
+      neighbours: vec![n1],
+      ..Default::default()
+    },
+  );
+  let c1 = server.register_local_client("user 1".to_string()).await;
+  let c2 = server.register_local_client("user 2".to_string()).await;
+  let rs = ClientId::default();
+  server
+    .handle_server_message(ServerMessage::Announce {
+      route: vec![origin, n1],
+      clients: HashMap::from([(rs, "remote sender".to_string())]),
+      signature: Vec::new(),
+      server_name: String::new(),
+      version: 1,
+    })
+    .await;
+  let to = |dest| {
+    ServerMessage::Message(FullyQualifiedMessage {
+      src: rs,
+      srcsrv: origin,
+      dsts: vec![(dest, sid)],
+      content: "hello".into(),
+      id: MessageId::from(1),
+      hops: 0,
+      signature: Vec::new(),
+    })
+  };
+  let reported = |error, from| {
+    ServerReply::Outgoing(vec![Outgoing {
+      nexthop: n1,
+      message: ServerMessage::DeliveryError {
+        src: rs,
+        srcsrv: origin,
+        error,
+        origin: from,
+        signature: Vec::new(),
+      },
+    }])
+  };
+
+  let unknown = ClientId::default();
+  let r = stripped_outgoing(&server.handle_server_message(to(unknown)).await);
+  let expected = reported(DelayedError::UnknownRecipient(unknown), sid);
+  if r != expected {
+    anyhow::bail!("expected {:?}\n,    got {:?}", expected, r);
+  }
+
+  for n in 0..MAILBOX_SIZE {
+    send_text(&server, c2, c1, &format!("{n}")).await?;
+  }
+  let r = stripped_outgoing(&server.handle_server_message(to(c1)).await);
+  let expected = reported(DelayedError::RemoteBoxFull(c1), sid);
+  if r != expected {
+    anyhow::bail!("expected {:?}\n,    got {:?}", expected, r);
+  }
+
+  // errors for other servers are passed on as they were signed, the ones for local clients end up in their
+  // mailbox
+  let r = server
+    .handle_server_message(ServerMessage::DeliveryError {
+      src: rs,
+      srcsrv: origin,
+      error: DelayedError::Unroutable(c1),
+      origin: far,
+      signature: Vec::new(),
+    })
+    .await;
+  let expected = reported(DelayedError::Unroutable(c1), far);
+  if r != expected {
+    anyhow::bail!("expected {:?}\n,    got {:?}", expected, r);
+  }
+  let r = server
+    .handle_server_message(ServerMessage::DeliveryError {
+      src: c2,
+      srcsrv: sid,
+      error: DelayedError::RemoteBoxFull(rs),
+      origin: n1,
+      signature: Vec::new(),
+    })
+    .await;
+  if r != ServerReply::Outgoing(Vec::new()) {
+    anyhow::bail!("a local delivery error was sent away: {:?}", r);
+  }
+  let expected = ClientPollReply::DelayedError(DelayedError::RemoteBoxFull(rs));
+  let reply = server.client_poll(c2).await;
+  if reply != expected {
+    anyhow::bail!("expected {:?}, received {:?}", expected, reply);
+  }
+  Ok(())
+}
+
+/// messages passing through that never find a route are reported as unroutable
+#[cfg(feature = "federation")]
+async fn unroutable_transit<M: MessageServer>() -> anyhow::Result<()> {
+  let sid = ServerId::default();
+  let n1 = ServerId::default();
+  let n2 = ServerId::default();
+  let origin = ServerId::default();
+  let far = ServerId::default();
+  let server: M = MessageServer::with_config(
+    sid,
+    ServerConfig {
+      neighbours: vec![n1, n2],
+      delayed_ttl: Some(Duration::from_millis(50)),
+      ..Default::default()
+    },
+  );
+  let (rs, r1) = (ClientId::default(), ClientId::default());
+  for (route, client) in [(vec![origin, n1], rs), (vec![far, n2], r1)] {
+    server
+      .handle_server_message(ServerMessage::Announce {
+        route,
+        clients: HashMap::from([(client, "remote".to_string())]),
+        signature: Vec::new(),
+        server_name: String::new(),
+        version: 1,
+      })
+      .await;
+  }
+  server
+    .handle_server_message(ServerMessage::Withdraw {
+      route: vec![n2],
+      signature: Vec::new(),
+      version: 1,
+    })
+    .await;
+  let r = server
+    .handle_server_message(ServerMessage::Message(FullyQualifiedMessage {
+      src: rs,
+      srcsrv: origin,
+      dsts: vec![(r1, far)],
+      content: "nowhere to go".into(),
+      id: MessageId::from(1),
+      hops: 1,
+      signature: Vec::new(),
+    }))
+    .await;
+  if r != ServerReply::Outgoing(Vec::new()) {
+    anyhow::bail!("the message was sent without a route: {:?}", r);
+  }
+
+  async_std::task::sleep(Duration::from_millis(80)).await;
+  let reported = Outgoing {
+    nexthop: n1,
+    message: ServerMessage::DeliveryError {
+      src: rs,
+      srcsrv: origin,
+      error: DelayedError::Unroutable(r1),
+      origin: sid,
+      signature: Vec::new(),
+    },
+  };
+  let r = stripped_messages(&server.announces().await);
+  if !r.contains(&reported) {
+    anyhow::bail!("expected {:?} with the announces, got {:?}", reported, r);
+  }
+  let r = stripped_messages(&server.announces().await);
+  if r.contains(&reported) {
+    anyhow::bail!("the error was reported twice");
+  }
+  Ok(())
+}
+
+/// messages that were forwarded too many times are reported, and copies of a message are dropped
+#[cfg(feature = "federation")]
+async fn hop_limit<M: MessageServer>() -> anyhow::Result<()> {
+  let sid = ServerId::default();
+  let n1 = ServerId::default();
+  let n2 = ServerId::default();
+  let origin = ServerId::default();
+  let far = ServerId::default();
+  let server: M = MessageServer::with_config(
+    sid,
+    ServerConfig {
+      neighbours: vec![n1, n2],
+      ..Default::default()
+    },
+  );
+  let c1 = server.register_local_client("user 1".to_string()).await;
+  let c2 = server.register_local_client("user 2".to_string()).await;
+  let (rs, r1) = (ClientId::default(), ClientId::default());
+  for (route, client) in [(vec![origin, n1], rs), (vec![far, n2], r1)] {
+    server
+      .handle_server_message(ServerMessage::Announce {
+        route,
+        clients: HashMap::from([(client, "remote".to_string())]),
+        signature: Vec::new(),
+        server_name: String::new(),
+        version: 1,
+      })
+      .await;
+  }
+  let message = |dsts, hops| {
+    ServerMessage::Message(FullyQualifiedMessage {
+      src: rs,
+      srcsrv: origin,
+      dsts,
+      content: "looping".into(),
+      id: MessageId::from(7),
+      hops,
+      signature: Vec::new(),
+    })
+  };
+
+  // the local client still gets it
+  let r = server
+    .handle_server_message(message(vec![(r1, far), (c1, sid)], 0))
+    .await;
+  let r = stripped_outgoing(&r);
+  let expected = ServerReply::Outgoing(vec![Outgoing {
+    nexthop: n1,
+    message: ServerMessage::DeliveryError {
+      src: rs,
+      srcsrv: origin,
+      error: DelayedError::HopLimit(r1),
+      origin: sid,
+      signature: Vec::new(),
+    },
+  }]);
+  if r != expected {
+    anyhow::bail!("expected {:?}\n,    got {:?}", expected, r);
+  }
+  match server.client_poll(c1).await {
+    ClientPollReply::Message { src, .. } if src == rs => (),
+    r => anyhow::bail!("expected the message of {}, got {:?}", rs, r),
+  }
+
+  // a copy that comes back, with hops left or not, is dropped
+  let r = server
+    .handle_server_message(message(vec![(r1, far), (c1, sid)], 5))
+    .await;
+  if r != ServerReply::Outgoing(Vec::new()) {
+    anyhow::bail!("a copy of the message was handled again: {:?}", r);
+  }
+  let r = server.client_poll(c1).await;
+  if r != ClientPollReply::Nothing {
+    anyhow::bail!("{} got a copy of the message: {:?}", c1, r);
+  }
+
+  // another part of the same message, for other destinations
+  let r = server
+    .handle_server_message(message(vec![(c2, sid)], 5))
+    .await;
+  if r != ServerReply::Outgoing(Vec::new()) {
+    anyhow::bail!("expected no outgoing messages, got {:?}", r);
+  }
+  match server.client_poll(c2).await {
+    ClientPollReply::Message { src, .. } if src == rs => Ok(()),
+    r => anyhow::bail!("expected the message of {}, got {:?}", rs, r),
+  }
+}
+
+/// announces and messages from servers that are not accepted are rejected, and recorded
+#[cfg(feature = "federation")]
+async fn federation_policy<M: MessageServer>() -> anyhow::Result<()> {
+  let sid = ServerId::default();
+  let n1 = ServerId::default();
+  let (bad, small, big) = (
+    ServerId::default(),
+    ServerId::default(),
+    ServerId::default(),
+  );
+  let server: M = MessageServer::with_config(
+    sid,
+    ServerConfig {
+      neighbours: vec![n1],
+      federation: FederationPolicy {
+        peers: PeerList::Deny(HashSet::from([bad])),
+        max_clients: Some(2),
+        client_limits: HashMap::from([(big, 3)]),
+        ..Default::default()
+      },
+      ..Default::default()
+    },
+  );
+  let c1 = server.register_local_client("user 1".to_string()).await;
+  let clients = |n: usize| {
+    (0..n)
+      .map(|i| (ClientId::default(), format!("remote {i}")))
+      .collect::<HashMap<_, _>>()
+  };
+  let rejected = |r: &ServerReply, what: &str| match r {
+    ServerReply::Error(reason) if !reason.is_empty() => Ok(()),
+    r => Err(anyhow::anyhow!(
+      "expected {} to be rejected, got {:?}",
+      what,
+      r
+    )),
+  };
+
+  let r = server
+    .handle_server_message(ServerMessage::Announce {
+      route: vec![bad, n1],
+      clients: clients(1),
+      signature: Vec::new(),
+      server_name: String::new(),
+      version: 1,
+    })
+    .await;
+  rejected(&r, "an announce from a denied server")?;
+  let r = server
+    .handle_server_message(ServerMessage::Announce {
+      route: vec![small, bad, n1],
+      clients: clients(1),
+      signature: Vec::new(),
+      server_name: String::new(),
+      version: 1,
+    })
+    .await;
+  rejected(&r, "a route through a denied server")?;
+  let r = server
+    .handle_server_message(ServerMessage::Announce {
+      route: vec![small, n1],
+      clients: HashMap::from([(c1, "impostor".to_string())]),
+      signature: Vec::new(),
+      server_name: String::new(),
+      version: 1,
+    })
+    .await;
+  rejected(&r, "an announce of a local client")?;
+  let r = server
+    .handle_server_message(ServerMessage::Announce {
+      route: vec![small, n1],
+      clients: clients(3),
+      signature: Vec::new(),
+      server_name: String::new(),
+      version: 1,
+    })
+    .await;
+  rejected(&r, "an announce of too many clients")?;
+  let users = server.list_users().await;
+  if users != HashMap::from([(c1, "user 1".to_string())]) {
+    anyhow::bail!("rejected clients were learned: {:?}", users);
+  }
+
+  // big has a limit of its own
+  let r = server
+    .handle_server_message(ServerMessage::Announce {
+      route: vec![big, n1],
+      clients: clients(3),
+      signature: Vec::new(),
+      server_name: String::new(),
+      version: 1,
+    })
+    .await;
+  if !matches!(r, ServerReply::Outgoing(_)) {
+    anyhow::bail!(
+      "expected the announce of {} to be accepted, got {:?}",
+      big,
+      r
+    );
+  }
+  let r = server
+    .handle_server_message(ServerMessage::AnnounceDelta {
+      route: vec![small, n1],
+      since: 0,
+      version: 1,
+      joined: clients(2),
+      left: Vec::new(),
+      signature: Vec::new(),
+      server_name: String::new(),
+    })
+    .await;
+  if !matches!(r, ServerReply::Outgoing(_)) {
+    anyhow::bail!(
+      "expected the delta of {} to be accepted, got {:?}",
+      small,
+      r
+    );
+  }
+  let r = server
+    .handle_server_message(ServerMessage::AnnounceDelta {
+      route: vec![small, n1],
+      since: 1,
+      version: 2,
+      joined: clients(1),
+      left: Vec::new(),
+      signature: Vec::new(),
+      server_name: String::new(),
+    })
+    .await;
+  rejected(&r, "a delta that goes over the limit")?;
+
+  let r = server
+    .handle_server_message(ServerMessage::Message(FullyQualifiedMessage {
+      src: ClientId::default(),
+      srcsrv: bad,
+      dsts: vec![(c1, sid)],
+      content: "spam".into(),
+      id: MessageId::from(1),
+      hops: 4,
+      signature: Vec::new(),
+    }))
+    .await;
+  rejected(&r, "a message from a denied server")?;
+  if server.client_poll(c1).await != ClientPollReply::Nothing {
+    anyhow::bail!("{} got the message of a denied server", c1);
+  }
+
+  let servers: Vec<ServerId> = server
+    .peer_audit_log()
+    .await
+    .iter()
+    .map(|e| e.server)
+    .collect();
+  let expected = vec![bad, bad, small, small, small, bad];
+  if servers != expected {
+    anyhow::bail!(
+      "expected audit entries for {:?}, got {:?}",
+      expected,
+      servers
+    );
+  }
+  Ok(())
+}
+
+/// delta announces apply on top of the version they follow, and missed ones lead to a full sync
+#[cfg(feature = "federation")]
+async fn delta_announces<M: MessageServer>() -> anyhow::Result<()> {
+  let sid = ServerId::default();
+  let n1 = ServerId::default();
+  let n2 = ServerId::default();
+  let far = ServerId::default();
+  let server: M = MessageServer::with_config(
+    sid,
+    ServerConfig {
+      neighbours: vec![n1, n2],
+      ..Default::default()
+    },
+  );
+  let c1 = server.register_local_client("user 1".to_string()).await;
+  let (r1, r2, r3) = (
+    ClientId::default(),
+    ClientId::default(),
+    ClientId::default(),
+  );
+  let delta = |route, since, version, joined: &[(ClientId, &str)], left: &[ClientId]| {
+    ServerMessage::AnnounceDelta {
+      route,
+      since,
+      version,
+      joined: joined
+        .iter()
+        .map(|(id, name)| (*id, name.to_string()))
+        .collect(),
+      left: left.to_vec(),
+      signature: Vec::new(),
+      server_name: String::new(),
+    }
+  };
+  let local = &server;
+  let users = move || async move {
+    let mut users: Vec<(ClientId, String)> = local
+      .list_users()
+      .await
+      .into_iter()
+      .filter(|(c, _)| *c != c1)
+      .collect();
+    users.sort();
+    users
+  };
+  let sorted = |mut u: Vec<(ClientId, String)>| {
+    u.sort();
+    u
+  };
+
+  let full = delta(vec![far, n1], 0, 10, &[(r1, "a"), (r2, "b")], &[]);
+  let r = server.handle_server_message(full).await;
+  let passed = Outgoing {
+    nexthop: n2,
+    message: delta(vec![far, n1, sid], 0, 10, &[(r1, "a"), (r2, "b")], &[]),
+  };
+  if r != ServerReply::Outgoing(vec![passed.clone()]) {
+    anyhow::bail!("expected {:?}\n,    got {:?}", passed, r);
+  }
+
+  let change = delta(vec![far, n1], 10, 11, &[(r1, "renamed")], &[r2]);
+  let r = server.handle_server_message(change.clone()).await;
+  let passed = Outgoing {
+    nexthop: n2,
+    message: delta(vec![far, n1, sid], 10, 11, &[(r1, "renamed")], &[r2]),
+  };
+  if r != ServerReply::Outgoing(vec![passed.clone()]) {
+    anyhow::bail!("expected {:?}\n,    got {:?}", passed, r);
+  }
+  let expected = sorted(vec![(r1, "renamed".to_string())]);
+  if users().await != expected {
+    anyhow::bail!("expected {:?}, users are {:?}", expected, users().await);
+  }
+  let r = server.handle_server_message(change).await;
+  if r != ServerReply::Outgoing(Vec::new()) {
+    anyhow::bail!("a delta that was already applied was passed on: {:?}", r);
+  }
+
+  // versions 12 and 13 were missed
+  let r = server
+    .handle_server_message(delta(vec![far, n1], 13, 14, &[(r3, "c")], &[]))
+    .await;
+  let request = Outgoing {
+    nexthop: n1,
+    message: ServerMessage::SyncRequest {
+      origin: far,
+      requester: sid,
+    },
+  };
+  if r != ServerReply::Outgoing(vec![request.clone()]) {
+    anyhow::bail!("expected {:?}\n,    got {:?}", request, r);
+  }
+  if users().await != expected {
+    anyhow::bail!("a delta was applied over missing changes");
+  }
+
+  // the deltas since the last full one, as far signed them, for a neighbour that missed changes
+  let r = server
+    .handle_server_message(ServerMessage::SyncRequest {
+      origin: far,
+      requester: n2,
+    })
+    .await;
+  let synced = vec![
+    Outgoing {
+      nexthop: n2,
+      message: delta(vec![far, n1, sid], 0, 10, &[(r1, "a"), (r2, "b")], &[]),
+    },
+    Outgoing {
+      nexthop: n2,
+      message: delta(vec![far, n1, sid], 10, 11, &[(r1, "renamed")], &[r2]),
+    },
+  ];
+  if r != ServerReply::Outgoing(synced.clone()) {
+    anyhow::bail!("expected {:?}\n,    got {:?}", synced, r);
+  }
+  let r = server
+    .handle_server_message(ServerMessage::SyncRequest {
+      origin: sid,
+      requester: n1,
+    })
+    .await;
+  let clients = HashMap::from([(c1, "user 1".to_string())]);
+  let full_local = |o: &[Outgoing<ServerMessage>]| match o {
+    [Outgoing {
+      nexthop,
+      message:
+        ServerMessage::AnnounceDelta {
+          route,
+          since: 0,
+          joined,
+          left,
+          ..
+        },
+    }] => *nexthop == n1 && *route == vec![sid] && *joined == clients && left.is_empty(),
+    _ => false,
+  };
+  match r {
+    ServerReply::Outgoing(o) if full_local(&o) => {}
+    r => anyhow::bail!("expected all the local clients, got {:?}", r),
+  }
+
+  // the full state replaces everything
+  server
+    .handle_server_message(delta(vec![far, n1], 0, 14, &[(r3, "c")], &[]))
+    .await;
+  let expected = sorted(vec![(r3, "c".to_string())]);
+  if users().await != expected {
+    anyhow::bail!("expected {:?}, users are {:?}", expected, users().await);
+  }
+  Ok(())
+}
+
+/// announces of trusted servers must carry their signature, and what this server sends is signed with its key
+#[cfg(feature = "federation")]
+async fn signed_announces<M: MessageServer>() -> anyhow::Result<()> {
+  let sid = ServerId::default();
+  let n1 = ServerId::default();
+  let (far, other) = (ServerId::default(), ServerId::default());
+  let (key, far_key) = (signing::generate(), signing::generate());
+  let server: M = MessageServer::with_config(
+    sid,
+    ServerConfig {
+      neighbours: vec![n1],
+      federation: FederationPolicy {
+        trusted: HashMap::from([(far, far_key.verifying_key())]),
+        ..Default::default()
+      },
+      signing_key: Some(key.clone()),
+      ..Default::default()
+    },
+  );
+  let c1 = server.register_local_client("user 1".to_string()).await;
+  let r1 = ClientId::default();
+  let announce = |origin, clients: HashMap<ClientId, String>| ServerMessage::Announce {
+    route: vec![origin, n1],
+    clients,
+    signature: Vec::new(),
+    server_name: String::new(),
+    version: 1,
+  };
+  let rejected = |r: &ServerReply, what: &str| match r {
+    ServerReply::Error(reason) if !reason.is_empty() => Ok(()),
+    r => Err(anyhow::anyhow!(
+      "expected {} to be rejected, got {:?}",
+      what,
+      r
+    )),
+  };
+
+  let clients = HashMap::from([(r1, "remote 1".to_string())]);
+  let r = server
+    .handle_server_message(announce(far, clients.clone()))
+    .await;
+  rejected(&r, "an unsigned announce of a trusted server")?;
+  let mut signed = announce(far, clients.clone());
+  signing::sign(&signing::generate(), &mut signed);
+  let r = server.handle_server_message(signed).await;
+  rejected(&r, "an announce signed with another key")?;
+  let mut signed = announce(far, clients);
+  signing::sign(&far_key, &mut signed);
+  if let ServerMessage::Announce { clients, .. } = &mut signed {
+    clients.insert(ClientId::default(), "injected".into());
+  }
+  let r = server.handle_server_message(signed.clone()).await;
+  rejected(&r, "an announce with an injected client")?;
+  if server.list_users().await.contains_key(&r1) {
+    anyhow::bail!("a rejected announce was learned");
+  }
+
+  if let ServerMessage::Announce { clients, .. } = &mut signed {
+    clients.retain(|c, _| *c == r1);
+  }
+  let r = server.handle_server_message(signed).await;
+  if !matches!(r, ServerReply::Outgoing(_)) {
+    anyhow::bail!("expected a signed announce to be accepted, got {:?}", r);
+  }
+  // servers that are not trusted do not need to sign
+  let r2 = ClientId::default();
+  let r = server
+    .handle_server_message(announce(
+      other,
+      HashMap::from([(r2, "remote 2".to_string())]),
+    ))
+    .await;
+  if !matches!(r, ServerReply::Outgoing(_)) {
+    anyhow::bail!(
+      "expected the announce of {} to be accepted, got {:?}",
+      other,
+      r
+    );
+  }
+  let users = server.list_users().await;
+  if !users.contains_key(&r1) || !users.contains_key(&r2) {
+    anyhow::bail!("expected the announced clients, got {:?}", users);
+  }
+
+  let servers: Vec<ServerId> = server
+    .peer_audit_log()
+    .await
+    .iter()
+    .map(|e| e.server)
+    .collect();
+  if servers != vec![far, far, far] {
+    anyhow::bail!("expected audit entries for {}, got {:?}", far, servers);
+  }
+
+  // what this server sends can be checked with its public key
+  let public = key.verifying_key();
+  let r = server.announces().await;
+  let local = r.iter().find(|o| signing::signer(&o.message) == Some(sid));
+  match local {
+    Some(o) if signing::verify(&public, &o.message) => {}
+    _ => anyhow::bail!("expected a signed delta of the local clients, got {:?}", r),
+  }
+  let r = server
+    .handle_client_message(
+      c1,
+      ClientMessage::Text {
+        dest: r1,
+        content: "signed".into(),
+      },
+    )
+    .await;
+  match &r[..] {
+    [ClientReply::Transfer(_, message)] if signing::verify(&public, message) => {}
+    r => anyhow::bail!("expected a signed transfer, got {:?}", r),
+  }
+
+  // a relay can not speak for a trusted server, nor give its messages to other clients
+  let forged = [
+    ServerMessage::Withdraw {
+      route: vec![other, far, n1],
+      signature: Vec::new(),
+      version: 1,
+    },
+    ServerMessage::WithdrawClients {
+      route: vec![far, n1],
+      clients: vec![r1],
+      signature: Vec::new(),
+    },
+    ServerMessage::DeliveryError {
+      src: c1,
+      srcsrv: sid,
+      error: DelayedError::UnknownRecipient(r1),
+      origin: far,
+      signature: Vec::new(),
+    },
+  ];
+  for message in forged {
+    let r = server.handle_server_message(message.clone()).await;
+    rejected(&r, &format!("{:?}", message))?;
+  }
+  if !server.list_users().await.contains_key(&r1) {
+    anyhow::bail!("a forged withdrawal was applied");
+  }
+  let c2 = server.register_local_client("user 2".to_string()).await;
+  let mut message = ServerMessage::Message(FullyQualifiedMessage {
+    src: r1,
+    srcsrv: far,
+    dsts: vec![(c1, sid)],
+    content: "for user 1".into(),
+    id: MessageId::from(1),
+    hops: 4,
+    signature: Vec::new(),
+  });
+  signing::sign(&far_key, &mut message);
+  if let ServerMessage::Message(fqm) = &mut message {
+    fqm.dsts = vec![(c2, sid)];
+  }
+  let r = server.handle_server_message(message).await;
+  rejected(&r, "a message redirected to another client")?;
+  for client in [c1, c2] {
+    let reply = server.client_poll(client).await;
+    if reply != ClientPollReply::Nothing {
+      anyhow::bail!("{} received {:?}", client, reply);
+    }
+  }
+  Ok(())
+}
+
+/// users are qualified with the names of their servers, and can be found by their addresses
+#[cfg(feature = "federation")]
+async fn qualified_addresses<M: MessageServer>() -> anyhow::Result<()> {
+  let sid = ServerId::default();
+  let n1 = ServerId::default();
+  let (paris, unnamed) = (ServerId::default(), ServerId::default());
+  let server: M = MessageServer::with_config(
+    sid,
+    ServerConfig {
+      neighbours: vec![n1],
+      name: Some("home".to_string()),
+      ..Default::default()
+    },
+  );
+  let alice = server.register_local_client("alice".to_string()).await;
+  let bob = server.register_local_client("bob".to_string()).await;
+  let (remote_alice, carol) = (ClientId::default(), ClientId::default());
+  let announce = |origin, client, name: &str, server_name: &str| ServerMessage::Announce {
+    route: vec![origin, n1],
+    clients: HashMap::from([(client, name.to_string())]),
+    signature: Vec::new(),
+    server_name: server_name.to_string(),
+    version: 1,
+  };
+  server
+    .handle_server_message(announce(paris, remote_alice, "alice", "paris"))
+    .await;
+  server
+    .handle_server_message(announce(unnamed, carol, "carol", ""))
+    .await;
+
+  let address = |s: &str| s.parse::<Address>().map_err(|rr| anyhow::anyhow!(rr));
+  let expected = HashMap::from([
+    (alice, address("alice@home")?),
+    (bob, address("bob@home")?),
+    (remote_alice, address("alice@paris")?),
+    (
+      carol,
+      address(&format!("carol@{}", unnamed.default_name()))?,
+    ),
+  ]);
+  let addresses = server.list_addresses().await;
+  if addresses != expected {
+    anyhow::bail!("expected addresses {:?}, got {:?}", expected, addresses);
+  }
+  for (client, a) in &expected {
+    let found = server.resolve(a).await;
+    if found != Some(*client) {
+      anyhow::bail!("{} resolved to {:?} instead of {}", a, found, client);
+    }
+  }
+  let found = server.resolve(&address("alice@nowhere")?).await;
+  if found.is_some() {
+    anyhow::bail!("an unknown address resolved to {:?}", found);
+  }
+  // two local users with the same name can not be told apart
+  server.register_local_client("bob".to_string()).await;
+  let found = server.resolve(&address("bob@home")?).await;
+  if found.is_some() {
+    anyhow::bail!("an ambiguous address resolved to {:?}", found);
+  }
+
+  // the name of this server goes with its announces
+  let r = server.announces().await;
+  let named = r.iter().all(|o| match &o.message {
+    ServerMessage::AnnounceDelta {
+      route, server_name, ..
+    } if *route == vec![sid] => server_name == "home",
+    _ => true,
+  });
+  if r.is_empty() || !named {
+    anyhow::bail!(
+      "expected the name of the server in its announces, got {:?}",
+      r
+    );
+  }
+  Ok(())
+}
+
+/// servers can not take the name of this server or of another one, unless they sign with a trusted key
+#[cfg(feature = "federation")]
+async fn server_name_takeover<M: MessageServer>() -> anyhow::Result<()> {
+  let sid = ServerId::default();
+  let n1 = ServerId::default();
+  let (paris, impostor, trusted) = (
+    ServerId::default(),
+    ServerId::default(),
+    ServerId::default(),
+  );
+  let key = signing::generate();
+  let server: M = MessageServer::with_config(
+    sid,
+    ServerConfig {
+      neighbours: vec![n1],
+      name: Some("home".to_string()),
+      federation: FederationPolicy {
+        trusted: HashMap::from([(trusted, key.verifying_key())]),
+        ..Default::default()
+      },
+      ..Default::default()
+    },
+  );
+  let alice = server.register_local_client("alice".to_string()).await;
+  let announce = |origin, client, server_name: &str| ServerMessage::Announce {
+    route: vec![origin, n1],
+    clients: HashMap::from([(client, "alice".to_string())]),
+    signature: Vec::new(),
+    server_name: server_name.to_string(),
+    version: 1,
+  };
+  let (remote_alice, fake_alice, trusted_alice) = (
+    ClientId::default(),
+    ClientId::default(),
+    ClientId::default(),
+  );
+  server
+    .handle_server_message(announce(paris, remote_alice, "paris"))
+    .await;
+  let address = |s: &str| s.parse::<Address>().map_err(|rr| anyhow::anyhow!(rr));
+
+  for name in ["home", "paris"] {
+    server
+      .handle_server_message(announce(impostor, fake_alice, name))
+      .await;
+    let addresses = server.list_addresses().await;
+    let expected = address(&format!("alice@{}", impostor.default_name()))?;
+    if addresses.get(&fake_alice) != Some(&expected) {
+      anyhow::bail!(
+        "{} took the name {}, addresses are {:?}",
+        impostor,
+        name,
+        addresses
+      );
+    }
+  }
+  for (a, client) in [("alice@home", alice), ("alice@paris", remote_alice)] {
+    let found = server.resolve(&address(a)?).await;
+    if found != Some(client) {
+      anyhow::bail!("{} resolved to {:?} instead of {}", a, found, client);
+    }
+  }
+
+  // a trusted server takes the name, the other server is known by its id again
+  let mut signed = announce(trusted, trusted_alice, "paris");
+  signing::sign(&key, &mut signed);
+  server.handle_server_message(signed).await;
+  let addresses = server.list_addresses().await;
+  let expected = [
+    (trusted_alice, address("alice@paris")?),
+    (
+      remote_alice,
+      address(&format!("alice@{}", paris.default_name()))?,
+    ),
+  ];
+  for (client, a) in expected {
+    if addresses.get(&client) != Some(&a) {
+      anyhow::bail!("expected {} for {}, got {:?}", a, client, addresses);
+    }
+  }
+  Ok(())
+}
+
+type Scenario = (
+  &'static str,
+  Pin<Box<dyn Future<Output = anyhow::Result<()>>>>,
+);
+
+macro_rules! scenario {
+  ($name:ident) => {
+    (
+      stringify!($name),
+      // some scenarios fail with a ClientError
+      Box::pin(async { $name::<M>().await.map_err(anyhow::Error::from) })
+        as Pin<Box<dyn Future<Output = _>>>,
+    )
+  };
+}
+
+fn scenarios<M: MessageServer + Send + Sync + 'static>() -> Vec<Scenario> {
+  #[allow(unused_mut)]
+  let mut all: Vec<Scenario> = vec![
+    scenario!(sequence_correct),
+    scenario!(sequence_bad),
+    scenario!(workproof_bad),
+    scenario!(sequence_unknown_user),
+    scenario!(sequence_multiple_problems),
+    scenario!(sequence_near_max),
+    scenario!(simple_client_test),
+    scenario!(list_users_test),
+    scenario!(multiple_client_messages_test),
+    scenario!(mixed_results_client_message),
+    scenario!(mtext_duplicate_destinations),
+    scenario!(poll_ordering),
+    scenario!(delayed_error),
+    scenario!(mailbox_full),
+    scenario!(mailbox_full_bytes),
+    scenario!(mailbox_drop_oldest),
+    scenario!(mailbox_spill),
+    scenario!(filtered_messages),
+    scenario!(echo_bot),
+    scenario!(admin_commands),
+    scenario!(role_permissions),
+    scenario!(edit_unread_message),
+    scenario!(delete_unread_message),
+    scenario!(edit_polled_message),
+    scenario!(attachment_transfer),
+    scenario!(attachment_quota),
+    scenario!(attachment_release),
+    scenario!(history_pagination),
+    scenario!(history_retention),
+  ];
+  #[cfg(feature = "federation")]
+  all.extend([
+    scenario!(message_to_outer_user),
+    scenario!(message_to_outer_user_delayed),
+    scenario!(delayed_until_announce),
+    scenario!(announce_propagation),
+    scenario!(route_withdrawal),
+    scenario!(replayed_announces),
+    scenario!(client_withdrawal),
+    scenario!(route_expiry),
+    scenario!(transit_requeue),
+    scenario!(grouped_destinations),
+    scenario!(delivery_errors),
+    scenario!(unroutable_transit),
+    scenario!(delta_announces),
+    scenario!(hop_limit),
+    scenario!(federation_policy),
+    scenario!(signed_announces),
+    scenario!(qualified_addresses),
+    scenario!(server_name_takeover),
+  ]);
+  all
+}
+
+/// outcome of a conformance scenario
+#[derive(Debug)]
+pub struct ScenarioResult {
+  pub name: &'static str,
+  pub outcome: anyhow::Result<()>,
+}
+
+/// runs every scenario on its own server, in order
+/// a scenario that panics, for example on a todo!(), fails without stopping the others
+pub fn run_conformance<M: MessageServer + Send + Sync + 'static>() -> Vec<ScenarioResult> {
+  scenarios::<M>()
+    .into_iter()
+    .map(|(name, scenario)| {
+      let outcome =
+        std::panic::catch_unwind(AssertUnwindSafe(|| async_std::task::block_on(scenario)))
+          .unwrap_or_else(|panic| {
+            let message = panic
+              .downcast_ref::<&str>()
+              .map(|s| s.to_string())
+              .or_else(|| panic.downcast_ref::<String>().cloned())
+              .unwrap_or_default();
+            Err(anyhow::anyhow!("panicked: {}", message))
+          });
+      ScenarioResult { name, outcome }
+    })
+    .collect()
+}
+
+/// runs the conformance suite, prints the outcome of each scenario, and fails if one of them failed
+pub fn test_message_server<M: MessageServer + Send + Sync + 'static>() {
+  let _ = pretty_env_logger::try_init();
+  let results = run_conformance::<M>();
+  let mut failed = 0;
+  for r in &results {
+    match &r.outcome {
+      Ok(()) => println!("{} ... ok", r.name),
+      Err(rr) => {
+        failed += 1;
+        println!("{} ... FAILED: {:?}", r.name, rr);
+      }
+    }
+  }
+  if failed > 0 {
+    panic!(
+      "{} of the {} {} scenarios failed",
+      failed,
+      results.len(),
+      M::GROUP_NAME
+    );
+  }
 }
@@ -0,0 +1,240 @@
+use std::{
+  collections::{BTreeMap, HashMap, VecDeque},
+  time::{Duration, Instant},
+};
+
+use crate::messages::ServerId;
+
+/// links between servers learned from the announced routes, and the shortest route to each server
+/// routes are written like announced ones: the destination first, and the neighbour last
+pub struct Topology {
+  local: ServerId,
+  /// links that are not announced again for that long are forgotten
+  ttl: Option<Duration>,
+  /// when each link was last announced
+  /// sorted, so that routes of the same length are always chosen the same way
+  links: BTreeMap<ServerId, BTreeMap<ServerId, Instant>>,
+  routes: HashMap<ServerId, Vec<ServerId>>,
+}
+
+impl Topology {
+  pub fn new(local: ServerId, ttl: Option<Duration>) -> Self {
+    Topology {
+      local,
+      ttl,
+      links: BTreeMap::new(),
+      routes: HashMap::new(),
+    }
+  }
+
+  fn connect(&mut self, a: ServerId, b: ServerId, now: Instant) -> bool {
+    let added = self.links.entry(a).or_default().insert(b, now).is_none();
+    self.links.entry(b).or_default().insert(a, now);
+    added
+  }
+
+  fn disconnect(&mut self, a: ServerId, b: ServerId) -> bool {
+    let mut removed = false;
+    for (x, y) in [(a, b), (b, a)] {
+      if let Some(links) = self.links.get_mut(&x) {
+        removed |= links.remove(&y).is_some();
+        if links.is_empty() {
+          self.links.remove(&x);
+        }
+      }
+    }
+    removed
+  }
+
+  /// learns the links of a route received from its last server, returns true if routes changed
+  /// the links that were already known are refreshed
+  pub fn learn(&mut self, route: &[ServerId]) -> bool {
+    let now = Instant::now();
+    let mut hops = route.to_vec();
+    hops.push(self.local);
+    let mut changed = false;
+    for pair in hops.windows(2) {
+      if pair[0] != pair[1] {
+        changed |= self.connect(pair[0], pair[1], now);
+      }
+    }
+    if changed {
+      self.compute();
+    }
+    changed
+  }
+
+  /// forgets the link between the first server of the route and the second one, or the local server if
+  /// the route has a single server, returns true if routes changed
+  pub fn withdraw(&mut self, route: &[ServerId]) -> bool {
+    let changed = match route {
+      [] => false,
+      [srv] => self.disconnect(*srv, self.local),
+      [srv, next, ..] => self.disconnect(*srv, *next),
+    };
+    if changed {
+      self.compute();
+    }
+    changed
+  }
+
+  /// true if some links were not announced for longer than the ttl
+  pub fn expired(&self) -> bool {
+    match self.ttl {
+      Some(ttl) => self
+        .links
+        .values()
+        .flat_map(|l| l.values())
+        .any(|seen| seen.elapsed() >= ttl),
+      None => false,
+    }
+  }
+
+  /// forgets the links that were not announced for longer than the ttl, returns true if routes changed
+  pub fn expire(&mut self) -> bool {
+    let ttl = match self.ttl {
+      Some(ttl) => ttl,
+      None => return false,
+    };
+    let mut changed = false;
+    for links in self.links.values_mut() {
+      let before = links.len();
+      links.retain(|_, seen| seen.elapsed() < ttl);
+      changed |= links.len() != before;
+    }
+    self.links.retain(|_, l| !l.is_empty());
+    if changed {
+      self.compute();
+    }
+    changed
+  }
+
+  /// breadth first search from the local server
+  fn compute(&mut self) {
+    let mut previous: HashMap<ServerId, ServerId> = HashMap::new();
+    let mut queue = VecDeque::from([self.local]);
+    while let Some(srv) = queue.pop_front() {
+      for next in self.links.get(&srv).into_iter().flat_map(|l| l.keys()) {
+        if *next != self.local && !previous.contains_key(next) {
+          previous.insert(*next, srv);
+          queue.push_back(*next);
+        }
+      }
+    }
+    self.routes = previous
+      .keys()
+      .map(|dest| {
+        let mut route = vec![*dest];
+        let mut cur = *dest;
+        while previous[&cur] != self.local {
+          cur = previous[&cur];
+          route.push(cur);
+        }
+        (*dest, route)
+      })
+      .collect();
+  }
+
+  pub fn route_to(&self, dest: &ServerId) -> Option<&Vec<ServerId>> {
+    self.routes.get(dest)
+  }
+
+  /// the neighbour messages for dest must be sent to
+  pub fn nexthop(&self, dest: &ServerId) -> Option<ServerId> {
+    self.routes.get(dest).and_then(|r| r.last()).copied()
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  fn servers(n: usize) -> Vec<ServerId> {
+    (0..n).map(|_| ServerId::default()).collect()
+  }
+
+  #[test]
+  fn diamond() {
+    // us - a - d, us - b - d, and d - e
+    let s = servers(5);
+    let (us, a, b, d, e) = (s[0], s[1], s[2], s[3], s[4]);
+    let mut topology = Topology::new(us, None);
+    assert!(topology.learn(&[e, d, a]));
+    assert_eq!(topology.route_to(&e), Some(&vec![e, d, a]));
+    assert!(topology.learn(&[e, d, b]));
+    assert!(!topology.learn(&[d, b]));
+    let route = topology.route_to(&e).unwrap().clone();
+    assert!(route == vec![e, d, a] || route == vec![e, d, b]);
+    // both are as short, the choice does not change
+    assert!(!topology.learn(&[e, d, a]));
+    assert_eq!(topology.route_to(&e), Some(&route));
+    assert_eq!(topology.nexthop(&d), route.last().copied());
+  }
+
+  #[test]
+  fn ring() {
+    // us - s1 - s2 - s3 - s4 - s5 - us
+    let s = servers(6);
+    let us = s[0];
+    let mut topology = Topology::new(us, None);
+    // learned from s1, the long way around
+    assert!(topology.learn(&[s[5], s[4], s[3], s[2], s[1]]));
+    assert_eq!(topology.nexthop(&s[4]), Some(s[1]));
+    assert_eq!(topology.route_to(&s[5]).unwrap().len(), 5);
+    // then from s5
+    assert!(topology.learn(&[s[4], s[5]]));
+    assert_eq!(topology.route_to(&s[4]), Some(&vec![s[4], s[5]]));
+    assert_eq!(topology.route_to(&s[5]), Some(&vec![s[5]]));
+    assert_eq!(topology.route_to(&s[2]), Some(&vec![s[2], s[1]]));
+    let three = topology.route_to(&s[3]).unwrap();
+    assert_eq!(three.len(), 3);
+    assert_eq!(topology.route_to(&us), None);
+  }
+
+  #[test]
+  fn shorter_route() {
+    let s = servers(5);
+    let mut topology = Topology::new(s[0], None);
+    topology.learn(&[s[4], s[3], s[2], s[1]]);
+    assert_eq!(topology.nexthop(&s[4]), Some(s[1]));
+    topology.learn(&[s[4]]);
+    assert_eq!(topology.route_to(&s[4]), Some(&vec![s[4]]));
+    assert_eq!(topology.route_to(&s[3]), Some(&vec![s[3], s[4]]));
+  }
+
+  #[test]
+  fn withdrawn_links() {
+    // us - s1 - s2, us - s3 - s2
+    let s = servers(4);
+    let mut topology = Topology::new(s[0], None);
+    topology.learn(&[s[2], s[1]]);
+    topology.learn(&[s[2], s[3]]);
+    let first = topology.nexthop(&s[2]).unwrap();
+    let other = if first == s[1] { s[3] } else { s[1] };
+    // the link between s2 and the neighbour that was used is gone
+    assert!(topology.withdraw(&[s[2], first]));
+    assert_eq!(topology.route_to(&s[2]), Some(&vec![s[2], other]));
+    assert!(!topology.withdraw(&[s[2], first]));
+    // and then the other neighbour
+    assert!(topology.withdraw(&[other]));
+    assert_eq!(topology.route_to(&s[2]), None);
+    assert_eq!(topology.route_to(&other), None);
+    assert_eq!(topology.route_to(&first), Some(&vec![first]));
+  }
+
+  #[test]
+  fn expired_links() {
+    let s = servers(4);
+    let mut topology = Topology::new(s[0], Some(Duration::from_millis(50)));
+    topology.learn(&[s[2], s[1]]);
+    std::thread::sleep(Duration::from_millis(30));
+    topology.learn(&[s[3]]);
+    assert!(!topology.expired());
+    std::thread::sleep(Duration::from_millis(30));
+    assert!(topology.expired());
+    assert!(topology.expire());
+    assert_eq!(topology.route_to(&s[2]), None);
+    assert_eq!(topology.route_to(&s[3]), Some(&vec![s[3]]));
+    assert!(!topology.expired());
+  }
+}
@@ -0,0 +1,447 @@
+//! load generator: thousands of simulated clients, speaking the UDP protocol to a server
+//!
+//! cargo run --release --bin chat-bench -- --clients 2000 --rate 2 --mix text=50,mtext=10,poll=30,list=10
+
+use async_std::net::UdpSocket;
+use chatproto::client::Client;
+use chatproto::core::WORKPROOF_STRENGTH;
+use chatproto::messages::{
+  ClientError, ClientId, ClientMessage, ClientPollReply, ClientQuery, ClientReply, Sequence,
+};
+use chatproto::netproto::{decode, encode};
+use chatproto::workproof::gen_workproof;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use std::collections::BTreeMap;
+use std::io::Cursor;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use structopt::StructOpt;
+
+#[derive(StructOpt)]
+struct Opt {
+  #[structopt(long, default_value = "4666")]
+  /// port to connect to
+  port: u16,
+
+  #[structopt(long, default_value = "127.0.0.1")]
+  /// address to connect to
+  host: IpAddr,
+
+  #[structopt(long, default_value = "1000")]
+  /// number of simulated clients
+  clients: usize,
+
+  #[structopt(long, default_value = "10")]
+  /// duration of the load, in seconds, registration excluded
+  duration: u64,
+
+  #[structopt(long, default_value = "1")]
+  /// requests per second sent by each client
+  rate: f64,
+
+  #[structopt(long, default_value = "text=60,mtext=10,poll=25,list=5")]
+  /// relative weights of the requests
+  mix: Mix,
+
+  #[structopt(long, default_value = "3")]
+  /// number of destinations of MText messages
+  recipients: usize,
+
+  #[structopt(long, default_value = "2000")]
+  /// time to wait for a reply, in milliseconds
+  timeout: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Op {
+  Register,
+  Text,
+  MText,
+  Poll,
+  ListUsers,
+}
+
+impl Op {
+  fn name(&self) -> &'static str {
+    match self {
+      Op::Register => "register",
+      Op::Text => "text",
+      Op::MText => "mtext",
+      Op::Poll => "poll",
+      Op::ListUsers => "list",
+    }
+  }
+}
+
+/// weighted requests, parsed from "text=60,mtext=10,poll=25,list=5"
+#[derive(Clone, Debug)]
+struct Mix(Vec<(Op, u32)>);
+
+impl FromStr for Mix {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let mut mix = Vec::new();
+    for part in s.split(',') {
+      let (name, weight) = part
+        .split_once('=')
+        .ok_or_else(|| format!("expected name=weight, got {:?}", part))?;
+      let op = [Op::Text, Op::MText, Op::Poll, Op::ListUsers]
+        .into_iter()
+        .find(|op| op.name() == name.trim())
+        .ok_or_else(|| {
+          format!(
+            "unknown request {:?}, expected text, mtext, poll or list",
+            name
+          )
+        })?;
+      let weight = weight
+        .trim()
+        .parse()
+        .map_err(|rr| format!("bad weight for {}: {}", name, rr))?;
+      mix.push((op, weight));
+    }
+    if mix.iter().all(|(_, w)| *w == 0) {
+      return Err("at least one weight must be positive".to_string());
+    }
+    Ok(Mix(mix))
+  }
+}
+
+impl Mix {
+  fn pick(&self, rng: &mut StdRng) -> Op {
+    let total: u32 = self.0.iter().map(|(_, w)| w).sum();
+    let mut n = rng.gen_range(0..total);
+    for (op, w) in &self.0 {
+      if n < *w {
+        return *op;
+      }
+      n -= w;
+    }
+    unreachable!()
+  }
+}
+
+fn error_name(e: &ClientError) -> &'static str {
+  match e {
+    ClientError::WorkProofError => "WorkProofError",
+    ClientError::UnknownClient => "UnknownClient",
+    ClientError::SequenceError => "SequenceError",
+    ClientError::BoxFull(_) => "BoxFull",
+    ClientError::InternalError => "InternalError",
+    ClientError::QuotaExceeded => "QuotaExceeded",
+    ClientError::UnknownAttachment => "UnknownAttachment",
+    ClientError::InvalidChunk => "InvalidChunk",
+    ClientError::Rejected(_) => "Rejected",
+    ClientError::Banned => "Banned",
+    ClientError::NotAdmin => "NotAdmin",
+    ClientError::UnknownRole => "UnknownRole",
+    ClientError::PermissionDenied { .. } => "PermissionDenied",
+  }
+}
+
+fn reply_name(r: &ClientReply) -> String {
+  match r {
+    ClientReply::Delivered(_) => "Delivered".to_string(),
+    ClientReply::Error(e) => format!("Error({})", error_name(e)),
+    ClientReply::Delayed => "Delayed".to_string(),
+    ClientReply::Transfer(_, _) => "Transfer".to_string(),
+  }
+}
+
+fn poll_name(r: &ClientPollReply) -> String {
+  match r {
+    ClientPollReply::Message { .. } => "Message".to_string(),
+    ClientPollReply::Edited { .. } => "Edited".to_string(),
+    ClientPollReply::Deleted { .. } => "Deleted".to_string(),
+    ClientPollReply::Attachment { .. } => "Attachment".to_string(),
+    ClientPollReply::DelayedError(_) => "DelayedError".to_string(),
+    ClientPollReply::Nothing => "Nothing".to_string(),
+    ClientPollReply::Dropped { .. } => "Dropped".to_string(),
+    ClientPollReply::Notice(_) => "Notice".to_string(),
+    ClientPollReply::Error(e) => format!("Error({})", error_name(e)),
+  }
+}
+
+/// one socket per simulated client
+struct Network {
+  target: SocketAddr,
+  socket: UdpSocket,
+  timeout: Duration,
+}
+
+impl Network {
+  async fn new(target: SocketAddr, timeout: Duration) -> anyhow::Result<Self> {
+    Ok(Network {
+      target,
+      socket: Self::bind(target).await?,
+      timeout,
+    })
+  }
+
+  async fn bind(target: SocketAddr) -> anyhow::Result<UdpSocket> {
+    let local = if target.is_ipv4() {
+      "0.0.0.0:0"
+    } else {
+      "[::]:0"
+    };
+    let socket = UdpSocket::bind(local).await?;
+    socket.connect(target).await?;
+    Ok(socket)
+  }
+
+  /// sends a query and decodes its reply, failures are named like outcomes
+  async fn exchange<X, F>(&mut self, sq: &Sequence<ClientQuery>, f: F) -> Result<X, &'static str>
+  where
+    F: FnOnce(&mut Cursor<Vec<u8>>) -> anyhow::Result<X>,
+  {
+    let mut wr = Cursor::new(Vec::new());
+    encode::sequence(&mut wr, sq, encode::client_query).map_err(|_| "encode error")?;
+    self
+      .socket
+      .send(&wr.into_inner())
+      .await
+      .map_err(|_| "io error")?;
+    let mut buf = vec![0u8; 65536];
+    let n = match async_std::future::timeout(self.timeout, self.socket.recv(&mut buf)).await {
+      Ok(r) => r.map_err(|_| "io error")?,
+      Err(_) => {
+        // a new socket, so that the late reply is not mistaken for the next one
+        if let Ok(socket) = Self::bind(self.target).await {
+          self.socket = socket;
+        }
+        return Err("timeout");
+      }
+    };
+    f(&mut Cursor::new(buf[..n].to_vec())).map_err(|_| "decode error")
+  }
+}
+
+#[derive(Default)]
+struct Stats {
+  /// latencies of the answered requests
+  latencies: BTreeMap<Op, Vec<Duration>>,
+  /// reply variants, and failures, by request
+  outcomes: BTreeMap<(Op, String), u64>,
+}
+
+impl Stats {
+  fn record<I: IntoIterator<Item = String>>(
+    &mut self,
+    op: Op,
+    latency: Option<Duration>,
+    outcomes: I,
+  ) {
+    if let Some(l) = latency {
+      self.latencies.entry(op).or_default().push(l);
+    }
+    for o in outcomes {
+      *self.outcomes.entry((op, o)).or_default() += 1;
+    }
+  }
+
+  fn merge(&mut self, other: Stats) {
+    for (op, l) in other.latencies {
+      self.latencies.entry(op).or_default().extend(l);
+    }
+    for (k, n) in other.outcomes {
+      *self.outcomes.entry(k).or_default() += n;
+    }
+  }
+
+  fn report(&mut self, elapsed: Duration) {
+    fn ms(d: Duration) -> String {
+      format!("{:.2}ms", d.as_secs_f64() * 1000.0)
+    }
+    println!(
+      "{:<10} {:>9} {:>10} {:>10} {:>10} {:>10} {:>10}",
+      "request", "answered", "per sec", "p50", "p90", "p99", "max"
+    );
+    for (op, l) in self.latencies.iter_mut() {
+      l.sort();
+      let pct = |p: usize| l[(l.len() * p / 100).min(l.len() - 1)];
+      let per_sec = if *op == Op::Register {
+        "-".to_string()
+      } else {
+        format!("{:.1}", l.len() as f64 / elapsed.as_secs_f64())
+      };
+      println!(
+        "{:<10} {:>9} {:>10} {:>10} {:>10} {:>10} {:>10}",
+        op.name(),
+        l.len(),
+        per_sec,
+        ms(pct(50)),
+        ms(pct(90)),
+        ms(pct(99)),
+        ms(l[l.len() - 1])
+      );
+    }
+    println!();
+    println!("{:<10} {:<24} {:>9}", "request", "outcome", "count");
+    for ((op, outcome), n) in &self.outcomes {
+      println!("{:<10} {:<24} {:>9}", op.name(), outcome, n);
+    }
+  }
+}
+
+async fn register(network: &mut Network, name: String, stats: &mut Stats) -> Option<ClientId> {
+  let tempid = ClientId::default();
+  let workproof = gen_workproof((&tempid).into(), WORKPROOF_STRENGTH, u128::MAX).unwrap();
+  let sq = Sequence {
+    seqid: 0,
+    src: tempid,
+    workproof,
+    content: ClientQuery::Register(name),
+  };
+  let start = Instant::now();
+  match network.exchange(&sq, decode::clientid).await {
+    Ok(id) => {
+      stats.record(
+        Op::Register,
+        Some(start.elapsed()),
+        ["ClientId".to_string()],
+      );
+      Some(id)
+    }
+    Err(failure) => {
+      stats.record(Op::Register, None, [failure.to_string()]);
+      None
+    }
+  }
+}
+
+/// sends one request, and records its outcome
+async fn request(
+  network: &mut Network,
+  client: &mut Client,
+  op: Op,
+  dest: Vec<ClientId>,
+  stats: &mut Stats,
+) {
+  let start = Instant::now();
+  let outcomes = match op {
+    Op::Register => unreachable!("clients are registered before the load starts"),
+    Op::Text | Op::MText => {
+      let content = format!("load test message from {}", client.id());
+      let msg = if op == Op::Text {
+        ClientMessage::Text {
+          dest: dest[0],
+          content,
+        }
+      } else {
+        ClientMessage::MText { dest, content }
+      };
+      let sq = client.sequence(ClientQuery::Message(msg));
+      network
+        .exchange(&sq, decode::client_replies)
+        .await
+        .map(|r| r.iter().map(reply_name).collect::<Vec<_>>())
+    }
+    Op::Poll => {
+      let sq = client.sequence(ClientQuery::Poll);
+      network
+        .exchange(&sq, decode::client_poll_reply)
+        .await
+        .map(|r| vec![poll_name(&r)])
+    }
+    Op::ListUsers => {
+      let sq = client.sequence(ClientQuery::ListUsers);
+      network
+        .exchange(&sq, decode::userlist_reply)
+        .await
+        .map(|r| match r {
+          Ok(_) => vec!["UserList".to_string()],
+          Err(rr) => vec![error_name(&rr).to_string()],
+        })
+    }
+  };
+  match outcomes {
+    Ok(o) => stats.record(op, Some(start.elapsed()), o),
+    Err(failure) => stats.record(op, None, [failure.to_string()]),
+  }
+}
+
+/// sends requests at the configured rate until the deadline
+async fn simulate(
+  opt: Arc<Opt>,
+  mut network: Network,
+  mut client: Client,
+  peers: Arc<Vec<ClientId>>,
+  seed: u64,
+  deadline: Instant,
+) -> Stats {
+  let mut stats = Stats::default();
+  let mut rng = StdRng::seed_from_u64(seed);
+  let interval = Duration::from_secs_f64(1.0 / opt.rate);
+  // clients do not all start at the same time
+  let mut next = Instant::now() + interval.mul_f64(rng.gen::<f64>());
+  while next < deadline {
+    let now = Instant::now();
+    if next > now {
+      async_std::task::sleep(next - now).await;
+    }
+    next += interval;
+    let op = opt.mix.pick(&mut rng);
+    let count = if op == Op::MText { opt.recipients } else { 1 };
+    let dest = (0..count)
+      .map(|_| peers[rng.gen_range(0..peers.len())])
+      .collect();
+    request(&mut network, &mut client, op, dest, &mut stats).await;
+  }
+  stats
+}
+
+fn main() -> anyhow::Result<()> {
+  async_std::task::block_on(async { main_task().await })
+}
+
+async fn main_task() -> anyhow::Result<()> {
+  pretty_env_logger::init();
+
+  let opt = Arc::new(Opt::from_args());
+  anyhow::ensure!(opt.rate > 0.0, "the rate must be positive");
+  let target = SocketAddr::from((opt.host, opt.port));
+  let timeout = Duration::from_millis(opt.timeout);
+
+  let mut stats = Stats::default();
+  let mut registrations = Vec::new();
+  for n in 0..opt.clients {
+    registrations.push(async_std::task::spawn(async move {
+      let mut stats = Stats::default();
+      let mut network = Network::new(target, timeout).await?;
+      let id = register(&mut network, format!("bench {n}"), &mut stats).await;
+      anyhow::Ok((id.map(|id| (network, Client::new(id))), stats))
+    }));
+  }
+  let mut clients = Vec::new();
+  for r in registrations {
+    let (client, s) = r.await?;
+    stats.merge(s);
+    clients.extend(client);
+  }
+  log::info!("{} clients registered", clients.len());
+  anyhow::ensure!(!clients.is_empty(), "no client could register");
+
+  let peers = Arc::new(clients.iter().map(|(_, c)| c.id()).collect::<Vec<_>>());
+  let start = Instant::now();
+  let deadline = start + Duration::from_secs(opt.duration);
+  let mut tasks = Vec::new();
+  for (n, (network, client)) in clients.into_iter().enumerate() {
+    let sim = simulate(
+      opt.clone(),
+      network,
+      client,
+      peers.clone(),
+      n as u64,
+      deadline,
+    );
+    tasks.push(async_std::task::spawn(sim));
+  }
+  for t in tasks {
+    stats.merge(t.await);
+  }
+  stats.report(start.elapsed());
+  Ok(())
+}
@@ -0,0 +1,410 @@
+//! protocol conformance of a running server: each scenario registers its own clients over UDP
+//!
+//! cargo run --bin chat-conformance -- --host 127.0.0.1 --port 4666
+
+use async_std::net::UdpSocket;
+use chatproto::client::Client;
+use chatproto::core::{MAILBOX_SIZE, WORKPROOF_STRENGTH};
+use chatproto::messages::{
+  ClientError, ClientId, ClientMessage, ClientPollReply, ClientQuery, ClientReply, Sequence,
+};
+use chatproto::netproto::{decode, encode};
+use chatproto::workproof::{gen_workproof, verify_workproof};
+use std::io::Cursor;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use structopt::StructOpt;
+
+#[derive(StructOpt)]
+struct Opt {
+  #[structopt(long, default_value = "4666")]
+  /// port to connect to
+  port: u16,
+
+  #[structopt(long, default_value = "127.0.0.1")]
+  /// address to connect to
+  host: IpAddr,
+
+  #[structopt(long, default_value = "2000")]
+  /// time to wait for a reply, in milliseconds
+  timeout: u64,
+
+  #[structopt(long)]
+  /// number of messages a mailbox holds, the protocol default if absent
+  mailbox: Option<usize>,
+
+  /// scenarios to run, all of them if none are given
+  scenarios: Vec<String>,
+}
+
+const SCENARIOS: &[&str] = &[
+  "registration",
+  "workproof_rejection",
+  "sequence_replay",
+  "mailbox_full",
+  "poll_ordering",
+  "user_listing",
+  "address_listing",
+];
+
+/// a datagram sent to the server, and its answer
+struct Exchange {
+  sent: Vec<u8>,
+  received: Option<Vec<u8>>,
+}
+
+/// a socket that keeps every datagram it exchanged, to be shown when a scenario fails
+struct Session {
+  socket: UdpSocket,
+  timeout: Duration,
+  /// prefix of the names registered by this session, so that runs do not mix up their users
+  tag: String,
+  exchanges: Vec<Exchange>,
+}
+
+impl Session {
+  async fn new(target: SocketAddr, timeout: Duration, tag: String) -> anyhow::Result<Self> {
+    let local = if target.is_ipv4() {
+      "0.0.0.0:0"
+    } else {
+      "[::]:0"
+    };
+    let socket = UdpSocket::bind(local).await?;
+    socket.connect(target).await?;
+    Ok(Session {
+      socket,
+      timeout,
+      tag,
+      exchanges: Vec::new(),
+    })
+  }
+
+  /// sends a datagram as is, and waits for the answer
+  async fn raw(&mut self, sent: Vec<u8>) -> anyhow::Result<Option<Vec<u8>>> {
+    self.socket.send(&sent).await?;
+    let mut buf = vec![0u8; 65536];
+    let received = match async_std::future::timeout(self.timeout, self.socket.recv(&mut buf)).await
+    {
+      Ok(n) => Some(buf[..n?].to_vec()),
+      Err(_) => None,
+    };
+    self.exchanges.push(Exchange {
+      sent,
+      received: received.clone(),
+    });
+    Ok(received)
+  }
+
+  /// sends an already encoded query, and decodes the whole answer
+  async fn send<X, F>(&mut self, sent: Vec<u8>, f: F) -> anyhow::Result<X>
+  where
+    F: FnOnce(&mut Cursor<Vec<u8>>) -> anyhow::Result<X>,
+  {
+    let received = match self.raw(sent).await? {
+      Some(r) => r,
+      None => anyhow::bail!("no reply after {:?}", self.timeout),
+    };
+    let len = received.len() as u64;
+    let mut rd = Cursor::new(received);
+    let x = f(&mut rd).map_err(|rr| anyhow::anyhow!("could not decode the reply: {}", rr))?;
+    if rd.position() != len {
+      anyhow::bail!("{} trailing bytes in the reply", len - rd.position());
+    }
+    Ok(x)
+  }
+
+  async fn query<X, F>(&mut self, sq: &Sequence<ClientQuery>, f: F) -> anyhow::Result<X>
+  where
+    F: FnOnce(&mut Cursor<Vec<u8>>) -> anyhow::Result<X>,
+  {
+    self.send(encoded(sq)?, f).await
+  }
+
+  async fn register(&mut self, name: &str) -> anyhow::Result<(Client, String)> {
+    let name = format!("{} {}", self.tag, name);
+    let tempid = ClientId::default();
+    let workproof = gen_workproof((&tempid).into(), WORKPROOF_STRENGTH, u128::MAX).unwrap();
+    let sq = Sequence {
+      seqid: 0,
+      src: tempid,
+      workproof,
+      content: ClientQuery::Register(name.clone()),
+    };
+    let id = self.query(&sq, decode::clientid).await?;
+    Ok((Client::new(id), name))
+  }
+
+  async fn text(
+    &mut self,
+    client: &mut Client,
+    dest: ClientId,
+    content: &str,
+  ) -> anyhow::Result<Vec<ClientReply>> {
+    let sq = client.sequence(ClientQuery::Message(ClientMessage::Text {
+      dest,
+      content: content.to_string(),
+    }));
+    self.query(&sq, decode::client_replies).await
+  }
+
+  async fn poll(&mut self, client: &mut Client) -> anyhow::Result<ClientPollReply> {
+    let sq = client.sequence(ClientQuery::Poll);
+    self.query(&sq, decode::client_poll_reply).await
+  }
+
+  /// expects the next messages of the mailbox, in order, and then an empty mailbox
+  async fn expect_messages(
+    &mut self,
+    client: &mut Client,
+    src: ClientId,
+    contents: &[&str],
+  ) -> anyhow::Result<()> {
+    for expected in contents {
+      match self.poll(client).await? {
+        ClientPollReply::Message {
+          src: s, content, ..
+        } if s == src && content == *expected => (),
+        r => anyhow::bail!("expected {:?} from {}, got {:?}", expected, src, r),
+      }
+    }
+    match self.poll(client).await? {
+      ClientPollReply::Nothing => Ok(()),
+      r => anyhow::bail!("expected Nothing, got {:?}", r),
+    }
+  }
+}
+
+fn encoded(sq: &Sequence<ClientQuery>) -> anyhow::Result<Vec<u8>> {
+  let mut wr = Cursor::new(Vec::new());
+  encode::sequence(&mut wr, sq, encode::client_query)?;
+  Ok(wr.into_inner())
+}
+
+fn expect_delivered(replies: &[ClientReply]) -> anyhow::Result<()> {
+  match replies {
+    [ClientReply::Delivered(_)] => Ok(()),
+    _ => anyhow::bail!("expected Delivered, got {:?}", replies),
+  }
+}
+
+fn expect_error(replies: &[ClientReply], expected: ClientError) -> anyhow::Result<()> {
+  match replies {
+    [ClientReply::Error(e)] if *e == expected => Ok(()),
+    _ => anyhow::bail!("expected Error({:?}), got {:?}", expected, replies),
+  }
+}
+
+async fn registration(s: &mut Session) -> anyhow::Result<()> {
+  let (mut c1, _) = s.register("registration").await?;
+  let (mut c2, _) = s.register("registration").await?;
+  if c1.id() == c2.id() {
+    anyhow::bail!("two registrations got the same id {}", c1.id());
+  }
+  // registered clients can send queries right away
+  for c in [&mut c1, &mut c2] {
+    match s.poll(c).await? {
+      ClientPollReply::Nothing => (),
+      r => anyhow::bail!("expected Nothing from a new mailbox, got {:?}", r),
+    }
+  }
+  Ok(())
+}
+
+async fn workproof_rejection(s: &mut Session) -> anyhow::Result<()> {
+  let (mut c1, _) = s.register("workproof").await?;
+  let mut sq = c1.sequence(ClientQuery::Message(ClientMessage::Text {
+    dest: c1.id(),
+    content: "unproven".to_string(),
+  }));
+  sq.workproof = (0..)
+    .find(|&w| !verify_workproof((&sq.src).into(), w, WORKPROOF_STRENGTH))
+    .unwrap();
+  let replies = s.query(&sq, decode::client_replies).await?;
+  expect_error(&replies, ClientError::WorkProofError)?;
+  // the rejected message must not have been delivered
+  let src = c1.id();
+  s.expect_messages(&mut c1, src, &[]).await
+}
+
+async fn sequence_replay(s: &mut Session) -> anyhow::Result<()> {
+  let (mut c1, _) = s.register("replay 1").await?;
+  let (mut c2, _) = s.register("replay 2").await?;
+  let sq = c1.sequence(ClientQuery::Message(ClientMessage::Text {
+    dest: c2.id(),
+    content: "once".to_string(),
+  }));
+  let datagram = encoded(&sq)?;
+  expect_delivered(&s.send(datagram.clone(), decode::client_replies).await?)?;
+  // the exact same datagram
+  let replies = s.send(datagram, decode::client_replies).await?;
+  expect_error(&replies, ClientError::SequenceError)?;
+  // an older sequence number
+  let older = Sequence {
+    seqid: sq.seqid - 1,
+    ..c1.sequence(ClientQuery::Message(ClientMessage::Text {
+      dest: c2.id(),
+      content: "older".to_string(),
+    }))
+  };
+  let replies = s.query(&older, decode::client_replies).await?;
+  expect_error(&replies, ClientError::SequenceError)?;
+  s.expect_messages(&mut c2, c1.id(), &["once"]).await
+}
+
+async fn mailbox_full(s: &mut Session, size: usize) -> anyhow::Result<()> {
+  let (mut c1, _) = s.register("full 1").await?;
+  let (mut c2, _) = s.register("full 2").await?;
+  for n in 0..size {
+    let replies = s.text(&mut c1, c2.id(), &format!("{n}")).await?;
+    expect_delivered(&replies).map_err(|rr| anyhow::anyhow!("message {}: {}", n, rr))?;
+  }
+  let replies = s.text(&mut c1, c2.id(), "FULL").await?;
+  expect_error(&replies, ClientError::BoxFull(c2.id()))?;
+  // the oldest message is still there
+  match s.poll(&mut c2).await? {
+    ClientPollReply::Message { content, .. } if content == "0" => Ok(()),
+    r => anyhow::bail!("expected the first message, got {:?}", r),
+  }
+}
+
+async fn poll_ordering(s: &mut Session) -> anyhow::Result<()> {
+  let (mut c1, _) = s.register("ordering 1").await?;
+  let (mut c2, _) = s.register("ordering 2").await?;
+  let contents = ["first", "second", "third", "fourth"];
+  for content in contents {
+    expect_delivered(&s.text(&mut c1, c2.id(), content).await?)?;
+  }
+  s.expect_messages(&mut c2, c1.id(), &contents).await
+}
+
+async fn user_listing(s: &mut Session) -> anyhow::Result<()> {
+  let (mut c1, name1) = s.register("listing 1").await?;
+  let (c2, name2) = s.register("listing 2").await?;
+  let sq = c1.sequence(ClientQuery::ListUsers);
+  let users = s.query(&sq, decode::userlist_reply).await??;
+  for (id, name) in [(c1.id(), name1), (c2.id(), name2)] {
+    match users.get(&id) {
+      Some(n) if *n == name => (),
+      n => anyhow::bail!("expected {} to be listed as {:?}, got {:?}", id, name, n),
+    }
+  }
+  Ok(())
+}
+
+/// local users share the name of their server
+async fn address_listing(s: &mut Session) -> anyhow::Result<()> {
+  let (mut c1, name1) = s.register("address 1").await?;
+  let (c2, name2) = s.register("address 2").await?;
+  let sq = c1.sequence(ClientQuery::ListAddresses);
+  let addresses = s.query(&sq, decode::addresses_reply).await??;
+  let (a1, a2) = match (addresses.get(&c1.id()), addresses.get(&c2.id())) {
+    (Some(a1), Some(a2)) => (a1, a2),
+    _ => anyhow::bail!(
+      "expected {} and {} to be listed, got {:?}",
+      c1.id(),
+      c2.id(),
+      addresses
+    ),
+  };
+  anyhow::ensure!(
+    a1.name == name1 && a2.name == name2,
+    "expected the names {:?} and {:?}, got {} and {}",
+    name1,
+    name2,
+    a1,
+    a2
+  );
+  anyhow::ensure!(
+    a1.server == a2.server && !a1.server.is_empty(),
+    "expected the same server name for {} and {}",
+    a1,
+    a2
+  );
+  Ok(())
+}
+
+async fn run(name: &str, s: &mut Session, opt: &Opt) -> anyhow::Result<()> {
+  match name {
+    "registration" => registration(s).await,
+    "workproof_rejection" => workproof_rejection(s).await,
+    "sequence_replay" => sequence_replay(s).await,
+    "mailbox_full" => mailbox_full(s, opt.mailbox.unwrap_or(MAILBOX_SIZE)).await,
+    "poll_ordering" => poll_ordering(s).await,
+    "user_listing" => user_listing(s).await,
+    "address_listing" => address_listing(s).await,
+    _ => unreachable!("unknown scenario {}", name),
+  }
+}
+
+/// offset, then 16 bytes per line
+fn hexdump(prefix: &str, data: &[u8]) {
+  if data.is_empty() {
+    println!("    {} (empty datagram)", prefix);
+  }
+  for (n, line) in data.chunks(16).enumerate() {
+    let hex: Vec<String> = line.iter().map(|b| format!("{:02x}", b)).collect();
+    println!("    {} {:04x}  {}", prefix, n * 16, hex.join(" "));
+  }
+}
+
+fn report(exchanges: &[Exchange]) {
+  for (n, e) in exchanges.iter().enumerate() {
+    println!("  exchange {}, {} bytes sent", n + 1, e.sent.len());
+    hexdump(">", &e.sent);
+    match &e.received {
+      Some(r) => {
+        println!("  {} bytes received", r.len());
+        hexdump("<", r);
+      }
+      None => println!("  no reply"),
+    }
+  }
+}
+
+fn main() -> anyhow::Result<()> {
+  async_std::task::block_on(async { main_task().await })
+}
+
+async fn main_task() -> anyhow::Result<()> {
+  pretty_env_logger::init();
+
+  let opt = Opt::from_args();
+  for name in &opt.scenarios {
+    anyhow::ensure!(
+      SCENARIOS.contains(&name.as_str()),
+      "unknown scenario {}, expected one of {}",
+      name,
+      SCENARIOS.join(", ")
+    );
+  }
+  let target = SocketAddr::from((opt.host, opt.port));
+  let timeout = Duration::from_millis(opt.timeout);
+  let run_id = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis();
+
+  let mut failed = 0;
+  let mut total = 0;
+  for name in SCENARIOS {
+    if !opt.scenarios.is_empty() && !opt.scenarios.iter().any(|s| s == name) {
+      continue;
+    }
+    total += 1;
+    let mut session = Session::new(target, timeout, format!("conformance {run_id}")).await?;
+    match run(name, &mut session, &opt).await {
+      Ok(()) => println!("{} ... ok", name),
+      Err(rr) => {
+        failed += 1;
+        println!("{} ... FAILED: {}", name, rr);
+        report(&session.exchanges);
+      }
+    }
+  }
+  println!();
+  println!(
+    "{} scenarios, {} passed, {} failed",
+    total,
+    total - failed,
+    failed
+  );
+  anyhow::ensure!(failed == 0, "{} of the {} scenarios failed", failed, total);
+  Ok(())
+}
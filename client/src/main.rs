@@ -1,10 +1,12 @@
 use async_std::channel::{Receiver, Sender};
 use async_std::net::UdpSocket;
 use async_std::sync::RwLock;
+use chatproto::attachments;
 use chatproto::client::Client;
-use chatproto::core::WORKPROOF_STRENGTH;
+use chatproto::core::{HISTORY_PAGE_SIZE, WORKPROOF_STRENGTH};
 use chatproto::messages::{
-  ClientId, ClientMessage, ClientPollReply, ClientQuery, ClientReply, Sequence,
+  Address, AdminCommand, AdminReply, AttachmentMeta, ClientId, ClientMessage, ClientPollReply,
+  ClientQuery, ClientReply, HistoryBound, HistoryQuery, MessageId, Sequence,
 };
 use chatproto::netproto::{decode, encode};
 use chatproto::workproof::gen_workproof;
@@ -81,10 +83,36 @@ impl Network {
 enum Command {
   Quit,
   ListUsers,
-  SendMessage { message: String },
+  SendMessage {
+    message: String,
+  },
+  /// rewrites the last message sent to the selected user
+  EditMessage {
+    message: String,
+  },
+  /// retracts the last message sent to the selected user
+  DeleteMessage,
+  /// uploads a file and sends it to the selected user
+  SendFile {
+    path: String,
+  },
+  /// downloads an attachment received from the selected user
+  SaveFile {
+    id: MessageId,
+    path: String,
+  },
+  /// fetches older messages of the conversation with the selected user
+  FetchHistory,
+  /// administration command
+  Admin(AdminCommand),
+  /// administration command about the selected user
+  AdminSelected(fn(ClientId) -> AdminCommand),
+  /// selects the user with this address
+  Select(Address),
   Poll,
 }
 
+#[derive(PartialEq, Eq)]
 enum Source {
   Me,
   Other,
@@ -94,8 +122,13 @@ enum Source {
 struct UserInfo {
   name: String,
   active: bool,
-  messages: Vec<(Source, String)>,
+  messages: Vec<(Source, Option<MessageId>, String)>,
+  attachments: HashMap<MessageId, AttachmentMeta>,
   unread: usize,
+  /// number of messages hidden below the Messages pane
+  scroll: usize,
+  /// set when the server has no older messages
+  history_complete: bool,
 }
 
 #[derive(Default)]
@@ -136,13 +169,32 @@ async fn show_ui(rx: Receiver<UIEvent>, tx: Sender<Command>) -> anyhow::Result<(
   let backend = CrosstermBackend::new(stdout);
   let mut terminal = Terminal::new(backend)?;
   let mut inputbox = inputbox::IBox::new();
+  let mut messages_height = 0;
 
   loop {
     // show ui
     {
       let users = USERS.read().await;
       let errors = ERRORS.read().await;
-      terminal.draw(|f| ui(f, &inputbox, &users, &errors))?;
+      terminal.draw(|f| messages_height = ui(f, &inputbox, &users, &errors))?;
+    }
+
+    /// returns true when trying to scroll past the top of the conversation
+    async fn scroll_messages(is_up: bool, height: usize) -> bool {
+      let mut w = USERS.write().await;
+      let uinfo = match w.selected.and_then(|s| w.userlist.get_mut(&s)) {
+        Some(u) => u,
+        None => return false,
+      };
+      if !is_up {
+        uinfo.scroll = uinfo.scroll.saturating_sub(1);
+        false
+      } else if uinfo.messages.len() <= uinfo.scroll + height {
+        true
+      } else {
+        uinfo.scroll += 1;
+        false
+      }
     }
 
     async fn move_selected(is_up: bool) {
@@ -182,10 +234,10 @@ async fn show_ui(rx: Receiver<UIEvent>, tx: Sender<Command>) -> anyhow::Result<(
     match event {
       UIEvent::Key(k) => match k {
         KeyCode::Enter => {
-          tx.send(Command::SendMessage {
-            message: inputbox.message().to_string(),
-          })
-          .await?;
+          match parse_input(inputbox.message()) {
+            Ok(cmd) => tx.send(cmd).await?,
+            Err(rr) => ERRORS.write().await.push(rr),
+          }
           inputbox.reset()
         }
         KeyCode::Char(to_insert) => {
@@ -206,6 +258,15 @@ async fn show_ui(rx: Receiver<UIEvent>, tx: Sender<Command>) -> anyhow::Result<(
         KeyCode::Down => {
           move_selected(false).await;
         }
+        KeyCode::PageUp => {
+          let at_top = scroll_messages(true, messages_height).await;
+          if at_top {
+            tx.send(Command::FetchHistory).await?;
+          }
+        }
+        KeyCode::PageDown => {
+          scroll_messages(false, messages_height).await;
+        }
         KeyCode::Esc => {
           break;
         }
@@ -225,7 +286,49 @@ async fn show_ui(rx: Receiver<UIEvent>, tx: Sender<Command>) -> anyhow::Result<(
   Ok(())
 }
 
-fn ui(f: &mut Frame, input: &inputbox::IBox, users: &Users, errors: &[String]) {
+/// turns the input box content into a command, known /commands being handled separately
+fn parse_input(input: &str) -> Result<Command, String> {
+  Ok(match input.split_once(' ').unwrap_or((input, "")) {
+    ("/edit", message) => Command::EditMessage {
+      message: message.to_string(),
+    },
+    ("/delete", _) => Command::DeleteMessage,
+    ("/send", path) if !path.is_empty() => Command::SendFile {
+      path: path.to_string(),
+    },
+    ("/save", args) => match args.split_once(' ') {
+      Some((id, path)) if !path.is_empty() => Command::SaveFile {
+        id: id
+          .parse::<u128>()
+          .map_err(|_| format!("invalid attachment id {}", id))?
+          .into(),
+        path: path.to_string(),
+      },
+      _ => return Err("usage: /save <id> <path>".to_string()),
+    },
+    ("/send", _) => return Err("usage: /send <path>".to_string()),
+    ("/to", address) => Command::Select(address.parse()?),
+    ("/ban", _) => Command::AdminSelected(AdminCommand::Ban),
+    ("/unban", _) => Command::AdminSelected(AdminCommand::Unban),
+    ("/kick", _) => Command::AdminSelected(AdminCommand::Kick),
+    ("/depth", _) => Command::AdminSelected(AdminCommand::MailboxDepth),
+    ("/banip", addr) => Command::Admin(AdminCommand::BanAddress(
+      addr
+        .parse()
+        .map_err(|_| "usage: /banip <address>".to_string())?,
+    )),
+    ("/broadcast", notice) if !notice.is_empty() => {
+      Command::Admin(AdminCommand::Broadcast(notice.to_string()))
+    }
+    ("/broadcast", _) => return Err("usage: /broadcast <notice>".to_string()),
+    _ => Command::SendMessage {
+      message: input.to_string(),
+    },
+  })
+}
+
+/// draws the ui, and returns the height of the Messages pane
+fn ui(f: &mut Frame, input: &inputbox::IBox, users: &Users, errors: &[String]) -> usize {
   let create_block = |title| {
     Block::default()
       .borders(Borders::ALL)
@@ -286,16 +389,20 @@ fn ui(f: &mut Frame, input: &inputbox::IBox, users: &Users, errors: &[String]) {
   let userlist = Paragraph::new(userlist_lines).block(create_block("Users"));
   f.render_widget(userlist, chunks[0]);
 
+  let height = chunks[1].height.saturating_sub(2) as usize;
   let messages_lines = match users.selected.as_ref() {
     None => vec![Line::from("no user selected")],
     Some(x) => users
       .userlist
       .get(x)
-      .map(|u| &u.messages)
+      .map(|u| {
+        let end = u.messages.len().saturating_sub(u.scroll);
+        &u.messages[end.saturating_sub(height)..end]
+      })
       .iter()
       .copied()
       .flatten()
-      .map(|(source, msg)| match source {
+      .map(|(source, _, msg)| match source {
         Source::Me => Line::from(format!("> {}", msg).blue()),
         Source::Other => Line::from(format!("< {}", msg)),
       })
@@ -303,6 +410,7 @@ fn ui(f: &mut Frame, input: &inputbox::IBox, users: &Users, errors: &[String]) {
   };
   let messages = Paragraph::new(messages_lines).block(create_block("Messages"));
   f.render_widget(messages, chunks[1]);
+  height
 }
 
 async fn handle_network(
@@ -321,9 +429,20 @@ async fn handle_network(
     match cmd {
       Command::Quit => break,
       Command::ListUsers => {
-        let msg = client.sequence(ClientQuery::ListUsers);
+        let msg = client.sequence(ClientQuery::ListAddresses);
         network.send(&msg).await?;
-        let list = network.get(decode::userlist).await?;
+        // users are shown with their qualified address, so that users of other servers can be told apart
+        let list = match network.get(decode::addresses_reply).await? {
+          Ok(list) => list,
+          Err(rr) => {
+            ERRORS.write().await.push(format!("listing failed: {}", rr));
+            continue;
+          }
+        };
+        let list = list
+          .into_iter()
+          .map(|(id, address)| (id, address.to_string()))
+          .collect::<HashMap<_, _>>();
         let mut lk = USERS.write().await;
         let known_users = lk
           .userlist
@@ -347,8 +466,11 @@ async fn handle_network(
             UserInfo {
               active: true,
               messages: Vec::new(),
+              attachments: HashMap::new(),
               name: list.get(new_user).unwrap().clone(),
               unread: 0,
+              scroll: 0,
+              history_complete: false,
             },
           );
         }
@@ -370,14 +492,81 @@ async fn handle_network(
         match reply {
           ClientPollReply::Nothing => continue,
           ClientPollReply::DelayedError(msg) => ERRORS.write().await.push(format!("{:?}", msg)),
-          ClientPollReply::Message { src, content } => {
+          ClientPollReply::Notice(notice) => {
+            ERRORS.write().await.push(format!("notice: {}", notice))
+          }
+          ClientPollReply::Dropped { count } => ERRORS
+            .write()
+            .await
+            .push(format!("mailbox full, {} messages were lost", count)),
+          ClientPollReply::Error(rr) => ERRORS.write().await.push(format!("poll failed: {}", rr)),
+          ClientPollReply::Message { src, id, content } => {
             let uinfo = lk.userlist.entry(src).or_default();
-            uinfo.messages.push((Source::Other, content));
+            uinfo.messages.push((Source::Other, Some(id), content));
             if selected != Some(src) {
               uinfo.unread += 1;
             }
           }
+          ClientPollReply::Edited { src, id, content } => {
+            if let Some(uinfo) = lk.userlist.get_mut(&src) {
+              for m in uinfo.messages.iter_mut() {
+                if m.0 == Source::Other && m.1 == Some(id) {
+                  m.2 = content.clone();
+                }
+              }
+            }
+          }
+          ClientPollReply::Deleted { src, id } => {
+            if let Some(uinfo) = lk.userlist.get_mut(&src) {
+              uinfo
+                .messages
+                .retain(|m| !(m.0 == Source::Other && m.1 == Some(id)));
+            }
+          }
+          ClientPollReply::Attachment { src, id, meta } => {
+            let uinfo = lk.userlist.entry(src).or_default();
+            uinfo.messages.push((
+              Source::Other,
+              Some(id),
+              format!(
+                "[{}, {} bytes, /save {} <path>]",
+                meta.name,
+                meta.size,
+                u128::from(&id)
+              ),
+            ));
+            uinfo.attachments.insert(id, meta);
+            if selected != Some(src) {
+              uinfo.unread += 1;
+            }
+          }
+        }
+      }
+      Command::Select(address) => {
+        // the server also knows the users that are not listed yet
+        let msg = client.sequence(ClientQuery::Resolve(address.clone()));
+        network.send(&msg).await?;
+        let c = match network.get(decode::resolve_reply).await? {
+          Ok(c) => c,
+          Err(rr) => {
+            ERRORS
+              .write()
+              .await
+              .push(format!("could not find {}: {}", address, rr));
+            continue;
+          }
+        };
+        let mut lk = USERS.write().await;
+        let uinfo = lk.userlist.entry(c).or_insert_with(|| UserInfo {
+          active: true,
+          name: address.to_string(),
+          ..Default::default()
+        });
+        uinfo.unread = 0;
+        if !lk.sorted.contains(&c) {
+          lk.sorted.push(c);
         }
+        lk.selected = Some(c);
       }
       Command::SendMessage { message } => {
         let mut lk = USERS.write().await;
@@ -391,29 +580,217 @@ async fn handle_network(
             continue;
           }
         };
-        lk.userlist
-          .entry(target)
-          .or_default()
-          .messages
-          .push((Source::Me, message.clone()));
+        let uinfo = lk.userlist.entry(target).or_default();
+        uinfo.messages.push((Source::Me, None, message.clone()));
+        let pos = uinfo.messages.len() - 1;
         let msg = client.sequence(ClientQuery::Message(ClientMessage::Text {
           dest: target,
           content: message,
         }));
         network.send(&msg).await?;
         let repls = network.get(decode::client_replies).await?;
-        for repl in repls {
-          match repl {
-            ClientReply::Delivered => (),
-            ClientReply::Delayed => ERRORS
+        if let Some(id) = check_replies(target, repls).await {
+          uinfo.messages[pos].1 = Some(id);
+        }
+      }
+      Command::EditMessage { message } => {
+        let mut lk = USERS.write().await;
+        let (target, pos, id) = match last_sent(&lk).await {
+          Some(x) => x,
+          None => continue,
+        };
+        let msg = client.sequence(ClientQuery::Message(ClientMessage::Edit {
+          dest: vec![target],
+          id,
+          content: message.clone(),
+        }));
+        network.send(&msg).await?;
+        let repls = network.get(decode::client_replies).await?;
+        if check_replies(target, repls).await.is_some() {
+          if let Some(uinfo) = lk.userlist.get_mut(&target) {
+            uinfo.messages[pos].2 = message;
+          }
+        }
+      }
+      Command::FetchHistory => {
+        let mut lk = USERS.write().await;
+        let target = match lk.selected {
+          Some(t) => t,
+          None => continue,
+        };
+        let uinfo = lk.userlist.entry(target).or_default();
+        if uinfo.history_complete {
+          continue;
+        }
+        let before = uinfo
+          .messages
+          .iter()
+          .find_map(|m| m.1)
+          .map(HistoryBound::Id)
+          .unwrap_or(HistoryBound::Latest);
+        let msg = client.sequence(ClientQuery::History(HistoryQuery {
+          with: target,
+          before,
+          limit: HISTORY_PAGE_SIZE,
+        }));
+        network.send(&msg).await?;
+        let page = match network.get(decode::history_reply).await? {
+          Ok(page) => page,
+          Err(rr) => {
+            ERRORS.write().await.push(format!("history failed: {}", rr));
+            continue;
+          }
+        };
+        uinfo.history_complete = !page.more;
+        let known = uinfo
+          .messages
+          .iter()
+          .filter_map(|m| m.1)
+          .collect::<HashSet<_>>();
+        let older = page
+          .entries
+          .into_iter()
+          .filter(|e| !known.contains(&e.id))
+          .map(|e| {
+            let source = if e.src == client.id() {
+              Source::Me
+            } else {
+              Source::Other
+            };
+            (source, Some(e.id), e.content)
+          })
+          .collect::<Vec<_>>();
+        uinfo.messages.splice(0..0, older);
+      }
+      Command::SendFile { path } => {
+        let mut lk = USERS.write().await;
+        let target = match lk.selected.as_ref() {
+          Some(t) => *t,
+          None => {
+            ERRORS
               .write()
               .await
-              .push(format!("message to {} delayed ...", target)),
-            ClientReply::Error(rr) => ERRORS
+              .push("Can't send a file with no selected users!".to_string());
+            continue;
+          }
+        };
+        let data = match async_std::fs::read(&path).await {
+          Ok(d) => d,
+          Err(rr) => {
+            ERRORS.write().await.push(format!("{}: {}", path, rr));
+            continue;
+          }
+        };
+        let meta = AttachmentMeta {
+          name: std::path::Path::new(&path)
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or(path),
+          size: data.len() as u64,
+          hash: attachments::content_hash(&data),
+        };
+        let mut uploaded = true;
+        for chunk in attachments::chunks(&data) {
+          let msg = client.sequence(ClientQuery::Upload(chunk));
+          network.send(&msg).await?;
+          if let Err(rr) = network.get(decode::upload_reply).await? {
+            ERRORS
+              .write()
+              .await
+              .push(format!("upload of {}: {}", meta.name, rr));
+            uploaded = false;
+            break;
+          }
+        }
+        if !uploaded {
+          continue;
+        }
+        let description = format!("[{}, {} bytes]", meta.name, meta.size);
+        let msg = client.sequence(ClientQuery::Message(ClientMessage::Attachment {
+          dest: vec![target],
+          meta,
+        }));
+        network.send(&msg).await?;
+        let repls = network.get(decode::client_replies).await?;
+        if check_replies(target, repls).await.is_some() {
+          lk.userlist
+            .entry(target)
+            .or_default()
+            .messages
+            .push((Source::Me, None, description));
+        }
+      }
+      Command::SaveFile { id, path } => {
+        let lk = USERS.read().await;
+        let found = lk.selected.and_then(|src| {
+          let meta = lk.userlist.get(&src)?.attachments.get(&id)?;
+          Some((src, meta.clone()))
+        });
+        drop(lk);
+        let (src, meta) = match found {
+          Some(x) => x,
+          None => {
+            ERRORS
               .write()
               .await
-              .push(format!("message to {}: {}", target, rr)),
-            ClientReply::Transfer(_, _) => todo!(),
+              .push(format!("no attachment {} from the selected user", id));
+            continue;
+          }
+        };
+        let mut data = Vec::new();
+        while (data.len() as u64) < meta.size {
+          let msg = client.sequence(ClientQuery::Download {
+            src,
+            hash: meta.hash,
+            offset: data.len() as u64,
+          });
+          network.send(&msg).await?;
+          match network.get(decode::download_reply).await? {
+            Ok(chunk) if !chunk.data.is_empty() => data.extend(chunk.data),
+            Ok(_) => break,
+            Err(rr) => {
+              ERRORS
+                .write()
+                .await
+                .push(format!("download of {}: {}", meta.name, rr));
+              break;
+            }
+          }
+        }
+        if attachments::content_hash(&data) != meta.hash {
+          ERRORS
+            .write()
+            .await
+            .push(format!("download of {} failed", meta.name));
+          continue;
+        }
+        if let Err(rr) = async_std::fs::write(&path, data).await {
+          ERRORS.write().await.push(format!("{}: {}", path, rr));
+        }
+      }
+      Command::AdminSelected(command) => {
+        let selected = USERS.read().await.selected;
+        match selected {
+          Some(target) => admin(&mut client, &network, command(target)).await?,
+          None => ERRORS.write().await.push("no selected user".to_string()),
+        }
+      }
+      Command::Admin(command) => admin(&mut client, &network, command).await?,
+      Command::DeleteMessage => {
+        let mut lk = USERS.write().await;
+        let (target, pos, id) = match last_sent(&lk).await {
+          Some(x) => x,
+          None => continue,
+        };
+        let msg = client.sequence(ClientQuery::Message(ClientMessage::Delete {
+          dest: vec![target],
+          id,
+        }));
+        network.send(&msg).await?;
+        let repls = network.get(decode::client_replies).await?;
+        if check_replies(target, repls).await.is_some() {
+          if let Some(uinfo) = lk.userlist.get_mut(&target) {
+            uinfo.messages.remove(pos);
           }
         }
       }
@@ -423,6 +800,68 @@ async fn handle_network(
   Ok(())
 }
 
+/// sends an administration command, and reports its outcome
+async fn admin(
+  client: &mut Client,
+  network: &Network,
+  command: AdminCommand,
+) -> anyhow::Result<()> {
+  let msg = client.sequence(ClientQuery::Admin(command.clone()));
+  network.send(&msg).await?;
+  let outcome = match network.get(decode::admin_reply).await? {
+    Ok(AdminReply::Done) => format!("{:?}: done", command),
+    Ok(AdminReply::Depth(depth)) => format!("{:?}: {} unread messages", command, depth),
+    Ok(AdminReply::Reached(count)) => format!("{:?}: sent to {} users", command, count),
+    Err(rr) => format!("{:?}: {}", command, rr),
+  };
+  ERRORS.write().await.push(outcome);
+  Ok(())
+}
+
+/// reports errors, and returns the message id if it was delivered
+async fn check_replies(target: ClientId, repls: Vec<ClientReply>) -> Option<MessageId> {
+  let mut delivered = None;
+  for repl in repls {
+    match repl {
+      ClientReply::Delivered(id) => delivered = Some(id),
+      ClientReply::Delayed => ERRORS
+        .write()
+        .await
+        .push(format!("message to {} delayed ...", target)),
+      ClientReply::Error(rr) => ERRORS
+        .write()
+        .await
+        .push(format!("message to {}: {}", target, rr)),
+      // relayed by the server to another one, that does not give a message id back
+      ClientReply::Transfer(_, _) => (),
+    }
+  }
+  delivered
+}
+
+/// finds the last delivered message sent to the selected user, with its position in the history
+async fn last_sent(users: &Users) -> Option<(ClientId, usize, MessageId)> {
+  let found = users.selected.and_then(|target| {
+    let uinfo = users.userlist.get(&target)?;
+    uinfo
+      .messages
+      .iter()
+      .enumerate()
+      .rev()
+      .find_map(|(pos, m)| match m {
+        (Source::Me, Some(id), _) => Some((target, pos, *id)),
+        _ => None,
+      })
+  });
+  if found.is_none() {
+    ERRORS
+      .write()
+      .await
+      .push("No sent message to the selected user!".to_string());
+  }
+  found
+}
+
 fn main() -> anyhow::Result<()> {
   async_std::task::block_on(async { main_task().await })
 }
@@ -0,0 +1,196 @@
+//! the local admin socket
+//!
+//! Operators connect to a unix socket, and send one command per line. Commands are run as
+//! `Operator::Console`, that is always allowed, and each one is answered with lines of text, the last one
+//! being `done`, `depth <n>`, `reached <n>` or `error: <reason>`. Anyone who can open the socket is an
+//! admin, its permissions are the ones of the file.
+
+use async_std::io::{prelude::*, BufReader};
+use async_std::os::unix::net::{UnixListener, UnixStream};
+use async_std::stream::StreamExt;
+use chatproto::admin::Operator;
+use chatproto::core::MessageServer;
+use chatproto::messages::{AdminCommand, AdminReply, ClientId};
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::Arc;
+
+/// a line sent to the console
+#[derive(Debug, PartialEq, Eq)]
+enum Request {
+  /// lists the users, with the ids the other commands take
+  Users,
+  Admin(AdminCommand),
+}
+
+fn client(arg: Option<&str>) -> anyhow::Result<ClientId> {
+  let arg = arg.ok_or_else(|| anyhow::anyhow!("expected a client uuid"))?;
+  Ok(uuid::Uuid::parse_str(arg)?.into())
+}
+
+/// the uuid of a client, as the commands take it
+fn uuid(id: &ClientId) -> uuid::Uuid {
+  uuid::Uuid::from_u128_le(id.into())
+}
+
+fn address(arg: Option<&str>) -> anyhow::Result<IpAddr> {
+  let arg = arg.ok_or_else(|| anyhow::anyhow!("expected an address"))?;
+  Ok(arg.parse()?)
+}
+
+fn parse(line: &str) -> anyhow::Result<Request> {
+  let line = line.trim();
+  let (command, rest) = line.split_once(' ').unwrap_or((line, ""));
+  let mut args = rest.split_whitespace();
+  let admin = match command {
+    "users" => return Ok(Request::Users),
+    "ban" => AdminCommand::Ban(client(args.next())?),
+    "unban" => AdminCommand::Unban(client(args.next())?),
+    "ban-address" => AdminCommand::BanAddress(address(args.next())?),
+    "unban-address" => AdminCommand::UnbanAddress(address(args.next())?),
+    "kick" => AdminCommand::Kick(client(args.next())?),
+    "broadcast" if !rest.trim().is_empty() => AdminCommand::Broadcast(rest.trim().to_string()),
+    "depth" => AdminCommand::MailboxDepth(client(args.next())?),
+    "grant" => AdminCommand::Grant(client(args.next())?),
+    "revoke" => AdminCommand::Revoke(client(args.next())?),
+    "role" => AdminCommand::AssignRole {
+      client: client(args.next())?,
+      role: args
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("expected a role"))?
+        .to_string(),
+    },
+    _ => anyhow::bail!("unknown command: {}", line),
+  };
+  Ok(Request::Admin(admin))
+}
+
+/// runs a line, and returns the answer
+pub async fn run<M: MessageServer>(server: &M, line: &str) -> String {
+  let request = match parse(line) {
+    Ok(r) => r,
+    Err(rr) => return format!("error: {}", rr),
+  };
+  match request {
+    Request::Users => {
+      let mut users = server
+        .list_addresses()
+        .await
+        .into_iter()
+        .map(|(id, address)| format!("{} {}\n", uuid(&id), address))
+        .collect::<Vec<_>>();
+      users.sort();
+      users.concat() + "done"
+    }
+    Request::Admin(command) => match server.admin(Operator::Console, command).await {
+      Ok(AdminReply::Done) => "done".to_string(),
+      Ok(AdminReply::Depth(n)) => format!("depth {}", n),
+      Ok(AdminReply::Reached(n)) => format!("reached {}", n),
+      Err(rr) => format!("error: {}", rr),
+    },
+  }
+}
+
+async fn session<M: MessageServer>(server: &M, stream: UnixStream) -> anyhow::Result<()> {
+  let mut lines = BufReader::new(stream.clone()).lines();
+  let mut stream = stream;
+  while let Some(line) = lines.next().await {
+    let line = line?;
+    if line.trim().is_empty() {
+      continue;
+    }
+    let answer = run(server, &line).await;
+    stream.write_all(format!("{}\n", answer).as_bytes()).await?;
+  }
+  Ok(())
+}
+
+/// accepts the operators on the socket, a stale socket file is replaced
+pub async fn listen<M: MessageServer + Send + Sync + 'static>(
+  server: Arc<M>,
+  path: &Path,
+) -> anyhow::Result<()> {
+  if path.exists() {
+    std::fs::remove_file(path)?;
+  }
+  let listener = UnixListener::bind(path).await?;
+  log::info!("admin console on {}", path.display());
+  let mut incoming = listener.incoming();
+  while let Some(stream) = incoming.next().await {
+    let stream = stream?;
+    let server = server.clone();
+    async_std::task::spawn(async move {
+      if let Err(rr) = session(&*server, stream).await {
+        log::warn!("admin console session failed: {}", rr);
+      }
+    });
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod test {
+  use chatproto::core::ServerConfig;
+  use chatproto::messages::{ClientPollReply, ServerId};
+  use chatproto::solutions::reference::Server;
+
+  use super::*;
+
+  #[test]
+  fn parse_commands() {
+    let id = uuid::Uuid::new_v4();
+    assert_eq!(
+      parse(&format!("grant {}", id)).unwrap(),
+      Request::Admin(AdminCommand::Grant(id.into()))
+    );
+    assert_eq!(
+      parse("broadcast  maintenance at 5 ").unwrap(),
+      Request::Admin(AdminCommand::Broadcast("maintenance at 5".into()))
+    );
+    assert_eq!(
+      parse(&format!("role {} moderator", id)).unwrap(),
+      Request::Admin(AdminCommand::AssignRole {
+        client: id.into(),
+        role: "moderator".into()
+      })
+    );
+    assert!(parse("ban nobody").is_err());
+    assert!(parse("broadcast").is_err());
+    assert!(parse("reboot").is_err());
+  }
+
+  #[async_std::test]
+  async fn console_commands() {
+    let server = Server::with_config(
+      ServerId::default(),
+      ServerConfig {
+        name: Some("paris".into()),
+        ..Default::default()
+      },
+    );
+    let c1 = server.register_local_client("alice".into()).await;
+    assert_eq!(
+      run(&server, "users").await,
+      format!("{} alice@paris\ndone", uuid(&c1))
+    );
+    assert_eq!(run(&server, "broadcast hello").await, "reached 1");
+    assert_eq!(
+      server.client_poll(c1).await,
+      ClientPollReply::Notice("hello".into())
+    );
+    assert_eq!(run(&server, &format!("grant {}", uuid(&c1))).await, "done");
+    assert_eq!(
+      server
+        .admin(Operator::Client(c1), AdminCommand::MailboxDepth(c1))
+        .await,
+      Ok(AdminReply::Depth(0))
+    );
+    assert_eq!(
+      run(&server, &format!("depth {}", uuid::Uuid::new_v4())).await,
+      "error: UnknownClient"
+    );
+    let audit = server.audit_log().await;
+    assert_eq!(audit.len(), 4);
+    assert!(audit[..2].iter().all(|e| e.by == Operator::Console));
+  }
+}
@@ -0,0 +1,836 @@
+use async_std::channel::{self, Receiver, Sender, TrySendError};
+#[cfg(feature = "federation")]
+use async_std::net::TcpListener;
+use async_std::net::UdpSocket;
+use chatproto::admin::Operator;
+use chatproto::core::{MessageServer, ServerConfig, WORKPROOF_STRENGTH};
+#[cfg(feature = "federation")]
+use chatproto::federation::{FederationPolicy, PeerList};
+use chatproto::filter::{BannedWords, MaxLength, MessageFilter, Spam, StripLinks};
+use chatproto::history::Retention;
+use chatproto::mailbox::{MailboxQuota, OverflowPolicy};
+use chatproto::messages::{
+  Address, AdminReply, Chunk, ClientError, ClientId, ClientPollReply, ClientQuery, ClientReply,
+  HistoryPage, Sequence, ServerId, ServerMessage,
+};
+use chatproto::netproto::{decode, encode};
+use chatproto::roles::RoleConfig;
+#[cfg(feature = "federation")]
+use chatproto::signing::{self, SigningKey, VerifyingKey};
+use chatproto::solutions::reference::Server;
+use chatproto::workproof::verify_workproof;
+use std::collections::HashMap;
+#[cfg(feature = "federation")]
+use std::collections::HashSet;
+use std::future::Future;
+use std::io::Cursor;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+#[cfg(feature = "federation")]
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use structopt::StructOpt;
+
+#[cfg(unix)]
+mod console;
+#[cfg(feature = "federation")]
+mod peers;
+
+#[derive(StructOpt)]
+struct Opt {
+  #[structopt(long, default_value = "4666")]
+  /// port to listen on
+  port: u16,
+
+  #[structopt(long, default_value = "127.0.0.1")]
+  /// address to listen on
+  host: IpAddr,
+
+  #[structopt(long)]
+  /// uuid of this server, random if not given
+  id: Option<uuid::Uuid>,
+
+  #[structopt(long, parse(try_from_str = server_name))]
+  /// name of this server in the addresses of its users, name@server, its uuid if not given
+  name: Option<String>,
+
+  #[structopt(long)]
+  /// reject the messages longer than this number of characters
+  max_length: Option<usize>,
+
+  #[structopt(long = "banned-word")]
+  /// reject the messages containing this word, ignoring case, can be repeated
+  banned_words: Vec<String>,
+
+  #[structopt(long)]
+  /// replace the links of the messages with a placeholder
+  strip_links: bool,
+
+  #[structopt(long)]
+  /// reject the messages sent more than this number of times in a row, and the ones to more than
+  /// --spam-max-recipients users
+  spam_max_repeats: Option<usize>,
+
+  #[structopt(long, default_value = "64")]
+  /// with --spam-max-repeats, maximum number of recipients of a message
+  spam_max_recipients: usize,
+
+  #[structopt(long, default_value = "60")]
+  /// seconds within which identical messages count as repeats
+  spam_window: u64,
+
+  #[structopt(long)]
+  /// maximum number of messages in a mailbox
+  mailbox_messages: Option<usize>,
+
+  #[structopt(long)]
+  /// maximum total size of the messages in a mailbox
+  mailbox_bytes: Option<usize>,
+
+  #[structopt(long, default_value = "reject", parse(try_from_str = overflow))]
+  /// what happens to messages sent to a full mailbox: reject, drop-oldest, or spill:<directory> to
+  /// store them in files in the directory
+  overflow: Overflow,
+
+  #[structopt(long, default_value = "16777216")]
+  /// with --overflow spill, maximum size of the file of a mailbox
+  spill_max_bytes: u64,
+
+  #[structopt(long)]
+  /// maximum number of messages kept in the history of a conversation
+  history_messages: Option<usize>,
+
+  #[structopt(long)]
+  /// seconds after which messages are forgotten from the history
+  history_max_age: Option<u64>,
+
+  #[structopt(long)]
+  /// JSON file with the roles, their permissions, and the roles of clients and addresses, see roles.rs
+  roles: Option<PathBuf>,
+
+  #[cfg(unix)]
+  #[structopt(long)]
+  /// unix socket that takes admin commands, one per line, see console.rs
+  admin_socket: Option<PathBuf>,
+
+  #[cfg(feature = "federation")]
+  #[structopt(long, default_value = "4667")]
+  /// TCP port the neighbours connect to
+  peer_port: u16,
+
+  #[cfg(feature = "federation")]
+  #[structopt(long = "neighbour")]
+  /// a neighbour server, as <server uuid>@<address>:<peer port>, can be repeated
+  neighbours: Vec<peers::Neighbour>,
+
+  #[cfg(feature = "federation")]
+  #[structopt(long, default_value = "10")]
+  /// seconds between announces, routes that are not announced for three times that are forgotten
+  announce_interval: u64,
+
+  #[cfg(feature = "federation")]
+  #[structopt(long = "allow-server", conflicts_with = "denied-servers")]
+  /// only accept this server and the neighbours, can be repeated
+  allowed_servers: Vec<uuid::Uuid>,
+
+  #[cfg(feature = "federation")]
+  #[structopt(long = "deny-server")]
+  /// do not accept this server, can be repeated
+  denied_servers: Vec<uuid::Uuid>,
+
+  #[cfg(feature = "federation")]
+  #[structopt(long)]
+  /// maximum number of clients another server can announce
+  max_remote_clients: Option<usize>,
+
+  #[cfg(feature = "federation")]
+  #[structopt(long)]
+  /// file with the secret key this server signs with, in hex, created with a new key if missing
+  key: Option<PathBuf>,
+
+  #[cfg(feature = "federation")]
+  #[structopt(long = "trust")]
+  /// public key of a server, as <server uuid>=<key in hex>, its announces and messages must be signed with it
+  trusted: Vec<TrustedKey>,
+
+  #[cfg(feature = "federation")]
+  #[structopt(long)]
+  /// reject the announces and messages of the servers that are not trusted
+  require_signatures: bool,
+}
+
+/// a trusted server, written `<server uuid>=<public key in hex>` on the command line
+#[cfg(feature = "federation")]
+struct TrustedKey {
+  id: ServerId,
+  key: VerifyingKey,
+}
+
+#[cfg(feature = "federation")]
+impl FromStr for TrustedKey {
+  type Err = anyhow::Error;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let (id, key) = s
+      .split_once('=')
+      .ok_or_else(|| anyhow::anyhow!("expected <server uuid>=<public key>, got {}", s))?;
+    Ok(TrustedKey {
+      id: uuid::Uuid::parse_str(id)?.into(),
+      key: signing::parse_public_key(key)?,
+    })
+  }
+}
+
+/// the key in the file, or a new one that is written to it
+#[cfg(feature = "federation")]
+fn load_key(path: &PathBuf) -> anyhow::Result<SigningKey> {
+  if !path.exists() {
+    let key = signing::generate();
+    std::fs::write(path, signing::to_hex(&key.to_bytes()))?;
+    log::info!("wrote a new key to {}", path.display());
+    return Ok(key);
+  }
+  let bytes: [u8; 32] = signing::from_hex(std::fs::read_to_string(path)?.trim())?
+    .try_into()
+    .map_err(|_| anyhow::anyhow!("a secret key is 32 bytes, in {}", path.display()))?;
+  Ok(SigningKey::from_bytes(&bytes))
+}
+
+/// the '@' separates the name of a user from the name of its server
+fn server_name(s: &str) -> anyhow::Result<String> {
+  anyhow::ensure!(
+    !s.is_empty() && !s.contains('@'),
+    "a server name is not empty, and has no '@'"
+  );
+  Ok(s.to_string())
+}
+
+/// the overflow policy, without the limit of the spill files that has its own option
+#[derive(Debug)]
+enum Overflow {
+  Reject,
+  DropOldest,
+  Spill(PathBuf),
+}
+
+fn overflow(s: &str) -> anyhow::Result<Overflow> {
+  Ok(match s {
+    "reject" => Overflow::Reject,
+    "drop-oldest" => Overflow::DropOldest,
+    _ => match s.strip_prefix("spill:") {
+      Some(dir) if !dir.is_empty() => Overflow::Spill(dir.into()),
+      _ => anyhow::bail!(
+        "expected reject, drop-oldest or spill:<directory>, got {}",
+        s
+      ),
+    },
+  })
+}
+
+/// the mailbox limits given on the command line
+fn mailbox(opt: &Opt) -> (MailboxQuota, OverflowPolicy) {
+  let default = MailboxQuota::default();
+  let quota = MailboxQuota {
+    max_messages: opt.mailbox_messages.unwrap_or(default.max_messages),
+    max_bytes: opt.mailbox_bytes.unwrap_or(default.max_bytes),
+  };
+  let policy = match &opt.overflow {
+    Overflow::Reject => OverflowPolicy::Reject,
+    Overflow::DropOldest => OverflowPolicy::DropOldest,
+    Overflow::Spill(dir) => OverflowPolicy::Spill {
+      dir: dir.clone(),
+      max_bytes: opt.spill_max_bytes,
+    },
+  };
+  (quota, policy)
+}
+
+/// the filters given on the command line, links are stripped before the other filters see the message
+fn filters(opt: &Opt) -> Vec<Arc<dyn MessageFilter>> {
+  let mut filters: Vec<Arc<dyn MessageFilter>> = Vec::new();
+  if opt.strip_links {
+    filters.push(Arc::new(StripLinks));
+  }
+  if let Some(max) = opt.max_length {
+    filters.push(Arc::new(MaxLength(max)));
+  }
+  if !opt.banned_words.is_empty() {
+    filters.push(Arc::new(BannedWords(opt.banned_words.clone())));
+  }
+  if let Some(max_repeats) = opt.spam_max_repeats {
+    filters.push(Arc::new(Spam::new(
+      opt.spam_max_recipients,
+      max_repeats,
+      Duration::from_secs(opt.spam_window),
+    )));
+  }
+  filters
+}
+
+/// the servers given on the command line, allowed servers include the neighbours
+#[cfg(feature = "federation")]
+fn federation_policy(opt: &Opt) -> FederationPolicy {
+  let servers = |ids: &[uuid::Uuid]| ids.iter().map(|id| ServerId::from(*id)).collect();
+  let peers = if !opt.allowed_servers.is_empty() {
+    let mut allowed: HashSet<ServerId> = servers(&opt.allowed_servers);
+    allowed.extend(opt.neighbours.iter().map(|n| n.id));
+    PeerList::Allow(allowed)
+  } else if !opt.denied_servers.is_empty() {
+    PeerList::Deny(servers(&opt.denied_servers))
+  } else {
+    PeerList::Any
+  };
+  FederationPolicy {
+    peers,
+    max_clients: opt.max_remote_clients,
+    trusted: opt.trusted.iter().map(|t| (t.id, t.key)).collect(),
+    require_signatures: opt.require_signatures,
+    ..Default::default()
+  }
+}
+
+/// hands a message for a client of another server to the link with the next hop
+type Transfer = Arc<dyn Fn(ServerId, ServerMessage) + Send + Sync>;
+
+/// what the datagrams are handled with, besides the server
+struct Context {
+  transfer: Transfer,
+}
+
+impl Context {
+  fn new(transfer: Transfer) -> Self {
+    Context { transfer }
+  }
+}
+
+/// the answer to a query, before it is encoded
+#[derive(Debug, PartialEq)]
+enum Answer {
+  Registered(ClientId),
+  Replies(Vec<ClientReply>),
+  Poll(ClientPollReply),
+  Users(Result<HashMap<ClientId, String>, ClientError>),
+  Addresses(Result<HashMap<ClientId, Address>, ClientError>),
+  Upload(Result<u64, ClientError>),
+  Download(Result<Chunk, ClientError>),
+  History(Result<HistoryPage, ClientError>),
+  Resolve(Result<ClientId, ClientError>),
+  Admin(Result<AdminReply, ClientError>),
+}
+
+impl Answer {
+  /// the answer to a query that was refused with rr
+  fn refused(query: &ClientQuery, rr: ClientError) -> Self {
+    match query {
+      ClientQuery::Register(_) => unreachable!(),
+      ClientQuery::Message(_) => Answer::Replies(vec![ClientReply::Error(rr)]),
+      ClientQuery::Poll => Answer::Poll(ClientPollReply::Error(rr)),
+      ClientQuery::ListUsers => Answer::Users(Err(rr)),
+      ClientQuery::ListAddresses => Answer::Addresses(Err(rr)),
+      ClientQuery::Upload(_) => Answer::Upload(Err(rr)),
+      ClientQuery::Download { .. } => Answer::Download(Err(rr)),
+      ClientQuery::History(_) => Answer::History(Err(rr)),
+      ClientQuery::Resolve(_) => Answer::Resolve(Err(rr)),
+      ClientQuery::Admin(_) => Answer::Admin(Err(rr)),
+    }
+  }
+
+  fn encode(&self) -> anyhow::Result<Vec<u8>> {
+    let mut out = Cursor::new(Vec::new());
+    match self {
+      Answer::Registered(id) => encode::clientid(&mut out, id)?,
+      Answer::Replies(replies) => encode::client_replies(&mut out, replies)?,
+      Answer::Poll(reply) => encode::client_poll_reply(&mut out, reply)?,
+      Answer::Users(users) => encode::userlist_reply(&mut out, users)?,
+      Answer::Addresses(addresses) => encode::addresses_reply(&mut out, addresses)?,
+      Answer::Upload(received) => encode::upload_reply(&mut out, received)?,
+      Answer::Download(chunk) => encode::download_reply(&mut out, chunk)?,
+      Answer::History(page) => encode::history_reply(&mut out, page)?,
+      Answer::Resolve(found) => encode::resolve_reply(&mut out, found)?,
+      Answer::Admin(reply) => encode::admin_reply(&mut out, reply)?,
+    }
+    Ok(out.into_inner())
+  }
+}
+
+/// decodes a datagram, and returns the encoded answer
+async fn handle<M: MessageServer>(
+  server: &M,
+  data: Vec<u8>,
+  addr: IpAddr,
+  ctx: &Context,
+) -> anyhow::Result<Vec<u8>> {
+  let sq = decode::sequence(&mut Cursor::new(data), decode::client_query)?;
+  respond(server, sq, addr, ctx).await?.encode()
+}
+
+/// answers a query
+/// messages for clients of other servers are handed to the transfer of the context
+async fn respond<M: MessageServer>(
+  server: &M,
+  sq: Sequence<ClientQuery>,
+  addr: IpAddr,
+  ctx: &Context,
+) -> anyhow::Result<Answer> {
+  let Sequence {
+    seqid,
+    src,
+    workproof,
+    content,
+  } = sq;
+
+  // clients are not known yet when they register
+  if let ClientQuery::Register(name) = content {
+    anyhow::ensure!(
+      verify_workproof((&src).into(), workproof, WORKPROOF_STRENGTH),
+      "bad workproof for a registration"
+    );
+    server.registration_permitted(addr).await?;
+    return Ok(Answer::Registered(server.register_local_client(name).await));
+  }
+
+  let checked = server
+    .handle_sequenced_message(Sequence {
+      seqid,
+      src,
+      workproof,
+      content: (),
+    })
+    .await;
+  if let Err(rr) = checked {
+    log::debug!("refusing a query from {}: {}", src, rr);
+    return Ok(Answer::refused(&content, rr));
+  }
+
+  Ok(match content {
+    ClientQuery::Register(_) => unreachable!(),
+    ClientQuery::Message(msg) => {
+      let replies = server.handle_client_message(src, msg).await;
+      for (n, reply) in replies.iter().enumerate() {
+        // destinations that share a next hop share the transfer
+        if let ClientReply::Transfer(nexthop, message) = reply {
+          if !replies[..n].contains(reply) {
+            (ctx.transfer)(*nexthop, message.clone());
+          }
+        }
+      }
+      Answer::Replies(replies)
+    }
+    ClientQuery::Poll => Answer::Poll(server.client_poll(src).await),
+    ClientQuery::ListUsers => Answer::Users(Ok(server.list_users().await)),
+    ClientQuery::ListAddresses => Answer::Addresses(Ok(server.list_addresses().await)),
+    ClientQuery::Upload(chunk) => Answer::Upload(server.upload_chunk(src, chunk).await),
+    ClientQuery::Download {
+      src: owner,
+      hash,
+      offset,
+    } => Answer::Download(server.download_chunk(src, owner, hash, offset).await),
+    ClientQuery::History(query) => Answer::History(Ok(server.history(src, query).await)),
+    ClientQuery::Admin(command) => {
+      Answer::Admin(server.admin(Operator::Client(src), command).await)
+    }
+    ClientQuery::Resolve(address) => {
+      let found = server.resolve(&address).await;
+      Answer::Resolve(found.ok_or(ClientError::UnknownClient))
+    }
+  })
+}
+
+fn main() -> anyhow::Result<()> {
+  async_std::task::block_on(async { main_task().await })
+}
+
+async fn main_task() -> anyhow::Result<()> {
+  pretty_env_logger::init();
+
+  let opt = Opt::from_args();
+  let id = opt.id.map(ServerId::from).unwrap_or_default();
+  let roles = match &opt.roles {
+    Some(path) => RoleConfig::from_json(&std::fs::read_to_string(path)?)
+      .map_err(|rr| anyhow::anyhow!("in {}: {}", path.display(), rr))?,
+    None => RoleConfig::default(),
+  };
+  let (mailbox, overflow) = mailbox(&opt);
+  let history = Retention {
+    max_messages: opt
+      .history_messages
+      .unwrap_or(Retention::default().max_messages),
+    max_age: opt.history_max_age.map(Duration::from_secs),
+  };
+  let config = ServerConfig {
+    name: opt.name.clone(),
+    filters: filters(&opt),
+    roles,
+    mailbox,
+    overflow,
+    history,
+    ..Default::default()
+  };
+  #[cfg(feature = "federation")]
+  let (config, auth) = {
+    let key = match &opt.key {
+      Some(path) => load_key(path)?,
+      None => signing::generate(),
+    };
+    log::info!(
+      "public key of {}: {}",
+      id,
+      signing::to_hex(key.verifying_key().as_bytes())
+    );
+    let federation = federation_policy(&opt);
+    // the links are authenticated with the keys that check the messages
+    let auth = peers::LinkAuth {
+      key: key.clone(),
+      trusted: federation.trusted.clone(),
+      require_signatures: federation.require_signatures,
+    };
+    let config = ServerConfig {
+      neighbours: opt.neighbours.iter().map(|n| n.id).collect(),
+      route_ttl: Some(Duration::from_secs(opt.announce_interval * 3)),
+      federation,
+      signing_key: Some(key),
+      ..config
+    };
+    (config, auth)
+  };
+  let server = Arc::new(Server::with_config(id, config));
+  #[cfg(unix)]
+  if let Some(path) = opt.admin_socket.clone() {
+    let s = server.clone();
+    async_std::task::spawn(async move {
+      if let Err(rr) = console::listen(s, &path).await {
+        log::error!("no admin console: {}", rr);
+      }
+    });
+  }
+  let socket = Arc::new(UdpSocket::bind(SocketAddr::from((opt.host, opt.port))).await?);
+  log::info!("server {} listening on {}", id, socket.local_addr()?);
+
+  #[cfg(feature = "federation")]
+  let peers = {
+    let peers = peers::Peers::start(server.clone(), id, opt.neighbours, auth);
+    let listener = TcpListener::bind(SocketAddr::from((opt.host, opt.peer_port))).await?;
+    log::info!("neighbours connect on {}", listener.local_addr()?);
+    let (p, s) = (peers.clone(), server.clone());
+    async_std::task::spawn(async move {
+      if let Err(rr) = peers::listen(p, s, listener).await {
+        log::error!("no longer accepting neighbours: {}", rr);
+      }
+    });
+    let every = Duration::from_secs(opt.announce_interval);
+    async_std::task::spawn(peers::announce(peers.clone(), server.clone(), every));
+    peers
+  };
+
+  #[cfg(feature = "federation")]
+  let transfer: Transfer = Arc::new(move |nexthop, message| peers.send(nexthop, message));
+  #[cfg(not(feature = "federation"))]
+  let transfer: Transfer =
+    Arc::new(|nexthop, _| log::warn!("no link to {}, dropping a transfer", nexthop));
+  serve(server, socket, Arc::new(Context::new(transfer))).await
+}
+
+/// how long the task of a source waits for its next datagram before it stops
+const SOURCE_IDLE: Duration = Duration::from_secs(30);
+/// datagrams of a source that can wait for their turn, the next ones are dropped
+const SOURCE_QUEUE: usize = 64;
+
+/// answers the datagrams of the clients
+/// the datagrams of a source are handled in order, by a task that stops when the source is idle
+async fn serve<M: MessageServer + Send + Sync + 'static>(
+  server: Arc<M>,
+  socket: Arc<UdpSocket>,
+  ctx: Arc<Context>,
+) -> anyhow::Result<()> {
+  let mut sources: HashMap<SocketAddr, Sender<Vec<u8>>> = HashMap::new();
+  let mut buf = vec![0u8; 65536];
+  loop {
+    let (n, addr) = socket.recv_from(&mut buf).await?;
+    if server.address_banned(addr.ip()).await {
+      log::debug!("ignoring a datagram from banned address {}", addr);
+      continue;
+    }
+    if !sources.contains_key(&addr) {
+      sources.retain(|_, queue| !queue.is_closed());
+    }
+    let mut data = buf[..n].to_vec();
+    loop {
+      let queue = sources.entry(addr).or_insert_with(|| {
+        let (tx, rx) = channel::bounded(SOURCE_QUEUE);
+        let (server, socket, ctx) = (server.clone(), socket.clone(), ctx.clone());
+        async_std::task::spawn(source(rx, move |data| {
+          let (server, socket, ctx) = (server.clone(), socket.clone(), ctx.clone());
+          async move { answer(&*server, &socket, &ctx, addr, data).await }
+        }));
+        tx
+      });
+      match queue.try_send(data) {
+        Ok(()) => break,
+        // the task stopped in the meantime
+        Err(TrySendError::Closed(d)) => {
+          sources.remove(&addr);
+          data = d;
+        }
+        Err(TrySendError::Full(_)) => {
+          log::warn!("too many queries from {}, dropping one", addr);
+          break;
+        }
+      }
+    }
+  }
+}
+
+/// handles the datagrams of a source one after the other, until it is idle
+async fn source<T, F, A>(rx: Receiver<T>, mut handle: F)
+where
+  F: FnMut(T) -> A,
+  A: Future<Output = ()>,
+{
+  while let Ok(Ok(data)) = async_std::future::timeout(SOURCE_IDLE, rx.recv()).await {
+    handle(data).await;
+  }
+  // the datagrams queued before the channel is closed are still answered
+  rx.close();
+  while let Ok(data) = rx.try_recv() {
+    handle(data).await;
+  }
+}
+
+async fn answer<M: MessageServer>(
+  server: &M,
+  socket: &UdpSocket,
+  ctx: &Context,
+  addr: SocketAddr,
+  data: Vec<u8>,
+) {
+  let answer = match handle(server, data, addr.ip(), ctx).await {
+    Ok(a) => a,
+    Err(rr) => {
+      log::warn!("bad query from {}: {}", addr, rr);
+      return;
+    }
+  };
+  if let Err(rr) = socket.send_to(&answer, addr).await {
+    log::error!("could not answer {}: {}", addr, rr);
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use chatproto::bot::{Bot, UdpTransport};
+  use chatproto::messages::{AdminCommand, ClientMessage, HistoryBound, HistoryQuery};
+  use chatproto::workproof::gen_workproof;
+  use std::sync::Mutex;
+
+  use super::*;
+
+  const LOCALHOST: [u8; 4] = [127, 0, 0, 1];
+
+  /// the tests over UDP need the netproto codec, that is left to implement in chatproto
+  fn codec_implemented() -> bool {
+    std::panic::catch_unwind(|| encode::u128(&mut Vec::new(), &0)).is_ok()
+  }
+
+  fn sequence(src: ClientId, seqid: u128, content: ClientQuery) -> Sequence<ClientQuery> {
+    Sequence {
+      seqid,
+      src,
+      workproof: gen_workproof((&src).into(), WORKPROOF_STRENGTH, u128::MAX).unwrap(),
+      content,
+    }
+  }
+
+  async fn register(server: &Server, ctx: &Context, name: &str) -> anyhow::Result<ClientId> {
+    let sq = sequence(
+      ClientId::default(),
+      0,
+      ClientQuery::Register(name.to_string()),
+    );
+    match respond(server, sq, LOCALHOST.into(), ctx).await? {
+      Answer::Registered(id) => Ok(id),
+      answer => anyhow::bail!("expected a client id, got {:?}", answer),
+    }
+  }
+
+  /// no name makes an admin, only a grant from the console does
+  #[async_std::test]
+  async fn bootstrap_admin() -> anyhow::Result<()> {
+    let server = Server::new(ServerId::default());
+    let ctx = Context::new(Arc::new(|_, _| ()));
+    let root = register(&server, &ctx, "root").await?;
+    let other = register(&server, &ctx, "root").await?;
+    let broadcast = |src, seqid| {
+      let command = AdminCommand::Broadcast("maintenance".to_string());
+      respond(
+        &server,
+        sequence(src, seqid, ClientQuery::Admin(command)),
+        LOCALHOST.into(),
+        &ctx,
+      )
+    };
+    let not_admin = Answer::Admin(Err(ClientError::NotAdmin));
+    assert_eq!(broadcast(root, 1).await?, not_admin);
+    server
+      .admin(Operator::Console, AdminCommand::Grant(root))
+      .await?;
+    assert_eq!(
+      broadcast(root, 2).await?,
+      Answer::Admin(Ok(AdminReply::Reached(2)))
+    );
+    assert_eq!(broadcast(other, 1).await?, not_admin);
+    Ok(())
+  }
+
+  /// a replayed query gets an error, whatever its kind
+  #[async_std::test]
+  async fn refused_queries() -> anyhow::Result<()> {
+    let server = Server::new(ServerId::default());
+    let ctx = Context::new(Arc::new(|_, _| ()));
+    let client = register(&server, &ctx, "user").await?;
+    let query = |seqid, content| {
+      respond(
+        &server,
+        sequence(client, seqid, content),
+        LOCALHOST.into(),
+        &ctx,
+      )
+    };
+    match query(2, ClientQuery::ListUsers).await? {
+      Answer::Users(Ok(users)) => assert_eq!(users.len(), 1),
+      answer => panic!("expected the users, got {:?}", answer),
+    }
+    let refused = ClientError::SequenceError;
+    assert_eq!(
+      query(2, ClientQuery::ListUsers).await?,
+      Answer::Users(Err(refused.clone()))
+    );
+    assert_eq!(
+      query(1, ClientQuery::ListAddresses).await?,
+      Answer::Addresses(Err(refused.clone()))
+    );
+    let history = HistoryQuery {
+      with: client,
+      before: HistoryBound::Latest,
+      limit: 10,
+    };
+    assert_eq!(
+      query(2, ClientQuery::History(history)).await?,
+      Answer::History(Err(refused.clone()))
+    );
+    assert_eq!(
+      query(2, ClientQuery::Poll).await?,
+      Answer::Poll(ClientPollReply::Error(refused.clone()))
+    );
+    let address = server.list_addresses().await[&client].clone();
+    assert_eq!(
+      query(3, ClientQuery::Resolve(address.clone())).await?,
+      Answer::Resolve(Ok(client))
+    );
+    assert_eq!(
+      query(3, ClientQuery::Resolve(address)).await?,
+      Answer::Resolve(Err(refused))
+    );
+    let nobody = "nobody@nowhere".parse().unwrap();
+    assert_eq!(
+      query(4, ClientQuery::Resolve(nobody)).await?,
+      Answer::Resolve(Err(ClientError::UnknownClient))
+    );
+    Ok(())
+  }
+
+  /// the queries of a source are handled in the order they were received
+  #[async_std::test]
+  async fn queries_in_order() -> anyhow::Result<()> {
+    let server = Server::new(ServerId::default());
+    let ctx = Context::new(Arc::new(|_, _| ()));
+    let me = server.register_local_client("user".to_string()).await;
+    let (tx, rx) = channel::bounded(SOURCE_QUEUE);
+    for seqid in 1..=20 {
+      let msg = ClientMessage::Text {
+        dest: me,
+        content: format!("{}", seqid),
+      };
+      tx.send(sequence(me, seqid, ClientQuery::Message(msg)))
+        .await?;
+    }
+    drop(tx);
+    let answers = Mutex::new(Vec::new());
+    source(rx, |sq| {
+      let (server, ctx, answers) = (&server, &ctx, &answers);
+      async move {
+        let answer = respond(server, sq, LOCALHOST.into(), ctx).await;
+        answers.lock().unwrap().push(answer);
+      }
+    })
+    .await;
+    let answers = answers.into_inner().unwrap();
+    assert_eq!(answers.len(), 20);
+    for answer in answers {
+      assert!(
+        matches!(&answer, Ok(Answer::Replies(r)) if matches!(r[..], [ClientReply::Delivered(_)])),
+        "expected the message to be delivered, got {:?}",
+        answer
+      );
+    }
+    for seqid in 1..=20 {
+      match server.client_poll(me).await {
+        ClientPollReply::Message { content, .. } => assert_eq!(content, format!("{}", seqid)),
+        reply => panic!("expected message {}, got {:?}", seqid, reply),
+      }
+    }
+    Ok(())
+  }
+
+  /// a bot that talks to the server over UDP registers again when the server forgets it
+  #[async_std::test]
+  async fn bot_recovers_after_restart() -> anyhow::Result<()> {
+    if !codec_implemented() {
+      eprintln!("skipped, the netproto codec is not implemented");
+      return Ok(());
+    }
+    let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await?);
+    let addr = socket.local_addr()?;
+    let ctx = Arc::new(Context::new(Arc::new(|_, _| ())));
+    let first = Arc::new(Server::new(ServerId::default()));
+    let task = async_std::task::spawn(serve(first, socket.clone(), ctx.clone()));
+    let mut bot = Bot::new("echo", UdpTransport::new(addr).await?)
+      .on_message(|_, content| Some(content.to_string()))
+      .poll_interval(Duration::from_millis(10));
+    let before = bot.connect().await?;
+    task.cancel().await;
+
+    // a new server on the same socket, that does not know the bot
+    let second = Arc::new(Server::new(ServerId::default()));
+    async_std::task::spawn(serve(second.clone(), socket, ctx));
+    async_std::task::spawn(async move { bot.run().await });
+    let user = second.register_local_client("user".to_string()).await;
+    async_std::future::timeout(Duration::from_secs(10), async {
+      let echo = loop {
+        let users = second.list_users().await;
+        match users.iter().find(|(_, name)| *name == "echo") {
+          Some((id, _)) => break *id,
+          None => async_std::task::sleep(Duration::from_millis(10)).await,
+        }
+      };
+      assert_ne!(echo, before);
+      let msg = ClientMessage::Text {
+        dest: echo,
+        content: "hello".to_string(),
+      };
+      second.handle_client_message(user, msg).await;
+      loop {
+        match second.client_poll(user).await {
+          ClientPollReply::Nothing => async_std::task::sleep(Duration::from_millis(10)).await,
+          ClientPollReply::Message { src, content, .. } => {
+            assert_eq!((src, content.as_str()), (echo, "hello"));
+            break;
+          }
+          reply => panic!("expected the echo, got {:?}", reply),
+        }
+      }
+    })
+    .await?;
+    Ok(())
+  }
+}
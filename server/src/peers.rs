@@ -0,0 +1,474 @@
+//! links to the neighbour servers
+//!
+//! Each server connects to the neighbours of its configuration and sends them its messages, and receives
+//! theirs on the connections they opened. On both, frames are a little endian u32 length followed by an
+//! encoded `ServerMessage`, except for the handshake: the server that connects sends its `ServerId`, the
+//! other one answers with a random challenge, and the server that connects signs it, see
+//! `signing::sign_link`. Neighbours with a trusted key must sign it with that key, the others must connect
+//! from the address of their configuration.
+
+use async_std::channel::{self, Receiver, Sender, TrySendError};
+use async_std::io::{prelude::*, Read, Write};
+use async_std::net::{TcpListener, TcpStream};
+use chatproto::core::MessageServer;
+use chatproto::messages::{ServerId, ServerMessage, ServerReply};
+use chatproto::netproto::{decode, encode};
+use chatproto::signing::{self, SigningKey, VerifyingKey};
+use std::collections::HashMap;
+use std::io::Cursor;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// messages waiting for a link, the others are dropped
+const QUEUE_SIZE: usize = 1024;
+/// larger frames are a protocol error
+const MAX_FRAME: usize = 16 * 1024 * 1024;
+const MIN_BACKOFF: Duration = Duration::from_millis(200);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+/// time a neighbour that connects has to answer the challenge
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// a neighbour, written `<server uuid>@<address>` on the command line
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Neighbour {
+  pub id: ServerId,
+  pub addr: SocketAddr,
+}
+
+impl FromStr for Neighbour {
+  type Err = anyhow::Error;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let (id, addr) = s
+      .split_once('@')
+      .ok_or_else(|| anyhow::anyhow!("expected <server uuid>@<address>, got {}", s))?;
+    Ok(Neighbour {
+      id: uuid::Uuid::parse_str(id)?.into(),
+      addr: addr.parse()?,
+    })
+  }
+}
+
+/// how the servers prove who they are when they connect
+pub struct LinkAuth {
+  /// the key this server signs the challenges of its neighbours with
+  pub key: SigningKey,
+  /// keys of the neighbours, the others are checked against the address they connect from
+  pub trusted: HashMap<ServerId, VerifyingKey>,
+  /// refuses the neighbours that do not have a trusted key
+  pub require_signatures: bool,
+}
+
+/// the queues of the messages to send to each neighbour
+pub struct Peers {
+  local: ServerId,
+  links: HashMap<ServerId, Sender<ServerMessage>>,
+  addrs: HashMap<ServerId, SocketAddr>,
+  auth: LinkAuth,
+}
+
+impl Peers {
+  fn new(
+    local: ServerId,
+    neighbours: &[Neighbour],
+    auth: LinkAuth,
+  ) -> (Self, Vec<Receiver<ServerMessage>>) {
+    let mut links = HashMap::new();
+    let mut queues = Vec::new();
+    for neighbour in neighbours {
+      let (tx, rx) = channel::bounded(QUEUE_SIZE);
+      links.insert(neighbour.id, tx);
+      queues.push(rx);
+    }
+    let addrs = neighbours.iter().map(|n| (n.id, n.addr)).collect();
+    let peers = Peers {
+      local,
+      links,
+      addrs,
+      auth,
+    };
+    (peers, queues)
+  }
+
+  /// starts a link to each neighbour, that connects again with a backoff when it goes down
+  pub fn start<M: MessageServer + Send + Sync + 'static>(
+    server: Arc<M>,
+    local: ServerId,
+    neighbours: Vec<Neighbour>,
+    auth: LinkAuth,
+  ) -> Arc<Self> {
+    let (peers, queues) = Peers::new(local, &neighbours, auth);
+    let peers = Arc::new(peers);
+    for (neighbour, queue) in neighbours.into_iter().zip(queues) {
+      async_std::task::spawn(link(peers.clone(), server.clone(), neighbour, queue));
+    }
+    peers
+  }
+
+  pub fn is_neighbour(&self, id: &ServerId) -> bool {
+    self.links.contains_key(id)
+  }
+
+  /// checks the answer of a neighbour to its challenge, or its address if it does not have a trusted key
+  fn check_link(
+    &self,
+    id: &ServerId,
+    from: IpAddr,
+    challenge: &[u8],
+    answer: &[u8],
+  ) -> anyhow::Result<()> {
+    let addr = self
+      .addrs
+      .get(id)
+      .ok_or_else(|| anyhow::anyhow!("{} is not a neighbour", id))?;
+    match self.auth.trusted.get(id) {
+      Some(key) => anyhow::ensure!(
+        signing::verify_link(key, challenge, id, &self.local, answer),
+        "{} did not sign the challenge with its trusted key",
+        id
+      ),
+      None => {
+        anyhow::ensure!(
+          !self.auth.require_signatures,
+          "{} does not have a trusted key",
+          id
+        );
+        anyhow::ensure!(
+          addr.ip() == from,
+          "{} connected from {}, not from {}",
+          id,
+          from,
+          addr.ip()
+        );
+      }
+    }
+    Ok(())
+  }
+
+  /// queues a message for a neighbour, it is dropped if the neighbour is unknown or too far behind
+  pub fn send(&self, nexthop: ServerId, message: ServerMessage) {
+    let link = match self.links.get(&nexthop) {
+      Some(l) => l,
+      None => {
+        log::warn!("{} is not a neighbour, dropping a message", nexthop);
+        return;
+      }
+    };
+    match link.try_send(message) {
+      Ok(()) => (),
+      Err(TrySendError::Full(_)) => {
+        log::warn!("the queue of {} is full, dropping a message", nexthop)
+      }
+      Err(TrySendError::Closed(_)) => log::error!("the link to {} is gone", nexthop),
+    }
+  }
+
+  /// sends the outgoing messages of a reply
+  pub fn forward(&self, reply: ServerReply) {
+    match reply {
+      ServerReply::Outgoing(outgoing) => {
+        for o in outgoing {
+          self.send(o.nexthop, o.message);
+        }
+      }
+      r => log::warn!("a server message was rejected: {:?}", r),
+    }
+  }
+}
+
+/// sends the periodic announces
+pub async fn announce<M: MessageServer>(peers: Arc<Peers>, server: Arc<M>, every: Duration) {
+  loop {
+    async_std::task::sleep(every).await;
+    for o in server.announces().await {
+      peers.send(o.nexthop, o.message);
+    }
+  }
+}
+
+/// accepts the connections of the neighbours, and handles the messages they send
+pub async fn listen<M: MessageServer + Send + Sync + 'static>(
+  peers: Arc<Peers>,
+  server: Arc<M>,
+  listener: TcpListener,
+) -> anyhow::Result<()> {
+  loop {
+    let (stream, addr) = listener.accept().await?;
+    let peers = peers.clone();
+    let server = server.clone();
+    async_std::task::spawn(async move {
+      if let Err(rr) = receive(&peers, &*server, stream, addr.ip()).await {
+        log::warn!("connection from {} closed: {}", addr, rr);
+      }
+    });
+  }
+}
+
+async fn receive<M: MessageServer>(
+  peers: &Peers,
+  server: &M,
+  mut stream: TcpStream,
+  from: IpAddr,
+) -> anyhow::Result<()> {
+  let id =
+    async_std::future::timeout(HANDSHAKE_TIMEOUT, authenticate(peers, &mut stream, from)).await??;
+  log::info!("{} connected", id);
+  loop {
+    let frame = read_frame(&mut stream).await?;
+    let message = decode::server(&mut Cursor::new(frame))?;
+    // only the server that lost a link hands its withdrawal without its own id, and it is unsigned
+    if matches!(&message, ServerMessage::Withdraw { route, .. } if route.len() < 2) {
+      log::warn!("{} sent a withdrawal without a signer, dropping it", id);
+      continue;
+    }
+    peers.forward(server.handle_server_message(message).await);
+  }
+}
+
+/// the neighbour that connected, once it answered the challenge
+async fn authenticate<S: Read + Write + Unpin>(
+  peers: &Peers,
+  stream: &mut S,
+  from: IpAddr,
+) -> anyhow::Result<ServerId> {
+  let hello = read_frame(stream).await?;
+  let id = decode::serverid(&mut Cursor::new(hello))?;
+  anyhow::ensure!(peers.is_neighbour(&id), "{} is not a neighbour", id);
+  let challenge = signing::challenge();
+  write_frame(stream, &challenge).await?;
+  let answer = read_frame(stream).await?;
+  peers.check_link(&id, from, &challenge, &answer)?;
+  Ok(id)
+}
+
+/// sends the id of this server to the neighbour, and signs its challenge
+async fn introduce<S: Read + Write + Unpin>(
+  peers: &Peers,
+  stream: &mut S,
+  neighbour: &ServerId,
+) -> anyhow::Result<()> {
+  let mut hello = Vec::new();
+  encode::serverid(&mut hello, &peers.local)?;
+  write_frame(stream, &hello).await?;
+  let challenge = read_frame(stream).await?;
+  let answer = signing::sign_link(&peers.auth.key, &challenge, &peers.local, neighbour);
+  write_frame(stream, &answer).await
+}
+
+/// keeps a connection to the neighbour, and sends it the queued messages
+async fn link<M: MessageServer>(
+  peers: Arc<Peers>,
+  server: Arc<M>,
+  neighbour: Neighbour,
+  queue: Receiver<ServerMessage>,
+) {
+  let mut backoff = MIN_BACKOFF;
+  // the message that was being sent when the connection broke, it is sent first on the next one
+  let mut pending = None;
+  loop {
+    let stream = match TcpStream::connect(neighbour.addr).await {
+      Ok(s) => s,
+      Err(rr) => {
+        log::debug!("could not connect to {}: {}", neighbour.addr, rr);
+        async_std::task::sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+        continue;
+      }
+    };
+    backoff = MIN_BACKOFF;
+    log::info!("connected to {} at {}", neighbour.id, neighbour.addr);
+    // the neighbour might have forgotten everything about this server
+    for o in server.announces().await {
+      if o.nexthop == neighbour.id {
+        peers.send(o.nexthop, o.message);
+      }
+    }
+    let rr = match send_all(&peers, stream, &neighbour.id, &queue, &mut pending).await {
+      Ok(()) => return,
+      Err(rr) => rr,
+    };
+    log::warn!("link to {} lost: {}", neighbour.id, rr);
+    // the server versions the withdrawal of its own link
+    let withdrawal = ServerMessage::Withdraw {
+      route: vec![neighbour.id],
+      signature: Vec::new(),
+      version: 0,
+    };
+    peers.forward(server.handle_server_message(withdrawal).await);
+  }
+}
+
+/// returns when the queue is closed, or with the error that broke the connection
+/// a message that could not be written is left in pending
+async fn send_all(
+  peers: &Peers,
+  mut stream: TcpStream,
+  neighbour: &ServerId,
+  queue: &Receiver<ServerMessage>,
+  pending: &mut Option<ServerMessage>,
+) -> anyhow::Result<()> {
+  introduce(peers, &mut stream, neighbour).await?;
+  loop {
+    let message = match pending.take() {
+      Some(m) => m,
+      None => match queue.recv().await {
+        Ok(m) => m,
+        Err(_) => return Ok(()),
+      },
+    };
+    let mut frame = Vec::new();
+    if let Err(rr) = encode::server(&mut frame, &message) {
+      log::error!("could not encode a message for {}: {}", neighbour, rr);
+      continue;
+    }
+    if frame.len() > MAX_FRAME {
+      log::error!(
+        "dropping a message of {} bytes for {}",
+        frame.len(),
+        neighbour
+      );
+      continue;
+    }
+    if let Err(rr) = write_frame(&mut stream, &frame).await {
+      *pending = Some(message);
+      return Err(rr);
+    }
+  }
+}
+
+async fn write_frame<W: Write + Unpin>(w: &mut W, frame: &[u8]) -> anyhow::Result<()> {
+  anyhow::ensure!(frame.len() <= MAX_FRAME, "frame too large: {}", frame.len());
+  w.write_all(&(frame.len() as u32).to_le_bytes()).await?;
+  w.write_all(frame).await?;
+  w.flush().await?;
+  Ok(())
+}
+
+async fn read_frame<R: Read + Unpin>(r: &mut R) -> anyhow::Result<Vec<u8>> {
+  let mut len = [0u8; 4];
+  r.read_exact(&mut len).await?;
+  let len = u32::from_le_bytes(len) as usize;
+  anyhow::ensure!(len <= MAX_FRAME, "frame too large: {}", len);
+  let mut frame = vec![0u8; len];
+  r.read_exact(&mut frame).await?;
+  Ok(frame)
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use async_std::io::Cursor;
+
+  #[test]
+  fn neighbours() {
+    let n: Neighbour = "732037af-d384-4d93-ab4e-ebaf64de871b@127.0.0.1:4667"
+      .parse()
+      .unwrap();
+    assert_eq!(
+      n.id,
+      uuid::uuid!("732037af-d384-4d93-ab4e-ebaf64de871b").into()
+    );
+    assert_eq!(n.addr, "127.0.0.1:4667".parse().unwrap());
+    assert!("127.0.0.1:4667".parse::<Neighbour>().is_err());
+    assert!("not-a-uuid@127.0.0.1:4667".parse::<Neighbour>().is_err());
+  }
+
+  fn peers(
+    local: ServerId,
+    neighbour: &str,
+    key: &SigningKey,
+    trusted: Option<VerifyingKey>,
+  ) -> Peers {
+    let neighbour: Neighbour = neighbour.parse().unwrap();
+    let auth = LinkAuth {
+      key: key.clone(),
+      trusted: trusted.map(|k| (neighbour.id, k)).into_iter().collect(),
+      require_signatures: false,
+    };
+    Peers::new(local, &[neighbour], auth).0
+  }
+
+  /// checks the answer of client to a challenge of server, when client connects from the address
+  fn handshake(server: &Peers, client: &Peers, from: IpAddr) -> anyhow::Result<()> {
+    let challenge = signing::challenge();
+    let answer = signing::sign_link(&client.auth.key, &challenge, &client.local, &server.local);
+    server.check_link(&client.local, from, &challenge, &answer)
+  }
+
+  #[test]
+  fn authentication() {
+    let a = "732037af-d384-4d93-ab4e-ebaf64de871b";
+    let b = "27293ea0-23c5-49e3-97ba-9d9337c1f414";
+    let (ida, idb): (ServerId, ServerId) = (
+      a.parse::<uuid::Uuid>().unwrap().into(),
+      b.parse::<uuid::Uuid>().unwrap().into(),
+    );
+    let (ka, kb) = (signing::generate(), signing::generate());
+    let localhost = IpAddr::from([127, 0, 0, 1]);
+    let remote = IpAddr::from([10, 0, 0, 1]);
+    let client = peers(ida, &format!("{}@127.0.0.1:4667", b), &ka, None);
+
+    // with a trusted key, the address does not matter
+    let server = peers(
+      idb,
+      &format!("{}@127.0.0.1:4667", a),
+      &kb,
+      Some(ka.verifying_key()),
+    );
+    assert!(handshake(&server, &client, remote).is_ok());
+    // but the answer must be to this challenge, for this server
+    let challenge = signing::challenge();
+    let answer = signing::sign_link(&ka, &challenge, &ida, &idb);
+    assert!(server
+      .check_link(&ida, remote, &signing::challenge(), &answer)
+      .is_err());
+    let answer = signing::sign_link(&ka, &challenge, &ida, &ida);
+    assert!(server
+      .check_link(&ida, remote, &challenge, &answer)
+      .is_err());
+    let server = peers(
+      idb,
+      &format!("{}@127.0.0.1:4667", a),
+      &kb,
+      Some(kb.verifying_key()),
+    );
+    assert!(handshake(&server, &client, localhost).is_err());
+
+    // without, it must be the one of the neighbour
+    let mut server = peers(idb, &format!("{}@10.0.0.1:4667", a), &kb, None);
+    assert!(handshake(&server, &client, remote).is_ok());
+    assert!(handshake(&server, &client, localhost).is_err());
+    server.auth.require_signatures = true;
+    assert!(handshake(&server, &client, remote).is_err());
+
+    // and servers that are not neighbours are refused
+    let server = peers(idb, &format!("{}@127.0.0.1:4667", b), &kb, None);
+    assert!(handshake(&server, &client, localhost).is_err());
+  }
+
+  #[test]
+  fn frames() {
+    async_std::task::block_on(async {
+      let mut out = Cursor::new(Vec::new());
+      write_frame(&mut out, b"first").await.unwrap();
+      write_frame(&mut out, b"").await.unwrap();
+      write_frame(&mut out, b"third").await.unwrap();
+      let data = out.into_inner();
+      assert_eq!(&data[..9], b"\x05\x00\x00\x00first");
+
+      let mut rd = Cursor::new(data);
+      assert_eq!(read_frame(&mut rd).await.unwrap(), b"first");
+      assert_eq!(read_frame(&mut rd).await.unwrap(), b"");
+      assert_eq!(read_frame(&mut rd).await.unwrap(), b"third");
+      assert!(read_frame(&mut rd).await.is_err());
+
+      // truncated, and too large
+      let mut rd = Cursor::new(b"\x05\x00\x00\x00fir".to_vec());
+      assert!(read_frame(&mut rd).await.is_err());
+      let mut rd = Cursor::new(u32::MAX.to_le_bytes().to_vec());
+      assert!(read_frame(&mut rd).await.is_err());
+    })
+  }
+}